@@ -0,0 +1,267 @@
+//! Micro benchmarks for the RESP parser and the cache, to get before and after numbers when
+//! redesigning either. It's an example since the manifest can't pull in a benchmark framework.
+//!
+//! cargo run --release --example bench -- [--time seconds] [filter ...]
+//!
+//! Each benchmark runs for the given time, one second by default, after a short warmup. Only
+//! benchmarks whose name contains one of the filters are run.
+
+use redis_starter_rust::{
+    cache::Cache,
+    resp_type::{Protocol, RespParser, RespType},
+};
+
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Barrier,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Number of distinct keys used by the cache benchmarks.
+const KEYS: usize = 10_000;
+
+struct Bench {
+    time: Duration,
+    filters: Vec<String>,
+}
+
+impl Bench {
+    fn enabled(&self, name: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|filter| name.contains(filter))
+    }
+
+    /// Run `op` in a loop on one thread and report the time per call.
+    fn run(&self, name: &str, mut op: impl FnMut(u64)) {
+        if !self.enabled(name) {
+            return;
+        }
+
+        let warmup = Instant::now();
+        let mut i = 0;
+        while warmup.elapsed() < self.time / 10 {
+            op(i);
+            i += 1;
+        }
+
+        let start = Instant::now();
+        let mut ops = 0;
+        // Check the clock every batch so reading it doesn't dominate fast operations.
+        while start.elapsed() < self.time {
+            for _ in 0..100 {
+                op(ops);
+                ops += 1;
+            }
+        }
+
+        report(name, ops, start.elapsed());
+    }
+
+    /// Run `op` on `threads` threads at once and report the combined throughput.
+    fn run_threads(&self, name: &str, threads: usize, op: impl Fn(usize, u64) + Sync) {
+        if !self.enabled(name) {
+            return;
+        }
+
+        let stop = AtomicBool::new(false);
+        let total = AtomicU64::new(0);
+        let barrier = Barrier::new(threads + 1);
+
+        let elapsed = thread::scope(|scope| {
+            for thread in 0..threads {
+                let (op, stop, total, barrier) = (&op, &stop, &total, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+
+                    let mut ops = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        for _ in 0..100 {
+                            op(thread, ops);
+                            ops += 1;
+                        }
+                    }
+
+                    total.fetch_add(ops, Ordering::Relaxed);
+                });
+            }
+
+            barrier.wait();
+            let start = Instant::now();
+            thread::sleep(self.time);
+            stop.store(true, Ordering::Relaxed);
+
+            start.elapsed()
+        });
+
+        report(name, total.load(Ordering::Relaxed), elapsed);
+    }
+}
+
+fn report(name: &str, ops: u64, elapsed: Duration) {
+    let per_sec = ops as f64 / elapsed.as_secs_f64();
+    let ns_per_op = elapsed.as_nanos() as f64 / ops as f64;
+
+    println!("{name:<40} {ns_per_op:>10.1} ns/op {per_sec:>14.0} ops/s");
+}
+
+fn bench_resp(bench: &Bench) {
+    let set = RespType::array(vec!["SET".into(), "key:1234".into(), "x".repeat(64).into()]);
+    let set_bytes = set.to_bytes(Protocol::Resp2);
+
+    // Many commands in one read, like a client pipelining.
+    let pipeline = set_bytes.repeat(100);
+
+    let nested = RespType::array(
+        (0..100)
+            .map(|i| RespType::array(vec![i.into(), format!("value:{i}").into()]))
+            .collect(),
+    );
+    let nested_bytes = nested.to_bytes(Protocol::Resp2);
+
+    bench.run("resp/parse/command", |_| {
+        let mut parser = RespParser::new();
+        parser.feed(&set_bytes);
+        black_box(parser.next_frame().unwrap());
+    });
+
+    bench.run("resp/parse/pipeline-100", |_| {
+        let mut parser = RespParser::new();
+        parser.feed(&pipeline);
+        while let Some(frame) = parser.next_frame().unwrap() {
+            black_box(frame);
+        }
+    });
+
+    bench.run("resp/parse/nested-array", |_| {
+        let mut parser = RespParser::new();
+        parser.feed(&nested_bytes);
+        black_box(parser.next_frame().unwrap());
+    });
+
+    // Feeding a byte at a time exercises resuming partial frames.
+    bench.run("resp/parse/byte-at-a-time", |_| {
+        let mut parser = RespParser::new();
+        for byte in &set_bytes {
+            parser.feed(std::slice::from_ref(byte));
+            black_box(parser.next_frame().unwrap());
+        }
+    });
+
+    let mut out = Vec::with_capacity(nested_bytes.len());
+    bench.run("resp/encode/command", |_| {
+        out.clear();
+        set.encode(&mut out, Protocol::Resp2).unwrap();
+        black_box(&out);
+    });
+
+    bench.run("resp/encode/nested-array", |_| {
+        out.clear();
+        nested.encode(&mut out, Protocol::Resp3).unwrap();
+        black_box(&out);
+    });
+}
+
+fn bench_cache(bench: &Bench) {
+    let keys = (0..KEYS).map(|i| format!("key:{i}")).collect::<Vec<_>>();
+    let key = |i: u64| keys[i as usize % KEYS].as_str();
+
+    for shards in [1, 16] {
+        let cache = Cache::new(shards);
+        for key in &keys {
+            cache.set(key, "value", None);
+        }
+
+        for threads in [1, 8, 64] {
+            bench.run_threads(
+                &format!("cache/get/shards-{shards}/threads-{threads}"),
+                threads,
+                |thread, i| {
+                    black_box(cache.get_bytes(key(i * 7 + thread as u64)));
+                },
+            );
+
+            bench.run_threads(
+                &format!("cache/set/shards-{shards}/threads-{threads}"),
+                threads,
+                |thread, i| cache.set(key(i * 7 + thread as u64), "value", None),
+            );
+
+            // Nine reads for every write, closer to a typical cache.
+            bench.run_threads(
+                &format!("cache/mixed/shards-{shards}/threads-{threads}"),
+                threads,
+                |thread, i| {
+                    let key = key(i * 7 + thread as u64);
+                    if i % 10 == 0 {
+                        cache.set(key, "value", None);
+                    } else {
+                        black_box(cache.get_bytes(key));
+                    }
+                },
+            );
+        }
+    }
+}
+
+fn bench_expiry(bench: &Bench) {
+    let keys = (0..KEYS).map(|i| format!("key:{i}")).collect::<Vec<_>>();
+    let key = |i: u64| keys[i as usize % KEYS].as_str();
+
+    // Every write has a TTL so the expiry queue grows with each overwrite.
+    let cache = Cache::new(1);
+    bench.run("cache/expiry/set-with-ttl", |i| {
+        cache.set(key(i), "value", Some(Duration::from_secs(60)));
+    });
+
+    // Keys expire almost right away, so reads keep finding expired keys and removing them while
+    // the eviction loop competes for the shard.
+    let cache = Cache::new(4);
+    for threads in [1, 8] {
+        bench.run_threads(
+            &format!("cache/expiry/churn/threads-{threads}"),
+            threads,
+            |thread, i| {
+                let key = key(i * 7 + thread as u64);
+                if i % 2 == 0 {
+                    cache.set(key, "value", Some(Duration::from_millis(1)));
+                } else {
+                    black_box(cache.get_bytes(key));
+                }
+            },
+        );
+    }
+}
+
+fn main() {
+    let mut bench = Bench {
+        time: Duration::from_secs(1),
+        filters: Vec::new(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--time" => {
+                let seconds = args.next().and_then(|s| s.parse::<f64>().ok());
+                let Some(seconds) = seconds.filter(|s| *s > 0.0) else {
+                    eprintln!("--time expects a positive number of seconds");
+                    std::process::exit(1);
+                };
+
+                bench.time = Duration::from_secs_f64(seconds);
+            }
+            _ => bench.filters.push(arg),
+        }
+    }
+
+    if cfg!(debug_assertions) {
+        eprintln!("warning: running a debug build, use --release for meaningful numbers");
+    }
+
+    bench_resp(&bench);
+    bench_cache(&bench);
+    bench_expiry(&bench);
+}
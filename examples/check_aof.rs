@@ -0,0 +1,69 @@
+//! Validate an append-only file like `redis-check-aof`, reporting the first corrupt command. With
+//! `--fix` the file is truncated to the last valid command so a crash in the middle of a write
+//! doesn't leave it unloadable. It's an example rather than a binary so `cargo run` keeps starting
+//! the server.
+//!
+//! cargo run --example check_aof -- [--fix] <file.aof>
+
+use redis_starter_rust::aof::{self, Report};
+
+use std::fs::OpenOptions;
+
+fn main() {
+    let mut fix = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            _ => path = Some(arg),
+        }
+    }
+
+    let Some(path) = path else {
+        eprintln!("usage: check_aof [--fix] <file.aof>");
+        std::process::exit(1);
+    };
+
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not read {path}: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let size = data.len();
+    let mut report = Report::default();
+    let result = aof::check(&data.into(), &mut report);
+
+    println!(
+        "AOF analyzed: size={size}, ok_up_to={}, commands={}, diff={}",
+        report.valid_len,
+        report.commands,
+        size - report.valid_len
+    );
+
+    let Err(err) = result else {
+        println!("AOF is valid");
+        return;
+    };
+
+    println!("{err}");
+    if !fix {
+        println!("AOF is not valid. Use the --fix option to try fixing it.");
+        std::process::exit(1);
+    }
+
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(report.valid_len as u64));
+
+    match truncated {
+        Ok(()) => println!("Successfully truncated AOF to {} bytes", report.valid_len),
+        Err(err) => {
+            eprintln!("Failed to truncate AOF: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Validate an RDB file like `redis-check-rdb`, checking every opcode, length and the checksum, and
+//! print how many keys of each type every database holds. It's an example rather than a binary so
+//! `cargo run` keeps starting the server.
+//!
+//! cargo run --example check_rdb -- <file.rdb>
+
+use redis_starter_rust::rdb::{self, Checksum, Report};
+
+fn print_report(report: &Report) {
+    println!("RDB version {}", report.version);
+    for (key, value) in &report.aux {
+        println!("[info] {key} = '{value}'");
+    }
+
+    for (db, census) in &report.databases {
+        println!("db{db}: keys={} expires={}", census.keys(), census.expires);
+
+        for (name, keys) in &census.types {
+            println!("  {name:<8} {keys:>10}");
+        }
+    }
+}
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: check_rdb <file.rdb>");
+        std::process::exit(1);
+    };
+
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not read {path}: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Checking RDB file {path}");
+
+    let mut report = Report::default();
+    let result = rdb::check(&data, &mut report);
+    print_report(&report);
+
+    match result {
+        Ok(()) => {
+            match report.checksum {
+                Checksum::Verified => println!("Checksum OK"),
+                Checksum::Disabled => println!("Checksum disabled, not verified"),
+                Checksum::Absent => println!("No checksum in this version"),
+            }
+
+            println!("RDB looks OK!");
+        }
+        Err(err) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
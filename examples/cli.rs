@@ -0,0 +1,146 @@
+//! A small redis-cli like client. It's an example rather than a binary so `cargo run` keeps
+//! starting the server.
+//!
+//! cargo run --example cli -- [-h host] [-p port] [--pipe] [command [arg ...]]
+//!
+//! Without a command an interactive prompt is started. With `--pipe` stdin is streamed to the
+//! server for mass inserts like `redis-cli --pipe`, while replies are read in the background.
+//! Only errors and a final summary are printed. The input is sent as is if it's already in RESP,
+//! otherwise every line is sent as a command.
+
+use redis_starter_rust::{
+    client::{format_reply, split_args, Client},
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::{
+    io::{BufRead, Read, Write},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let mut host = "127.0.0.1".to_string();
+    let mut port = "6379".to_string();
+    let mut pipe = false;
+    let mut command = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" if command.is_empty() => host = args.next().unwrap_or(host),
+            "-p" if command.is_empty() => port = args.next().unwrap_or(port),
+            "--pipe" if command.is_empty() => pipe = true,
+            _ => command.push(arg),
+        }
+    }
+
+    let addr = format!("{host}:{port}");
+    let mut client = match Client::connect(&addr) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Could not connect to {addr}: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let result = if pipe {
+        run_pipe(&mut client)
+    } else if !command.is_empty() {
+        run_command(&mut client, &command)
+    } else {
+        run_interactive(&mut client, &addr)
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn run_command(client: &mut Client, command: &[String]) -> Result<(), RedisError> {
+    let reply = client.command(command)?;
+    println!("{}", format_reply(&reply, 0));
+
+    Ok(())
+}
+
+fn run_pipe(client: &mut Client) -> Result<(), RedisError> {
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+
+    // The last reply is recognized by echoing a marker no command in the input would reply with.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    let marker = format!("pipe-end-{nanos:x}-{}", std::process::id());
+
+    // Replies are read while sending so neither side blocks on a full socket buffer.
+    let mut reader = client.try_clone()?;
+    let replies = thread::spawn({
+        let marker = marker.clone();
+        move || {
+            let (mut replies, mut errors) = (0u64, 0u64);
+            loop {
+                match reader.read_reply()? {
+                    RespType::BulkString(_, data) if data == marker.as_bytes() => break,
+                    reply @ (RespType::SimpleError(_) | RespType::BulkError(..)) => {
+                        println!("{}", format_reply(&reply, 0));
+                        errors += 1;
+                    }
+                    _ => (),
+                }
+
+                replies += 1;
+            }
+
+            Ok::<_, RedisError>((replies, errors))
+        }
+    });
+
+    if input.first() == Some(&b'*') {
+        client.send_raw(&input)?;
+    } else {
+        for line in String::from_utf8_lossy(&input).lines() {
+            let args = split_args(line);
+            if !args.is_empty() {
+                client.send(&args)?;
+            }
+        }
+    }
+
+    client.send(&["ECHO", &marker])?;
+    eprintln!("All data transferred. Waiting for the last reply...");
+
+    let (replies, errors) = replies.join().expect("reply reader panicked")?;
+    eprintln!("errors: {errors}, replies: {replies}");
+
+    if errors > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn run_interactive(client: &mut Client, addr: &str) -> Result<(), RedisError> {
+    let stdin = std::io::stdin();
+    let mut line = String::new();
+
+    loop {
+        print!("{addr}> ");
+        std::io::stdout().flush()?;
+
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let args = split_args(&line);
+        match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+            None => continue,
+            Some("quit") | Some("exit") => return Ok(()),
+            _ => run_command(client, &args)?,
+        }
+    }
+}
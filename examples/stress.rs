@@ -0,0 +1,366 @@
+//! A load generator opening many connections that send a mix of SET and GET commands, reporting
+//! throughput and a latency histogram. It's an example rather than a binary so `cargo run` keeps
+//! starting the server.
+//!
+//! cargo run --release --example stress -- [options]
+//!
+//!   -h host          server host (127.0.0.1)
+//!   -p port          server port (6379)
+//!   --embedded       start a server in the process instead of connecting to one
+//!   --shards n       cache shards for the embedded server (16)
+//!   -c connections   concurrent connections, each on its own thread (50)
+//!   -d seconds       how long to run (10)
+//!   -P pipeline      commands sent per round trip (1)
+//!   -r sets:gets     ratio of SET to GET commands (1:9)
+//!   -k keys          size of the keyspace (10000)
+//!   -s bytes         size of SET values (32)
+
+use redis_starter_rust::{
+    client::Client,
+    error::RedisError,
+    resp_type::{Protocol, RespType},
+    server::Server,
+};
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+struct Options {
+    addr: String,
+    embedded: bool,
+    shards: u64,
+    connections: usize,
+    duration: Duration,
+    pipeline: usize,
+    sets: u64,
+    gets: u64,
+    keys: u64,
+    value_size: usize,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut host = "127.0.0.1".to_string();
+        let mut port = "6379".to_string();
+        let mut options = Self {
+            addr: String::new(),
+            embedded: false,
+            shards: 16,
+            connections: 50,
+            duration: Duration::from_secs(10),
+            pipeline: 1,
+            sets: 1,
+            gets: 9,
+            keys: 10_000,
+            value_size: 32,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} expects a value"));
+            let number = |value: String| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{arg} expects a number, got '{value}'"))
+            };
+
+            match arg.as_str() {
+                "-h" => host = value()?,
+                "-p" => port = value()?,
+                "--embedded" => options.embedded = true,
+                "--shards" => options.shards = number(value()?)?,
+                "-c" => options.connections = number(value()?)?.max(1) as usize,
+                "-d" => options.duration = Duration::from_secs(number(value()?)?),
+                "-P" => options.pipeline = number(value()?)?.max(1) as usize,
+                "-k" => options.keys = number(value()?)?.max(1),
+                "-s" => options.value_size = number(value()?)? as usize,
+                "-r" => {
+                    let ratio = value()?;
+                    let (sets, gets) = ratio
+                        .split_once(':')
+                        .ok_or(format!("-r expects sets:gets, got '{ratio}'"))?;
+                    options.sets = number(sets.to_string())?;
+                    options.gets = number(gets.to_string())?;
+
+                    if options.sets + options.gets == 0 {
+                        return Err("-r needs at least one SET or GET".to_string());
+                    }
+                }
+                _ => return Err(format!("unknown option '{arg}'")),
+            }
+        }
+
+        options.addr = format!("{host}:{port}");
+
+        Ok(options)
+    }
+}
+
+/// Latencies in microseconds bucketed with 3 bits of precision below each power of two, so the
+/// error of any reported value is at most 12.5% while the histogram stays small and mergeable.
+#[derive(Clone)]
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            counts: vec![0; (64 - SUB_BITS as usize + 1) << SUB_BITS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+
+        let shift = 63 - value.leading_zeros() - SUB_BITS;
+        (((shift + 1) as u64) << SUB_BITS | (value >> shift) & (SUB_BUCKETS - 1)) as usize
+    }
+
+    /// The largest value that falls in the bucket.
+    fn upper_bound(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            return index;
+        }
+
+        let shift = (index >> SUB_BITS) - 1;
+        let mantissa = index & (SUB_BUCKETS - 1) | SUB_BUCKETS;
+        ((mantissa + 1) << shift) - 1
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.counts[Self::index(micros)] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    fn percentile(&self, percentile: f64) -> u64 {
+        let target = ((self.total as f64 * percentile / 100.0).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Self::upper_bound(index).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Print the counts per power of two with a bar relative to the largest one.
+    fn print(&self) {
+        let mut rows = Vec::<(u64, u64)>::new();
+        for (index, count) in self.counts.iter().enumerate() {
+            let bound = Self::upper_bound(index).next_power_of_two();
+            match rows.last_mut() {
+                Some((last, total)) if *last == bound => *total += count,
+                _ => rows.push((bound, *count)),
+            }
+        }
+
+        let first = rows.iter().position(|(_, count)| *count > 0).unwrap_or(0);
+        let last = rows.iter().rposition(|(_, count)| *count > 0).unwrap_or(0);
+        let widest = rows
+            .iter()
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(1)
+            .max(1);
+
+        for (bound, count) in &rows[first..=last] {
+            let bar = "#".repeat((count * 50 / widest) as usize);
+            println!("  <= {:>9} us {count:>10} {bar}", bound);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Results {
+    latencies: Histogram,
+    errors: u64,
+}
+
+/// xorshift64, good enough to pick commands and keys without a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn run_connection(addr: SocketAddr, id: usize, options: &Options, deadline: Instant) -> Results {
+    let mut results = Results {
+        latencies: Histogram::new(),
+        errors: 0,
+    };
+
+    let mut client = match Client::connect(addr) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("connection {id} failed to connect: {err}");
+            results.errors += 1;
+            return results;
+        }
+    };
+
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (id as u64 + 1));
+    let value = "x".repeat(options.value_size);
+    let mut batch = Vec::new();
+
+    while Instant::now() < deadline {
+        // The whole pipeline is written at once like a client flushing queued commands.
+        batch.clear();
+        for _ in 0..options.pipeline {
+            let key = format!("key:{}", rng.next() % options.keys);
+            let command = if rng.next() % (options.sets + options.gets) < options.sets {
+                vec!["SET".into(), key.into(), value.as_str().into()]
+            } else {
+                vec!["GET".into(), key.into()]
+            };
+
+            batch.extend(RespType::array(command).to_bytes(Protocol::Resp2));
+        }
+
+        let start = Instant::now();
+        let sent = client.send_raw(&batch).and_then(|_| {
+            for _ in 0..options.pipeline {
+                if let RespType::SimpleError(_) = client.read_reply()? {
+                    results.errors += 1;
+                }
+
+                results.latencies.record(start.elapsed());
+            }
+
+            Ok::<_, RedisError>(())
+        });
+
+        if let Err(err) = sent {
+            eprintln!("connection {id} failed: {err}");
+            results.errors += 1;
+            break;
+        }
+    }
+
+    results
+}
+
+fn main() {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let embedded = options.embedded.then(|| {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .shards(options.shards)
+            .build()
+            .expect("failed to start the embedded server");
+
+        server.start()
+    });
+
+    let addr = match &embedded {
+        Some(handle) => handle.local_addr(),
+        None => match options.addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            _ => {
+                eprintln!("Could not resolve {}", options.addr);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    println!(
+        "{} connections to {addr} for {}s, pipeline {}, SET:GET {}:{}, {} keys, {} byte values",
+        options.connections,
+        options.duration.as_secs(),
+        options.pipeline,
+        options.sets,
+        options.gets,
+        options.keys,
+        options.value_size,
+    );
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+
+    let results = thread::scope(|scope| {
+        let handles = (0..options.connections)
+            .map(|id| {
+                let options = &options;
+                scope.spawn(move || run_connection(addr, id, options, deadline))
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("connection thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = Histogram::new();
+    let mut errors = 0;
+    for result in &results {
+        latencies.merge(&result.latencies);
+        errors += result.errors;
+    }
+
+    println!();
+    println!(
+        "{} requests in {:.2}s, {:.0} requests/s, {errors} errors",
+        latencies.total,
+        elapsed.as_secs_f64(),
+        latencies.total as f64 / elapsed.as_secs_f64(),
+    );
+
+    println!();
+    println!("latency (us)");
+    for percentile in [50.0, 90.0, 99.0, 99.9] {
+        println!(
+            "  p{percentile:<5} {:>10}",
+            latencies.percentile(percentile)
+        );
+    }
+    println!("  max    {:>10}", latencies.max);
+
+    println!();
+    latencies.print();
+
+    if let Some(handle) = embedded {
+        handle.shutdown();
+        handle.join();
+    }
+}
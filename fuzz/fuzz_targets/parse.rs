@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::resp_type::{Protocol, RespParser, RespType};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = RespType::parse(&mut std::io::Cursor::new(data));
+
+    // Feed the input in two halves to exercise frames split across reads.
+    let mut parser = RespParser::new();
+    let (head, tail) = data.split_at(data.len() / 2);
+
+    for chunk in [head, tail] {
+        parser.feed(chunk);
+
+        loop {
+            match parser.next_frame() {
+                Ok(Some(frame)) => {
+                    let _ = frame.to_bytes(Protocol::Resp2);
+                    let _ = frame.to_bytes(Protocol::Resp3);
+                }
+                Err(err) if err.is_recoverable() => (),
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
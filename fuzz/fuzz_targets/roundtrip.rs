@@ -0,0 +1,113 @@
+#![no_main]
+
+use libfuzzer_sys::{
+    arbitrary::{self, Arbitrary, Unstructured},
+    fuzz_target,
+};
+use redis_starter_rust::resp_type::{Protocol, RespParser, RespType};
+
+/// Max nesting of generated aggregates, well below the parser's depth limit.
+const MAX_DEPTH: usize = 4;
+
+/// A frame that survives an encode and parse round trip for the protocol it's generated for. The
+/// wrapper is needed since neither the trait nor the type belongs to this crate.
+#[derive(Debug)]
+struct Frame(Protocol, RespType);
+
+impl<'a> Arbitrary<'a> for Frame {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let protocol = if bool::arbitrary(u)? {
+            Protocol::Resp3
+        } else {
+            Protocol::Resp2
+        };
+
+        Ok(Self(protocol, frame(u, protocol, MAX_DEPTH)?))
+    }
+}
+
+/// Text for line based types which can't contain CR or LF.
+fn line(u: &mut Unstructured) -> arbitrary::Result<String> {
+    Ok(String::arbitrary(u)?.replace(['\r', '\n'], " "))
+}
+
+fn aggregate(
+    u: &mut Unstructured,
+    protocol: Protocol,
+    depth: usize,
+) -> arbitrary::Result<Vec<RespType>> {
+    let len = u.int_in_range(0..=4)?;
+    (0..len).map(|_| frame(u, protocol, depth - 1)).collect()
+}
+
+fn frame(u: &mut Unstructured, protocol: Protocol, depth: usize) -> arbitrary::Result<RespType> {
+    let types = match protocol {
+        Protocol::Resp2 => 6,
+        Protocol::Resp3 => 15,
+    };
+
+    let mut kind = u.int_in_range(0..=types - 1)?;
+    // Fall back to null for aggregates once the max depth is reached.
+    if depth == 0 && (kind == 5 || kind >= 11) {
+        kind = 4;
+    }
+
+    Ok(match kind {
+        0 => RespType::SimpleString(line(u)?),
+        1 => RespType::error("ERR", line(u)?),
+        2 => RespType::Integer(i64::arbitrary(u)?),
+        3 => RespType::bulk(Vec::<u8>::arbitrary(u)?),
+        4 => RespType::Null,
+        5 => RespType::array(aggregate(u, protocol, depth)?),
+        6 => RespType::Boolean(bool::arbitrary(u)?),
+        7 => {
+            // NaN is left out since it has many bit patterns but a single encoding.
+            let value = f64::arbitrary(u)?;
+            RespType::Double(if value.is_nan() { 0.0 } else { value })
+        }
+        8 => RespType::big_number(&i128::arbitrary(u)?.to_string())
+            .unwrap_or(RespType::Integer(0)),
+        9 => {
+            let message = format!("ERR {}", String::arbitrary(u)?);
+            RespType::BulkError(message.len(), message)
+        }
+        10 => RespType::verbatim("txt", String::arbitrary(u)?),
+        11 => {
+            let values = aggregate(u, protocol, depth)?;
+            RespType::Set(values.len(), values)
+        }
+        12 | 13 => {
+            let keys = aggregate(u, protocol, depth)?;
+            let pairs = keys
+                .into_iter()
+                .map(|key| Ok((key, frame(u, protocol, depth - 1)?)))
+                .collect::<arbitrary::Result<_>>()?;
+            RespType::map(pairs)
+        }
+        _ => RespType::Attribute(
+            vec![(RespType::SimpleString(line(u)?), frame(u, protocol, depth - 1)?)],
+            Box::new(frame(u, protocol, depth - 1)?),
+        ),
+    })
+}
+
+fuzz_target!(|input: Frame| {
+    let Frame(protocol, frame) = input;
+    let encoded = frame.to_bytes(protocol);
+
+    let parsed = RespType::parse(&mut std::io::Cursor::new(&encoded)).unwrap();
+    assert_eq!(parsed, frame);
+
+    // Feed the frame a byte at a time to exercise every possible split point.
+    let mut parser = RespParser::new();
+    for (i, byte) in encoded.iter().enumerate() {
+        parser.feed(std::slice::from_ref(byte));
+
+        let next = parser.next_frame().unwrap();
+        if i + 1 < encoded.len() {
+            assert_eq!(next, None);
+        } else {
+            assert_eq!(next, Some(frame.clone()));
+        }
+    }
+});
@@ -0,0 +1,542 @@
+//! The append-only file, a log of every write command in the RESP format clients send them in.
+//!
+//! Commands are appended by a thread of their own so clients only wait for the disk with the
+//! `always` fsync policy. BGREWRITEAOF hands that thread a snapshot of the keyspace, which it
+//! replaces the file with before appending anything else, so no write is lost or applied twice.
+
+use crate::{
+    cache::{Expiry, Value},
+    error::RedisError,
+    resp_type::{Limits, Protocol, RespType},
+};
+use bytes::Bytes;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the file is synced with the `everysec` policy.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max number of queued messages written before the file is flushed.
+const MAX_BATCH: usize = 1024;
+
+/// When the file is synced to disk, like `appendfsync` in Redis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write, before its reply is sent.
+    Always,
+    /// Once a second, so a crash loses at most about a second of writes.
+    #[default]
+    EverySec,
+    /// Whenever the operating system decides to.
+    No,
+}
+
+impl AppendFsync {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        }
+    }
+}
+
+impl FromStr for AppendFsync {
+    type Err = RedisError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" => Ok(Self::No),
+            _ => Err(RedisError::Other(format!(
+                "invalid appendfsync policy '{s}'"
+            ))),
+        }
+    }
+}
+
+type Snapshot = Vec<(String, Value, Option<Expiry>)>;
+
+enum Message {
+    Append(Bytes),
+    /// Acknowledged once everything appended before it is synced to disk.
+    Sync(mpsc::Sender<()>),
+    /// Replace the file with the commands recreating the keys.
+    Rewrite(Snapshot),
+}
+
+#[derive(Debug, Default)]
+struct RewriteState {
+    in_progress: AtomicBool,
+    last_failed: AtomicBool,
+}
+
+/// The open append-only file and the thread writing it.
+#[derive(Debug)]
+pub(crate) struct AppendOnlyFile {
+    fsync: AppendFsync,
+    sender: mpsc::Sender<Message>,
+    state: Arc<RewriteState>,
+}
+
+impl AppendOnlyFile {
+    /// Open the file at `path` for appending, creating it if needed.
+    pub(crate) fn open(path: &Path, fsync: AppendFsync) -> Result<Self, RedisError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::new(RewriteState::default());
+
+        let writer = Writer {
+            path: path.to_path_buf(),
+            fsync,
+            file: BufWriter::new(file),
+            state: state.clone(),
+        };
+        thread::Builder::new()
+            .name("aof-writer".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self {
+            fsync,
+            sender,
+            state,
+        })
+    }
+
+    /// Append a write command. With the `always` policy this waits until it's synced to disk.
+    pub(crate) fn append(&self, frame: &RespType) {
+        let data = Bytes::from(frame.to_bytes(Protocol::Resp2));
+        if self.sender.send(Message::Append(data)).is_err() {
+            return;
+        }
+
+        if self.fsync == AppendFsync::Always {
+            let (ack, synced) = mpsc::channel();
+            if self.sender.send(Message::Sync(ack)).is_ok() {
+                let _ = synced.recv();
+            }
+        }
+    }
+
+    /// Replace the file with the commands recreating `snapshot` from the writer thread. Commands
+    /// appended afterwards go to the new file, so the snapshot must be taken while no other write
+    /// can happen.
+    pub(crate) fn rewrite(&self, snapshot: Snapshot) -> Result<(), RedisError> {
+        if self.state.in_progress.swap(true, Ordering::Relaxed) {
+            return Err(RedisError::Other(
+                "Background append only file rewriting already in progress".to_string(),
+            ));
+        }
+
+        self.sender
+            .send(Message::Rewrite(snapshot))
+            .map_err(|_| RedisError::Other("the append only file is closed".to_string()))
+    }
+
+    /// The lines to add to the persistence section of INFO.
+    pub(crate) fn info(&self) -> String {
+        let status = match self.state.last_failed.load(Ordering::Relaxed) {
+            true => "err",
+            false => "ok",
+        };
+
+        format!(
+            "aof_enabled:1\r\naof_rewrite_in_progress:{}\r\naof_last_bgrewrite_status:{status}\r\n",
+            u8::from(self.state.in_progress.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    fsync: AppendFsync,
+    file: BufWriter<File>,
+    state: Arc<RewriteState>,
+}
+
+impl Writer {
+    /// Write messages until the file is dropped, flushing whenever the queue runs dry.
+    fn run(mut self, receiver: mpsc::Receiver<Message>) {
+        let mut last_sync = Instant::now();
+        let mut unsynced = false;
+
+        loop {
+            // With unsynced writes the `everysec` policy only waits until the next sync is due.
+            let first = match (self.fsync, unsynced) {
+                (AppendFsync::EverySec, true) => {
+                    match receiver.recv_timeout(SYNC_INTERVAL.saturating_sub(last_sync.elapsed())) {
+                        Ok(message) => Some(message),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                _ => match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                },
+            };
+
+            let mut waiting = Vec::new();
+            for message in first.into_iter().chain(receiver.try_iter().take(MAX_BATCH)) {
+                match message {
+                    Message::Append(data) => {
+                        if let Err(err) = self.file.write_all(&data) {
+                            tracing::error!(%err, "failed to write to the append only file");
+                        }
+                        unsynced = true;
+                    }
+                    Message::Sync(ack) => waiting.push(ack),
+                    Message::Rewrite(snapshot) => {
+                        self.rewrite(snapshot);
+                        unsynced = false;
+                    }
+                }
+            }
+
+            let sync = match self.fsync {
+                AppendFsync::Always => unsynced || !waiting.is_empty(),
+                AppendFsync::EverySec => unsynced && last_sync.elapsed() >= SYNC_INTERVAL,
+                AppendFsync::No => false,
+            };
+
+            if let Err(err) = self.flush(sync) {
+                tracing::error!(%err, "failed to flush the append only file");
+            }
+            if sync {
+                last_sync = Instant::now();
+                unsynced = false;
+            }
+
+            for ack in waiting {
+                let _ = ack.send(());
+            }
+        }
+
+        if let Err(err) = self.flush(true) {
+            tracing::error!(%err, "failed to flush the append only file");
+        }
+    }
+
+    fn flush(&mut self, sync: bool) -> io::Result<()> {
+        self.file.flush()?;
+        if sync {
+            self.file.get_ref().sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn rewrite(&mut self, snapshot: Snapshot) {
+        let result = self
+            .flush(false)
+            .and_then(|()| write_rewritten(&self.path, snapshot));
+
+        let failed = match result {
+            Ok(file) => {
+                tracing::info!(path = %self.path.display(), "background append only file rewriting done");
+                self.file = file;
+                false
+            }
+            Err(err) => {
+                tracing::error!(path = %self.path.display(), %err, "background append only file rewriting failed");
+                true
+            }
+        };
+
+        self.state.last_failed.store(failed, Ordering::Relaxed);
+        self.state.in_progress.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Write the commands recreating the keys to a temporary file and rename it over `path`, returning
+/// the new file to append to.
+fn write_rewritten(path: &Path, snapshot: Snapshot) -> io::Result<BufWriter<File>> {
+    let temp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+    let mut file = BufWriter::new(File::create(&temp)?);
+
+    for (key, value, expiry) in snapshot {
+        let mut commands = commands(&value, &key);
+        if let Some(expiry) = expiry {
+            commands.push(RespType::array(vec![
+                "PEXPIREAT".into(),
+                key.as_str().into(),
+                expiry.unix_millis().to_string().into(),
+            ]));
+        }
+
+        for command in commands {
+            file.write_all(&command.to_bytes(Protocol::Resp2))?;
+        }
+    }
+
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&temp, path)?;
+
+    Ok(file)
+}
+
+/// The commands that recreate `value` under `key`.
+fn commands(value: &Value, key: &str) -> Vec<RespType> {
+    match value {
+        Value::String(_) | Value::Int(_) | Value::Inline(_) => {
+            vec![RespType::array(vec![
+                "SET".into(),
+                key.into(),
+                RespType::bulk(value.as_string().unwrap_or_default()),
+            ])]
+        }
+        Value::Json(json) => json.commands(key),
+        Value::Stream(stream) => stream.commands(key),
+        Value::List(list) => list.commands(key),
+        Value::Hash(hash) => hash.commands(key),
+        Value::Bloom(filter) => filter.commands(key),
+    }
+}
+
+/// The problems [`check`] reports that can only be found at the end of a file.
+const TRUNCATED: &[&str] = &[
+    "truncated annotation",
+    "truncated command",
+    "MULTI without EXEC",
+];
+
+/// What was found in an append-only file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub commands: u64,
+    /// The length of the file up to the end of the last complete command outside a transaction,
+    /// which is what it can be truncated to if the rest is corrupt.
+    pub valid_len: usize,
+}
+
+/// Check that `data` only holds complete commands, filling in `report` as it's read. Errors include
+/// the offset where the first invalid command starts.
+pub fn check(data: &Bytes, report: &mut Report) -> Result<(), RedisError> {
+    let error = |offset: usize, message: &dyn std::fmt::Display| {
+        RedisError::Other(format!("{message} at offset {offset}"))
+    };
+
+    let limits = Limits::default();
+    let mut pos = 0;
+    // Where the transaction that hasn't been executed yet starts.
+    let mut multi = None;
+
+    while pos < data.len() {
+        let start = pos;
+
+        // Annotations like `#TS:1700000000` are written on their own lines between commands.
+        if data[pos] == b'#' {
+            let Some(end) = data[pos..].iter().position(|b| *b == b'\n') else {
+                return Err(error(start, &"truncated annotation"));
+            };
+
+            pos += end + 1;
+            if multi.is_none() {
+                report.valid_len = pos;
+            }
+
+            continue;
+        }
+
+        let args = match RespType::decode_from(data, &mut pos, &limits) {
+            Ok(RespType::Array(args)) if !args.is_empty() => args,
+            Ok(_) => return Err(error(start, &"expected a command")),
+            Err(RedisError::Incomplete) => return Err(error(start, &"truncated command")),
+            Err(err) => return Err(error(start, &err)),
+        };
+
+        let RespType::BulkString(_, name) = &args[0] else {
+            return Err(error(start, &"expected a command"));
+        };
+
+        let ends_multi =
+            name.eq_ignore_ascii_case(b"exec") || name.eq_ignore_ascii_case(b"discard");
+        if name.eq_ignore_ascii_case(b"multi") {
+            if multi.is_some() {
+                return Err(error(start, &"nested MULTI"));
+            }
+
+            multi = Some(start);
+        } else if ends_multi && multi.take().is_none() {
+            return Err(error(start, &"EXEC without MULTI"));
+        }
+
+        report.commands += 1;
+        if multi.is_none() {
+            report.valid_len = pos;
+        }
+    }
+
+    match multi {
+        Some(start) => Err(error(start, &"MULTI without EXEC")),
+        None => Ok(()),
+    }
+}
+
+/// How much of `data` can be loaded: all of it, or the complete commands before a command or
+/// transaction that was cut short at the end, like by a crash while it was written. Anything else
+/// that's invalid means the file is corrupt.
+pub(crate) fn loadable_len(data: &Bytes) -> Result<usize, RedisError> {
+    let mut report = Report::default();
+    match check(data, &mut report) {
+        Ok(()) => Ok(data.len()),
+        Err(err)
+            if TRUNCATED
+                .iter()
+                .any(|problem| err.to_string().starts_with(problem)) =>
+        {
+            Ok(report.valid_len)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let args = args.iter().map(|arg| RespType::from(*arg)).collect();
+        RespType::array(args).to_bytes(Protocol::Resp2)
+    }
+
+    fn check(data: &[u8]) -> (Report, Result<(), String>) {
+        let mut report = Report::default();
+        let result = super::check(&Bytes::copy_from_slice(data), &mut report);
+        (report, result.map_err(|err| err.to_string()))
+    }
+
+    #[test]
+    fn test_check() {
+        let mut data = command(&["SET", "a", "1"]);
+        data.extend_from_slice(b"#TS:1700000000\r\n");
+        data.extend(command(&["MULTI"]));
+        data.extend(command(&["SET", "b", "2"]));
+        data.extend(command(&["EXEC"]));
+
+        let (report, result) = check(&data);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            report,
+            Report {
+                commands: 4,
+                valid_len: data.len(),
+            }
+        );
+
+        let valid = data.len();
+        data.extend(command(&["SET", "c", "3"]));
+        data.truncate(data.len() - 3);
+
+        let (report, result) = check(&data);
+        assert_eq!(result, Err(format!("truncated command at offset {valid}")));
+        assert_eq!(report.valid_len, valid);
+    }
+
+    #[test]
+    fn test_loadable_len() {
+        let mut data = command(&["SET", "a", "1"]);
+        let valid = data.len();
+        assert_eq!(loadable_len(&Bytes::from(data.clone())).unwrap(), valid);
+
+        data.extend(command(&["MULTI"]));
+        data.extend(command(&["SET", "b", "2"]));
+        assert_eq!(loadable_len(&Bytes::from(data.clone())).unwrap(), valid);
+
+        // Corruption before the end isn't dropped.
+        let mut corrupt = b"+OK\r\n".to_vec();
+        corrupt.extend(command(&["SET", "a", "1"]));
+        assert!(loadable_len(&Bytes::from(corrupt)).is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let path = std::env::temp_dir().join(format!("rewrite-{}.aof", std::process::id()));
+        let cache = Cache::new(1);
+        for args in [
+            &["SET", "s", "v"][..],
+            &["RPUSH", "l", "a", "b"],
+            &["HSET", "h", "f", "1"],
+            &["XADD", "x", "1-1", "f", "v"],
+            &["XADD", "empty", "MAXLEN", "0", "5-0", "f", "v"],
+            &["JSON.SET", "j", "$", r#"{"a":[1,"b"]}"#],
+            &["BF.MADD", "b", "x", "y"],
+            &["PEXPIREAT", "s", "4102444800000"],
+        ] {
+            let frames = args.iter().map(|a| RespType::from(*a)).collect::<Vec<_>>();
+            crate::command::execute(&crate::command::Command::parse(&frames).unwrap(), &cache);
+        }
+
+        let file = write_rewritten(&path, cache.snapshot().collect()).unwrap();
+        drop(file);
+
+        // Replaying the file recreates every key.
+        let data = Bytes::from(fs::read(&path).unwrap());
+        let restored = Cache::new(1);
+        let mut pos = 0;
+        while pos < data.len() {
+            let RespType::Array(frames) =
+                RespType::decode_from(&data, &mut pos, &Limits::default()).unwrap()
+            else {
+                panic!("expected a command");
+            };
+            let command = crate::command::Command::parse(&frames).unwrap();
+            crate::command::execute(&command, &restored);
+        }
+
+        let keys = |cache: &Cache| {
+            let mut keys = cache
+                .snapshot()
+                .map(|(key, value, expiry)| (key, value, expiry.map(|at| at.unix_millis())))
+                .collect::<Vec<_>>();
+            keys.sort_by(|a, b| a.0.cmp(&b.0));
+            keys
+        };
+        assert_eq!(keys(&restored), keys(&cache));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_check_errors() {
+        let set = command(&["SET", "a", "1"]);
+
+        // A transaction cut short is dropped entirely.
+        let mut data = set.clone();
+        data.extend(command(&["MULTI"]));
+        data.extend(command(&["SET", "b", "2"]));
+        let (report, result) = check(&data);
+        assert_eq!(
+            result,
+            Err(format!("MULTI without EXEC at offset {}", set.len()))
+        );
+        assert_eq!(report.valid_len, set.len());
+
+        let mut data = set.clone();
+        data.extend_from_slice(b"+OK\r\n");
+        data.extend(set.clone());
+        let (report, result) = check(&data);
+        assert_eq!(
+            result,
+            Err(format!("expected a command at offset {}", set.len()))
+        );
+        assert_eq!(report.commands, 1);
+
+        let (_, result) = check(b"*1\r\n$4\r\nEXEC\r\n");
+        assert_eq!(result, Err("EXEC without MULTI at offset 0".to_string()));
+    }
+}
@@ -1,11 +1,110 @@
 // Some good reference for streams
 // https://github.com/thepacketgeek/rust-tcpstream-demo
 
+use redis_starter_rust::{server::Server, service};
+
 fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let server = redis_starter_rust::server::Server::new("127.0.0.1:6379");
-    server.serve_forever();
+    let mut builder = Server::builder().addr("127.0.0.1:6379");
+    let mut load = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().unwrap_or_else(|| {
+                tracing::error!("missing value for '{arg}'");
+                std::process::exit(1);
+            })
+        };
+
+        match arg.as_str() {
+            "--load" => load = Some(value()),
+            "--port" => {
+                let port = value();
+                let Ok(port) = port.parse::<u16>() else {
+                    tracing::error!("invalid port '{port}'");
+                    std::process::exit(1);
+                };
+                builder = builder.addr(format!("127.0.0.1:{port}"));
+            }
+            "--dir" => builder = builder.dir(value()),
+            "--dbfilename" => builder = builder.dbfilename(value()),
+            "--appendonly" => builder = builder.appendonly(value().eq_ignore_ascii_case("yes")),
+            "--appendfilename" => builder = builder.appendfilename(value()),
+            "--appendfsync" => match value().parse() {
+                Ok(fsync) => builder = builder.appendfsync(fsync),
+                Err(err) => {
+                    tracing::error!("{err}");
+                    std::process::exit(1);
+                }
+            },
+            // Either `--replicaof "host port"` like Redis or the host and port as two arguments.
+            "--replicaof" => {
+                let master = value();
+                let (host, port) = match master.split_once(' ') {
+                    Some((host, port)) => (host.to_string(), port.to_string()),
+                    None => (master, value()),
+                };
+
+                let Ok(port) = port.trim().parse() else {
+                    tracing::error!("invalid master port '{port}'");
+                    std::process::exit(1);
+                };
+                builder = builder.replicaof(host, port);
+            }
+            _ => {
+                tracing::error!("unknown option '{arg}'");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Any RDB file in the configured directory, or the append-only file if it's enabled, is loaded
+    // when the server is built.
+    let server = match builder.build() {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!("failed to start server: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    // Commands are replayed before the first client is accepted.
+    if let Some(path) = load {
+        match std::fs::read(&path)
+            .map_err(Into::into)
+            .and_then(|data| server.load(&data))
+        {
+            Ok(summary) => tracing::info!(
+                commands = summary.commands,
+                errors = summary.errors,
+                "loaded {path}"
+            ),
+            Err(err) => {
+                tracing::error!("failed to load {path}: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let handle = server.start();
+
+    // The listener is bound and the data loaded, so a service manager can start routing traffic to
+    // the server.
+    if let Err(err) = service::notify("READY=1") {
+        tracing::warn!(%err, "failed to notify the service manager");
+    }
+    service::start_watchdog(&server);
+
+    if let Err(err) = service::wait_for_shutdown_signal() {
+        tracing::error!(%err, "failed to wait for shutdown signals");
+    }
+
+    tracing::info!("shutting down");
+    let _ = service::notify("STOPPING=1");
+
+    handle.shutdown();
+    handle.join();
 }
@@ -0,0 +1,465 @@
+use crate::{
+    cache::{Cache, KeyLocks},
+    resp_type::RespType,
+    timer::{TimerId, TimerWheel},
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
+};
+
+/// Tries to serve a blocked client once the given key is ready, returning what it got if it could.
+type Serve = Box<dyn FnMut(&str, &Cache) -> Option<Served> + Send>;
+
+/// The reply to a blocking command, and the write it turned into if it changed anything, which is
+/// propagated in place of the command.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Served {
+    pub(crate) reply: RespType,
+    pub(crate) write: Option<RespType>,
+}
+
+impl Served {
+    /// A reply that didn't write anything.
+    pub(crate) fn reply(reply: RespType) -> Self {
+        Self { reply, write: None }
+    }
+}
+
+/// The outcome of a blocking command.
+pub(crate) enum Blocking {
+    Done(Served),
+    /// The client is queued and waits for a key with [`Waiting::wait`].
+    Waiting(Waiting),
+}
+
+/// A client queued by [`BlockedClients::block`].
+pub(crate) struct Waiting {
+    waiter: Arc<Waiter>,
+}
+
+impl Waiting {
+    /// Wait until the client is served, returning `None` if it times out or is unblocked first.
+    /// What serving it wrote is propagated by the write that served it.
+    pub(crate) fn wait(self) -> Option<RespType> {
+        let mut reply = self.waiter.reply.lock().unwrap();
+        while reply.is_none() {
+            reply = self.waiter.done.wait(reply).unwrap();
+        }
+
+        reply.take().flatten()
+    }
+}
+
+struct Waiter {
+    keys: Vec<String>,
+    serve: Mutex<Serve>,
+    /// Unblocks the client when its timeout passes.
+    timer: Option<TimerId>,
+    /// Set once the waiter is done, with `None` if it was unblocked without being served.
+    reply: Mutex<Option<Option<RespType>>>,
+    done: Condvar,
+}
+
+impl Waiter {
+    fn finish(&self, reply: Option<RespType>) {
+        *self.reply.lock().unwrap() = Some(reply);
+        self.done.notify_one();
+    }
+}
+
+/// The number of clients waiting for each key, which the write hook checks before marking a key as
+/// ready. It's apart from [`Waiters`] since the hook runs with a shard locked, while the waiters are
+/// locked first when serving.
+type Watched = Arc<RwLock<HashMap<String, usize>>>;
+
+#[derive(Default)]
+struct Waiters {
+    /// Clients waiting for each key in the order they blocked.
+    by_key: HashMap<String, VecDeque<u64>>,
+    by_client: HashMap<u64, Arc<Waiter>>,
+    watched: Watched,
+}
+
+impl Waiters {
+    fn remove(&mut self, id: u64) -> Option<Arc<Waiter>> {
+        let waiter = self.by_client.remove(&id)?;
+
+        for key in &waiter.keys {
+            if let Some(queue) = self.by_key.get_mut(key) {
+                queue.retain(|waiting| *waiting != id);
+                if queue.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+        self.unwatch(&waiter.keys);
+
+        Some(waiter)
+    }
+
+    fn watch(&self, keys: &[String]) {
+        let mut watched = self.watched.write().unwrap();
+        for key in keys {
+            *watched.entry(key.clone()).or_default() += 1;
+        }
+    }
+
+    fn unwatch(&self, keys: &[String]) {
+        let mut watched = self.watched.write().unwrap();
+        for key in keys {
+            if let Some(count) = watched.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    watched.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Clients blocked until a key they wait for is written to, shared by every blocking command.
+///
+/// Writes only mark keys as ready from the cache's write hook since those run with the shard
+/// locked, and only keys some client waits for. The ready keys are handled by [`BlockedClients::serve_ready`] after each write, which
+/// runs the blocked clients' serve functions in the order they blocked so the client that waited
+/// the longest is served first, like Redis does. Clients are served by the writer's thread while it
+/// still holds its write order locks, so what they write is propagated right after the write that
+/// served them.
+///
+/// Timeouts are scheduled on the server's [`TimerWheel`] which unblocks the client, so a blocked
+/// client's thread just sleeps until it's woken.
+pub(crate) struct BlockedClients {
+    waiters: Arc<Mutex<Waiters>>,
+    ready: Arc<Mutex<Vec<String>>>,
+    timers: Arc<TimerWheel>,
+}
+
+impl std::fmt::Debug for BlockedClients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockedClients")
+            .field("clients", &self.len())
+            .finish()
+    }
+}
+
+impl BlockedClients {
+    /// Create the registry and mark keys clients wait for as ready whenever they're written to in
+    /// `cache`.
+    pub(crate) fn new(cache: &Cache, timers: Arc<TimerWheel>) -> Self {
+        let blocked = Self {
+            waiters: Arc::default(),
+            ready: Arc::default(),
+            timers,
+        };
+
+        let watched = blocked.waiters.lock().unwrap().watched.clone();
+        let ready = blocked.ready.clone();
+        cache.on_write(move |key| {
+            if watched.read().unwrap().contains_key(key) {
+                ready.lock().unwrap().push(key.to_string());
+            }
+        });
+
+        blocked
+    }
+
+    /// Number of blocked clients.
+    pub(crate) fn len(&self) -> usize {
+        self.waiters.lock().unwrap().by_client.len()
+    }
+
+    /// Serve client `id` right away if possible, otherwise queue it until one of `keys` is ready
+    /// and `serve` returns a reply for it. The client should wait with [`Waiting::wait`] without
+    /// holding any locks, since the write serving it needs them. It's unblocked without a reply
+    /// once `timeout` passes, a timeout of `None` blocks forever.
+    pub(crate) fn block(
+        &self,
+        id: u64,
+        cache: &Cache,
+        keys: &[String],
+        timeout: Option<Duration>,
+        mut serve: impl FnMut(&str, &Cache) -> Option<Served> + Send + 'static,
+    ) -> Blocking {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        // Keep the registry locked from the first attempt until the client is queued, and watch
+        // the keys before it, so a key can't become ready in between without the client being
+        // served.
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters.watch(keys);
+
+        if let Some(served) = keys.iter().find_map(|key| serve(key, cache)) {
+            waiters.unwatch(keys);
+            return Blocking::Done(served);
+        }
+
+        let waiter = Arc::new_cyclic(|weak| Waiter {
+            keys: keys.to_vec(),
+            serve: Mutex::new(Box::new(serve)),
+            timer: deadline.map(|deadline| self.schedule_timeout(id, weak.clone(), deadline)),
+            reply: Mutex::default(),
+            done: Condvar::new(),
+        });
+
+        for key in keys {
+            waiters.by_key.entry(key.clone()).or_default().push_back(id);
+        }
+
+        waiters.by_client.insert(id, waiter.clone());
+
+        Blocking::Waiting(Waiting { waiter })
+    }
+
+    /// Unblock client `id` at the deadline unless it's done waiting by then. The timer only fires
+    /// once the registry is unlocked so the waiter is in place by then.
+    fn schedule_timeout(&self, id: u64, waiter: Weak<Waiter>, deadline: Instant) -> TimerId {
+        let waiters = self.waiters.clone();
+
+        self.timers.schedule(deadline, move || {
+            let mut waiters = waiters.lock().unwrap();
+            let Some(waiter) = waiter.upgrade() else {
+                return;
+            };
+
+            // The client might have been served and blocked again in the meantime.
+            let current = waiters.by_client.get(&id);
+            if current.is_some_and(|current| Arc::ptr_eq(current, &waiter)) {
+                waiters.remove(id);
+                waiter.finish(None);
+            }
+        })
+    }
+
+    /// Wake a waiter that has been removed from the registry, cancelling its timeout.
+    fn finish(&self, waiter: &Waiter, reply: Option<RespType>) {
+        if let Some(timer) = waiter.timer {
+            self.timers.cancel(timer);
+        }
+
+        waiter.finish(reply);
+    }
+
+    /// Serve blocked clients waiting for keys written to since the last call, passing what
+    /// serving them wrote to `propagate`. Only keys in the shards of `locks` are served, keys in
+    /// other shards are left to the writer holding them since its write may not be propagated yet.
+    /// Keys written while serving are handled as well.
+    pub(crate) fn serve_ready(
+        &self,
+        cache: &Cache,
+        locks: &KeyLocks,
+        mut propagate: impl FnMut(&RespType),
+    ) {
+        loop {
+            let ready = {
+                let mut ready = self.ready.lock().unwrap();
+                let (held, others) = ready.drain(..).partition(|key| locks.holds(key));
+                *ready = others;
+                held
+            };
+            if ready.is_empty() {
+                return;
+            }
+
+            let mut waiters = self.waiters.lock().unwrap();
+
+            for key in ready {
+                let queue = waiters.by_key.get(&key).cloned().unwrap_or_default();
+
+                for id in queue {
+                    let Some(waiter) = waiters.by_client.get(&id).cloned() else {
+                        continue;
+                    };
+
+                    let served = (waiter.serve.lock().unwrap())(&key, cache);
+                    if let Some(Served { reply, write }) = served {
+                        waiters.remove(id);
+                        if let Some(write) = write {
+                            propagate(&write);
+                        }
+                        self.finish(&waiter, Some(reply));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wake client `id` without a reply, as if it timed out. Returns whether it was blocked.
+    pub(crate) fn unblock(&self, id: u64) -> bool {
+        match self.waiters.lock().unwrap().remove(id) {
+            Some(waiter) => {
+                self.finish(&waiter, None);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn unblock_all(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let ids = waiters.by_client.keys().copied().collect::<Vec<_>>();
+
+        for id in ids {
+            if let Some(waiter) = waiters.remove(id) {
+                self.finish(&waiter, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Serve by taking the value, like a pop from a single element list.
+    fn take(key: &str, cache: &Cache) -> Option<Served> {
+        let value = cache.get(key)?;
+        cache.delete(key);
+
+        Some(Served {
+            reply: RespType::array(vec![RespType::from(key), RespType::from(value)]),
+            write: Some(RespType::array(vec!["DEL".into(), key.into()])),
+        })
+    }
+
+    fn wait(blocking: Blocking) -> Option<RespType> {
+        match blocking {
+            Blocking::Done(served) => Some(served.reply),
+            Blocking::Waiting(waiting) => waiting.wait(),
+        }
+    }
+
+    #[test]
+    fn test_serve_immediately() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+        cache.set("b", "1", None);
+
+        let reply = blocked.block(1, &cache, &["a".to_string(), "b".to_string()], None, take);
+        assert_eq!(
+            wait(reply),
+            Some(RespType::array(vec!["b".into(), "1".into()]))
+        );
+        assert_eq!(blocked.len(), 0);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+
+        let start = Instant::now();
+        let reply = blocked.block(
+            1,
+            &cache,
+            &["k".to_string()],
+            Some(Duration::from_millis(20)),
+            take,
+        );
+
+        assert_eq!(wait(reply), None);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(blocked.len(), 0);
+    }
+
+    #[test]
+    fn test_fifo_wakeup() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+
+        // Clients are queued as they block and only wait afterwards.
+        let waiting = (1..=2)
+            .map(|id| blocked.block(id, &cache, &["k".to_string()], None, take))
+            .collect::<Vec<_>>();
+        assert_eq!(blocked.len(), 2);
+
+        let mut writes = Vec::new();
+        for value in ["first", "second"] {
+            cache.set("k", value, None);
+            blocked.serve_ready(&cache, &cache.lock_keys(&["k"]), |write| {
+                writes.push(write.clone())
+            });
+        }
+
+        let replies = waiting.into_iter().map(wait).collect::<Vec<_>>();
+
+        assert_eq!(
+            replies,
+            vec![
+                Some(RespType::array(vec!["k".into(), "first".into()])),
+                Some(RespType::array(vec!["k".into(), "second".into()])),
+            ]
+        );
+        assert_eq!(
+            writes,
+            vec![RespType::array(vec!["DEL".into(), "k".into()]); 2]
+        );
+    }
+
+    #[test]
+    fn test_serve_locked_shards() {
+        let cache = Cache::new(16);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+        let other = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| !cache.lock_keys(&["k"]).holds(key))
+            .unwrap();
+
+        let keys = ["k".to_string(), other.clone()];
+        let waiting = blocked.block(1, &cache, &keys, None, take);
+        cache.set(&other, "v", None);
+
+        // The write to the other shard is left to whoever holds it.
+        blocked.serve_ready(&cache, &cache.lock_keys(&["k"]), |_| ());
+        assert_eq!(blocked.len(), 1);
+
+        blocked.serve_ready(&cache, &cache.lock_keys(&[&other]), |_| ());
+        assert_eq!(
+            wait(waiting),
+            Some(RespType::array(vec![other.as_str().into(), "v".into()]))
+        );
+    }
+
+    #[test]
+    fn test_ready_only_when_waited_for() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+        let ready = || blocked.ready.lock().unwrap().clone();
+
+        cache.set("k", "v", None);
+        cache.delete("k");
+        assert!(ready().is_empty());
+
+        let waiting = blocked.block(1, &cache, &["k".to_string()], None, take);
+        cache.set("other", "v", None);
+        cache.set("k", "v", None);
+        assert_eq!(ready(), ["k"]);
+
+        blocked.serve_ready(&cache, &cache.lock_keys(&[]), |_| ());
+        assert!(wait(waiting).is_some());
+        assert!(blocked
+            .waiters
+            .lock()
+            .unwrap()
+            .watched
+            .read()
+            .unwrap()
+            .is_empty());
+
+        cache.set("k", "v", None);
+        assert!(ready().is_empty());
+    }
+
+    #[test]
+    fn test_unblock() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+
+        let waiting = blocked.block(7, &cache, &["k".to_string()], None, take);
+        assert!(blocked.unblock(7));
+        assert!(!blocked.unblock(7));
+        assert_eq!(wait(waiting), None);
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    cache::{Cache, Change, MemoryUsage, Value},
+    command::BloomCommand,
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::hash::Hasher;
+
+/// Defaults used when BF.ADD or BF.MADD create a filter, the same as RedisBloom.
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+const DEFAULT_CAPACITY: u64 = 100;
+const DEFAULT_EXPANSION: u32 = 2;
+
+/// Each new sub-filter gets a tighter error rate so the compounded rate of all of them stays close
+/// to the one the filter was created with.
+const TIGHTENING_RATIO: f64 = 0.5;
+
+/// Refuse filters whose bit arrays would be larger than this, like the 512 MB string limit.
+const MAX_BITS: u64 = 512 * 1024 * 1024 * 8;
+
+/// A single fixed size Bloom filter.
+#[derive(Debug, Clone, PartialEq)]
+struct SubFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    hashes: u32,
+    capacity: u64,
+    count: u64,
+}
+
+impl SubFilter {
+    fn new(capacity: u64, error_rate: f64) -> Result<Self, RedisError> {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+
+        if num_bits > MAX_BITS {
+            return Err(RedisError::Other(
+                "Insufficient memory to create filter".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            hashes: (-error_rate.log2()).ceil().max(1.0) as u32,
+            capacity,
+            count: 0,
+        })
+    }
+
+    /// The bit positions of an item, derived from two hashes with double hashing.
+    fn positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = u64> + '_ {
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.positions(hash)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        let positions = self.positions(hash).collect::<Vec<_>>();
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+
+        self.count += 1;
+    }
+}
+
+/// A scalable Bloom filter. Once the newest sub-filter holds as many items as it was sized for a
+/// new one `expansion` times larger is added, unless the filter was created as non-scaling.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BloomFilter {
+    error_rate: f64,
+    /// Growth factor of new sub-filters, 0 for a non-scaling filter.
+    expansion: u32,
+    filters: Vec<SubFilter>,
+}
+
+// The error rate is validated to be in (0, 1) so it's never NaN.
+impl Eq for BloomFilter {}
+
+impl BloomFilter {
+    pub(crate) fn new(error_rate: f64, capacity: u64, expansion: u32) -> Result<Self, RedisError> {
+        Ok(Self {
+            error_rate,
+            expansion,
+            filters: vec![SubFilter::new(capacity, error_rate)?],
+        })
+    }
+
+    fn hash(item: &str) -> (u64, u64) {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(item.as_bytes());
+        let h1 = hasher.finish();
+
+        // A second hash from a different starting state. Making it odd keeps the positions from
+        // collapsing onto a few bits.
+        let mut hasher = fnv::FnvHasher::with_key(h1);
+        hasher.write(item.as_bytes());
+
+        (h1, hasher.finish() | 1)
+    }
+
+    pub(crate) fn contains(&self, item: &str) -> bool {
+        let hash = Self::hash(item);
+        self.filters.iter().any(|filter| filter.contains(hash))
+    }
+
+    /// Add the item, returning whether it was new. Items that may already exist aren't added again
+    /// so they don't count towards the capacity.
+    pub(crate) fn add(&mut self, item: &str) -> Result<bool, RedisError> {
+        let hash = Self::hash(item);
+        if self.filters.iter().any(|filter| filter.contains(hash)) {
+            return Ok(false);
+        }
+
+        let last = self.filters.last().expect("filter has a sub-filter");
+        if last.count >= last.capacity {
+            if self.expansion == 0 {
+                return Err(RedisError::Other("non scaling filter is full".to_string()));
+            }
+
+            let capacity = last.capacity.saturating_mul(self.expansion as u64);
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.filters.len() as i32);
+            self.filters.push(SubFilter::new(capacity, error_rate)?);
+        }
+
+        self.filters
+            .last_mut()
+            .expect("filter has a sub-filter")
+            .insert(hash);
+
+        Ok(true)
+    }
+
+    /// The whole filter as the single chunk BF.SCANDUMP replies with and BF.LOADCHUNK reads back.
+    /// Numbers are little endian: the error rate and expansion, then for each sub-filter its size,
+    /// hash count, capacity, item count and bits.
+    pub(crate) fn dump(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.error_rate.to_le_bytes());
+        data.extend_from_slice(&self.expansion.to_le_bytes());
+        data.extend_from_slice(&(self.filters.len() as u32).to_le_bytes());
+
+        for filter in &self.filters {
+            data.extend_from_slice(&filter.num_bits.to_le_bytes());
+            data.extend_from_slice(&filter.hashes.to_le_bytes());
+            data.extend_from_slice(&filter.capacity.to_le_bytes());
+            data.extend_from_slice(&filter.count.to_le_bytes());
+            for word in &filter.bits {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        data
+    }
+
+    /// Read a filter written by [`BloomFilter::dump`], or `None` if `data` isn't one.
+    fn load(mut data: &[u8]) -> Option<Self> {
+        fn take<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+            let bytes = data.get(..N)?.try_into().ok()?;
+            *data = &data[N..];
+            Some(bytes)
+        }
+
+        let error_rate = f64::from_le_bytes(take(&mut data)?);
+        let expansion = u32::from_le_bytes(take(&mut data)?);
+        let count = u32::from_le_bytes(take(&mut data)?);
+        if !(error_rate > 0.0 && error_rate < 1.0) || count == 0 {
+            return None;
+        }
+
+        let mut filters = Vec::new();
+        for _ in 0..count {
+            let num_bits = u64::from_le_bytes(take(&mut data)?);
+            let hashes = u32::from_le_bytes(take(&mut data)?);
+            let capacity = u64::from_le_bytes(take(&mut data)?);
+            let count = u64::from_le_bytes(take(&mut data)?);
+            if num_bits == 0 || num_bits > MAX_BITS || hashes == 0 {
+                return None;
+            }
+
+            let words = num_bits.div_ceil(64) as usize;
+            if data.len() < words * 8 {
+                return None;
+            }
+            let bits = (0..words)
+                .map(|_| take(&mut data).map(u64::from_le_bytes))
+                .collect::<Option<_>>()?;
+
+            filters.push(SubFilter {
+                bits,
+                num_bits,
+                hashes,
+                capacity,
+                count,
+            });
+        }
+
+        data.is_empty().then_some(Self {
+            error_rate,
+            expansion,
+            filters,
+        })
+    }
+
+    /// The command that recreates the filter under `key`, used to rewrite the append-only file.
+    pub(crate) fn commands(&self, key: &str) -> Vec<RespType> {
+        vec![RespType::array(vec![
+            "BF.LOADCHUNK".into(),
+            key.into(),
+            "1".into(),
+            RespType::bulk(self.dump()),
+        ])]
+    }
+}
+
+impl MemoryUsage for BloomFilter {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.filters.capacity() * std::mem::size_of::<SubFilter>()
+            + self
+                .filters
+                .iter()
+                .map(|filter| filter.bits.capacity() * std::mem::size_of::<u64>())
+                .sum::<usize>()
+    }
+
+    /// The number of items added.
+    fn elements(&self) -> usize {
+        self.filters
+            .iter()
+            .map(|filter| filter.count as usize)
+            .sum()
+    }
+}
+
+pub(crate) fn execute(command: &BloomCommand, cache: &Cache) -> RespType {
+    match command {
+        BloomCommand::Reserve {
+            key,
+            error_rate,
+            capacity,
+            expansion,
+        } => cache.update(key, |current| {
+            if current.is_some() {
+                return (
+                    RedisError::Other("item exists".to_string()).to_resp(),
+                    Change::Keep,
+                );
+            }
+
+            match BloomFilter::new(*error_rate, *capacity, *expansion) {
+                Ok(filter) => (RespType::ok(), Change::Set(Value::Bloom(filter))),
+                Err(err) => (err.to_resp(), Change::Keep),
+            }
+        }),
+        BloomCommand::Add { key, item } => match add(key, std::slice::from_ref(item), cache) {
+            Ok(mut added) => added.remove(0),
+            Err(err) => err.to_resp(),
+        },
+        BloomCommand::MAdd { key, items } => match add(key, items, cache) {
+            Ok(added) => RespType::array(added),
+            Err(err) => err.to_resp(),
+        },
+        BloomCommand::Exists { key, item } => {
+            let exists = cache.read(key, |value| match value {
+                Value::Bloom(filter) => filter.contains(item),
+                _ => false,
+            });
+
+            RespType::from(exists.unwrap_or_default() as i64)
+        }
+        BloomCommand::ScanDump { key, iterator } => {
+            let dump = cache.read(key, |value| match value {
+                Value::Bloom(filter) => Ok(filter.dump()),
+                _ => Err(RedisError::WrongType),
+            });
+
+            // The filter is dumped in one chunk, so the iterator after it is the one that ends
+            // the scan.
+            match dump {
+                Some(Ok(dump)) if *iterator == 0 => {
+                    RespType::array(vec![1.into(), RespType::bulk(dump)])
+                }
+                Some(Ok(_)) => RespType::array(vec![0.into(), RespType::bulk("")]),
+                Some(Err(err)) => err.to_resp(),
+                None => RedisError::Other("not found".to_string()).to_resp(),
+            }
+        }
+        BloomCommand::LoadChunk {
+            key,
+            iterator,
+            data,
+        } => {
+            let filter = match BloomFilter::load(data) {
+                Some(filter) if *iterator == 1 => filter,
+                _ => return RedisError::Other("received bad data".to_string()).to_resp(),
+            };
+
+            cache.update(key, |current| match current {
+                Some(Value::Bloom(_)) | None => (RespType::ok(), Change::Set(Value::Bloom(filter))),
+                Some(_) => (RedisError::WrongType.to_resp(), Change::Keep),
+            })
+        }
+    }
+}
+
+/// Add items to the filter, creating it with the default options if it doesn't exist. An item that
+/// can't be added because a non-scaling filter is full gets an error in its place.
+fn add(key: &str, items: &[String], cache: &Cache) -> Result<Vec<RespType>, RedisError> {
+    let add_items = |filter: &mut BloomFilter| {
+        items
+            .iter()
+            .map(|item| match filter.add(item) {
+                Ok(added) => RespType::from(added as i64),
+                Err(err) => err.to_resp(),
+            })
+            .collect()
+    };
+
+    cache.update(key, |current| match current {
+        Some(Value::Bloom(filter)) => (Ok(add_items(filter)), Change::Modified),
+        Some(_) => (Err(RedisError::WrongType), Change::Keep),
+        None => match BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY, DEFAULT_EXPANSION) {
+            Ok(mut filter) => {
+                let replies = add_items(&mut filter);
+                (Ok(replies), Change::Set(Value::Bloom(filter)))
+            }
+            Err(err) => (Err(err), Change::Keep),
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        command::{execute, Command},
+        testing::run,
+    };
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = BloomFilter::new(0.01, 1000, 2).unwrap();
+        for i in 0..1000 {
+            assert!(filter.add(&format!("item:{i}")).unwrap());
+        }
+
+        assert!((0..1000).all(|i| filter.contains(&format!("item:{i}"))));
+
+        let false_positives = (0..10_000)
+            .filter(|i| filter.contains(&format!("other:{i}")))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_scaling() {
+        let mut filter = BloomFilter::new(0.01, 10, 2).unwrap();
+        for i in 0..100 {
+            filter.add(&i.to_string()).unwrap();
+        }
+
+        assert!(filter.filters.len() > 1);
+        assert!((0..100).all(|i| filter.contains(&i.to_string())));
+
+        let mut filter = BloomFilter::new(0.01, 10, 0).unwrap();
+        for i in 0..10 {
+            filter.add(&i.to_string()).unwrap();
+        }
+        assert!(filter.add("more").is_err());
+        assert_eq!(filter.filters.len(), 1);
+    }
+
+    #[test]
+    fn test_commands() {
+        let cache = Cache::new(1);
+
+        assert_eq!(
+            run(&cache, &["BF.RESERVE", "bf", "0.001", "100"]),
+            RespType::ok()
+        );
+        assert_eq!(
+            run(&cache, &["BF.RESERVE", "bf", "0.01", "100"]),
+            RespType::error("ERR", "item exists")
+        );
+        assert_eq!(run(&cache, &["BF.ADD", "bf", "a"]), RespType::from(1));
+        assert_eq!(run(&cache, &["BF.ADD", "bf", "a"]), RespType::from(0));
+        assert_eq!(
+            run(&cache, &["BF.MADD", "bf", "a", "b", "c"]),
+            RespType::array(vec![0.into(), 1.into(), 1.into()])
+        );
+        assert_eq!(run(&cache, &["BF.EXISTS", "bf", "c"]), RespType::from(1));
+        assert_eq!(run(&cache, &["BF.EXISTS", "bf", "d"]), RespType::from(0));
+        assert_eq!(
+            run(&cache, &["BF.EXISTS", "missing", "d"]),
+            RespType::from(0)
+        );
+
+        // Adding to a missing key creates a filter with the defaults.
+        assert_eq!(run(&cache, &["BF.ADD", "new", "x"]), RespType::from(1));
+        assert_eq!(run(&cache, &["BF.EXISTS", "new", "x"]), RespType::from(1));
+
+        assert_eq!(
+            run(&cache, &["BF.RESERVE", "r", "1", "100"]),
+            RespType::error("ERR", "(0 < error rate range < 1)")
+        );
+        assert_eq!(
+            run(&cache, &["BF.RESERVE", "r", "0.1", "0"]),
+            RespType::error("ERR", "(capacity should be larger than 0)")
+        );
+        assert_eq!(
+            run(&cache, &["BF.RESERVE", "r", "0.1", "1", "NONSCALING"]),
+            RespType::ok()
+        );
+        assert_eq!(
+            run(&cache, &["BF.MADD", "r", "a", "b"]),
+            RespType::array(vec![
+                1.into(),
+                RespType::error("ERR", "non scaling filter is full")
+            ])
+        );
+
+        cache.set("s", "v", None);
+        assert_eq!(
+            run(&cache, &["BF.ADD", "s", "a"]),
+            RedisError::WrongType.to_resp()
+        );
+        assert_eq!(run(&cache, &["GET", "bf"]), RedisError::WrongType.to_resp());
+    }
+
+    #[test]
+    fn test_scandump() {
+        let cache = Cache::new(1);
+        run(
+            &cache,
+            &["BF.RESERVE", "bf", "0.01", "10", "EXPANSION", "3"],
+        );
+        for i in 0..30 {
+            run(&cache, &["BF.ADD", "bf", &i.to_string()]);
+        }
+
+        let RespType::Array(chunk) = run(&cache, &["BF.SCANDUMP", "bf", "0"]) else {
+            panic!("expected an array");
+        };
+        let [iterator, RespType::BulkString(_, data)] = &chunk[..] else {
+            panic!("expected an iterator and data");
+        };
+        assert_eq!(iterator, &RespType::from(1));
+        assert_eq!(
+            run(&cache, &["BF.SCANDUMP", "bf", "1"]),
+            RespType::array(vec![0.into(), RespType::bulk("")])
+        );
+
+        let frames = vec![
+            RespType::from("BF.LOADCHUNK"),
+            RespType::from("copy"),
+            RespType::from("1"),
+            RespType::bulk(data.clone()),
+        ];
+        let load = Command::parse(&frames).unwrap();
+        assert_eq!(execute(&load, &cache), RespType::ok());
+        assert_eq!(
+            cache.read("copy", Value::clone),
+            cache.read("bf", Value::clone)
+        );
+
+        assert_eq!(
+            run(&cache, &["BF.LOADCHUNK", "other", "1", "junk"]),
+            RespType::error("ERR", "received bad data")
+        );
+        assert_eq!(
+            run(&cache, &["BF.SCANDUMP", "missing", "0"]),
+            RespType::error("ERR", "not found")
+        );
+    }
+}
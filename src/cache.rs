@@ -1,163 +1,1109 @@
+use crate::{
+    bloom::BloomFilter,
+    clock::{Clock, SystemClock},
+    error::panic_message,
+    hash::Hash,
+    json::Json,
+    list::List,
+    resp_type::DEFAULT_READ_SIZE,
+    stream::Stream,
+};
+
+use bytes::Bytes;
+
 use std::{
+    cmp::Reverse,
     collections::{BinaryHeap, HashMap},
     hash::Hasher,
-    sync::{Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
-#[derive(Debug, Eq, PartialEq)]
+/// The kind of value stored under a key. Commands declare the type they operate on so mismatches
+/// can be rejected with WRONGTYPE before the command runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Json,
+    Bloom,
+    Stream,
+    List,
+    Hash,
+}
+
+impl ValueType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Json => "ReJSON-RL",
+            Self::Bloom => "MBbloom--",
+            Self::Stream => "stream",
+            Self::List => "list",
+            Self::Hash => "hash",
+        }
+    }
+}
+
+/// Strings up to this long are stored inside the value itself instead of on the heap.
+const INLINE_LEN: usize = 22;
+
+/// Strings received at least this long are stored as the handle they came in. Shorter ones are
+/// copied so they don't keep the whole buffer they were read into alive.
+const SHARED_MIN_LEN: usize = DEFAULT_READ_SIZE;
+
+/// A short string stored without a heap allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InlineString {
+    len: u8,
+    data: [u8; INLINE_LEN],
+}
+
+impl InlineString {
+    fn new(value: &[u8]) -> Option<Self> {
+        let mut data = [0; INLINE_LEN];
+        data.get_mut(..value.len())?.copy_from_slice(value);
+
+        Some(Self {
+            len: value.len() as u8,
+            data,
+        })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..usize::from(self.len)]
+    }
+}
+
+/// A value stored under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    /// Strings are shared with readers, so a GET hands out a handle instead of a copy. Changing one
+    /// means replacing it with a new string.
+    String(Bytes),
+    /// A string that's the canonical representation of an integer, like Redis' `int` encoding.
+    Int(i64),
+    /// A string short enough that copying it on reads is cheaper than allocating it on its own.
+    Inline(InlineString),
+    Json(Json),
+    Bloom(BloomFilter),
+    Stream(Stream),
+    List(List),
+    Hash(Hash),
+}
+
+impl Value {
+    /// A string value in its most compact representation.
+    pub(crate) fn string(value: &[u8]) -> Self {
+        if let Some(int) = parse_canonical_int(value) {
+            return Value::Int(int);
+        }
+
+        match InlineString::new(value) {
+            Some(inline) => Value::Inline(inline),
+            None => Value::String(Bytes::copy_from_slice(value)),
+        }
+    }
+
+    /// Like [`Value::string`] but keeps the handle to a long string instead of copying it.
+    pub(crate) fn from_bytes(value: Bytes) -> Self {
+        match value.len() >= SHARED_MIN_LEN {
+            true => Value::String(value),
+            false => Value::string(&value),
+        }
+    }
+
+    /// The value as a string, or `None` if it's of another type. Only strings stored on the heap
+    /// are shared, the compact ones are small enough to be copied.
+    pub(crate) fn as_string(&self) -> Option<Bytes> {
+        match self {
+            Value::String(value) => Some(value.clone()),
+            Value::Int(int) => Some(int.to_string().into()),
+            Value::Inline(inline) => Some(Bytes::copy_from_slice(inline.as_bytes())),
+            Value::Json(_)
+            | Value::Bloom(_)
+            | Value::Stream(_)
+            | Value::List(_)
+            | Value::Hash(_) => None,
+        }
+    }
+
+    pub(crate) fn value_type(&self) -> ValueType {
+        match self {
+            Value::String(_) | Value::Int(_) | Value::Inline(_) => ValueType::String,
+            Value::Json(_) => ValueType::Json,
+            Value::Bloom(_) => ValueType::Bloom,
+            Value::Stream(_) => ValueType::Stream,
+            Value::List(_) => ValueType::List,
+            Value::Hash(_) => ValueType::Hash,
+        }
+    }
+}
+
+/// The integer `value` represents if formatting it gives back the same string, so storing the
+/// integer instead doesn't change what's read back.
+fn parse_canonical_int(value: &[u8]) -> Option<i64> {
+    if value.len() > 20 {
+        return None;
+    }
+
+    std::str::from_utf8(value)
+        .ok()?
+        .parse::<i64>()
+        .ok()
+        .filter(|int| int.to_string().as_bytes() == value)
+}
+
+/// An estimate of the memory used by a value, including what it owns on the heap. It doesn't
+/// account for allocator overhead so it's only meant to compare values.
+pub(crate) trait MemoryUsage {
+    fn memory_usage(&self) -> usize;
+
+    /// The number of elements, which for strings is their length in bytes like `redis-cli
+    /// --bigkeys` reports.
+    fn elements(&self) -> usize;
+}
+
+impl MemoryUsage for Value {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + match self {
+                Value::String(value) => value.len(),
+                Value::Int(_) | Value::Inline(_) => 0,
+                Value::Json(json) => json.memory_usage() - std::mem::size_of::<Json>(),
+                Value::Bloom(filter) => filter.memory_usage() - std::mem::size_of::<BloomFilter>(),
+                Value::Stream(stream) => stream.memory_usage() - std::mem::size_of::<Stream>(),
+                Value::List(list) => list.memory_usage() - std::mem::size_of::<List>(),
+                Value::Hash(hash) => hash.memory_usage() - std::mem::size_of::<Hash>(),
+            }
+    }
+
+    fn elements(&self) -> usize {
+        match self {
+            Value::String(value) => value.len(),
+            Value::Int(int) => int.to_string().len(),
+            Value::Inline(inline) => inline.as_bytes().len(),
+            Value::Json(json) => json.elements(),
+            Value::Bloom(filter) => filter.elements(),
+            Value::Stream(stream) => stream.elements(),
+            Value::List(list) => list.elements(),
+            Value::Hash(hash) => hash.elements(),
+        }
+    }
+}
+
+/// How [`Cache::update`] changed the value it was given.
+#[derive(Debug)]
+pub(crate) enum Change {
+    Keep,
+    /// The value was changed in place.
+    Modified,
+    /// Store a new value, keeping the key's TTL.
+    Set(Value),
+    Delete,
+}
+
+/// When a key expires. The deadline is a Unix time in milliseconds so it can be persisted and
+/// replicated, with the matching monotonic instant kept alongside so expiry checks don't read the
+/// system clock and aren't affected by it jumping while the server runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Expiry {
+    instant: Instant,
+    unix_millis: u64,
+}
+
+impl Expiry {
+    /// The deadline `ttl` from now.
+    pub(crate) fn after(clock: &dyn Clock, ttl: Duration) -> Self {
+        Self {
+            instant: clock.now() + ttl,
+            unix_millis: (clock.unix_now() + ttl).as_millis() as u64,
+        }
+    }
+
+    /// The deadline at a Unix time in milliseconds, which may already have passed.
+    pub(crate) fn at_unix_millis(clock: &dyn Clock, unix_millis: u64) -> Self {
+        let now = clock.now();
+        let at = Duration::from_millis(unix_millis);
+        let since_epoch = clock.unix_now();
+
+        let instant = match at.checked_sub(since_epoch) {
+            Some(ttl) => now + ttl,
+            None => now.checked_sub(since_epoch - at).unwrap_or(now),
+        };
+
+        Self {
+            instant,
+            unix_millis,
+        }
+    }
+
+    pub(crate) fn unix_millis(&self) -> u64 {
+        self.unix_millis
+    }
+
+    pub(crate) fn has_passed(&self, now: Instant) -> bool {
+        self.instant <= now
+    }
+
+    /// The time left until the deadline, zero if it has passed.
+    pub(crate) fn remaining(&self, now: Instant) -> Duration {
+        self.instant.saturating_duration_since(now)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 struct CacheItem {
     key: String,
-    value: String,
-    expiration_time: Option<std::time::Instant>,
+    value: Value,
+    expiration_time: Option<Expiry>,
+}
+
+impl CacheItem {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expiration_time, Some(expiry) if expiry.has_passed(now))
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.value.value_type()
+    }
+
+    /// The estimated memory used by the key and value, including the item itself.
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() - std::mem::size_of::<Value>()
+            + self.key.capacity()
+            + self.value.memory_usage()
+    }
+
+    /// The internal encoding Redis would use for the value, as reported by OBJECT ENCODING. Strings
+    /// holding an integer are `int`, short strings `embstr` and the rest `raw`.
+    fn encoding(&self) -> &'static str {
+        match &self.value {
+            Value::Int(_) => "int",
+            Value::Inline(_) => "embstr",
+            Value::String(value) if value.len() <= 44 => "embstr",
+            Value::String(_) | Value::Json(_) | Value::Bloom(_) => "raw",
+            Value::Stream(_) => "stream",
+            Value::List(_) => "quicklist",
+            Value::Hash(_) => "hashtable",
+        }
+    }
+}
+
+/// Aggregated keyspace statistics, mirroring the `keyspace_*`, `expired_keys` and `evicted_keys`
+/// fields in the INFO stats section.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CacheStats {
+    pub(crate) keyspace_hits: u64,
+    pub(crate) keyspace_misses: u64,
+    pub(crate) expired_keys: u64,
+    pub(crate) evicted_keys: u64,
+}
+
+/// The size of the keyspace as reported by the keyspace section of INFO.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyspaceStats {
+    pub(crate) keys: u64,
+    /// Keys with a TTL.
+    pub(crate) expires: u64,
+    /// The average remaining time to live of the keys with one.
+    pub(crate) avg_ttl: Duration,
+}
+
+#[derive(Debug, Default)]
+struct ShardStats {
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    expired_keys: AtomicU64,
+    evicted_keys: AtomicU64,
+    /// Keys with a TTL, including expired ones that haven't been removed yet.
+    volatile_keys: AtomicU64,
+    /// The sum of the deadlines of the volatile keys in milliseconds since the shard's epoch, so
+    /// the average TTL is known without visiting every key. Relative deadlines keep the sum from
+    /// overflowing.
+    deadline_sum: AtomicI64,
 }
 
-impl PartialOrd for CacheItem {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+impl ShardStats {
+    fn add_to(&self, stats: &mut CacheStats) {
+        stats.keyspace_hits += self.keyspace_hits.load(Ordering::Relaxed);
+        stats.keyspace_misses += self.keyspace_misses.load(Ordering::Relaxed);
+        stats.expired_keys += self.expired_keys.load(Ordering::Relaxed);
+        stats.evicted_keys += self.evicted_keys.load(Ordering::Relaxed);
     }
 }
 
-impl Ord for CacheItem {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.expiration_time.is_none() {
-            return std::cmp::Ordering::Less;
+type Hook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// Callbacks invoked with the affected key whenever the keyspace is mutated. Hooks are called while
+/// the shard owning the key is locked so they must not call back into the cache.
+#[derive(Default)]
+struct Hooks {
+    on_write: RwLock<Vec<Hook>>,
+    on_delete: RwLock<Vec<Hook>>,
+    on_expire: RwLock<Vec<Hook>>,
+}
+
+impl Hooks {
+    fn fire(hooks: &RwLock<Vec<Hook>>, key: &str) {
+        for hook in hooks.read().unwrap().iter() {
+            hook(key);
         }
+    }
+}
 
-        other.expiration_time.cmp(&self.expiration_time)
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_write", &self.on_write.read().unwrap().len())
+            .field("on_delete", &self.on_delete.read().unwrap().len())
+            .field("on_expire", &self.on_expire.read().unwrap().len())
+            .finish()
     }
 }
 
+/// The deadlines of the keys with a TTL, soonest first. Entries hold the key rather than the item so
+/// they don't keep old values alive or force a copy when a value is changed in place. An entry is
+/// stale once the key has been deleted or given another deadline, and it's skipped when popped.
+type ExpiryQueue = BinaryHeap<Reverse<(Expiry, String)>>;
+
+/// Stale entries are dropped from the expiry queue once it holds more than twice as many entries as
+/// there are keys with a TTL, plus this many, so keys whose TTL keeps being pushed back don't grow
+/// it until the old deadlines pass.
+const MIN_STALE_EXPIRIES: usize = 64;
+
 #[derive(Debug)]
 struct Shard {
-    pq: Arc<Mutex<BinaryHeap<Arc<CacheItem>>>>,
+    pq: Arc<Mutex<ExpiryQueue>>,
     items: Arc<Mutex<HashMap<String, Arc<CacheItem>>>>,
+    stats: ShardStats,
+    hooks: Arc<Hooks>,
+    clock: Arc<dyn Clock>,
+    /// The Unix time in milliseconds deadlines are summed relative to.
+    epoch: u64,
 }
 
 impl Shard {
-    fn new() -> Self {
+    fn new(hooks: Arc<Hooks>, clock: Arc<dyn Clock>) -> Self {
         Self {
             pq: Arc::new(Mutex::new(BinaryHeap::new())),
             items: Arc::new(Mutex::new(HashMap::new())),
+            stats: ShardStats::default(),
+            hooks,
+            epoch: clock.unix_now().as_millis() as u64,
+            clock,
+        }
+    }
+
+    /// Count an item with a TTL being added to or removed from the map.
+    fn track_volatile(&self, item: &CacheItem, added: bool) {
+        let Some(expiry) = item.expiration_time else {
+            return;
+        };
+
+        let deadline = expiry.unix_millis() as i64 - self.epoch as i64;
+        if added {
+            self.stats.volatile_keys.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .deadline_sum
+                .fetch_add(deadline, Ordering::Relaxed);
+        } else {
+            self.stats.volatile_keys.fetch_sub(1, Ordering::Relaxed);
+            self.stats
+                .deadline_sum
+                .fetch_sub(deadline, Ordering::Relaxed);
         }
     }
 
-    fn set(&mut self, key: &str, value: &str, ttl: Option<Duration>) {
+    /// Queue the key's deadline, dropping the stale entries if there are too many of them. Called
+    /// with the items locked, after the item with the deadline is in the map.
+    fn queue_expiry(&self, items: &HashMap<String, Arc<CacheItem>>, key: &str, expiry: Expiry) {
+        let mut pq = self.pq.lock().unwrap();
+        pq.push(Reverse((expiry, key.to_string())));
+
+        let volatile = self.stats.volatile_keys.load(Ordering::Relaxed) as usize;
+        if pq.len() > volatile * 2 + MIN_STALE_EXPIRIES {
+            pq.retain(|Reverse((expiry, key))| {
+                items
+                    .get(key)
+                    .is_some_and(|item| item.expiration_time == Some(*expiry))
+            });
+        }
+    }
+
+    /// The number of keys, volatile keys and the sum of their remaining time to live in
+    /// milliseconds, negative for keys that have expired but not been removed yet.
+    fn keyspace(&self) -> (u64, u64, i128) {
+        let keys = self.items.lock().unwrap().len() as u64;
+        let volatile = self.stats.volatile_keys.load(Ordering::Relaxed);
+        let deadline_sum = self.stats.deadline_sum.load(Ordering::Relaxed);
+
+        let now = self.clock.unix_now().as_millis() as i128 - i128::from(self.epoch);
+        let ttl_sum = i128::from(deadline_sum) - now * i128::from(volatile);
+
+        (keys, volatile, ttl_sum)
+    }
+
+    fn set(&mut self, key: &str, value: Bytes, ttl: Option<Duration>) {
+        let expiry = ttl.map(|ttl| Expiry::after(self.clock.as_ref(), ttl));
+        self.set_value(key, Value::from_bytes(value), expiry);
+    }
+
+    fn set_value(&mut self, key: &str, value: Value, expiration_time: Option<Expiry>) {
+        self.insert(key, value, expiration_time);
+        Hooks::fire(&self.hooks.on_write, key);
+    }
+
+    /// Store a value without firing the write hooks.
+    fn insert(&mut self, key: &str, value: Value, expiration_time: Option<Expiry>) {
         let item = Arc::new(CacheItem {
             key: key.to_string(),
-            value: value.to_string(),
-            expiration_time: ttl.map(|ttl| std::time::Instant::now() + ttl),
+            value,
+            expiration_time,
         });
 
         let mut items = self.items.lock().unwrap();
 
-        // If the key didn't already exist add it to the queue.
-        if items.get(key).is_none() {
-            let mut pq = self.pq.lock().unwrap();
-            pq.push(item.clone());
+        self.track_volatile(&item, true);
+        if let Some(previous) = items.insert(key.to_string(), item) {
+            self.track_volatile(&previous, false);
+        }
+
+        // Only keys with a TTL go in the queue. Any previous entry for the same key is left in the
+        // queue and discarded by the eviction loop since it no longer matches the map.
+        if let Some(expiry) = expiration_time {
+            self.queue_expiry(&items, key, expiry);
+        }
+    }
+
+    /// Set a value if `f`, given the key's current expiry or `None` if it doesn't exist, returns
+    /// the expiry for it. Returns whether the value was set.
+    fn set_with(
+        &mut self,
+        key: &str,
+        value: Value,
+        f: impl FnOnce(Option<Option<Expiry>>) -> Option<Option<Expiry>>,
+    ) -> bool {
+        let current = {
+            let items = self.items.lock().unwrap();
+            let now = self.clock.now();
+
+            items
+                .get(key)
+                .filter(|item| !item.is_expired(now))
+                .map(|item| item.expiration_time)
+        };
+
+        match f(current) {
+            Some(expiry) => {
+                self.set_value(key, value, expiry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change when the key expires without touching its value, returning its previous expiry or
+    /// `None` if it doesn't exist. A deadline that has already passed deletes the key.
+    fn set_expiry(&mut self, key: &str, expiry: Option<Expiry>) -> Option<Option<Expiry>> {
+        let now = self.clock.now();
+        let expired = expiry.is_some_and(|expiry| expiry.has_passed(now));
+
+        let previous = {
+            let mut items = self.items.lock().unwrap();
+            let item = items.get_mut(key).filter(|item| !item.is_expired(now))?;
+            let previous = item.expiration_time;
+
+            if previous.is_none() && expiry.is_none() {
+                return Some(None);
+            }
+
+            if !expired {
+                // Any queued entry for the previous deadline is left to be discarded.
+                self.track_volatile(item, false);
+                Arc::make_mut(item).expiration_time = expiry;
+                self.track_volatile(item, true);
+
+                if let Some(expiry) = expiry {
+                    self.queue_expiry(&items, key, expiry);
+                }
+            }
+
+            previous
+        };
+
+        if expired {
+            self.delete(key);
+        } else {
+            Hooks::fire(&self.hooks.on_write, key);
+        }
+
+        Some(previous)
+    }
+
+    /// Remove every item without firing any hooks.
+    fn flush(&mut self) {
+        let mut items = self.items.lock().unwrap();
+        let mut pq = self.pq.lock().unwrap();
+
+        items.clear();
+        pq.clear();
+        self.stats.volatile_keys.store(0, Ordering::Relaxed);
+        self.stats.deadline_sum.store(0, Ordering::Relaxed);
+    }
+
+    fn delete(&mut self, key: &str) -> bool {
+        let removed = {
+            let mut items = self.items.lock().unwrap();
+            match items.remove(key) {
+                Some(item) => {
+                    // Any queued deadline is skipped by the eviction loop once it's reached.
+                    self.track_volatile(&item, false);
+                    !item.is_expired(self.clock.now())
+                }
+                None => false,
+            }
+        };
+
+        if removed {
+            Hooks::fire(&self.hooks.on_delete, key);
         }
 
-        items.insert(key.to_string(), item.clone());
+        removed
+    }
+
+    fn get(&self, key: &str) -> Option<Bytes> {
+        self.get_item(key).and_then(|item| item.value.as_string())
+    }
+
+    /// Look up an item, counting it as a keyspace hit or miss.
+    fn get_item(&self, key: &str) -> Option<Arc<CacheItem>> {
+        let mut items = self.items.lock().unwrap();
+        let now = self.clock.now();
+
+        let value = match items.get(key) {
+            Some(item) if !item.is_expired(now) => Some(item.clone()),
+            Some(_) => {
+                // The item is expired, remove it right away instead of waiting for the eviction
+                // loop to reclaim it. Its queued deadline is skipped once it's reached.
+                if let Some(item) = items.remove(key) {
+                    drop(items);
+                    self.track_volatile(&item, false);
+
+                    self.stats.expired_keys.fetch_add(1, Ordering::Relaxed);
+                    Hooks::fire(&self.hooks.on_expire, key);
+                }
+
+                None
+            }
+            None => None,
+        };
+
+        let counter = if value.is_some() {
+            &self.stats.keyspace_hits
+        } else {
+            &self.stats.keyspace_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        value
     }
 
-    fn get(&self, key: &str) -> Option<String> {
+    fn update<T>(&mut self, key: &str, f: impl FnOnce(Option<&mut Value>) -> (T, Change)) -> T {
+        let (result, change, expiration_time) = {
+            let mut items = self.items.lock().unwrap();
+            let now = self.clock.now();
+
+            match items.get_mut(key).filter(|item| !item.is_expired(now)) {
+                Some(item) => {
+                    // The item is only copied if something else, like a snapshot, holds on to it.
+                    let (result, change) = f(Some(&mut Arc::make_mut(item).value));
+                    (result, change, item.expiration_time)
+                }
+                None => {
+                    let (result, change) = f(None);
+                    (result, change, None)
+                }
+            }
+        };
+
+        match change {
+            Change::Keep => (),
+            Change::Modified => Hooks::fire(&self.hooks.on_write, key),
+            Change::Set(value) => self.set_value(key, value, expiration_time),
+            Change::Delete => {
+                self.delete(key);
+            }
+        }
+
+        result
+    }
+
+    /// The type of the value stored under the key without counting it as a keyspace hit or miss.
+    fn value_type(&self, key: &str) -> Option<ValueType> {
         let items = self.items.lock().unwrap();
-        items.get(key).and_then(|item| match item.expiration_time {
-            Some(expiry) if expiry > std::time::Instant::now() => Some(item.value.to_string()),
-            Some(_) => None,
-            None => Some(item.value.to_string()),
-        })
+        items
+            .get(key)
+            .filter(|item| !item.is_expired(self.clock.now()))
+            .map(|item| item.value_type())
+    }
+
+    fn encoding(&self, key: &str) -> Option<&'static str> {
+        let items = self.items.lock().unwrap();
+        items
+            .get(key)
+            .filter(|item| !item.is_expired(self.clock.now()))
+            .map(|item| item.encoding())
+    }
+
+    /// The remaining time to live of the key, `Some(None)` if it exists without one.
+    fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let items = self.items.lock().unwrap();
+        let now = self.clock.now();
+
+        items
+            .get(key)
+            .filter(|item| !item.is_expired(now))
+            .map(|item| item.expiration_time.map(|at| at.remaining(now)))
+    }
+
+    fn evict_expired(&self) {
+        let mut items = self.items.lock().unwrap();
+        let mut pq = self.pq.lock().unwrap();
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+
+        while let Some(Reverse((expiry, _))) = pq.peek() {
+            if !expiry.has_passed(now) {
+                tracing::debug!("No items with TTL that expired!");
+                break;
+            }
+
+            let Some(Reverse((expiry, key))) = pq.pop() else {
+                break;
+            };
+
+            // The key might have been removed or given another deadline since it was queued, only
+            // evict it if the map still holds it with this deadline.
+            match items.get(&key) {
+                Some(current) if current.expiration_time == Some(expiry) => {
+                    tracing::debug!("Evicting item - it was expired!");
+                    if let Some(item) = items.remove(&key) {
+                        self.track_volatile(&item, false);
+                    }
+                    self.stats.expired_keys.fetch_add(1, Ordering::Relaxed);
+                    expired.push(key);
+                }
+                _ => tracing::debug!("Item has been updated - should not evict!"),
+            }
+        }
+
+        drop(pq);
+        drop(items);
+
+        for key in expired {
+            Hooks::fire(&self.hooks.on_expire, &key);
+        }
+    }
+}
+
+/// Lock a shard even if a hook panicked while it was held. Hooks are only called once the shard's
+/// items and queue are unlocked so its data is consistent regardless.
+fn lock_shard(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Periodically evict expired items from the shard until the cache is dropped. The loop is
+/// restarted if an eviction panics so expiry doesn't stop for the shard for good.
+fn eviction_loop(shard: &Mutex<Shard>, rx: &mpsc::Receiver<()>) {
+    let run = || {
+        while let Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(CLEANUP_INTERVAL)
+        {
+            tracing::debug!("Running eviction loop");
+
+            lock_shard(shard).evict_expired();
+        }
+    };
+
+    while let Err(panic) = panic::catch_unwind(AssertUnwindSafe(run)) {
+        tracing::error!(
+            panic = panic_message(&*panic),
+            "eviction loop panicked, restarting"
+        );
+    }
+
+    tracing::debug!("Evicion loop terminated");
+}
+
+/// The write order locks of the shards owning a command's keys, see [`Cache::lock_keys`].
+#[derive(Debug)]
+pub(crate) struct KeyLocks<'a> {
+    number_of_shards: u64,
+    indices: Vec<usize>,
+    _guards: Vec<MutexGuard<'a, ()>>,
+}
+
+impl KeyLocks<'_> {
+    /// Whether the shard owning `key` is locked.
+    pub(crate) fn holds(&self, key: &str) -> bool {
+        let index = shard_from_key(key, self.number_of_shards) as usize;
+        self.indices.binary_search(&index).is_ok()
     }
 }
 
 #[derive(Debug)]
-pub(crate) struct Cache {
+pub struct Cache {
     shards: Vec<Arc<Mutex<Shard>>>,
+    /// One per shard, held by writers from executing until they're propagated.
+    write_order: Vec<Mutex<()>>,
+    hooks: Arc<Hooks>,
+    clock: Arc<dyn Clock>,
+    /// Never sent on, the eviction loops stop once these are dropped with the cache.
     #[allow(dead_code)]
     txs: Vec<std::sync::mpsc::Sender<()>>,
 }
 
 impl Cache {
-    pub(crate) fn new(number_of_shards: u64) -> Self {
+    pub fn new(number_of_shards: u64) -> Self {
+        Self::with_clock(number_of_shards, Arc::new(SystemClock))
+    }
+
+    /// Create a cache reading the time for expiry from `clock`.
+    pub fn with_clock(number_of_shards: u64, clock: Arc<dyn Clock>) -> Self {
         let mut shards = Vec::new();
         let mut txs: Vec<std::sync::mpsc::Sender<()>> = Vec::new();
+        let hooks = Arc::new(Hooks::default());
 
         for _ in 0..number_of_shards {
             let (tx, rx) = std::sync::mpsc::channel();
             txs.push(tx);
 
-            let shard = Arc::new(Mutex::new(Shard::new()));
+            let shard = Arc::new(Mutex::new(Shard::new(hooks.clone(), clock.clone())));
             shards.push(shard.clone());
 
-            thread::spawn(move || {
-                while let Ok(()) | Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
-                    rx.recv_timeout(CLEANUP_INTERVAL)
-                {
-                    tracing::debug!("Running eviction loop");
-
-                    let shard = shard.lock().unwrap();
-                    let mut pq = shard.pq.lock().unwrap();
-                    let mut items = shard.items.lock().unwrap();
-                    let now = std::time::Instant::now();
-
-                    while let Some(item) = pq.peek() {
-                        let expiry = if let Some(expiry) = item.expiration_time {
-                            expiry
-                        } else {
-                            tracing::debug!("No items with TTL in queue!");
-                            break;
-                        };
-
-                        if expiry > now {
-                            tracing::debug!("No items with TTL that expired!");
-                            break;
-                        }
-
-                        if let Some(item) = pq.pop() {
-                            if let Some(item) = items.remove(&item.key) {
-                                if let Some(expiry) = item.expiration_time {
-                                    // Item in pq was expired but map was updated. Re-insert
-                                    // and continue.
-                                    if expiry > now {
-                                        tracing::debug!(
-                                            "Item has been updated - should not evict!"
-                                        );
-
-                                        pq.push(item.clone());
-                                        items.insert(item.key.clone(), item);
-                                    } else {
-                                        tracing::debug!("Evicting item - it was expired!");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+            thread::spawn(move || eviction_loop(&shard, &rx));
+        }
 
-                tracing::debug!("Evicion loop terminated");
-            });
+        Self {
+            write_order: shards.iter().map(|_| Mutex::default()).collect(),
+            shards,
+            hooks,
+            clock,
+            txs,
+        }
+    }
+
+    /// Create a cache without background threads evicting expired keys. They're only removed when
+    /// read or by calling [`Cache::evict_expired`].
+    pub(crate) fn without_eviction_loop(number_of_shards: u64, clock: Arc<dyn Clock>) -> Self {
+        let hooks = Arc::new(Hooks::default());
+        let shards = (0..number_of_shards)
+            .map(|_| Arc::new(Mutex::new(Shard::new(hooks.clone(), clock.clone()))))
+            .collect::<Vec<_>>();
+
+        Self {
+            write_order: shards.iter().map(|_| Mutex::default()).collect(),
+            shards,
+            hooks,
+            clock,
+            txs: Vec::new(),
+        }
+    }
+
+    /// Keep other writers to the shards owning `keys`, or to every shard if there are none, waiting
+    /// until the locks are dropped. Holding them from executing a write until it's propagated
+    /// keeps replicas and the append-only file in the order writes were applied. Shards are locked
+    /// in index order so writers to several keys can't deadlock.
+    pub(crate) fn lock_keys(&self, keys: &[&str]) -> KeyLocks<'_> {
+        let mut indices = keys
+            .iter()
+            .map(|key| shard_from_key(key, self.shards.len() as u64) as usize)
+            .collect::<Vec<_>>();
+        if indices.is_empty() {
+            indices = (0..self.shards.len()).collect();
+        }
+        indices.sort_unstable();
+        indices.dedup();
+
+        let guards = indices
+            .iter()
+            .map(|index| {
+                self.write_order[*index]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+            })
+            .collect();
+
+        KeyLocks {
+            number_of_shards: self.shards.len() as u64,
+            indices,
+            _guards: guards,
         }
+    }
+
+    /// Remove every expired key now instead of waiting for the eviction loop.
+    pub(crate) fn evict_expired(&self) {
+        for shard in &self.shards {
+            lock_shard(shard).evict_expired();
+        }
+    }
+
+    /// The clock keys expire by.
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.get_bytes(key)
+            .map(|value| String::from_utf8_lossy(&value).into_owned())
+    }
+
+    /// Like [`Cache::get`] but returns a handle to the stored string instead of a copy.
+    pub fn get_bytes(&self, key: &str) -> Option<Bytes> {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).get(key)
+    }
+
+    pub fn set(&self, key: &str, value: impl Into<Bytes>, ttl: Option<std::time::Duration>) {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).set(key, value.into(), ttl)
+    }
 
-        Self { shards, txs }
+    /// Set a value of any type expiring at an absolute deadline, like one restored from a dump.
+    pub(crate) fn set_with_expiry(&self, key: &str, value: Value, expiry: Option<Expiry>) {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).set_value(key, value, expiry)
+    }
+
+    /// Set a value loaded from an RDB file without firing the write hooks. No client waits for the
+    /// keys: the server loads its file before accepting clients, and a replica loading its master's
+    /// refuses the blocking pops that could wait for a list.
+    pub(crate) fn load(&self, key: &str, value: Value, expiry: Option<Expiry>) {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).insert(key, value, expiry)
+    }
+
+    /// Set a string value if `f`, given the key's current expiry or `None` if it doesn't exist,
+    /// returns the expiry for it. Returns whether the value was set.
+    pub(crate) fn set_with(
+        &self,
+        key: &str,
+        value: Bytes,
+        f: impl FnOnce(Option<Option<Expiry>>) -> Option<Option<Expiry>>,
+    ) -> bool {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        let value = Value::from_bytes(value);
+        lock_shard(&self.shards[index]).set_with(key, value, f)
+    }
+
+    /// Change when the key expires, or remove its TTL with `None`, without rewriting its value.
+    /// Returns the previous expiry or `None` if the key doesn't exist. A deadline that has already
+    /// passed deletes the key.
+    pub(crate) fn set_expiry(&self, key: &str, expiry: Option<Expiry>) -> Option<Option<Expiry>> {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).set_expiry(key, expiry)
+    }
+
+    pub(crate) fn value_type(&self, key: &str) -> Option<ValueType> {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).value_type(key)
+    }
+
+    /// Read the value stored under the key, counting it as a keyspace hit or miss.
+    pub(crate) fn read<T>(&self, key: &str, f: impl FnOnce(&Value) -> T) -> Option<T> {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        let item = lock_shard(&self.shards[index]).get_item(key)?;
+
+        Some(f(&item.value))
+    }
+
+    /// Atomically read and change the value stored under the key, keeping its TTL. `f` may modify
+    /// the value in place or replace it. The shard owning the key is locked for the duration of `f`
+    /// so it must not call back into the cache.
+    pub(crate) fn update<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(Option<&mut Value>) -> (T, Change),
+    ) -> T {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).update(key, f)
+    }
+
+    /// The encoding of the value stored under the key without counting it as a keyspace hit or
+    /// miss.
+    pub(crate) fn encoding(&self, key: &str) -> Option<&'static str> {
+        let index = shard_from_key(key, self.shards.len() as u64) as usize;
+        lock_shard(&self.shards[index]).encoding(key)
     }
 
-    pub(crate) fn get(&self, key: &str) -> Option<String> {
+    /// The remaining time to live of the key without counting it as a keyspace hit or miss.
+    /// Returns `Some(None)` if the key exists without a TTL.
+    pub(crate) fn ttl(&self, key: &str) -> Option<Option<Duration>> {
         let index = shard_from_key(key, self.shards.len() as u64) as usize;
-        self.shards[index].lock().unwrap().get(key)
+        lock_shard(&self.shards[index]).ttl(key)
     }
 
-    pub(crate) fn set(&mut self, key: &str, value: &str, ttl: Option<std::time::Duration>) {
+    /// Remove a key, returning whether it existed.
+    pub(crate) fn delete(&self, key: &str) -> bool {
         let index = shard_from_key(key, self.shards.len() as u64) as usize;
-        self.shards[index].lock().unwrap().set(key, value, ttl)
+        lock_shard(&self.shards[index]).delete(key)
     }
+
+    /// Remove every key, like FLUSHALL.
+    pub(crate) fn flush(&self) {
+        for shard in &self.shards {
+            lock_shard(shard).flush();
+        }
+    }
+
+    /// Register a hook called with the key after every write.
+    pub(crate) fn on_write(&self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.hooks.on_write.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Register a hook called with the key after it's been explicitly deleted.
+    #[cfg(test)]
+    pub(crate) fn on_delete(&self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.hooks.on_delete.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Register a hook called with the key after it's been removed due to its TTL, either when
+    /// accessed or by the eviction loop.
+    #[cfg(test)]
+    pub(crate) fn on_expire(&self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.hooks.on_expire.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Take a point-in-time snapshot of every non expired item as `(key, value, expiry)`. All shards
+    /// are locked while the item handles are collected but the locks are released before the
+    /// iterator is returned so writers can continue while the snapshot is consumed.
+    pub(crate) fn snapshot(&self) -> impl Iterator<Item = (String, Value, Option<Expiry>)> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| lock_shard(shard))
+            .collect::<Vec<_>>();
+        let now = self.clock.now();
+
+        let items = shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .items
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|item| !item.is_expired(now))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        items
+            .into_iter()
+            .map(|item| (item.key.clone(), item.value.clone(), item.expiration_time))
+    }
+
+    /// Visit every non expired item as `(key, value, estimated bytes)`. Only one shard is locked at a
+    /// time so writers to the other shards can continue while the keyspace is scanned, which also
+    /// means the result isn't a point-in-time view. `f` must not call back into the cache.
+    pub(crate) fn scan(&self, mut f: impl FnMut(&str, &Value, usize)) {
+        for shard in &self.shards {
+            let shard = lock_shard(shard);
+            let items = shard.items.lock().unwrap();
+            let now = self.clock.now();
+
+            for item in items.values().filter(|item| !item.is_expired(now)) {
+                f(&item.key, &item.value, item.memory_usage());
+            }
+        }
+    }
+
+    /// The number of keys in each shard, including expired ones that haven't been removed yet.
+    pub(crate) fn shard_keys(&self) -> Vec<u64> {
+        self.shards
+            .iter()
+            .map(|shard| lock_shard(shard).keyspace().0)
+            .collect()
+    }
+
+    /// Sum the statistics of every shard.
+    pub(crate) fn keyspace(&self) -> KeyspaceStats {
+        let (mut keys, mut expires, mut ttl_sum) = (0, 0, 0);
+        for shard in &self.shards {
+            let (shard_keys, shard_expires, shard_ttl_sum) = lock_shard(shard).keyspace();
+            keys += shard_keys;
+            expires += shard_expires;
+            ttl_sum += shard_ttl_sum;
+        }
+
+        // Keys that expired without being removed yet can make the sum negative.
+        let avg_ttl = match expires {
+            0 => 0,
+            _ => (ttl_sum / i128::from(expires)).max(0) as u64,
+        };
+
+        KeyspaceStats {
+            keys,
+            expires,
+            avg_ttl: Duration::from_millis(avg_ttl),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in &self.shards {
+            lock_shard(shard).stats.add_to(&mut stats);
+        }
+
+        stats
+    }
+}
+
+/// The part of the key that decides its shard. Like Redis Cluster's hash tags, if the key contains
+/// a non-empty `{...}` only the part between the first `{` and the following `}` is used, so keys
+/// like `{user:1000}.following` and `{user:1000}.followers` end up in the same shard.
+fn hash_tag(key: &str) -> &str {
+    let Some(start) = key.find('{') else {
+        return key;
+    };
+
+    match key[start + 1..].find('}') {
+        Some(0) | None => key,
+        Some(len) => &key[start + 1..start + 1 + len],
+    }
+}
+
+/// Number of hash slots keys are spread over in Redis Cluster.
+pub const HASH_SLOTS: u16 = 16384;
+
+/// The Redis Cluster hash slot of the key, the CRC16 of its hash tag modulo [`HASH_SLOTS`].
+pub fn key_hash_slot(key: &str) -> u16 {
+    crc16(hash_tag(key).as_bytes()) % HASH_SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), the variant used by Redis Cluster.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
 }
 
 fn hash_for_key(key: &str) -> u64 {
     let mut hasher = fnv::FnvHasher::default();
-    hasher.write(key.as_bytes());
+    hasher.write(hash_tag(key).as_bytes());
     hasher.finish()
 }
 fn shard_from_key(key: &str, shards: u64) -> u64 {
@@ -167,31 +1113,391 @@ fn shard_from_key(key: &str, shards: u64) -> u64 {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_ordering() {
-        let mut cache = Cache::new(3);
-        cache.set("k", "v", Some(std::time::Duration::from_secs(1)));
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::with_clock(3, clock.clone());
+        cache.set("k", "v", Some(Duration::from_secs(1)));
+        cache.set("k3", "v3", None);
+        cache.set("k2", "v2", Some(Duration::from_secs(3)));
+        cache.set("k2", "v2", Some(Duration::from_secs(10)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.get("k"), None);
+        assert_eq!(cache.get("k2"), Some("v2".to_string()));
+        assert_eq!(cache.get("k3"), Some("v3".to_string()));
+        assert_eq!(cache.ttl("k2"), Some(Some(Duration::from_secs(5))));
+
+        // The eviction loop only removes what has expired, skipping the stale entry for the first
+        // TTL of k2.
+        clock.advance(Duration::from_secs(4));
+        for shard in &cache.shards {
+            lock_shard(shard).evict_expired();
+        }
+        assert_eq!(cache.stats().expired_keys, 1);
+
+        clock.advance(Duration::from_secs(1));
+        for shard in &cache.shards {
+            lock_shard(shard).evict_expired();
+        }
+        assert_eq!(cache.stats().expired_keys, 2);
+        assert_eq!(cache.value_type("k2"), None);
+        assert_eq!(cache.get("k3"), Some("v3".to_string()));
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = Cache::new(2);
+        cache.set("k", "v", None);
+        cache.set("expired", "v", Some(std::time::Duration::from_millis(0)));
+
+        assert_eq!(cache.get("k"), Some("v".to_string()));
+        assert_eq!(cache.get("missing"), None);
+        assert_eq!(cache.get("expired"), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.keyspace_hits, 1);
+        assert_eq!(stats.keyspace_misses, 2);
+        assert_eq!(stats.expired_keys, 1);
+    }
+
+    #[test]
+    fn test_get_shares_value() {
+        let cache = Cache::new(1);
+        cache.set("k", "x".repeat(1024), None);
+
+        let first = cache.get_bytes("k").unwrap();
+        let second = cache.get_bytes("k").unwrap();
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        // Writing replaces the value without affecting handles already given out.
+        cache.set("k", "v", None);
+        assert_eq!(first.len(), 1024);
+        assert_eq!(cache.get_bytes("k").unwrap(), "v");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let cache = Cache::new(3);
+        cache.set("k", "v", None);
+        cache.set("k2", "v2", Some(std::time::Duration::from_secs(60)));
+        cache.set("expired", "v", Some(std::time::Duration::from_millis(0)));
+
+        let snapshot = cache.snapshot();
         cache.set("k3", "v3", None);
-        cache.set("k2", "v2", Some(std::time::Duration::from_secs(3)));
 
-        println!(
-            "{:?}, {:?}, {:?}",
-            cache.get("k"),
-            cache.get("k2"),
-            cache.get("k3")
+        let mut items = snapshot.collect::<Vec<_>>();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].0, "k");
+        assert_eq!(items[0].1, Value::string(b"v"));
+        assert_eq!(items[0].2, None);
+        assert_eq!(items[1].0, "k2");
+        assert_eq!(items[1].1, Value::string(b"v2"));
+        assert!(items[1].2.is_some());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = MockClock::new();
+        let unix_now = clock.unix_now().as_millis() as u64;
+
+        let expiry = Expiry::after(&clock, Duration::from_secs(60));
+        assert_eq!(expiry.unix_millis(), unix_now + 60_000);
+
+        // Restoring the wall-clock deadline gives back the same monotonic deadline, apart from the
+        // sub-millisecond part that isn't kept.
+        let restored = Expiry::at_unix_millis(&clock, expiry.unix_millis());
+        assert!(restored.remaining(clock.now()) > Duration::from_millis(59_998));
+
+        let passed = Expiry::at_unix_millis(&clock, unix_now - 1000);
+        assert!(passed.has_passed(clock.now()));
+        assert_eq!(passed.remaining(clock.now()), Duration::ZERO);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!restored.has_passed(clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(restored.has_passed(clock.now()));
+    }
+
+    #[test]
+    fn test_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorder = |name: &'static str| {
+            let events = events.clone();
+            move |key: &str| events.lock().unwrap().push(format!("{name} {key}"))
+        };
+
+        let cache = Cache::new(2);
+        cache.on_write(recorder("write"));
+        cache.on_delete(recorder("delete"));
+        cache.on_expire(recorder("expire"));
+
+        cache.set("k", "v", None);
+        cache.set("expired", "v", Some(std::time::Duration::from_millis(0)));
+        cache.get("expired");
+        assert!(cache.delete("k"));
+        assert!(!cache.delete("k"));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["write k", "write expired", "expire expired", "delete k"]
         );
-        cache.set("k2", "v2", Some(std::time::Duration::from_secs(10)));
-
-        std::thread::sleep(std::time::Duration::from_secs(5));
-        println!(
-            "{:?}, {:?}, {:?}",
-            cache.get("k"),
-            cache.get("k2"),
-            cache.get("k3")
+    }
+
+    #[test]
+    fn test_hash_tag() {
+        let cases = [
+            ("{user:1000}.following", "user:1000"),
+            ("foo{bar}{zap}", "bar"),
+            ("foo{}{bar}", "foo{}{bar}"),
+            ("foo{{bar}}zap", "{bar"),
+            ("foo{bar", "foo{bar"),
+            ("plain", "plain"),
+        ];
+
+        for (key, tag) in cases {
+            assert_eq!(hash_tag(key), tag, "{key}");
+        }
+
+        assert_eq!(
+            shard_from_key("{user:1000}.following", 16),
+            shard_from_key("{user:1000}.followers", 16)
         );
+    }
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot("foo"), 12182);
+        assert_eq!(key_hash_slot("bar"), 5061);
+        assert_eq!(key_hash_slot("{foo}.bar"), 12182);
+    }
+
+    #[test]
+    fn test_value_type() {
+        let cache = Cache::new(2);
+        cache.set("k", "v", None);
+        cache.set("expired", "v", Some(std::time::Duration::from_millis(0)));
+
+        assert_eq!(cache.value_type("k"), Some(ValueType::String));
+        assert_eq!(cache.value_type("expired"), None);
+        assert_eq!(cache.value_type("missing"), None);
+        assert_eq!(cache.stats().keyspace_hits, 0);
+    }
+
+    #[test]
+    fn test_update() {
+        let cache = Cache::new(1);
+        cache.set("k", "v", Some(std::time::Duration::from_secs(60)));
+        let snapshot = cache.snapshot();
+
+        let append = |value: Option<&mut Value>| match value {
+            Some(value) => {
+                let appended = [value.as_string().unwrap().as_ref(), b"!"].concat();
+                *value = Value::string(&appended);
+                ((), Change::Modified)
+            }
+            None => ((), Change::Set(Value::string(b"new"))),
+        };
+
+        cache.update("k", append);
+        cache.update("missing", append);
+
+        assert_eq!(cache.get("k"), Some("v!".to_string()));
+        assert!(matches!(cache.ttl("k"), Some(Some(_))));
+        assert_eq!(cache.get("missing"), Some("new".to_string()));
+        assert_eq!(cache.ttl("missing"), Some(None));
+
+        // The snapshot still holds the value from before the update.
+        let items = snapshot.collect::<Vec<_>>();
+        assert_eq!(items[0].1, Value::string(b"v"));
+
+        cache.update("k", |_| ((), Change::Delete));
+        assert_eq!(cache.get("k"), None);
+    }
+
+    #[test]
+    fn test_update_in_place() {
+        let mut shard = Shard::new(Arc::default(), Arc::new(SystemClock));
+        shard.set("k", "v".into(), Some(Duration::from_secs(60)));
+        let item = |shard: &Shard| Arc::as_ptr(&shard.items.lock().unwrap()["k"]);
+        let before = item(&shard);
+
+        // A key with a TTL isn't copied to be changed since the expiry queue doesn't hold it.
+        shard.update("k", |value| {
+            *value.unwrap() = Value::string(b"v2");
+            ((), Change::Modified)
+        });
+
+        assert_eq!(item(&shard), before);
+        assert_eq!(shard.get("k"), Some("v2".into()));
+        assert_eq!(shard.pq.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_keyspace() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::without_eviction_loop(4, clock.clone());
+        assert_eq!(cache.keyspace(), KeyspaceStats::default());
+
+        cache.set("a", "v", Some(Duration::from_secs(10)));
+        cache.set("b", "v", Some(Duration::from_secs(20)));
+        cache.set("c", "v", None);
+        // Overwriting replaces the old deadline.
+        cache.set("c", "v", Some(Duration::from_secs(60)));
+        cache.set("c", "v", None);
+
+        let stats = |expires, avg_ttl| KeyspaceStats {
+            keys: 3,
+            expires,
+            avg_ttl: Duration::from_secs(avg_ttl),
+        };
+        assert_eq!(cache.keyspace(), stats(2, 15));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.keyspace(), stats(2, 10));
+
+        // Expired keys are counted until they're removed.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.keyspace(), stats(2, 0));
+
+        cache.evict_expired();
+        assert_eq!(
+            cache.keyspace(),
+            KeyspaceStats {
+                keys: 2,
+                expires: 1,
+                avg_ttl: Duration::from_secs(5),
+            }
+        );
+
+        assert!(cache.delete("b"));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(
+            cache.keyspace(),
+            KeyspaceStats {
+                keys: 1,
+                expires: 0,
+                avg_ttl: Duration::ZERO,
+            }
+        );
+
+        cache.set("d", "v", Some(Duration::from_secs(10)));
+        cache.flush();
+        assert_eq!(cache.keyspace(), KeyspaceStats::default());
+    }
+
+    #[test]
+    fn test_compact_strings() {
+        let long = "x".repeat(INLINE_LEN + 1);
+        let inline = "x".repeat(INLINE_LEN);
+
+        assert_eq!(Value::string(b"-42"), Value::Int(-42));
+        assert!(matches!(Value::string(b"+42"), Value::Inline(_)));
+        assert!(matches!(Value::string(inline.as_bytes()), Value::Inline(_)));
+        assert!(matches!(Value::string(long.as_bytes()), Value::String(_)));
+
+        for value in ["", "-42", "007", &inline, &long] {
+            assert_eq!(Value::string(value.as_bytes()).as_string().unwrap(), value);
+        }
+
+        // Neither compact representation makes values bigger.
+        assert!(std::mem::size_of::<InlineString>() <= std::mem::size_of::<Bytes>());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let cache = Cache::new(1);
+        let long = "x".repeat(45);
+        let cases = [
+            ("12345", "int"),
+            ("-1", "int"),
+            ("007", "embstr"),
+            ("99999999999999999999", "embstr"),
+            ("hello", "embstr"),
+            (long.as_str(), "raw"),
+        ];
+
+        for (value, encoding) in cases {
+            cache.set("k", value.to_string(), None);
+            assert_eq!(cache.encoding("k"), Some(encoding), "{value}");
+            assert_eq!(cache.get("k").as_deref(), Some(value));
+        }
+
+        assert_eq!(cache.encoding("missing"), None);
+    }
+
+    #[test]
+    fn test_passive_expiration() {
+        let mut shard = Shard::new(Arc::default(), Arc::new(SystemClock));
+        shard.set("k", "v".into(), Some(std::time::Duration::from_millis(0)));
+        shard.set("k2", "v2".into(), Some(std::time::Duration::from_secs(60)));
+
+        assert_eq!(shard.get("k"), None);
+        assert!(shard.items.lock().unwrap().get("k").is_none());
+        assert_eq!(shard.stats.expired_keys.load(Ordering::Relaxed), 1);
+
+        // The queued deadline is skipped rather than counted again.
+        shard.evict_expired();
+        assert_eq!(shard.pq.lock().unwrap().len(), 1);
+        assert_eq!(shard.stats.expired_keys.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_delete_queued() {
+        let mut shard = Shard::new(Arc::default(), Arc::new(SystemClock));
+        shard.set("k", "v".into(), Some(Duration::from_millis(0)));
+        assert!(!shard.delete("k"));
+        shard.set("k", "v".into(), None);
+
+        // The deadline of the deleted key doesn't expire the new one.
+        shard.evict_expired();
+        assert_eq!(shard.get("k"), Some("v".into()));
+        assert!(shard.pq.lock().unwrap().is_empty());
+        assert_eq!(shard.stats.expired_keys.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_stale_expiries() {
+        let mut shard = Shard::new(Arc::default(), Arc::new(SystemClock));
+        for i in 0..1000 {
+            shard.set("k", "v".into(), Some(Duration::from_secs(60 + i)));
+        }
+        shard.set("other", "v".into(), Some(Duration::from_secs(60)));
+
+        // Overwrites leave stale deadlines behind, but only until there are too many of them.
+        let queued = shard.pq.lock().unwrap().len();
+        assert!(queued <= 2 * 2 + MIN_STALE_EXPIRIES + 1, "{queued} queued");
+        assert!(shard.ttl("k").unwrap().unwrap() > Duration::from_secs(1000));
+    }
+
+    #[test]
+    fn test_evict_updated_item() {
+        let mut shard = Shard::new(Arc::default(), Arc::new(SystemClock));
+        shard.set("k", "v".into(), Some(std::time::Duration::from_millis(0)));
+        shard.set("k", "v2".into(), None);
+
+        shard.evict_expired();
+
+        assert_eq!(shard.get("k"), Some("v2".into()));
+        assert!(shard.pq.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_panicking_hook() {
+        let cache = Cache::new(1);
+        cache.on_write(|key| assert_ne!(key, "boom"));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.set("boom", "v", None)));
+        assert!(result.is_err());
 
-        drop(cache);
-        std::thread::sleep(std::time::Duration::from_secs(5));
+        // The shard is still usable after the hook panicked while holding its lock.
+        assert_eq!(cache.get("boom"), Some("v".to_string()));
+        cache.set("k", "v", None);
+        assert_eq!(cache.get("k"), Some("v".to_string()));
     }
 }
@@ -0,0 +1,248 @@
+use crate::{
+    error::RedisError,
+    resp_type::{Protocol, RespParser, RespType},
+};
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// A minimal synchronous client sending commands as arrays of bulk strings and reading replies as
+/// [`RespType`]. Commands can be pipelined by calling [`Client::send`] several times before reading
+/// the replies.
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+    parser: RespParser,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, RedisError> {
+        Ok(Self {
+            stream: TcpStream::connect(addr)?,
+            parser: RespParser::new(),
+        })
+    }
+
+    /// Connect with a timeout that also applies to every read and write on the connection.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Self, RedisError> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        Ok(Self {
+            stream,
+            parser: RespParser::new(),
+        })
+    }
+
+    /// A second handle to the same connection, e.g. to read replies on one thread while sending
+    /// commands on another. Each handle buffers the replies it reads on its own.
+    pub fn try_clone(&self) -> Result<Self, RedisError> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            parser: RespParser::new(),
+        })
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, RedisError> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Send a command and wait for its reply.
+    pub fn command<S: AsRef<[u8]>>(&mut self, args: &[S]) -> Result<RespType, RedisError> {
+        self.send(args)?;
+        self.read_reply()
+    }
+
+    /// Send a command without waiting for the reply.
+    pub fn send<S: AsRef<[u8]>>(&mut self, args: &[S]) -> Result<(), RedisError> {
+        let frame = RespType::array(
+            args.iter()
+                .map(|arg| RespType::bulk(arg.as_ref().to_vec()))
+                .collect(),
+        );
+
+        self.send_raw(&frame.to_bytes(Protocol::Resp2))
+    }
+
+    /// Write bytes as is, e.g. to test how the server handles malformed input.
+    pub fn send_raw(&mut self, data: &[u8]) -> Result<(), RedisError> {
+        Ok(self.stream.write_all(data)?)
+    }
+
+    /// Read the next reply, blocking until a whole frame has arrived.
+    pub fn read_reply(&mut self) -> Result<RespType, RedisError> {
+        let mut buf = [0u8; 4096];
+
+        loop {
+            if let Some(reply) = self.parser.next_frame()? {
+                return Ok(reply);
+            }
+
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(RedisError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            self.parser.feed(&buf[..n]);
+        }
+    }
+}
+
+/// Split a line into arguments on whitespace, keeping quoted strings together. Double quoted
+/// strings support `\n`, `\r`, `\t`, `\"` and `\\` escapes.
+pub fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let Some(&first) = chars.peek() else {
+            return args;
+        };
+
+        let mut arg = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    c if c == first => break,
+                    '\\' if first == '"' => match chars.next() {
+                        Some('n') => arg.push('\n'),
+                        Some('r') => arg.push('\r'),
+                        Some('t') => arg.push('\t'),
+                        Some(c) => arg.push(c),
+                        None => break,
+                    },
+                    c => arg.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+
+        args.push(arg);
+    }
+}
+
+/// Format a reply the way redis-cli does, with nested aggregates indented under their index.
+pub fn format_reply(reply: &RespType, indent: usize) -> String {
+    match reply {
+        RespType::SimpleString(s) => s.clone(),
+        RespType::SimpleError(e) | RespType::BulkError(_, e) => format!("(error) {e}"),
+        RespType::Integer(i) => format!("(integer) {i}"),
+        RespType::BulkString(_, data) => format!("{:?}", String::from_utf8_lossy(data)),
+        RespType::Null => "(nil)".to_string(),
+        RespType::Boolean(b) => format!("({b})"),
+        RespType::Double(d) => format!("(double) {d}"),
+        RespType::BigNumber(n) => format!("(big number) {n}"),
+        RespType::VerbatimString(_, _, text) => text.clone(),
+        RespType::Array(items) | RespType::Set(_, items) | RespType::Push(_, items) => {
+            format_aggregate(items.iter().map(|item| (None, item)), indent)
+        }
+        RespType::Map(_, pairs) => {
+            format_aggregate(pairs.iter().map(|(key, value)| (Some(key), value)), indent)
+        }
+        RespType::Attribute(_, value) => format_reply(value, indent),
+    }
+}
+
+fn format_aggregate<'a>(
+    items: impl ExactSizeIterator<Item = (Option<&'a RespType>, &'a RespType)>,
+    indent: usize,
+) -> String {
+    if items.len() == 0 {
+        return "(empty array)".to_string();
+    }
+
+    let width = items.len().to_string().len();
+    let mut lines = Vec::new();
+
+    for (i, (key, value)) in items.enumerate() {
+        let prefix = format!("{:>width$}) ", i + 1);
+        let nested = indent + prefix.len();
+        let value = match key {
+            Some(key) => format!(
+                "{} => {}",
+                format_reply(key, nested),
+                format_reply(value, nested)
+            ),
+            None => format_reply(value, nested),
+        };
+
+        let padding = if i == 0 {
+            String::new()
+        } else {
+            " ".repeat(indent)
+        };
+        lines.push(format!("{padding}{prefix}{value}"));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_pipeline() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        client.send(&["SET", "k", "v\r\n"]).unwrap();
+        client.send(&[b"GET".as_slice(), b"k"]).unwrap();
+        client.send_raw(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        // Inline commands, like netcat would send them.
+        client.send_raw(b"ping\r\nECHO \"a b\"\r\n").unwrap();
+
+        assert_eq!(client.read_reply().unwrap(), RespType::ok());
+        assert_eq!(client.read_reply().unwrap(), RespType::from("v\r\n"));
+        assert_eq!(client.read_reply().unwrap(), RespType::simple("PONG"));
+        assert_eq!(client.read_reply().unwrap(), RespType::simple("PONG"));
+        assert_eq!(client.read_reply().unwrap(), RespType::from("a b"));
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args("  SET k  v "), vec!["SET", "k", "v"]);
+        assert_eq!(
+            split_args(r#"SET "a \"key\"" 'single quoted' "x\ny""#),
+            vec!["SET", "a \"key\"", "single quoted", "x\ny"]
+        );
+        assert!(split_args("   ").is_empty());
+    }
+
+    #[test]
+    fn test_format_reply() {
+        assert_eq!(format_reply(&RespType::ok(), 0), "OK");
+        assert_eq!(format_reply(&RespType::null(), 0), "(nil)");
+        assert_eq!(format_reply(&"v\n".into(), 0), r#""v\n""#);
+        assert_eq!(
+            format_reply(&RespType::error("ERR", "bad"), 0),
+            "(error) ERR bad"
+        );
+        assert_eq!(format_reply(&RespType::array(vec![]), 0), "(empty array)");
+        assert_eq!(
+            format_reply(
+                &RespType::array(vec![
+                    1.into(),
+                    RespType::array(vec!["a".into(), "b".into()]),
+                ]),
+                0
+            ),
+            "1) (integer) 1\n2) 1) \"a\"\n   2) \"b\""
+        );
+    }
+}
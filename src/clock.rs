@@ -0,0 +1,70 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The source of time used for key expiry. The cache and every command dealing with TTLs read the
+/// time from it so tests can control it with a [`MockClock`].
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// The current monotonic time, which expiry checks compare against.
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time as the time since the Unix epoch, used for deadlines that are
+    /// persisted or replicated.
+    fn unix_now(&self) -> Duration;
+}
+
+/// The real clocks of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to. Both the monotonic and wall-clock time advance together,
+/// starting from the time the clock was created.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    unix_start: Duration,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            unix_start: SystemClock.unix_now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_now(&self) -> Duration {
+        self.unix_start + *self.elapsed.lock().unwrap()
+    }
+}
@@ -1,22 +1,2561 @@
-use std::time::Duration;
+use crate::{
+    bloom,
+    cache::{Cache, Change, Expiry, Value, ValueType},
+    clock::Clock,
+    debug, dump,
+    error::RedisError,
+    glob::glob_match,
+    hash, json, list,
+    resp_type::{Protocol, RespType},
+    stream,
+};
+
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
 
 #[derive(Debug)]
 pub enum Command {
     Literal(String),
     Ping,
     Echo(String),
-    Set(String, String, Option<Duration>),
+    Set(SetCommand),
     Get(String),
+    /// DEL with the keys to remove.
+    Del(Vec<String>),
+    /// EXISTS with the keys to count, a key given twice counting twice.
+    Exists(Vec<String>),
+    /// KEYS with the glob pattern keys must match.
+    Keys(String),
+    Type(String),
+    Incr(IncrCommand),
+    Info(Option<String>),
+    DbSize,
+    Command(CommandCommand),
+    Hello(Option<String>),
+    Client(ClientCommand),
+    Config(ConfigCommand),
+    Object(ObjectCommand),
+    Dump(String),
+    Restore(RestoreCommand),
+    Expire(ExpireCommand),
+    Migrate(MigrateCommand),
+    Json(JsonCommand),
+    Bloom(BloomCommand),
+    Stream(StreamCommand),
+    List(ListCommand),
+    Hash(HashCommand),
+    Debug(DebugCommand),
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Replconf(ReplconfCommand),
+    /// PSYNC with the replication ID and offset the replica wants to continue from.
+    Psync(String, i64),
+    /// Replicate the master at the host and port, or stop replicating with `NO ONE`.
+    ReplicaOf(Option<(String, u16)>),
+    Subscribe(Vec<String>),
+    /// Unsubscribe from the channels, or from all of them if none are given.
+    Unsubscribe(Vec<String>),
+    /// PUBLISH with the channel and the message.
+    Publish(String, String),
+    Multi,
+    Exec,
+    Discard,
+    /// The HELP subcommand of a container command, holding the container's lowercase name.
+    Help(&'static str),
+    /// A command registered with [`crate::server::Server::register_command`].
+    Custom(String, Vec<String>),
+}
+
+/// A built-in command with the number of arguments it takes including its name, following the
+/// COMMAND convention where a negative arity means at least that many, and the flags and key
+/// positions COMMAND reports for it.
+#[derive(Debug)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
+    pub(crate) arity: i64,
+    pub(crate) flags: &'static [&'static str],
+    /// The first and last argument that is a key, with a negative last one counting from the end,
+    /// and the step between keys. All zero for commands without keys, or with keys that can't be
+    /// found by position which are flagged `movablekeys` instead.
+    pub(crate) keys: (i64, i64, i64),
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const FIRST_KEY: (i64, i64, i64) = (1, 1, 1);
+/// Every argument, like the keys of DEL.
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+/// Every argument but the last one, like the timeout of BLPOP.
+const ALL_BUT_LAST: (i64, i64, i64) = (1, -2, 1);
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: (i64, i64, i64),
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        keys,
+    }
+}
+
+/// Every built-in command. A command is only parsed if it's listed here and given a number of
+/// arguments its arity allows, so parsers only have to handle the shapes they accept.
+const COMMANDS: &[CommandSpec] = &[
+    spec("ping", -1, &["fast"], NO_KEYS),
+    spec("echo", 2, &["fast"], NO_KEYS),
+    spec("set", -3, &["write", "denyoom"], FIRST_KEY),
+    spec("get", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("del", -2, &["write"], ALL_KEYS),
+    spec("exists", -2, &["readonly", "fast"], ALL_KEYS),
+    spec("keys", 2, &["readonly"], NO_KEYS),
+    spec("type", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("incr", 2, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("incrby", 3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("decr", 2, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("decrby", 3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("info", -1, &[], NO_KEYS),
+    spec("dbsize", 1, &["readonly", "fast"], NO_KEYS),
+    spec("command", -1, &[], NO_KEYS),
+    spec("hello", -1, &["fast"], NO_KEYS),
+    spec("client", -2, &[], NO_KEYS),
+    spec("config", -2, &["admin"], NO_KEYS),
+    spec("object", -2, &["readonly"], NO_KEYS),
+    spec("debug", -2, &["admin"], NO_KEYS),
+    spec("dump", 2, &["readonly"], FIRST_KEY),
+    spec("restore", -4, &["write", "denyoom"], FIRST_KEY),
+    spec("migrate", -6, &["write", "movablekeys"], (3, 3, 1)),
+    spec("expire", 3, &["write", "fast"], FIRST_KEY),
+    spec("pexpire", 3, &["write", "fast"], FIRST_KEY),
+    spec("expireat", 3, &["write", "fast"], FIRST_KEY),
+    spec("pexpireat", 3, &["write", "fast"], FIRST_KEY),
+    spec("ttl", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("pttl", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("persist", 2, &["write", "fast"], FIRST_KEY),
+    spec("save", 1, &["admin"], NO_KEYS),
+    spec("bgsave", 1, &["admin"], NO_KEYS),
+    spec("bgrewriteaof", 1, &["admin"], NO_KEYS),
+    spec("replconf", -1, &["admin"], NO_KEYS),
+    spec("psync", 3, &["admin"], NO_KEYS),
+    spec("replicaof", 3, &["admin"], NO_KEYS),
+    spec("slaveof", 3, &["admin"], NO_KEYS),
+    spec("subscribe", -2, &["pubsub"], NO_KEYS),
+    spec("unsubscribe", -1, &["pubsub"], NO_KEYS),
+    spec("publish", 3, &["pubsub", "fast"], NO_KEYS),
+    spec("multi", 1, &["fast"], NO_KEYS),
+    spec("exec", 1, &[], NO_KEYS),
+    spec("discard", 1, &["fast"], NO_KEYS),
+    spec("json.set", -4, &["write", "denyoom"], FIRST_KEY),
+    spec("json.get", -2, &["readonly"], FIRST_KEY),
+    spec("json.del", -2, &["write"], FIRST_KEY),
+    spec("bf.reserve", -4, &["write", "denyoom"], FIRST_KEY),
+    spec("bf.add", 3, &["write", "denyoom"], FIRST_KEY),
+    spec("bf.madd", -3, &["write", "denyoom"], FIRST_KEY),
+    spec("bf.exists", 3, &["readonly", "fast"], FIRST_KEY),
+    spec("bf.scandump", 3, &["readonly"], FIRST_KEY),
+    spec("bf.loadchunk", 4, &["write", "denyoom"], FIRST_KEY),
+    spec("lpush", -3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("rpush", -3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("llen", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("lrange", 4, &["readonly"], FIRST_KEY),
+    spec("lpop", -2, &["write", "fast"], FIRST_KEY),
+    spec("rpop", -2, &["write", "fast"], FIRST_KEY),
+    spec("blpop", -3, &["write", "blocking"], ALL_BUT_LAST),
+    spec("brpop", -3, &["write", "blocking"], ALL_BUT_LAST),
+    spec("hset", -4, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("hget", 3, &["readonly", "fast"], FIRST_KEY),
+    spec("hmget", -3, &["readonly", "fast"], FIRST_KEY),
+    spec("hdel", -3, &["write", "fast"], FIRST_KEY),
+    spec("hexists", 3, &["readonly", "fast"], FIRST_KEY),
+    spec("hgetall", 2, &["readonly"], FIRST_KEY),
+    spec("hincrby", 4, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("xadd", -5, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("xrange", -4, &["readonly"], FIRST_KEY),
+    spec("xrevrange", -4, &["readonly"], FIRST_KEY),
+    spec("xlen", 2, &["readonly", "fast"], FIRST_KEY),
+    spec(
+        "xread",
+        -4,
+        &["readonly", "blocking", "movablekeys"],
+        NO_KEYS,
+    ),
+];
+
+/// The built-in command with the lowercase `name`.
+pub(crate) fn command_spec(name: &str) -> Option<&'static CommandSpec> {
+    static BY_NAME: OnceLock<HashMap<&str, &CommandSpec>> = OnceLock::new();
+
+    BY_NAME
+        .get_or_init(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect())
+        .get(name)
+        .copied()
+}
+
+/// Whether a command with `arity` may be called with `given` arguments including its name.
+fn arity_allows(arity: i64, given: usize) -> bool {
+    let given = given as i64;
+
+    match arity {
+        arity if arity >= 0 => given == arity,
+        arity => given >= -arity,
+    }
+}
+
+/// A subcommand of a container command, described for the generated HELP reply.
+struct Subcommand {
+    name: &'static str,
+    args: &'static str,
+    summary: &'static str,
+}
+
+/// The container commands and their subcommands. Each container answers HELP from this table.
+const CONTAINERS: &[(&str, &[Subcommand])] = &[
+    (
+        "client",
+        &[
+            Subcommand {
+                name: "ID",
+                args: "",
+                summary: "Return the ID of the current connection.",
+            },
+            Subcommand {
+                name: "LIST",
+                args: "",
+                summary: "Return information about client connections.",
+            },
+            Subcommand {
+                name: "GETNAME",
+                args: "",
+                summary: "Return the name of the current connection.",
+            },
+            Subcommand {
+                name: "SETNAME",
+                args: "<name>",
+                summary: "Assign the name <name> to the current connection.",
+            },
+            Subcommand {
+                name: "KILL",
+                args: "<ip:port> | ID <client-id> | ADDR <ip:port>",
+                summary: "Kill connections by address or ID.",
+            },
+            Subcommand {
+                name: "NO-RATELIMIT",
+                args: "(ON|OFF)",
+                summary: "Exempt the current connection from rate limits.",
+            },
+        ],
+    ),
+    (
+        "command",
+        &[
+            Subcommand {
+                name: "(no subcommand)",
+                args: "",
+                summary: "Return details about all commands.",
+            },
+            Subcommand {
+                name: "COUNT",
+                args: "",
+                summary: "Return the total number of commands in this server.",
+            },
+            Subcommand {
+                name: "INFO",
+                args: "[<command-name> ...]",
+                summary: "Return details about multiple commands, or all of them if none are given.",
+            },
+            Subcommand {
+                name: "DOCS",
+                args: "[<command-name> ...]",
+                summary: "Return documentation details about multiple commands, or all of them if none are given.",
+            },
+        ],
+    ),
+    (
+        "config",
+        &[
+            Subcommand {
+                name: "GET",
+                args: "<pattern> [<pattern> ...]",
+                summary: "Return parameters matching the glob-like <pattern> and their values.",
+            },
+            Subcommand {
+                name: "SET",
+                args: "<directive> <value> [<directive> <value> ...]",
+                summary: "Set the configuration <directive> to <value>.",
+            },
+        ],
+    ),
+    (
+        "debug",
+        &[Subcommand {
+            name: "BIGKEYS",
+            args: "[<count>]",
+            summary: "Report the <count> biggest keys of each type, by elements and by bytes.",
+        }],
+    ),
+    (
+        "object",
+        &[Subcommand {
+            name: "ENCODING",
+            args: "<key>",
+            summary: "Return the kind of internal representation used to store <key>.",
+        }],
+    ),
+];
+
+/// The HELP reply of a container command, in the same layout as Redis.
+fn help_reply(container: &str) -> RespType {
+    let name = container.to_uppercase();
+    let subcommands = CONTAINERS
+        .iter()
+        .find(|(name, _)| *name == container)
+        .map(|(_, subcommands)| *subcommands)
+        .unwrap_or_default();
+
+    let mut lines = vec![format!(
+        "{name} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:"
+    )];
+    for subcommand in subcommands {
+        lines.push(
+            format!("{} {}", subcommand.name, subcommand.args)
+                .trim_end()
+                .to_string(),
+        );
+        lines.push(format!("    {}", subcommand.summary));
+    }
+    lines.push("HELP".to_string());
+    lines.push("    Print this help.".to_string());
+
+    RespType::array(lines.into_iter().map(RespType::simple).collect())
+}
+
+/// Subcommands of CLIENT, which are executed by the server since they need the connection registry.
+#[derive(Debug)]
+pub enum ClientCommand {
+    Id,
+    List,
+    GetName,
+    SetName(String),
+    /// Clients to disconnect and whether the legacy `CLIENT KILL addr` form was used, which replies
+    /// with OK instead of the number of killed clients.
+    Kill(KillFilter, bool),
+    /// Exempt the connection from the server's rate limits.
+    NoRateLimit(bool),
+}
+
+#[derive(Debug)]
+pub enum KillFilter {
+    Id(u64),
+    Addr(String),
+}
+
+/// Subcommands of CONFIG, executed by the server which owns the configuration.
+#[derive(Debug)]
+pub enum ConfigCommand {
+    /// Get every parameter matching any of the glob patterns.
+    Get(Vec<String>),
+    /// Set each parameter to the value following it.
+    Set(Vec<(String, String)>),
+}
+
+/// Subcommands of COMMAND, describing the commands the server knows.
+#[derive(Debug)]
+pub enum CommandCommand {
+    /// COMMAND without a subcommand, describing every command.
+    List,
+    Count,
+    /// Describe the named commands, or every command if none are given.
+    Info(Vec<String>),
+    /// The documentation of the named commands, or of every command if none are given.
+    Docs(Vec<String>),
+}
+
+/// REPLCONF, exchanged between a replica and its master.
+#[derive(Debug)]
+pub enum ReplconfCommand {
+    /// Configuration sent by a replica during the handshake. Only the port it listens on is kept,
+    /// capabilities are accepted and ignored.
+    Options { listening_port: Option<u16> },
+    /// The replica has processed the replication stream up to the offset.
+    Ack(u64),
+    /// The master asks the replica for an ACK.
+    GetAck,
+}
+
+impl ReplconfCommand {
+    fn parse(args: &[String]) -> Result<Self, RedisError> {
+        match args {
+            [option, offset] if option.eq_ignore_ascii_case("ack") => {
+                Ok(Self::Ack(offset.parse()?))
+            }
+            [option, _] if option.eq_ignore_ascii_case("getack") => Ok(Self::GetAck),
+            _ => {
+                let pairs = args.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    return Err(RedisError::Syntax);
+                }
+
+                let mut listening_port = None;
+                for pair in pairs {
+                    match pair[0].to_lowercase().as_str() {
+                        "listening-port" => listening_port = Some(pair[1].parse()?),
+                        "capa" | "ip-address" => (),
+                        _ => {
+                            return Err(RedisError::Other(format!(
+                                "Unrecognized REPLCONF option: {}",
+                                pair[0]
+                            )))
+                        }
+                    }
+                }
+
+                Ok(Self::Options { listening_port })
+            }
+        }
+    }
+}
+
+/// Subcommands of OBJECT, inspecting how values are stored.
+#[derive(Debug)]
+pub enum ObjectCommand {
+    Encoding(String),
+}
+
+/// Subcommands of DEBUG, for inspecting the server.
+#[derive(Debug)]
+pub enum DebugCommand {
+    /// Report the given number of biggest keys of each type.
+    BigKeys(usize),
+}
+
+impl DebugCommand {
+    fn parse(subcommand: &str, args: &[String]) -> Result<Self, RedisError> {
+        let lowercase = subcommand.to_lowercase();
+
+        match (lowercase.as_str(), args) {
+            ("bigkeys", []) => Ok(Self::BigKeys(1)),
+            ("bigkeys", [count]) => match count.parse::<usize>()? {
+                0 => Err(RedisError::Other(
+                    "value is out of range, must be positive".to_string(),
+                )),
+                count => Ok(Self::BigKeys(count)),
+            },
+            ("bigkeys", _) => Err(RedisError::WrongArity(format!("debug|{lowercase}"))),
+            _ => Err(RedisError::UnknownSubcommand(
+                subcommand.to_string(),
+                "DEBUG".to_string(),
+            )),
+        }
+    }
+}
+
+impl ObjectCommand {
+    fn parse(subcommand: &str, args: &[String]) -> Result<Self, RedisError> {
+        let lowercase = subcommand.to_lowercase();
+
+        match (lowercase.as_str(), args) {
+            ("encoding", [key]) => Ok(Self::Encoding(key.clone())),
+            ("encoding", _) => Err(RedisError::WrongArity(format!("object|{lowercase}"))),
+            _ => Err(RedisError::UnknownSubcommand(
+                subcommand.to_string(),
+                "OBJECT".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RestoreCommand {
+    pub key: String,
+    /// Time to live in milliseconds where 0 means none, or a Unix time if `absttl` is set.
+    pub ttl: u64,
+    pub absttl: bool,
+    pub payload: Bytes,
+    /// Replace the key if it already exists instead of replying with BUSYKEY.
+    pub replace: bool,
+}
+
+impl RestoreCommand {
+    fn parse(key: &str, ttl: &str, payload: Bytes, options: &[String]) -> Result<Self, RedisError> {
+        let ttl = ttl
+            .parse::<i64>()?
+            .try_into()
+            .map_err(|_| RedisError::Other("Invalid TTL value, must be >= 0".to_string()))?;
+
+        let mut restore = Self {
+            key: key.to_string(),
+            ttl,
+            absttl: false,
+            payload,
+            replace: false,
+        };
+
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_lowercase().as_str() {
+                "replace" => restore.replace = true,
+                "absttl" => restore.absttl = true,
+                // Eviction isn't implemented so the access hints are only validated.
+                "idletime" | "freq" => {
+                    options.next().ok_or(RedisError::Syntax)?.parse::<i64>()?;
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        Ok(restore)
+    }
+}
+
+/// A time to live in milliseconds, either from now or as a Unix time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    Relative(i64),
+    Absolute(i64),
+}
+
+impl Ttl {
+    /// Parse a time in seconds, or in milliseconds if `millis` is set, for the command `name`.
+    fn parse(time: &str, absolute: bool, millis: bool, name: &str) -> Result<Self, RedisError> {
+        let time = time.parse::<i64>()?;
+        let time = match millis {
+            true => time,
+            false => time
+                .checked_mul(1000)
+                .ok_or_else(|| invalid_expire_time(name))?,
+        };
+
+        Ok(match absolute {
+            true => Self::Absolute(time),
+            false => Self::Relative(time),
+        })
+    }
+
+    fn millis(self) -> i64 {
+        match self {
+            Self::Relative(millis) | Self::Absolute(millis) => millis,
+        }
+    }
+
+    /// The deadline, which has already passed unless the time is in the future, or `None` if it's
+    /// too far away to represent.
+    fn expiry(self, clock: &dyn Clock) -> Option<Expiry> {
+        let unix_now = clock.unix_now().as_millis() as i64;
+
+        match self {
+            Self::Relative(millis) if millis > 0 => {
+                unix_now.checked_add(millis)?;
+                Some(Expiry::after(clock, Duration::from_millis(millis as u64)))
+            }
+            Self::Relative(_) => Some(Expiry::at_unix_millis(clock, 0)),
+            Self::Absolute(millis) => Some(Expiry::at_unix_millis(clock, millis.max(0) as u64)),
+        }
+    }
+}
+
+fn invalid_expire_time(name: &str) -> RedisError {
+    RedisError::Other(format!("invalid expire time in '{name}' command"))
+}
+
+#[derive(Debug)]
+pub struct SetCommand {
+    pub key: String,
+    pub value: Bytes,
+    pub ttl: Option<Ttl>,
+    /// Keep the TTL the key already has instead of removing it.
+    pub keep_ttl: bool,
+    pub condition: Option<SetCondition>,
+}
+
+impl SetCommand {
+    fn parse(key: &str, value: Bytes, options: &[String]) -> Result<Self, RedisError> {
+        let mut set = Self {
+            key: key.to_string(),
+            value,
+            ttl: None,
+            keep_ttl: false,
+            condition: None,
+        };
+
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = option.to_lowercase();
+            match option.as_str() {
+                "nx" | "xx" if set.condition.is_some() => return Err(RedisError::Syntax),
+                "nx" => set.condition = Some(SetCondition::Nx),
+                "xx" => set.condition = Some(SetCondition::Xx),
+                "keepttl" if set.ttl.is_some() => return Err(RedisError::Syntax),
+                "keepttl" => set.keep_ttl = true,
+                "ex" | "px" | "exat" | "pxat" if set.ttl.is_some() || set.keep_ttl => {
+                    return Err(RedisError::Syntax)
+                }
+                "ex" | "px" | "exat" | "pxat" => {
+                    let time = options.next().ok_or(RedisError::Syntax)?;
+                    let ttl =
+                        Ttl::parse(time, option.ends_with("at"), option.starts_with('p'), "set")?;
+
+                    if ttl.millis() <= 0 {
+                        return Err(invalid_expire_time("set"));
+                    }
+                    set.ttl = Some(ttl);
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        Ok(set)
+    }
+}
+
+/// The commands reading and changing when keys expire.
+#[derive(Debug)]
+pub enum ExpireCommand {
+    /// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, `millis` telling if the time was in milliseconds.
+    Expire {
+        key: String,
+        ttl: Ttl,
+        millis: bool,
+    },
+    /// TTL, or PTTL if `millis` is set.
+    Ttl {
+        key: String,
+        millis: bool,
+    },
+    Persist {
+        key: String,
+    },
+}
+
+impl ExpireCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        match (name, args) {
+            ("expire" | "pexpire" | "expireat" | "pexpireat", [key, time]) => {
+                let millis = name.starts_with('p');
+                Ok(Self::Expire {
+                    key: key.clone(),
+                    ttl: Ttl::parse(time, name.ends_with("at"), millis, name)?,
+                    millis,
+                })
+            }
+            ("ttl" | "pttl", [key]) => Ok(Self::Ttl {
+                key: key.clone(),
+                millis: name == "pttl",
+            }),
+            ("persist", [key]) => Ok(Self::Persist { key: key.clone() }),
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Expire { ttl, millis, .. } => match (ttl, millis) {
+                (Ttl::Relative(_), false) => "expire",
+                (Ttl::Relative(_), true) => "pexpire",
+                (Ttl::Absolute(_), false) => "expireat",
+                (Ttl::Absolute(_), true) => "pexpireat",
+            },
+            Self::Ttl { millis: false, .. } => "ttl",
+            Self::Ttl { millis: true, .. } => "pttl",
+            Self::Persist { .. } => "persist",
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Self::Expire { key, .. } | Self::Ttl { key, .. } | Self::Persist { key } => key,
+        }
+    }
+}
+
+/// INCR, INCRBY, DECR and DECRBY, adding to the integer stored as a string under a key.
+#[derive(Debug)]
+pub struct IncrCommand {
+    pub key: String,
+    /// The amount given to INCRBY and DECRBY, or 1.
+    pub by: i64,
+    /// The amount is subtracted, as with DECR and DECRBY.
+    pub decrement: bool,
+    /// The amount was given, as with INCRBY and DECRBY.
+    pub explicit: bool,
+}
+
+impl IncrCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        let (key, by) = match args {
+            [key] => (key, 1),
+            [key, by] => (key, by.parse()?),
+            _ => return Err(RedisError::WrongArity(name.to_string())),
+        };
+
+        Ok(Self {
+            key: key.clone(),
+            by,
+            decrement: name.starts_with("decr"),
+            explicit: name.ends_with("by"),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match (self.decrement, self.explicit) {
+            (false, false) => "incr",
+            (false, true) => "incrby",
+            (true, false) => "decr",
+            (true, true) => "decrby",
+        }
+    }
+}
+
+/// Options for MIGRATE.
+#[derive(Debug)]
+pub struct MigrateCommand {
+    pub host: String,
+    pub port: u16,
+    pub keys: Vec<String>,
+    pub db: u64,
+    pub timeout: Duration,
+    /// Keep the keys on this instance.
+    pub copy: bool,
+    /// Replace keys that already exist on the target.
+    pub replace: bool,
+    /// The username, if any, and password to authenticate to the target with.
+    pub auth: Option<(Option<String>, String)>,
+}
+
+impl MigrateCommand {
+    fn parse(args: &[String]) -> Result<Self, RedisError> {
+        let [host, port, key, db, timeout, options @ ..] = args else {
+            return Err(RedisError::WrongArity("migrate".to_string()));
+        };
+
+        // Like Redis a timeout that isn't positive falls back to one second.
+        let timeout = match timeout.parse::<i64>()? {
+            timeout if timeout > 0 => Duration::from_millis(timeout as u64),
+            _ => Duration::from_secs(1),
+        };
+
+        let mut migrate = Self {
+            host: host.clone(),
+            port: port.parse()?,
+            keys: vec![key.clone()],
+            db: db.parse()?,
+            timeout,
+            copy: false,
+            replace: false,
+            auth: None,
+        };
+
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_lowercase().as_str() {
+                "copy" => migrate.copy = true,
+                "replace" => migrate.replace = true,
+                "auth" => {
+                    let password = options.next().ok_or(RedisError::Syntax)?;
+                    migrate.auth = Some((None, password.clone()));
+                }
+                "auth2" => {
+                    let username = options.next().ok_or(RedisError::Syntax)?;
+                    let password = options.next().ok_or(RedisError::Syntax)?;
+                    migrate.auth = Some((Some(username.clone()), password.clone()));
+                }
+                "keys" => {
+                    if !key.is_empty() {
+                        return Err(RedisError::Other(
+                            "When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                                .to_string(),
+                        ));
+                    }
+
+                    migrate.keys = options.by_ref().cloned().collect();
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        Ok(migrate)
+    }
+}
+
+/// The RedisJSON style commands, addressing values inside a document by path.
+#[derive(Debug)]
+pub enum JsonCommand {
+    Set {
+        key: String,
+        path: String,
+        /// The value as JSON text.
+        value: String,
+        condition: Option<SetCondition>,
+    },
+    /// Get the values at each path, or the whole document if none are given.
+    Get {
+        key: String,
+        paths: Vec<String>,
+        format: JsonFormat,
+    },
+    /// Delete the values at the path, or the whole key if none is given.
+    Del { key: String, path: Option<String> },
+}
+
+/// Only set the value if it does (XX) or doesn't (NX) already exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    Nx,
+    Xx,
+}
+
+/// Whitespace JSON.GET puts between elements, empty for compact output.
+#[derive(Debug, Default)]
+pub struct JsonFormat {
+    pub indent: String,
+    pub newline: String,
+    pub space: String,
+}
+
+impl JsonCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        match (name, args) {
+            ("json.set", [key, path, value, options @ ..]) => {
+                let condition = match options {
+                    [] => None,
+                    [option] if option.eq_ignore_ascii_case("nx") => Some(SetCondition::Nx),
+                    [option] if option.eq_ignore_ascii_case("xx") => Some(SetCondition::Xx),
+                    _ => return Err(RedisError::Syntax),
+                };
+
+                Ok(Self::Set {
+                    key: key.clone(),
+                    path: path.clone(),
+                    value: value.clone(),
+                    condition,
+                })
+            }
+            ("json.get", [key, args @ ..]) => {
+                let mut format = JsonFormat::default();
+                let mut paths = Vec::new();
+
+                let mut args = args.iter();
+                while let Some(arg) = args.next() {
+                    let option = match arg.to_lowercase().as_str() {
+                        "indent" => &mut format.indent,
+                        "newline" => &mut format.newline,
+                        "space" => &mut format.space,
+                        _ => {
+                            paths.push(arg.clone());
+                            continue;
+                        }
+                    };
+
+                    *option = args.next().ok_or(RedisError::Syntax)?.clone();
+                }
+
+                Ok(Self::Get {
+                    key: key.clone(),
+                    paths,
+                    format,
+                })
+            }
+            ("json.del", [key]) => Ok(Self::Del {
+                key: key.clone(),
+                path: None,
+            }),
+            ("json.del", [key, path]) => Ok(Self::Del {
+                key: key.clone(),
+                path: Some(path.clone()),
+            }),
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Self::Set { key, .. } | Self::Get { key, .. } | Self::Del { key, .. } => key,
+        }
+    }
+}
+
+/// The RedisBloom style Bloom filter commands.
+#[derive(Debug)]
+pub enum BloomCommand {
+    Reserve {
+        key: String,
+        error_rate: f64,
+        capacity: u64,
+        /// Growth factor of new sub-filters, 0 for a non-scaling filter.
+        expansion: u32,
+    },
+    Add {
+        key: String,
+        item: String,
+    },
+    MAdd {
+        key: String,
+        items: Vec<String>,
+    },
+    Exists {
+        key: String,
+        item: String,
+    },
+    ScanDump {
+        key: String,
+        iterator: i64,
+    },
+    LoadChunk {
+        key: String,
+        iterator: i64,
+        data: Bytes,
+    },
+}
+
+impl BloomCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        match (name, args) {
+            ("bf.reserve", [key, error_rate, capacity, options @ ..]) => {
+                let error_rate = error_rate
+                    .parse::<f64>()
+                    .map_err(|_| RedisError::Other("bad error rate".to_string()))?;
+                if !(error_rate > 0.0 && error_rate < 1.0) {
+                    return Err(RedisError::Other("(0 < error rate range < 1)".to_string()));
+                }
+
+                let capacity = capacity
+                    .parse::<u64>()
+                    .map_err(|_| RedisError::Other("bad capacity".to_string()))?;
+                if capacity == 0 {
+                    return Err(RedisError::Other(
+                        "(capacity should be larger than 0)".to_string(),
+                    ));
+                }
+
+                let mut expansion = None;
+                let mut non_scaling = false;
+
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    match option.to_lowercase().as_str() {
+                        "nonscaling" => non_scaling = true,
+                        "expansion" => {
+                            let value = options.next().ok_or(RedisError::Syntax)?;
+                            match value.parse::<u32>() {
+                                Ok(value) if value > 0 => expansion = Some(value),
+                                _ => return Err(RedisError::Other("bad expansion".to_string())),
+                            }
+                        }
+                        _ => return Err(RedisError::Syntax),
+                    }
+                }
+
+                let expansion = match (expansion, non_scaling) {
+                    (Some(_), true) => {
+                        return Err(RedisError::Other(
+                            "Nonscaling filters cannot expand".to_string(),
+                        ))
+                    }
+                    (_, true) => 0,
+                    (expansion, false) => expansion.unwrap_or(2),
+                };
+
+                Ok(Self::Reserve {
+                    key: key.clone(),
+                    error_rate,
+                    capacity,
+                    expansion,
+                })
+            }
+            ("bf.add", [key, item]) => Ok(Self::Add {
+                key: key.clone(),
+                item: item.clone(),
+            }),
+            ("bf.madd", [key, items @ ..]) if !items.is_empty() => Ok(Self::MAdd {
+                key: key.clone(),
+                items: items.to_vec(),
+            }),
+            ("bf.exists", [key, item]) => Ok(Self::Exists {
+                key: key.clone(),
+                item: item.clone(),
+            }),
+            ("bf.scandump", [key, iterator]) => Ok(Self::ScanDump {
+                key: key.clone(),
+                iterator: Self::iterator(iterator)?,
+            }),
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    /// BF.LOADCHUNK takes the chunk as is since a filter's bits aren't text.
+    fn parse_load_chunk(key: &str, iterator: &str, data: Bytes) -> Result<Self, RedisError> {
+        Ok(Self::LoadChunk {
+            key: key.to_string(),
+            iterator: Self::iterator(iterator)?,
+            data,
+        })
+    }
+
+    fn iterator(iterator: &str) -> Result<i64, RedisError> {
+        iterator
+            .parse()
+            .map_err(|_| RedisError::Other("Second argument must be numeric".to_string()))
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Self::Reserve { key, .. }
+            | Self::Add { key, .. }
+            | Self::MAdd { key, .. }
+            | Self::Exists { key, .. }
+            | Self::ScanDump { key, .. }
+            | Self::LoadChunk { key, .. } => key,
+        }
+    }
+}
+
+/// The list commands, where `left` picks the head of the list and otherwise the tail.
+#[derive(Debug)]
+pub enum ListCommand {
+    Push {
+        key: String,
+        values: Vec<String>,
+        left: bool,
+    },
+    Len {
+        key: String,
+    },
+    Range {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    Pop {
+        key: String,
+        /// Reply with an array of up to this many elements rather than a single one.
+        count: Option<usize>,
+        left: bool,
+    },
+    BlockingPop {
+        keys: Vec<String>,
+        /// How long to wait for an element, zero meaning forever.
+        timeout: Duration,
+        left: bool,
+    },
+}
+
+impl ListCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        let left = name.starts_with('l') || name.starts_with("bl");
+
+        match (name, args) {
+            ("lpush" | "rpush", [key, values @ ..]) if !values.is_empty() => Ok(Self::Push {
+                key: key.clone(),
+                values: values.to_vec(),
+                left,
+            }),
+            ("llen", [key]) => Ok(Self::Len { key: key.clone() }),
+            ("lrange", [key, start, stop]) => Ok(Self::Range {
+                key: key.clone(),
+                start: start.parse()?,
+                stop: stop.parse()?,
+            }),
+            ("lpop" | "rpop", [key]) => Ok(Self::Pop {
+                key: key.clone(),
+                count: None,
+                left,
+            }),
+            ("lpop" | "rpop", [key, count]) => {
+                let count = count.parse::<i64>()?;
+                let count = usize::try_from(count).map_err(|_| {
+                    RedisError::Other("value is out of range, must be positive".to_string())
+                })?;
+
+                Ok(Self::Pop {
+                    key: key.clone(),
+                    count: Some(count),
+                    left,
+                })
+            }
+            ("blpop" | "brpop", [keys @ .., timeout]) if !keys.is_empty() => {
+                let timeout = timeout
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|timeout| timeout.is_finite())
+                    .ok_or_else(|| {
+                        RedisError::Other("timeout is not a float or out of range".to_string())
+                    })?;
+                if timeout < 0.0 {
+                    return Err(RedisError::Other("timeout is negative".to_string()));
+                }
+
+                Ok(Self::BlockingPop {
+                    keys: keys.to_vec(),
+                    timeout: Duration::from_secs_f64(timeout),
+                    left,
+                })
+            }
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            Self::Push { key, .. }
+            | Self::Len { key }
+            | Self::Range { key, .. }
+            | Self::Pop { key, .. } => vec![key],
+            Self::BlockingPop { keys, .. } => keys.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// The hash commands.
+#[derive(Debug)]
+pub enum HashCommand {
+    Set {
+        key: String,
+        fields: Vec<(String, String)>,
+    },
+    Get {
+        key: String,
+        field: String,
+    },
+    MGet {
+        key: String,
+        fields: Vec<String>,
+    },
+    Del {
+        key: String,
+        fields: Vec<String>,
+    },
+    Exists {
+        key: String,
+        field: String,
+    },
+    GetAll {
+        key: String,
+    },
+    IncrBy {
+        key: String,
+        field: String,
+        increment: i64,
+    },
+}
+
+impl HashCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        match (name, args) {
+            ("hset", [key, fields @ ..]) if !fields.is_empty() && fields.len() % 2 == 0 => {
+                Ok(Self::Set {
+                    key: key.clone(),
+                    fields: fields
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect(),
+                })
+            }
+            ("hget", [key, field]) => Ok(Self::Get {
+                key: key.clone(),
+                field: field.clone(),
+            }),
+            ("hmget", [key, fields @ ..]) if !fields.is_empty() => Ok(Self::MGet {
+                key: key.clone(),
+                fields: fields.to_vec(),
+            }),
+            ("hdel", [key, fields @ ..]) if !fields.is_empty() => Ok(Self::Del {
+                key: key.clone(),
+                fields: fields.to_vec(),
+            }),
+            ("hexists", [key, field]) => Ok(Self::Exists {
+                key: key.clone(),
+                field: field.clone(),
+            }),
+            ("hgetall", [key]) => Ok(Self::GetAll { key: key.clone() }),
+            ("hincrby", [key, field, increment]) => Ok(Self::IncrBy {
+                key: key.clone(),
+                field: field.clone(),
+                increment: increment.parse()?,
+            }),
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Self::Set { key, .. }
+            | Self::Get { key, .. }
+            | Self::MGet { key, .. }
+            | Self::Del { key, .. }
+            | Self::Exists { key, .. }
+            | Self::GetAll { key }
+            | Self::IncrBy { key, .. } => key,
+        }
+    }
+}
+
+/// The stream commands.
+#[derive(Debug)]
+pub enum StreamCommand {
+    Add {
+        key: String,
+        /// `*`, `ms-*` or an explicit ID.
+        id: String,
+        /// Don't create the stream if it doesn't exist.
+        no_mkstream: bool,
+        /// Trim the stream to this many entries after adding.
+        maxlen: Option<usize>,
+        fields: Vec<(String, String)>,
+    },
+    Range {
+        key: String,
+        start: String,
+        end: String,
+        count: Option<usize>,
+        /// XREVRANGE, which takes the end before the start.
+        rev: bool,
+    },
+    Len {
+        key: String,
+    },
+    Read {
+        count: Option<usize>,
+        /// How long to wait for new entries, zero meaning forever.
+        block: Option<Duration>,
+        keys: Vec<String>,
+        /// The ID to read after for each key, or `$` for the last one when the command runs.
+        ids: Vec<String>,
+    },
+}
+
+impl StreamCommand {
+    fn parse(name: &str, args: &[String]) -> Result<Self, RedisError> {
+        let count = |value: &String| value.parse::<usize>().map_err(|_| RedisError::NotInteger);
+
+        match (name, args) {
+            ("xadd", [key, rest @ ..]) => {
+                let mut no_mkstream = false;
+                let mut maxlen = None;
+
+                let mut rest = rest;
+                loop {
+                    match rest {
+                        [option, tail @ ..] if option.eq_ignore_ascii_case("nomkstream") => {
+                            no_mkstream = true;
+                            rest = tail;
+                        }
+                        [option, tail @ ..] if option.eq_ignore_ascii_case("maxlen") => {
+                            // Trimming is always exact, so `~` is accepted but doesn't matter.
+                            let tail = match tail {
+                                [modifier, tail @ ..] if modifier == "=" || modifier == "~" => tail,
+                                tail => tail,
+                            };
+                            let [value, tail @ ..] = tail else {
+                                return Err(RedisError::Syntax);
+                            };
+                            maxlen = Some(count(value)?);
+                            rest = tail;
+                        }
+                        _ => break,
+                    }
+                }
+
+                let [id, fields @ ..] = rest else {
+                    return Err(RedisError::WrongArity(name.to_string()));
+                };
+                if fields.is_empty() || fields.len() % 2 != 0 {
+                    return Err(RedisError::WrongArity(name.to_string()));
+                }
+
+                Ok(Self::Add {
+                    key: key.clone(),
+                    id: id.clone(),
+                    no_mkstream,
+                    maxlen,
+                    fields: fields
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect(),
+                })
+            }
+            ("xrange" | "xrevrange", [key, first, second, options @ ..]) => {
+                let rev = name == "xrevrange";
+                let (start, end) = match rev {
+                    true => (second, first),
+                    false => (first, second),
+                };
+
+                let count = match options {
+                    [] => None,
+                    [option, value] if option.eq_ignore_ascii_case("count") => Some(count(value)?),
+                    _ => return Err(RedisError::Syntax),
+                };
+
+                Ok(Self::Range {
+                    key: key.clone(),
+                    start: start.clone(),
+                    end: end.clone(),
+                    count,
+                    rev,
+                })
+            }
+            ("xlen", [key]) => Ok(Self::Len { key: key.clone() }),
+            ("xread", _) => {
+                let mut read_count = None;
+                let mut block = None;
+
+                let mut rest = args;
+                let streams = loop {
+                    match rest {
+                        [option, value, tail @ ..] if option.eq_ignore_ascii_case("count") => {
+                            read_count = Some(count(value)?);
+                            rest = tail;
+                        }
+                        [option, value, tail @ ..] if option.eq_ignore_ascii_case("block") => {
+                            let ms = value.parse::<u64>().map_err(|_| {
+                                RedisError::Other(
+                                    "timeout is not an integer or out of range".into(),
+                                )
+                            })?;
+                            block = Some(Duration::from_millis(ms));
+                            rest = tail;
+                        }
+                        [option, tail @ ..] if option.eq_ignore_ascii_case("streams") => {
+                            break tail
+                        }
+                        [] => return Err(RedisError::WrongArity(name.to_string())),
+                        _ => return Err(RedisError::Syntax),
+                    }
+                };
+
+                if streams.is_empty() || streams.len() % 2 != 0 {
+                    return Err(RedisError::Other(
+                        "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string(),
+                    ));
+                }
+
+                let (keys, ids) = streams.split_at(streams.len() / 2);
+                Ok(Self::Read {
+                    count: read_count,
+                    block,
+                    keys: keys.to_vec(),
+                    ids: ids.to_vec(),
+                })
+            }
+            _ => Err(RedisError::WrongArity(name.to_string())),
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            Self::Add { key, .. } | Self::Range { key, .. } | Self::Len { key } => vec![key],
+            Self::Read { keys, .. } => keys.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+impl ConfigCommand {
+    fn parse(subcommand: &str, args: &[String]) -> Result<Self, RedisError> {
+        let lowercase = subcommand.to_lowercase();
+
+        match (lowercase.as_str(), args) {
+            ("get", [_, ..]) => Ok(Self::Get(args.to_vec())),
+            ("set", [_, _, ..]) if args.chunks_exact(2).remainder().is_empty() => Ok(Self::Set(
+                args.chunks_exact(2)
+                    .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                    .collect(),
+            )),
+            ("get" | "set", _) => Err(RedisError::WrongArity(format!("config|{lowercase}"))),
+            _ => Err(RedisError::UnknownSubcommand(
+                subcommand.to_string(),
+                "CONFIG".to_string(),
+            )),
+        }
+    }
+}
+
+impl CommandCommand {
+    fn parse(args: &[String]) -> Result<Self, RedisError> {
+        let Some((subcommand, args)) = args.split_first() else {
+            return Ok(Self::List);
+        };
+        let lowercase = subcommand.to_lowercase();
+        let names = || args.iter().map(|name| name.to_lowercase()).collect();
+
+        match (lowercase.as_str(), args) {
+            ("count", []) => Ok(Self::Count),
+            ("count", _) => Err(RedisError::WrongArity(format!("command|{lowercase}"))),
+            ("info", _) => Ok(Self::Info(names())),
+            ("docs", _) => Ok(Self::Docs(names())),
+            _ => Err(RedisError::UnknownSubcommand(
+                subcommand.to_string(),
+                "COMMAND".to_string(),
+            )),
+        }
+    }
+}
+
+impl ClientCommand {
+    fn parse(subcommand: &str, args: &[String]) -> Result<Self, RedisError> {
+        let lowercase = subcommand.to_lowercase();
+
+        match (lowercase.as_str(), args) {
+            ("id", []) => Ok(Self::Id),
+            ("list", []) => Ok(Self::List),
+            ("getname", []) => Ok(Self::GetName),
+            ("setname", [name]) => {
+                if name.chars().any(|c| !c.is_ascii_graphic()) {
+                    return Err(RedisError::Other(
+                        "Client names cannot contain spaces, newlines or special characters."
+                            .to_string(),
+                    ));
+                }
+
+                Ok(Self::SetName(name.clone()))
+            }
+            ("kill", [addr]) => Ok(Self::Kill(KillFilter::Addr(addr.clone()), true)),
+            ("kill", [filter, value]) if filter.eq_ignore_ascii_case("id") => {
+                Ok(Self::Kill(KillFilter::Id(value.parse()?), false))
+            }
+            ("kill", [filter, value]) if filter.eq_ignore_ascii_case("addr") => {
+                Ok(Self::Kill(KillFilter::Addr(value.clone()), false))
+            }
+            ("kill", [_, _]) => Err(RedisError::Syntax),
+            ("no-ratelimit", [flag]) => match flag.to_lowercase().as_str() {
+                "on" => Ok(Self::NoRateLimit(true)),
+                "off" => Ok(Self::NoRateLimit(false)),
+                _ => Err(RedisError::Syntax),
+            },
+            ("id" | "list" | "getname" | "setname" | "kill" | "no-ratelimit", _) => {
+                Err(RedisError::WrongArity(format!("client|{lowercase}")))
+            }
+            _ => Err(RedisError::UnknownSubcommand(
+                subcommand.to_string(),
+                "CLIENT".to_string(),
+            )),
+        }
+    }
 }
 
 impl Command {
-    pub fn literal_value(self) -> Result<String, std::io::Error> {
+    /// Parse a command from the elements of a request array, the first one being the command name.
+    pub fn parse(frames: &[RespType]) -> Result<Self, RedisError> {
+        let Some((name, rest)) = frames.split_first() else {
+            return Err(RedisError::Protocol("empty command".to_string()));
+        };
+
+        let name = argument(name)?;
+        let lowercase = name.to_lowercase();
+
+        // Binary arguments are taken from their frames as they are when the command is built, so
+        // they're only given a placeholder here.
+        let binary = match lowercase.as_str() {
+            "set" => Some(1),
+            "restore" => Some(2),
+            _ => None,
+        };
+        let mut args = rest
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| match Some(i) == binary {
+                true => Ok(String::new()),
+                false => argument(frame),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match command_spec(&lowercase) {
+            Some(spec) if arity_allows(spec.arity, args.len() + 1) => (),
+            Some(_) => return Err(RedisError::WrongArity(lowercase)),
+            None => return Err(RedisError::UnknownCommand(name, args)),
+        }
+
+        // HELP is answered the same way by every container command.
+        if let [subcommand, rest @ ..] = args.as_slice() {
+            if subcommand.eq_ignore_ascii_case("help") {
+                let container = CONTAINERS
+                    .iter()
+                    .find(|(container, _)| *container == lowercase);
+
+                match (container, rest) {
+                    (Some((container, _)), []) => return Ok(Self::Help(container)),
+                    (Some(_), _) => {
+                        return Err(RedisError::WrongArity(format!("{lowercase}|help")))
+                    }
+                    (None, _) => (),
+                }
+            }
+        }
+
+        match (lowercase.as_str(), args.as_mut_slice()) {
+            ("ping", [] | [_]) => Ok(Self::Ping),
+            ("echo", [message]) => Ok(Self::Echo(message.clone())),
+            ("set", [key, _, options @ ..]) => {
+                SetCommand::parse(key, argument_bytes(&frames[2])?, options).map(Self::Set)
+            }
+            ("get", [key]) => Ok(Self::Get(key.clone())),
+            ("del", _) => Ok(Self::Del(args.to_vec())),
+            ("exists", _) => Ok(Self::Exists(args.to_vec())),
+            ("keys", [pattern]) => Ok(Self::Keys(pattern.clone())),
+            ("type", [key]) => Ok(Self::Type(key.clone())),
+            ("incr" | "incrby" | "decr" | "decrby", _) => {
+                IncrCommand::parse(&lowercase, &args).map(Self::Incr)
+            }
+            ("info", []) => Ok(Self::Info(None)),
+            ("info", [section]) => Ok(Self::Info(Some(section.clone()))),
+            ("dbsize", []) => Ok(Self::DbSize),
+            ("command", _) => CommandCommand::parse(&args).map(Self::Command),
+            ("hello", []) => Ok(Self::Hello(None)),
+            ("hello", [protover]) => Ok(Self::Hello(Some(protover.clone()))),
+            ("client", [subcommand, args @ ..]) => {
+                ClientCommand::parse(subcommand, args).map(Self::Client)
+            }
+            ("config", [subcommand, args @ ..]) => {
+                ConfigCommand::parse(subcommand, args).map(Self::Config)
+            }
+            ("object", [subcommand, args @ ..]) => {
+                ObjectCommand::parse(subcommand, args).map(Self::Object)
+            }
+            ("debug", [subcommand, args @ ..]) => {
+                DebugCommand::parse(subcommand, args).map(Self::Debug)
+            }
+            ("dump", [key]) => Ok(Self::Dump(key.clone())),
+            ("save", []) => Ok(Self::Save),
+            ("bgsave", []) => Ok(Self::BgSave),
+            ("bgrewriteaof", []) => Ok(Self::BgRewriteAof),
+            ("replconf", _) => ReplconfCommand::parse(&args).map(Self::Replconf),
+            ("psync", [replid, offset]) => Ok(Self::Psync(replid.clone(), offset.parse()?)),
+            ("replicaof" | "slaveof", [host, port]) => {
+                if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                    Ok(Self::ReplicaOf(None))
+                } else {
+                    let port = port
+                        .parse()
+                        .map_err(|_| RedisError::Other("Invalid master port".to_string()))?;
+                    Ok(Self::ReplicaOf(Some((host.clone(), port))))
+                }
+            }
+            ("subscribe", [_, ..]) => Ok(Self::Subscribe(args.to_vec())),
+            ("unsubscribe", _) => Ok(Self::Unsubscribe(args.to_vec())),
+            ("publish", [channel, message]) => Ok(Self::Publish(channel.clone(), message.clone())),
+            ("multi", []) => Ok(Self::Multi),
+            ("exec", []) => Ok(Self::Exec),
+            ("discard", []) => Ok(Self::Discard),
+            ("restore", [key, ttl, _, options @ ..]) => {
+                let payload = argument_bytes(&frames[3])?;
+                RestoreCommand::parse(key, ttl, payload, options).map(Self::Restore)
+            }
+            ("expire" | "pexpire" | "expireat" | "pexpireat" | "ttl" | "pttl" | "persist", _) => {
+                ExpireCommand::parse(&lowercase, &args).map(Self::Expire)
+            }
+            ("migrate", [_, _, _, _, _, ..]) => MigrateCommand::parse(&args).map(Self::Migrate),
+            ("json.set" | "json.get" | "json.del", _) => {
+                JsonCommand::parse(&lowercase, &args).map(Self::Json)
+            }
+            ("bf.loadchunk", [key, iterator, _]) => {
+                let data = argument_bytes(&frames[3])?;
+                BloomCommand::parse_load_chunk(key, iterator, data).map(Self::Bloom)
+            }
+            ("bf.reserve" | "bf.add" | "bf.madd" | "bf.exists" | "bf.scandump", _) => {
+                BloomCommand::parse(&lowercase, &args).map(Self::Bloom)
+            }
+            ("lpush" | "rpush" | "llen" | "lrange" | "lpop" | "rpop" | "blpop" | "brpop", _) => {
+                ListCommand::parse(&lowercase, &args).map(Self::List)
+            }
+            ("hset" | "hget" | "hmget" | "hdel" | "hexists" | "hgetall" | "hincrby", _) => {
+                HashCommand::parse(&lowercase, &args).map(Self::Hash)
+            }
+            ("xadd" | "xrange" | "xrevrange" | "xlen" | "xread", _) => {
+                StreamCommand::parse(&lowercase, &args).map(Self::Stream)
+            }
+            // Shapes the arity allows but the command doesn't, like INFO with two sections.
+            _ => Err(RedisError::WrongArity(lowercase)),
+        }
+    }
+
+    /// The lowercase command name.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Literal(_) => "literal",
+            Self::Ping => "ping",
+            Self::Echo(_) => "echo",
+            Self::Set(..) => "set",
+            Self::Get(_) => "get",
+            Self::Del(_) => "del",
+            Self::Exists(_) => "exists",
+            Self::Keys(_) => "keys",
+            Self::Type(_) => "type",
+            Self::Incr(incr) => incr.name(),
+            Self::Info(_) => "info",
+            Self::DbSize => "dbsize",
+            Self::Command(_) => "command",
+            Self::Hello(_) => "hello",
+            Self::Client(_) => "client",
+            Self::Config(_) => "config",
+            Self::Object(_) => "object",
+            Self::Debug(_) => "debug",
+            Self::Save => "save",
+            Self::BgSave => "bgsave",
+            Self::BgRewriteAof => "bgrewriteaof",
+            Self::Replconf(_) => "replconf",
+            Self::Psync(..) => "psync",
+            Self::ReplicaOf(_) => "replicaof",
+            Self::Subscribe(_) => "subscribe",
+            Self::Unsubscribe(_) => "unsubscribe",
+            Self::Publish(..) => "publish",
+            Self::Multi => "multi",
+            Self::Exec => "exec",
+            Self::Discard => "discard",
+            Self::Help(container) => container,
+            Self::Dump(_) => "dump",
+            Self::Restore(_) => "restore",
+            Self::Expire(expire) => expire.name(),
+            Self::Migrate(_) => "migrate",
+            Self::Json(JsonCommand::Set { .. }) => "json.set",
+            Self::Json(JsonCommand::Get { .. }) => "json.get",
+            Self::Json(JsonCommand::Del { .. }) => "json.del",
+            Self::Bloom(BloomCommand::Reserve { .. }) => "bf.reserve",
+            Self::Bloom(BloomCommand::Add { .. }) => "bf.add",
+            Self::Bloom(BloomCommand::MAdd { .. }) => "bf.madd",
+            Self::Bloom(BloomCommand::Exists { .. }) => "bf.exists",
+            Self::Bloom(BloomCommand::ScanDump { .. }) => "bf.scandump",
+            Self::Bloom(BloomCommand::LoadChunk { .. }) => "bf.loadchunk",
+            Self::List(ListCommand::Push { left: true, .. }) => "lpush",
+            Self::List(ListCommand::Push { left: false, .. }) => "rpush",
+            Self::List(ListCommand::Len { .. }) => "llen",
+            Self::List(ListCommand::Range { .. }) => "lrange",
+            Self::List(ListCommand::Pop { left: true, .. }) => "lpop",
+            Self::List(ListCommand::Pop { left: false, .. }) => "rpop",
+            Self::List(ListCommand::BlockingPop { left: true, .. }) => "blpop",
+            Self::List(ListCommand::BlockingPop { left: false, .. }) => "brpop",
+            Self::Hash(HashCommand::Set { .. }) => "hset",
+            Self::Hash(HashCommand::Get { .. }) => "hget",
+            Self::Hash(HashCommand::MGet { .. }) => "hmget",
+            Self::Hash(HashCommand::Del { .. }) => "hdel",
+            Self::Hash(HashCommand::Exists { .. }) => "hexists",
+            Self::Hash(HashCommand::GetAll { .. }) => "hgetall",
+            Self::Hash(HashCommand::IncrBy { .. }) => "hincrby",
+            Self::Stream(StreamCommand::Add { .. }) => "xadd",
+            Self::Stream(StreamCommand::Range { rev: false, .. }) => "xrange",
+            Self::Stream(StreamCommand::Range { rev: true, .. }) => "xrevrange",
+            Self::Stream(StreamCommand::Len { .. }) => "xlen",
+            Self::Stream(StreamCommand::Read { .. }) => "xread",
+            Self::Custom(name, _) => name,
+        }
+    }
+
+    /// The keys the command accesses.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Self::Get(key) | Self::Object(ObjectCommand::Encoding(key)) | Self::Dump(key) => {
+                vec![key]
+            }
+            Self::Set(set) => vec![&set.key],
+            Self::Del(keys) | Self::Exists(keys) => keys.iter().map(String::as_str).collect(),
+            Self::Type(key) => vec![key],
+            Self::Incr(incr) => vec![&incr.key],
+            Self::Restore(restore) => vec![&restore.key],
+            Self::Expire(expire) => vec![expire.key()],
+            Self::Migrate(migrate) => migrate.keys.iter().map(String::as_str).collect(),
+            Self::Json(json) => vec![json.key()],
+            Self::Bloom(bloom) => vec![bloom.key()],
+            Self::Stream(stream) => stream.keys(),
+            Self::List(list) => list.keys(),
+            Self::Hash(hash) => vec![hash.key()],
+            _ => vec![],
+        }
+    }
+
+    /// Whether a RESP2 client subscribed to channels may run the command. Its connection only
+    /// carries messages and subscription changes, so other replies couldn't be told apart.
+    pub fn allowed_when_subscribed(&self) -> bool {
+        matches!(self, Self::Subscribe(_) | Self::Unsubscribe(_) | Self::Ping)
+    }
+
+    /// Whether the command may wait for another client's write before replying.
+    pub fn may_block(&self) -> bool {
+        matches!(
+            self,
+            Self::List(ListCommand::BlockingPop { .. })
+                | Self::Stream(StreamCommand::Read { block: Some(_), .. })
+        )
+    }
+
+    /// Whether the command may change the keyspace, which replicas only allow their master to do.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Self::Literal(_) | Self::Help(_) | Self::Custom(..) => false,
+            command => {
+                command_spec(command.name()).is_some_and(|spec| spec.flags.contains(&"write"))
+            }
+        }
+    }
+
+    pub fn literal_value(self) -> Result<String, RedisError> {
         match self {
             Self::Literal(v) => Ok(v),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "not a literal command",
-            )),
+            _ => Err(RedisError::Syntax),
+        }
+    }
+
+    /// The key the command operates on together with the type of value it expects to find there.
+    /// Commands that don't read existing values, like SET which overwrites any type, return `None`.
+    pub fn typed_key(&self) -> Option<(&str, ValueType)> {
+        match self {
+            Self::Get(key) => Some((key, ValueType::String)),
+            Self::Incr(incr) => Some((&incr.key, ValueType::String)),
+            Self::Json(json) => Some((json.key(), ValueType::Json)),
+            Self::Bloom(bloom) => Some((bloom.key(), ValueType::Bloom)),
+            Self::Stream(
+                StreamCommand::Add { key, .. }
+                | StreamCommand::Range { key, .. }
+                | StreamCommand::Len { key },
+            ) => Some((key, ValueType::Stream)),
+            Self::List(
+                ListCommand::Push { key, .. }
+                | ListCommand::Len { key }
+                | ListCommand::Range { key, .. }
+                | ListCommand::Pop { key, .. },
+            ) => Some((key, ValueType::List)),
+            Self::Hash(hash) => Some((hash.key(), ValueType::Hash)),
+            _ => None,
+        }
+    }
+}
+
+fn argument_bytes(frame: &RespType) -> Result<Bytes, RedisError> {
+    match frame {
+        RespType::BulkString(_, value) => Ok(value.clone()),
+        RespType::SimpleString(value) => Ok(Bytes::copy_from_slice(value.as_bytes())),
+        RespType::Attribute(_, value) => argument_bytes(value),
+        _ => Err(RedisError::Protocol(format!(
+            "unexpected {frame:?} in command"
+        ))),
+    }
+}
+
+/// An argument as text. Keys, options and the values of every type but strings are held as text,
+/// so any non UTF-8 data is replaced.
+fn argument(frame: &RespType) -> Result<String, RedisError> {
+    match frame {
+        RespType::BulkString(_, value) => Ok(String::from_utf8_lossy(value).into_owned()),
+        RespType::SimpleString(value) => Ok(value.to_string()),
+        RespType::Attribute(_, value) => argument(value),
+        _ => Err(RedisError::Protocol(format!(
+            "unexpected {frame:?} in command"
+        ))),
+    }
+}
+
+/// A command added by an embedder, see [`crate::server::Server::register_command`].
+pub trait CommandHandler: Send + Sync {
+    /// Number of arguments including the command name, following the COMMAND convention where a
+    /// negative arity means at least that many.
+    fn arity(&self) -> i64;
+
+    /// Flags reported by COMMAND, e.g. `write` or `readonly`.
+    fn flags(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Execute the command with the arguments following the command name.
+    fn execute(&self, args: &[String], cache: &Cache) -> RespType;
+}
+
+/// Custom commands keyed by their lowercase name. Built-in commands always take precedence.
+#[derive(Default)]
+pub(crate) struct CommandRegistry {
+    handlers: RwLock<HashMap<String, Arc<dyn CommandHandler>>>,
+}
+
+impl CommandRegistry {
+    pub(crate) fn register(&self, name: &str, handler: Arc<dyn CommandHandler>) {
+        self.handlers
+            .write()
+            .unwrap()
+            .insert(name.to_lowercase(), handler);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<dyn CommandHandler>> {
+        self.handlers
+            .read()
+            .unwrap()
+            .get(&name.to_lowercase())
+            .cloned()
+    }
+
+    /// The names of the custom commands that aren't shadowed by a built-in one, sorted.
+    fn names(&self) -> Vec<String> {
+        let mut names = self
+            .handlers
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|name| command_spec(name).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+
+        names
+    }
+
+    /// Execute a custom command after validating its arity.
+    pub(crate) fn execute(
+        &self,
+        handler: &dyn CommandHandler,
+        name: &str,
+        args: &[String],
+        cache: &Cache,
+    ) -> RespType {
+        if !arity_allows(handler.arity(), args.len() + 1) {
+            return RedisError::WrongArity(name.to_lowercase()).to_resp();
+        }
+
+        handler.execute(args, cache)
+    }
+}
+
+impl std::fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.handlers.read().unwrap().keys())
+            .finish()
+    }
+}
+
+/// Execute a command against the cache and return the reply. Commands that change connection state,
+/// like HELLO, only affect the reply.
+pub fn execute(command: &Command, cache: &Cache) -> RespType {
+    execute_with_protocol(command, cache, &mut Protocol::default())
+}
+
+/// Execute a command for a connection currently speaking `protocol`, which HELLO may switch.
+pub(crate) fn execute_with_protocol(
+    command: &Command,
+    cache: &Cache,
+    protocol: &mut Protocol,
+) -> RespType {
+    if let Err(err) = check_type(command, cache) {
+        return err.to_resp();
+    }
+
+    match command {
+        Command::Literal(value) => RedisError::UnknownCommand(value.clone(), vec![]).to_resp(),
+        // CLIENT, CONFIG, saving, replication, pub/sub, transactions and custom commands are
+        // resolved by the server.
+        Command::Client(_)
+        | Command::Config(_)
+        | Command::Save
+        | Command::BgSave
+        | Command::BgRewriteAof
+        | Command::Replconf(_)
+        | Command::Psync(..)
+        | Command::ReplicaOf(_)
+        | Command::Subscribe(_)
+        | Command::Unsubscribe(_)
+        | Command::Publish(..)
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard => {
+            RedisError::Other(format!("{} requires a server", command.name())).to_resp()
+        }
+        Command::Custom(name, args) => {
+            RedisError::UnknownCommand(name.clone(), args.clone()).to_resp()
+        }
+        Command::Ping => RespType::simple("PONG"),
+        Command::Echo(response) => response.as_str().into(),
+        Command::Set(set) => execute_set(set, cache),
+        Command::Get(key) => cache.get_bytes(key).map(RespType::bulk).into(),
+        Command::Del(keys) => (keys.iter().filter(|key| cache.delete(key)).count() as i64).into(),
+        Command::Exists(keys) => {
+            let existing = keys.iter().filter(|key| cache.value_type(key).is_some());
+            (existing.count() as i64).into()
+        }
+        Command::Keys(pattern) => {
+            let mut keys = Vec::new();
+            cache.scan(|key, _, _| {
+                if glob_match(pattern, key) {
+                    keys.push(RespType::from(key));
+                }
+            });
+
+            RespType::array(keys)
+        }
+        Command::Type(key) => RespType::simple(
+            cache
+                .value_type(key)
+                .map_or("none", |value_type| value_type.name()),
+        ),
+        Command::Incr(incr) => execute_incr(incr, cache),
+        Command::Object(ObjectCommand::Encoding(key)) => cache.encoding(key).into(),
+        Command::Dump(key) => match cache.read(key, dump::serialize) {
+            Some(Ok(payload)) => RespType::bulk(payload),
+            Some(Err(err)) => err.to_resp(),
+            None => RespType::null(),
+        },
+        Command::Restore(restore) => execute_restore(restore, cache),
+        Command::Expire(expire) => execute_expire(expire, cache),
+        Command::Migrate(migrate) => dump::migrate(migrate, cache),
+        Command::Json(command) => json::execute(command, cache),
+        Command::Bloom(command) => bloom::execute(command, cache),
+        Command::Stream(command) => stream::execute(command, cache),
+        Command::List(command) => list::execute(command, cache),
+        Command::Hash(command) => hash::execute(command, cache),
+        Command::Debug(command) => debug::execute(command, cache),
+        Command::Help(container) => help_reply(container),
+        Command::DbSize => (cache.keyspace().keys as i64).into(),
+        Command::Command(command) => command_reply(command, &CommandRegistry::default()),
+        Command::Info(section) => {
+            let info = match section.as_ref().map(|s| s.to_lowercase()).as_deref() {
+                None | Some("stats") | Some("all") | Some("default") | Some("everything") => {
+                    stats_info(cache)
+                }
+                Some(_) => String::new(),
+            };
+
+            RespType::verbatim("txt", info)
+        }
+        Command::Hello(protover) => match protover.as_deref() {
+            None => hello_reply(*protocol),
+            Some("2") => {
+                *protocol = Protocol::Resp2;
+                hello_reply(*protocol)
+            }
+            Some("3") => {
+                *protocol = Protocol::Resp3;
+                hello_reply(*protocol)
+            }
+            Some(_) => RespType::error("NOPROTO", "unsupported protocol version"),
+        },
+    }
+}
+
+/// Reply to COMMAND, describing the built-in commands and the custom ones in `custom`.
+pub(crate) fn command_reply(command: &CommandCommand, custom: &CommandRegistry) -> RespType {
+    let all = || {
+        COMMANDS
+            .iter()
+            .map(|spec| spec.name.to_string())
+            .chain(custom.names())
+            .collect::<Vec<_>>()
+    };
+
+    match command {
+        CommandCommand::Count => ((COMMANDS.len() + custom.names().len()) as i64).into(),
+        CommandCommand::List => RespType::array(
+            all()
+                .iter()
+                .filter_map(|name| command_info(name, custom))
+                .collect(),
+        ),
+        CommandCommand::Info(names) if names.is_empty() => {
+            command_reply(&CommandCommand::List, custom)
+        }
+        CommandCommand::Info(names) => RespType::array(
+            names
+                .iter()
+                .map(|name| command_info(name, custom).unwrap_or_else(RespType::null))
+                .collect(),
+        ),
+        // There's no documentation beyond what COMMAND INFO reports, so each command maps to an
+        // empty set of fields. Unknown commands are left out like Redis does.
+        CommandCommand::Docs(names) => {
+            let names = match names.is_empty() {
+                true => all(),
+                false => names.clone(),
+            };
+
+            RespType::map(
+                names
+                    .iter()
+                    .filter(|name| command_spec(name).is_some() || custom.get(name).is_some())
+                    .map(|name| (name.as_str().into(), RespType::map(vec![])))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// The COMMAND INFO entry of the command with the lowercase `name`: its name, arity, flags, key
+/// positions, and the ACL categories, tips, key specifications and subcommands Redis 7 adds which
+/// are always empty here.
+fn command_info(name: &str, custom: &CommandRegistry) -> Option<RespType> {
+    let handler;
+    let (arity, flags, (first, last, step)) = match command_spec(name) {
+        Some(spec) => (spec.arity, spec.flags, spec.keys),
+        None => {
+            handler = custom.get(name)?;
+            (handler.arity(), handler.flags(), NO_KEYS)
+        }
+    };
+
+    Some(RespType::array(vec![
+        name.into(),
+        arity.into(),
+        RespType::array(flags.iter().map(|flag| RespType::simple(*flag)).collect()),
+        first.into(),
+        last.into(),
+        step.into(),
+        RespType::array(vec![]),
+        RespType::array(vec![]),
+        RespType::array(vec![]),
+        RespType::array(vec![]),
+    ]))
+}
+
+fn execute_set(set: &SetCommand, cache: &Cache) -> RespType {
+    let expiry = match set.ttl.map(|ttl| ttl.expiry(cache.clock())) {
+        Some(None) => return invalid_expire_time("set").to_resp(),
+        Some(expiry) => expiry,
+        None => None,
+    };
+
+    let written = cache.set_with(&set.key, set.value.clone(), |current| {
+        match (set.condition, current) {
+            (Some(SetCondition::Nx), Some(_)) | (Some(SetCondition::Xx), None) => None,
+            (_, Some(current)) if set.keep_ttl => Some(current),
+            _ => Some(expiry),
         }
+    });
+
+    match written {
+        true => RespType::ok(),
+        false => RespType::null(),
+    }
+}
+
+fn execute_incr(incr: &IncrCommand, cache: &Cache) -> RespType {
+    let increment = match incr.decrement {
+        true => incr.by.checked_neg(),
+        false => Some(incr.by),
+    };
+
+    let result = cache.update(&incr.key, |current| {
+        let value = match &current {
+            None => 0,
+            Some(Value::Int(value)) => *value,
+            // Integers are always stored as such, so any other string isn't one.
+            Some(value) if value.value_type() == ValueType::String => {
+                return (Err(RedisError::NotInteger), Change::Keep)
+            }
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+        };
+
+        let Some(value) = increment.and_then(|increment| value.checked_add(increment)) else {
+            let err = RedisError::Other("increment or decrement would overflow".to_string());
+            return (Err(err), Change::Keep);
+        };
+
+        match current {
+            Some(current) => {
+                *current = Value::Int(value);
+                (Ok(value), Change::Modified)
+            }
+            None => (Ok(value), Change::Set(Value::Int(value))),
+        }
+    });
+
+    match result {
+        Ok(value) => value.into(),
+        Err(err) => err.to_resp(),
+    }
+}
+
+fn execute_expire(expire: &ExpireCommand, cache: &Cache) -> RespType {
+    match expire {
+        ExpireCommand::Expire { key, ttl, .. } => {
+            let Some(expiry) = ttl.expiry(cache.clock()) else {
+                return invalid_expire_time(expire.name()).to_resp();
+            };
+
+            i64::from(cache.set_expiry(key, Some(expiry)).is_some()).into()
+        }
+        ExpireCommand::Ttl { key, millis } => {
+            let ttl = match cache.ttl(key) {
+                None => -2,
+                Some(None) => -1,
+                Some(Some(ttl)) if *millis => ttl.as_millis() as i64,
+                // Rounded like Redis does, so a key set to expire in 10 seconds has a TTL of 10.
+                Some(Some(ttl)) => ((ttl.as_millis() + 500) / 1000) as i64,
+            };
+
+            ttl.into()
+        }
+        ExpireCommand::Persist { key } => {
+            i64::from(matches!(cache.set_expiry(key, None), Some(Some(_)))).into()
+        }
+    }
+}
+
+fn execute_restore(restore: &RestoreCommand, cache: &Cache) -> RespType {
+    let value = match dump::deserialize(&restore.payload) {
+        Ok(value) => value,
+        Err(err) => return err.to_resp(),
+    };
+
+    if !restore.replace && cache.ttl(&restore.key).is_some() {
+        return RespType::error("BUSYKEY", "Target key name already exists.");
+    }
+
+    let expiry = match (restore.ttl, restore.absttl) {
+        (0, _) => None,
+        (at, true) => Some(Expiry::at_unix_millis(cache.clock(), at)),
+        (ttl, false) => Some(Expiry::after(cache.clock(), Duration::from_millis(ttl))),
+    };
+
+    // A key that would expire right away is never created.
+    if expiry.is_some_and(|expiry| expiry.has_passed(cache.clock().now())) {
+        cache.delete(&restore.key);
+        return RespType::ok();
+    }
+
+    cache.set_with_expiry(&restore.key, value, expiry);
+
+    RespType::ok()
+}
+
+/// Reject commands targeting a key that holds a different kind of value than the command expects.
+fn check_type(command: &Command, cache: &Cache) -> Result<(), RedisError> {
+    match command.typed_key() {
+        Some((key, expected)) => match cache.value_type(key) {
+            Some(actual) if actual != expected => Err(RedisError::WrongType),
+            _ => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn stats_info(cache: &Cache) -> String {
+    let stats = cache.stats();
+
+    format!(
+        "# Stats\r\nkeyspace_hits:{}\r\nkeyspace_misses:{}\r\nexpired_keys:{}\r\nevicted_keys:{}\r\n",
+        stats.keyspace_hits, stats.keyspace_misses, stats.expired_keys, stats.evicted_keys,
+    )
+}
+
+/// The keyspace section of INFO. Like Redis databases without keys are left out.
+pub(crate) fn keyspace_info(cache: &Cache) -> String {
+    let keyspace = cache.keyspace();
+    let mut info = "# Keyspace\r\n".to_string();
+
+    if keyspace.keys > 0 {
+        info += &format!(
+            "db0:keys={},expires={},avg_ttl={}\r\n",
+            keyspace.keys,
+            keyspace.expires,
+            keyspace.avg_ttl.as_millis(),
+        );
+    }
+
+    info
+}
+
+fn hello_reply(protocol: Protocol) -> RespType {
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+
+    RespType::map(vec![
+        ("server".into(), "redis".into()),
+        ("version".into(), env!("CARGO_PKG_VERSION").into()),
+        ("proto".into(), proto.into()),
+        ("mode".into(), "standalone".into()),
+        ("role".into(), "master".into()),
+        ("modules".into(), RespType::array(vec![])),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn parse(args: &[&str]) -> Result<Command, RespType> {
+        let frames = args.iter().map(|a| RespType::from(*a)).collect::<Vec<_>>();
+        Command::parse(&frames).map_err(|err| err.to_resp())
+    }
+
+    #[test]
+    fn test_execute() {
+        let cache = Cache::new(1);
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+
+        assert_eq!(execute(&["PING"]), RespType::simple("PONG"));
+        assert_eq!(execute(&["get", "k"]), RespType::null());
+        assert_eq!(execute(&["SET", "k", "v"]), RespType::ok());
+        assert_eq!(execute(&["GET", "k"]), RespType::from("v"));
+        assert_eq!(
+            execute(&["OBJECT", "ENCODING", "k"]),
+            RespType::from("embstr")
+        );
+        assert_eq!(
+            execute(&["object", "encoding", "missing"]),
+            RespType::null()
+        );
+        assert_eq!(
+            execute(&["HELLO", "4"]),
+            RespType::error("NOPROTO", "unsupported protocol version")
+        );
+    }
+
+    #[test]
+    fn test_hello_switches_protocol() {
+        let cache = Cache::new(1);
+        let mut protocol = Protocol::Resp2;
+
+        execute_with_protocol(&Command::Hello(Some("3".into())), &cache, &mut protocol);
+        assert_eq!(protocol, Protocol::Resp3);
+    }
+
+    struct Append;
+
+    impl CommandHandler for Append {
+        fn arity(&self) -> i64 {
+            -3
+        }
+
+        fn execute(&self, args: &[String], cache: &Cache) -> RespType {
+            let value = cache.get(&args[0]).unwrap_or_default() + &args[1..].concat();
+            cache.set(&args[0], value.clone(), None);
+
+            value.into()
+        }
+    }
+
+    #[test]
+    fn test_command_registry() {
+        let cache = Cache::new(1);
+        let registry = CommandRegistry::default();
+        registry.register("MYAPPEND", Arc::new(Append));
+
+        let handler = registry.get("myappend").unwrap();
+        let execute = |args: &[&str]| {
+            let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+            registry.execute(handler.as_ref(), "MyAppend", &args, &cache)
+        };
+
+        assert_eq!(execute(&["k", "a", "b"]), RespType::from("ab"));
+        assert_eq!(execute(&["k", "c"]), RespType::from("abc"));
+        assert_eq!(
+            execute(&["k"]),
+            RespType::error("ERR", "wrong number of arguments for 'myappend' command")
+        );
+        assert!(registry.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_help() {
+        let cache = Cache::new(1);
+        let RespType::Array(lines) = execute(&parse(&["object", "Help"]).unwrap(), &cache) else {
+            panic!("expected an array");
+        };
+
+        assert_eq!(
+            lines,
+            [
+                "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "ENCODING <key>",
+                "    Return the kind of internal representation used to store <key>.",
+                "HELP",
+                "    Print this help.",
+            ]
+            .map(RespType::simple)
+        );
+
+        // Every container documents its subcommands.
+        for (container, subcommands) in CONTAINERS {
+            assert!(!subcommands.is_empty());
+            assert!(
+                matches!(parse(&[container, "HELP"]), Ok(Command::Help(name)) if name == *container)
+            );
+        }
+
+        assert_eq!(
+            parse(&["CLIENT", "help", "me"]).unwrap_err(),
+            RespType::error("ERR", "wrong number of arguments for 'client|help' command")
+        );
+        assert_eq!(
+            parse(&["config", "nope"]).unwrap_err(),
+            RespType::error("ERR", "unknown subcommand 'nope'. Try CONFIG HELP.")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse(&["foo", "a", "b"]).unwrap_err(),
+            RespType::error(
+                "ERR",
+                "unknown command 'foo', with args beginning with: 'a' 'b' "
+            )
+        );
+        assert_eq!(
+            parse(&["GET"]).unwrap_err(),
+            RespType::error("ERR", "wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            parse(&["set", "k", "v", "px", "soon"]).unwrap_err(),
+            RespType::error("ERR", "value is not an integer or out of range")
+        );
+        assert_eq!(
+            parse(&["set", "k", "v", "nx", "xx"]).unwrap_err(),
+            RespType::error("ERR", "syntax error")
+        );
+        assert_eq!(
+            parse(&["set", "k", "v", "ex", "10", "keepttl"]).unwrap_err(),
+            RespType::error("ERR", "syntax error")
+        );
+        assert_eq!(
+            parse(&["set", "k", "v", "px", "0"]).unwrap_err(),
+            RespType::error("ERR", "invalid expire time in 'set' command")
+        );
+        assert_eq!(
+            parse(&["expire", "k", "9223372036854775807"]).unwrap_err(),
+            RespType::error("ERR", "invalid expire time in 'expire' command")
+        );
+    }
+
+    #[test]
+    fn test_keyspace_commands() {
+        let cache = Cache::new(4);
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+
+        for key in ["user:1", "user:2", "session:1"] {
+            execute(&["SET", key, "v"]);
+        }
+        execute(&["RPUSH", "list", "a"]);
+
+        let RespType::Array(mut keys) = execute(&["KEYS", "user:*"]) else {
+            panic!("expected an array");
+        };
+        keys.sort();
+        assert_eq!(keys, vec!["user:1".into(), "user:2".into()]);
+        let RespType::Array(all) = execute(&["KEYS", "*"]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(all.len(), 4);
+        assert_eq!(execute(&["KEYS", "nope*"]), RespType::array(vec![]));
+
+        assert_eq!(execute(&["TYPE", "user:1"]), RespType::simple("string"));
+        assert_eq!(execute(&["TYPE", "list"]), RespType::simple("list"));
+        assert_eq!(execute(&["TYPE", "missing"]), RespType::simple("none"));
+
+        assert_eq!(
+            execute(&["EXISTS", "user:1", "user:1", "missing"]),
+            2.into()
+        );
+        assert_eq!(execute(&["DEL", "user:1", "list", "missing"]), 2.into());
+        assert_eq!(execute(&["EXISTS", "user:1", "list"]), 0.into());
+    }
+
+    #[test]
+    fn test_incr() {
+        let cache = Cache::new(1);
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+
+        assert_eq!(execute(&["INCR", "n"]), 1.into());
+        assert_eq!(execute(&["INCRBY", "n", "10"]), 11.into());
+        assert_eq!(execute(&["DECR", "n"]), 10.into());
+        assert_eq!(execute(&["DECRBY", "n", "-5"]), 15.into());
+        assert_eq!(execute(&["GET", "n"]), "15".into());
+        assert_eq!(execute(&["DECRBY", "fresh", "3"]), (-3).into());
+
+        // The TTL is kept.
+        execute(&["SET", "t", "1", "EX", "100"]);
+        assert_eq!(execute(&["INCR", "t"]), 2.into());
+        assert!(cache.ttl("t").unwrap().is_some());
+
+        execute(&["SET", "s", "abc"]);
+        assert_eq!(execute(&["INCR", "s"]), RedisError::NotInteger.to_resp());
+        assert_eq!(
+            execute(&["INCR", "s"]),
+            RespType::error("ERR", "value is not an integer or out of range")
+        );
+        assert_eq!(
+            parse(&["INCRBY", "n", "x"]).unwrap_err(),
+            RedisError::NotInteger.to_resp()
+        );
+
+        execute(&["SET", "max", &i64::MAX.to_string()]);
+        assert_eq!(
+            execute(&["INCR", "max"]),
+            RespType::error("ERR", "increment or decrement would overflow")
+        );
+        assert_eq!(
+            execute(&["DECRBY", "n", &i64::MIN.to_string()]),
+            RespType::error("ERR", "increment or decrement would overflow")
+        );
+
+        execute(&["RPUSH", "list", "a"]);
+        assert_eq!(execute(&["INCR", "list"]), RedisError::WrongType.to_resp());
+    }
+
+    #[test]
+    fn test_introspection() {
+        let cache = Cache::new(1);
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+
+        assert_eq!(execute(&["DBSIZE"]), 0.into());
+        cache.set("a", "1", None);
+        cache.set("b", "2", None);
+        assert_eq!(execute(&["DBSIZE"]), 2.into());
+
+        assert_eq!(
+            execute(&["COMMAND", "COUNT"]),
+            (COMMANDS.len() as i64).into()
+        );
+        let RespType::Array(all) = execute(&["COMMAND"]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(all.len(), COMMANDS.len());
+
+        let RespType::Array(infos) = execute(&["COMMAND", "INFO", "GET", "blpop", "nope"]) else {
+            panic!("expected an array");
+        };
+        let RespType::Array(get) = &infos[0] else {
+            panic!("expected an array");
+        };
+        assert_eq!(get[0], "get".into());
+        assert_eq!(get[1], 2.into());
+        assert_eq!(
+            get[2],
+            RespType::array(vec![RespType::simple("readonly"), RespType::simple("fast")])
+        );
+        assert_eq!(get[3..6], [1.into(), 1.into(), 1.into()]);
+        let RespType::Array(blpop) = &infos[1] else {
+            panic!("expected an array");
+        };
+        assert_eq!(blpop[3..6], [1.into(), (-2).into(), 1.into()]);
+        assert_eq!(infos[2], RespType::null());
+
+        assert_eq!(
+            execute(&["COMMAND", "DOCS", "get", "nope"]),
+            RespType::map(vec![("get".into(), RespType::map(vec![]))])
+        );
+        assert_eq!(
+            parse(&["COMMAND", "nope"]).unwrap_err(),
+            RedisError::UnknownSubcommand("nope".to_string(), "COMMAND".to_string()).to_resp()
+        );
+    }
+
+    #[test]
+    fn test_command_table() {
+        for spec in COMMANDS {
+            assert_eq!(command_spec(spec.name).unwrap().name, spec.name);
+
+            // Every listed command has a parser, which accepts its minimum number of arguments.
+            let mut args = vec!["0"; spec.arity.unsigned_abs() as usize];
+            args[0] = spec.name;
+            assert_ne!(
+                parse(&args).err(),
+                Some(RedisError::WrongArity(spec.name.to_string()).to_resp()),
+                "{}",
+                spec.name
+            );
+        }
+
+        assert!(arity_allows(2, 2));
+        assert!(!arity_allows(2, 3));
+        assert!(arity_allows(-2, 3));
+        assert!(!arity_allows(-2, 1));
+
+        assert!(parse(&["SET", "k", "v"]).unwrap().is_write());
+        assert!(!parse(&["get", "k"]).unwrap().is_write());
+        assert_eq!(
+            parse(&["Set", "k"]).unwrap_err(),
+            RespType::error("ERR", "wrong number of arguments for 'set' command")
+        );
+        assert_eq!(
+            parse(&["INFO", "a", "b"]).unwrap_err(),
+            RespType::error("ERR", "wrong number of arguments for 'info' command")
+        );
+    }
+
+    #[test]
+    fn test_set_options() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::without_eviction_loop(1, clock.clone());
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+        let unix_now = clock.unix_now().as_millis();
+
+        assert_eq!(execute(&["SET", "k", "v", "XX"]), RespType::null());
+        assert_eq!(
+            execute(&["SET", "k", "v", "NX", "EX", "10"]),
+            RespType::ok()
+        );
+        assert_eq!(execute(&["SET", "k", "w", "NX"]), RespType::null());
+        assert_eq!(execute(&["GET", "k"]), RespType::from("v"));
+
+        // KEEPTTL keeps the deadline while other writes replace it.
+        assert_eq!(execute(&["SET", "k", "w", "XX", "KEEPTTL"]), RespType::ok());
+        assert_eq!(execute(&["PTTL", "k"]), 10_000.into());
+        assert_eq!(execute(&["SET", "k", "w"]), RespType::ok());
+        assert_eq!(execute(&["TTL", "k"]), (-1).into());
+
+        let at = (unix_now + 20_000).to_string();
+        assert_eq!(execute(&["SET", "k", "v", "PXAT", &at]), RespType::ok());
+        assert_eq!(execute(&["TTL", "k"]), 20.into());
+
+        let at = (unix_now / 1000 - 1).to_string();
+        assert_eq!(execute(&["SET", "k", "v", "EXAT", &at]), RespType::ok());
+        assert_eq!(execute(&["GET", "k"]), RespType::null());
+    }
+
+    #[test]
+    fn test_binary_value() {
+        let cache = Cache::new(1);
+        let long = Bytes::from(vec![0xff; 64 * 1024]);
+
+        for value in [Bytes::from_static(b"\xff\xfe\x00"), long.clone()] {
+            let frames = ["SET".into(), "k".into(), RespType::bulk(value.clone())];
+            let set = Command::parse(&frames).unwrap();
+
+            assert_eq!(execute(&set, &cache), RespType::ok());
+            assert_eq!(cache.get_bytes("k"), Some(value));
+        }
+
+        // Long values are stored as the handle they were received in.
+        assert_eq!(cache.get_bytes("k").unwrap().as_ptr(), long.as_ptr());
+    }
+
+    #[test]
+    fn test_expire() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::without_eviction_loop(1, clock.clone());
+        let execute = |args: &[&str]| execute(&parse(args).unwrap(), &cache);
+
+        assert_eq!(execute(&["EXPIRE", "k", "10"]), 0.into());
+        assert_eq!(execute(&["TTL", "k"]), (-2).into());
+
+        execute(&["SET", "k", "v"]);
+        assert_eq!(execute(&["PERSIST", "k"]), 0.into());
+        assert_eq!(execute(&["EXPIRE", "k", "10"]), 1.into());
+        assert_eq!(execute(&["GET", "k"]), RespType::from("v"));
+        assert_eq!(cache.keyspace().expires, 1);
+
+        clock.advance(Duration::from_millis(4_400));
+        assert_eq!(execute(&["TTL", "k"]), 6.into());
+        assert_eq!(execute(&["PTTL", "k"]), 5_600.into());
+
+        assert_eq!(execute(&["PERSIST", "k"]), 1.into());
+        assert_eq!(execute(&["TTL", "k"]), (-1).into());
+        assert_eq!(cache.keyspace().expires, 0);
+
+        assert_eq!(execute(&["PEXPIRE", "k", "100"]), 1.into());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(execute(&["GET", "k"]), RespType::null());
+        assert_eq!(execute(&["PERSIST", "k"]), 0.into());
+
+        // A deadline in the past deletes the key right away.
+        execute(&["SET", "k", "v"]);
+        let at = (clock.unix_now().as_millis() + 60_000).to_string();
+        assert_eq!(execute(&["PEXPIREAT", "k", &at]), 1.into());
+        assert_eq!(execute(&["TTL", "k"]), 60.into());
+        assert_eq!(execute(&["EXPIREAT", "k", "1"]), 1.into());
+        assert_eq!(execute(&["TTL", "k"]), (-2).into());
+        assert_eq!(execute(&["EXPIRE", "k", "-1"]), 0.into());
+        assert_eq!(cache.keyspace().keys, 0);
     }
 }
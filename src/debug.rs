@@ -0,0 +1,200 @@
+use crate::{
+    cache::{Cache, MemoryUsage, ValueType},
+    command::DebugCommand,
+    resp_type::RespType,
+};
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// The biggest keys of one type seen so far, ranked both by element count and by estimated bytes.
+struct TypeReport {
+    keys: u64,
+    bytes: u64,
+    by_elements: BinaryHeap<Reverse<(usize, usize, String)>>,
+    by_bytes: BinaryHeap<Reverse<(usize, usize, String)>>,
+}
+
+impl TypeReport {
+    fn new() -> Self {
+        Self {
+            keys: 0,
+            bytes: 0,
+            by_elements: BinaryHeap::new(),
+            by_bytes: BinaryHeap::new(),
+        }
+    }
+
+    fn add(&mut self, key: &str, elements: usize, bytes: usize, count: usize) {
+        self.keys += 1;
+        self.bytes += bytes as u64;
+
+        // Min-heaps capped at `count` so memory stays bounded no matter how big the keyspace is.
+        for (heap, rank) in [
+            (&mut self.by_elements, (elements, bytes)),
+            (&mut self.by_bytes, (bytes, elements)),
+        ] {
+            let smallest = heap.peek().map(|Reverse((a, b, _))| (*a, *b));
+            if heap.len() < count {
+                heap.push(Reverse((rank.0, rank.1, key.to_string())));
+            } else if smallest.is_some_and(|smallest| rank > smallest) {
+                heap.pop();
+                heap.push(Reverse((rank.0, rank.1, key.to_string())));
+            }
+        }
+    }
+
+    fn to_resp(&self, value_type: ValueType) -> RespType {
+        // The heaps hold the values in the order they were ranked by, so each is told which one the
+        // element count is.
+        let entries = |heap: &BinaryHeap<Reverse<(usize, usize, String)>>, elements_first: bool| {
+            let mut entries = heap.iter().map(|Reverse(entry)| entry).collect::<Vec<_>>();
+            entries.sort_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)).then(a.2.cmp(&b.2)));
+
+            let entries = entries
+                .into_iter()
+                .map(|(first, second, key)| {
+                    let (elements, bytes) = match elements_first {
+                        true => (first, second),
+                        false => (second, first),
+                    };
+
+                    RespType::map(vec![
+                        ("key".into(), key.as_str().into()),
+                        ("elements".into(), (*elements as i64).into()),
+                        ("bytes".into(), (*bytes as i64).into()),
+                    ])
+                })
+                .collect();
+
+            RespType::array(entries)
+        };
+
+        RespType::map(vec![
+            ("type".into(), value_type.name().into()),
+            ("keys".into(), (self.keys as i64).into()),
+            ("bytes".into(), (self.bytes as i64).into()),
+            (
+                "biggest-by-elements".into(),
+                entries(&self.by_elements, true),
+            ),
+            ("biggest-by-bytes".into(), entries(&self.by_bytes, false)),
+        ])
+    }
+}
+
+pub(crate) fn execute(command: &DebugCommand, cache: &Cache) -> RespType {
+    match command {
+        DebugCommand::BigKeys(count) => big_keys(*count, cache),
+    }
+}
+
+/// Scan the keyspace and report the `count` biggest keys of each type by element count and by
+/// estimated bytes, like `redis-cli --bigkeys` and `--memkeys` combined. Types without any keys are
+/// left out.
+fn big_keys(count: usize, cache: &Cache) -> RespType {
+    let types = [
+        ValueType::String,
+        ValueType::Json,
+        ValueType::Bloom,
+        ValueType::Stream,
+        ValueType::List,
+        ValueType::Hash,
+    ];
+    let mut reports = types.map(|_| TypeReport::new());
+
+    cache.scan(|key, value, bytes| {
+        let index = types
+            .iter()
+            .position(|value_type| *value_type == value.value_type())
+            .expect("every type is reported");
+
+        reports[index].add(key, value.elements(), bytes, count);
+    });
+
+    let reports = types
+        .iter()
+        .zip(&reports)
+        .filter(|(_, report)| report.keys > 0)
+        .map(|(value_type, report)| report.to_resp(*value_type))
+        .collect();
+
+    RespType::array(reports)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::run;
+
+    /// The keys of a ranking in a report as `(key, elements)`.
+    fn ranking(report: &RespType, name: &str) -> Vec<(String, i64)> {
+        let RespType::Map(_, fields) = report else {
+            panic!("expected a map, got {report:?}");
+        };
+
+        let (_, RespType::Array(entries)) = fields
+            .iter()
+            .find(|(field, _)| *field == RespType::from(name))
+            .unwrap()
+        else {
+            panic!("expected an array");
+        };
+
+        entries
+            .iter()
+            .map(|entry| match entry {
+                RespType::Map(_, fields) => match (&fields[0].1, &fields[1].1) {
+                    (RespType::BulkString(_, key), RespType::Integer(elements)) => {
+                        (String::from_utf8_lossy(key).into_owned(), *elements)
+                    }
+                    other => panic!("unexpected entry {other:?}"),
+                },
+                other => panic!("unexpected entry {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_big_keys() {
+        let cache = Cache::new(4);
+        assert_eq!(run(&cache, &["DEBUG", "BIGKEYS"]), RespType::array(vec![]));
+
+        for (key, len) in [("a", 10), ("b", 300), ("c", 20), ("d", 5)] {
+            cache.set(key, "x".repeat(len), None);
+        }
+        run(&cache, &["JSON.SET", "small", "$", "[1,2,3]"]);
+        run(&cache, &["JSON.SET", "wide", "$", r#"[1,2,3,4,5,6]"#]);
+        let long = format!(r#"[{{"a":"{}"}}]"#, "x".repeat(500));
+        run(&cache, &["JSON.SET", "long", "$", &long]);
+
+        let RespType::Array(reports) = run(&cache, &["DEBUG", "BIGKEYS", "2"]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(reports.len(), 2);
+
+        assert_eq!(
+            ranking(&reports[0], "biggest-by-elements"),
+            vec![("b".to_string(), 300), ("c".to_string(), 20)]
+        );
+        assert_eq!(
+            ranking(&reports[0], "biggest-by-bytes"),
+            vec![("b".to_string(), 300), ("c".to_string(), 20)]
+        );
+
+        // The document with the most elements isn't the one using the most memory.
+        assert_eq!(
+            ranking(&reports[1], "biggest-by-elements"),
+            vec![("wide".to_string(), 6), ("small".to_string(), 3)]
+        );
+        assert_eq!(ranking(&reports[1], "biggest-by-bytes")[0].0, "long");
+
+        assert_eq!(
+            run(&cache, &["DEBUG", "BIGKEYS", "0"]),
+            RespType::error("ERR", "value is out of range, must be positive")
+        );
+        assert_eq!(
+            run(&cache, &["DEBUG", "NOPE"]),
+            RespType::error("ERR", "unknown subcommand 'NOPE'. Try DEBUG HELP.")
+        );
+    }
+}
@@ -0,0 +1,665 @@
+//! The DUMP payload format shared with Redis, used by DUMP, RESTORE and MIGRATE. A payload is the
+//! value in RDB encoding followed by the RDB version and a CRC64 of everything before it.
+
+use crate::{
+    cache::{Cache, Value, ValueType},
+    client::Client,
+    command::MigrateCommand,
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::net::ToSocketAddrs;
+
+pub(crate) const RDB_TYPE_STRING: u8 = 0;
+/// A list as a plain sequence of strings, which Redis no longer writes but still loads.
+pub(crate) const RDB_TYPE_LIST: u8 = 1;
+/// A hash as a plain sequence of fields and values, which Redis no longer writes but still loads.
+pub(crate) const RDB_TYPE_HASH: u8 = 4;
+/// The RDB version written to payloads, understood by Redis 5.0 and later.
+pub(crate) const RDB_VERSION: u16 = 9;
+/// Payloads written by versions newer than this are rejected.
+const MAX_RDB_VERSION: u16 = 11;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+/// CRC-64 with the Jones polynomial, reflected and without a final XOR, as used by Redis.
+pub(crate) fn crc64(data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ u64::from(*byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Serialize a value into a DUMP payload, or fail for types without an RDB encoding.
+pub(crate) fn serialize(value: &Value) -> Result<Vec<u8>, RedisError> {
+    let mut payload = Vec::new();
+    write_value(&mut payload, None, value)?;
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+
+    let crc = crc64(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+
+    Ok(payload)
+}
+
+/// Deserialize a DUMP payload after verifying its version and checksum.
+pub(crate) fn deserialize(payload: &[u8]) -> Result<Value, RedisError> {
+    let invalid = || RedisError::Other("DUMP payload version or checksum are wrong".to_string());
+
+    if payload.len() < 10 {
+        return Err(invalid());
+    }
+
+    let (data, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into().unwrap());
+
+    if version > MAX_RDB_VERSION || crc64(&payload[..payload.len() - 8]) != crc {
+        return Err(invalid());
+    }
+
+    let bad_data = || RedisError::Other("Bad data format".to_string());
+    let mut reader = Reader { data, pos: 0 };
+
+    let value_type = reader.byte().ok_or_else(bad_data)?;
+    let value = read_value(&mut reader, value_type).ok_or_else(bad_data)?;
+    if reader.pos != data.len() {
+        return Err(bad_data());
+    }
+
+    Ok(value)
+}
+
+pub(crate) fn write_length(out: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=0x3f => out.push(len as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(0x4000 | len as u16).to_be_bytes()),
+        _ => match u32::try_from(len) {
+            Ok(len) => {
+                out.push(0x80);
+                out.extend_from_slice(&len.to_be_bytes());
+            }
+            Err(_) => {
+                out.push(0x81);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        },
+    }
+}
+
+/// Write a string, using the integer encoding for strings that are the canonical representation
+/// of a 32 bit integer like Redis does.
+pub(crate) fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    let int = std::str::from_utf8(value)
+        .ok()
+        .and_then(|text| text.parse::<i32>().ok())
+        .filter(|int| int.to_string().as_bytes() == value);
+
+    match int {
+        Some(int) if i8::try_from(int).is_ok() => {
+            out.extend_from_slice(&[0xc0 | RDB_ENC_INT8, int as i8 as u8]);
+        }
+        Some(int) if i16::try_from(int).is_ok() => {
+            out.push(0xc0 | RDB_ENC_INT16);
+            out.extend_from_slice(&(int as i16).to_le_bytes());
+        }
+        Some(int) => {
+            out.push(0xc0 | RDB_ENC_INT32);
+            out.extend_from_slice(&int.to_le_bytes());
+        }
+        None => {
+            write_length(out, value.len());
+            out.extend_from_slice(value);
+        }
+    }
+}
+
+/// The error for values without an RDB encoding Redis could load. JSON documents and Bloom filters
+/// only have module encodings, which only the modules can read, and streams need listpacks which
+/// aren't implemented.
+pub(crate) fn unsupported(value_type: ValueType) -> RedisError {
+    RedisError::Other(format!(
+        "values of type {} can't be serialized",
+        value_type.name()
+    ))
+}
+
+/// The RDB type `value` is written as, or an error if it has none.
+pub(crate) fn rdb_type(value: &Value) -> Result<u8, RedisError> {
+    match value {
+        Value::String(_) | Value::Int(_) | Value::Inline(_) => Ok(RDB_TYPE_STRING),
+        Value::List(_) => Ok(RDB_TYPE_LIST),
+        Value::Hash(_) => Ok(RDB_TYPE_HASH),
+        Value::Json(_) | Value::Bloom(_) | Value::Stream(_) => Err(unsupported(value.value_type())),
+    }
+}
+
+/// Write a value in RDB encoding: its type, its key when written to an RDB file rather than a DUMP
+/// payload, and then the value itself.
+pub(crate) fn write_value(
+    out: &mut Vec<u8>,
+    key: Option<&str>,
+    value: &Value,
+) -> Result<(), RedisError> {
+    out.push(rdb_type(value)?);
+    if let Some(key) = key {
+        write_string(out, key.as_bytes());
+    }
+
+    match (value, value.as_string()) {
+        (_, Some(string)) => write_string(out, &string),
+        (Value::List(list), _) => {
+            write_length(out, list.iter().len());
+            for item in list.iter() {
+                write_string(out, item.as_bytes());
+            }
+        }
+        (Value::Hash(hash), _) => {
+            write_length(out, hash.iter().len());
+            for (field, value) in hash.iter() {
+                write_string(out, field.as_bytes());
+                write_string(out, value.as_bytes());
+            }
+        }
+        _ => unreachable!("the type was checked above"),
+    }
+
+    Ok(())
+}
+
+/// Read a value of `value_type` written by [`write_value`], or `None` if it's of any other type or
+/// the data is invalid. Lists and hashes hold text so any non UTF-8 data in them is replaced.
+pub(crate) fn read_value(reader: &mut Reader, value_type: u8) -> Option<Value> {
+    let text = |reader: &mut Reader| {
+        let bytes = reader.string()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    };
+
+    // Redis never writes empty lists or hashes since such keys don't exist.
+    let value = match value_type {
+        RDB_TYPE_STRING => Value::from_bytes(reader.string()?.into()),
+        RDB_TYPE_LIST => {
+            let len = reader.plain_length()?;
+            let list = (0..len).map(|_| text(reader)).collect::<Option<_>>()?;
+            (len > 0).then_some(Value::List(list))?
+        }
+        RDB_TYPE_HASH => {
+            let len = reader.plain_length()?;
+            let hash = (0..len)
+                .map(|_| Some((text(reader)?, text(reader)?)))
+                .collect::<Option<_>>()?;
+            (len > 0).then_some(Value::Hash(hash))?
+        }
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) data: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+/// A length or, if the two high bits are set, the kind of special encoding that follows.
+pub(crate) enum Length {
+    Len(usize),
+    Encoded(u8),
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+
+        Some(bytes)
+    }
+
+    pub(crate) fn byte(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    pub(crate) fn length(&mut self) -> Option<Length> {
+        let first = self.byte()?;
+
+        let len = match first >> 6 {
+            0 => usize::from(first & 0x3f),
+            1 => usize::from(first & 0x3f) << 8 | usize::from(self.byte()?),
+            2 if first == 0x80 => u32::from_be_bytes(self.bytes(4)?.try_into().ok()?) as usize,
+            2 if first == 0x81 => {
+                usize::try_from(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?)).ok()?
+            }
+            2 => return None,
+            _ => return Some(Length::Encoded(first & 0x3f)),
+        };
+
+        Some(Length::Len(len))
+    }
+
+    pub(crate) fn plain_length(&mut self) -> Option<usize> {
+        match self.length()? {
+            Length::Len(len) => Some(len),
+            Length::Encoded(_) => None,
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Option<Vec<u8>> {
+        let value = match self.length()? {
+            Length::Len(len) => self.bytes(len)?.to_vec(),
+            Length::Encoded(RDB_ENC_INT8) => (self.byte()? as i8).to_string().into_bytes(),
+            Length::Encoded(RDB_ENC_INT16) => i16::from_le_bytes(self.bytes(2)?.try_into().ok()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(RDB_ENC_INT32) => i32::from_le_bytes(self.bytes(4)?.try_into().ok()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_len = self.plain_length()?;
+                let len = self.plain_length()?;
+                lzf_decompress(self.bytes(compressed_len)?, len)?
+            }
+            Length::Encoded(_) => return None,
+        };
+
+        Some(value)
+    }
+}
+
+/// Decompress LZF data, which Redis uses for long strings when `rdbcompression` is enabled.
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // The length comes from the payload so don't trust it for more than a modest allocation.
+    let mut out = Vec::with_capacity(len.min(1024 * 1024));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = usize::from(input[i]);
+        i += 1;
+
+        if ctrl < 32 {
+            // A run of literal bytes.
+            let run = input.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // A back reference to data that's already been decompressed.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += usize::from(*input.get(i)?);
+                i += 1;
+            }
+
+            let offset = ((ctrl & 0x1f) << 8) + usize::from(*input.get(i)?) + 1;
+            i += 1;
+
+            let start = out.len().checked_sub(offset)?;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+/// Move keys to another instance by sending RESTORE commands with their DUMP payloads. The keys are
+/// only removed once the target has acknowledged them.
+pub(crate) fn migrate(options: &MigrateCommand, cache: &Cache) -> RespType {
+    let keys = options
+        .keys
+        .iter()
+        .filter_map(|key| {
+            let ttl = cache.ttl(key)?;
+            let payload = cache.read(key, serialize)?;
+            Some(payload.map(|payload| (key, payload, ttl)))
+        })
+        .collect::<Result<Vec<_>, _>>();
+
+    // Nothing is sent unless every key can be.
+    let keys = match keys {
+        Ok(keys) => keys,
+        Err(err) => return err.to_resp(),
+    };
+
+    if keys.is_empty() {
+        return RespType::simple("NOKEY");
+    }
+
+    let mut client = match connect(options) {
+        Ok(client) => client,
+        Err(_) => {
+            return RespType::error("IOERR", "error or timeout connecting to the client");
+        }
+    };
+
+    let mut commands = Vec::new();
+    if let Some((username, password)) = &options.auth {
+        let mut auth = vec![b"AUTH".to_vec()];
+        auth.extend(username.iter().map(|username| username.as_bytes().to_vec()));
+        auth.push(password.as_bytes().to_vec());
+        commands.push(auth);
+    }
+
+    if options.db != 0 {
+        commands.push(vec![
+            b"SELECT".to_vec(),
+            options.db.to_string().into_bytes(),
+        ]);
+    }
+
+    let setup = commands.len();
+
+    for (key, payload, ttl) in &keys {
+        let ttl = ttl.map_or(0, |ttl| ttl.as_millis().max(1));
+
+        let mut restore = vec![
+            b"RESTORE".to_vec(),
+            key.as_bytes().to_vec(),
+            ttl.to_string().into_bytes(),
+            payload.clone(),
+        ];
+
+        if options.replace {
+            restore.push(b"REPLACE".to_vec());
+        }
+
+        commands.push(restore);
+    }
+
+    let replies = commands
+        .iter()
+        .try_for_each(|command| client.send(command))
+        .and_then(|()| {
+            (0..commands.len())
+                .map(|_| client.read_reply())
+                .collect::<Result<Vec<_>, _>>()
+        });
+
+    let replies = match replies {
+        Ok(replies) => replies,
+        Err(_) => return RespType::error("IOERR", "error or timeout reading to target instance"),
+    };
+
+    let mut error = None;
+    for (i, reply) in replies.iter().enumerate() {
+        match reply {
+            RespType::SimpleError(message) | RespType::BulkError(_, message) => {
+                error.get_or_insert_with(|| message.clone());
+            }
+            _ if i >= setup && error.is_none() && !options.copy => {
+                cache.delete(keys[i - setup].0);
+            }
+            _ => (),
+        }
+    }
+
+    match error {
+        Some(message) => RedisError::Other(format!(
+            "Target instance replied with error: {}",
+            message.trim_start_matches("ERR ")
+        ))
+        .to_resp(),
+        None => RespType::ok(),
+    }
+}
+
+fn connect(options: &MigrateCommand) -> Result<Client, RedisError> {
+    let addr = (options.host.as_str(), options.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| RedisError::Other(format!("invalid address '{}'", options.host)))?;
+
+    Client::connect_timeout(&addr, options.timeout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{assert_reply, TestServer};
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn test_redis_payload() {
+        // DUMP of the value 10 from the Redis documentation.
+        let payload = b"\x00\xc0\x0a\x09\x00\xbe\x6d\x06\x89\x5a\x28\x00\x0a";
+
+        assert_eq!(deserialize(payload).unwrap(), Value::Int(10));
+        assert_eq!(serialize(&Value::Int(10)).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let long = "x".repeat(20_000);
+        let values = [
+            "",
+            "hello",
+            "-1",
+            "-128",
+            "300",
+            "-32769",
+            "2147483647",
+            "2147483648",
+            "007",
+            &long,
+        ];
+
+        for value in values {
+            let value = Value::string(value.as_bytes());
+            assert_eq!(deserialize(&serialize(&value).unwrap()).unwrap(), value);
+        }
+
+        let list = ["a", "1"].map(String::from).into_iter().collect();
+        let hash = [("f".to_string(), "v".to_string())].into_iter().collect();
+        for value in [Value::List(list), Value::Hash(hash)] {
+            assert_eq!(deserialize(&serialize(&value).unwrap()).unwrap(), value);
+        }
+
+        assert_eq!(
+            serialize(&Value::Stream(Default::default()))
+                .unwrap_err()
+                .to_string(),
+            "values of type stream can't be serialized"
+        );
+
+        let mut payload = serialize(&Value::string(b"hello")).unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(deserialize(&payload).is_err());
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa" compressed: a literal `a` followed by a back reference of 9 bytes.
+        assert_eq!(
+            lzf_decompress(b"\x00a\xe0\x00\x00", 10).unwrap(),
+            b"aaaaaaaaaa"
+        );
+        assert_eq!(lzf_decompress(b"\x00a\xe0\x00\x00", 5), None);
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        assert_reply(&mut client, &["DUMP", "k"], b"$-1\r\n");
+        assert_reply(&mut client, &["SET", "k", "10"], b"+OK\r\n");
+
+        let payload = client.command(&["DUMP", "k"]).unwrap();
+        let RespType::BulkString(_, payload) = payload else {
+            panic!("unexpected DUMP reply {payload:?}");
+        };
+
+        let restore = |key: &str, ttl: &str, options: &[&str]| {
+            let mut args = vec![
+                b"RESTORE".to_vec(),
+                key.into(),
+                ttl.into(),
+                payload.to_vec(),
+            ];
+            args.extend(options.iter().map(|option| option.as_bytes().to_vec()));
+            args
+        };
+
+        assert_reply(
+            &mut client,
+            &restore("k", "0", &[]),
+            b"-BUSYKEY Target key name already exists.\r\n",
+        );
+        assert_reply(&mut client, &restore("k", "0", &["REPLACE"]), b"+OK\r\n");
+        assert_reply(&mut client, &restore("k2", "1", &["ABSTTL"]), b"+OK\r\n");
+        assert_reply(&mut client, &["GET", "k2"], b"$-1\r\n");
+        assert_reply(
+            &mut client,
+            &restore("k2", "-1", &[]),
+            b"-ERR Invalid TTL value, must be >= 0\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["RESTORE", "k2", "0", "garbage"],
+            b"-ERR DUMP payload version or checksum are wrong\r\n",
+        );
+
+        // Every type with an RDB encoding can be dumped, others fail with a reason.
+        assert_reply(&mut client, &["RPUSH", "list", "a", "b"], b":2\r\n");
+        let payload = client.command(&["DUMP", "list"]).unwrap();
+        let RespType::BulkString(_, payload) = payload else {
+            panic!("unexpected DUMP reply {payload:?}");
+        };
+        let restore = [
+            b"RESTORE".to_vec(),
+            b"copy".to_vec(),
+            b"0".to_vec(),
+            payload.to_vec(),
+        ];
+        assert_reply(&mut client, &restore, b"+OK\r\n");
+        assert_reply(
+            &mut client,
+            &["LRANGE", "copy", "0", "-1"],
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+        );
+
+        assert_reply(
+            &mut client,
+            &["XADD", "s", "1-1", "f", "v"],
+            b"$3\r\n1-1\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["DUMP", "s"],
+            b"-ERR values of type stream can't be serialized\r\n",
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        let source = TestServer::start();
+        let target = TestServer::start();
+        let mut client = source.client();
+        let port = target.addr().port().to_string();
+
+        assert_reply(
+            &mut client,
+            &["MIGRATE", "127.0.0.1", &port, "missing", "0", "1000"],
+            b"+NOKEY\r\n",
+        );
+
+        assert_reply(&mut client, &["SET", "a", "1", "EX", "100"], b"+OK\r\n");
+        assert_reply(&mut client, &["SET", "b", "2"], b"+OK\r\n");
+        assert_reply(
+            &mut client,
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &port,
+                "",
+                "0",
+                "1000",
+                "KEYS",
+                "a",
+                "b",
+            ],
+            b"+OK\r\n",
+        );
+
+        assert_reply(&mut client, &["GET", "a"], b"$-1\r\n");
+        assert_eq!(target.server().cache().get("a"), Some("1".to_string()));
+        assert!(matches!(target.server().cache().ttl("a"), Some(Some(ttl)) if ttl.as_secs() >= 99));
+        assert_eq!(target.server().cache().ttl("b"), Some(None));
+
+        assert_reply(&mut client, &["SET", "a", "3"], b"+OK\r\n");
+        assert_reply(
+            &mut client,
+            &["MIGRATE", "127.0.0.1", &port, "a", "0", "1000", "COPY"],
+            b"-ERR Target instance replied with error: BUSYKEY Target key name already exists.\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &port,
+                "a",
+                "0",
+                "1000",
+                "COPY",
+                "REPLACE",
+            ],
+            b"+OK\r\n",
+        );
+        assert_reply(&mut client, &["GET", "a"], b"$1\r\n3\r\n");
+        assert_eq!(target.server().cache().get("a"), Some("3".to_string()));
+
+        assert_reply(&mut client, &["HSET", "h", "f", "v"], b":1\r\n");
+        assert_reply(
+            &mut client,
+            &["MIGRATE", "127.0.0.1", &port, "h", "0", "1000"],
+            b"+OK\r\n",
+        );
+        assert_eq!(
+            target.server().cache().value_type("h"),
+            Some(ValueType::Hash)
+        );
+
+        // A key that can't be sent fails the whole command before anything is moved.
+        assert_reply(
+            &mut client,
+            &["XADD", "s", "1-1", "f", "v"],
+            b"$3\r\n1-1\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &port,
+                "",
+                "0",
+                "1000",
+                "KEYS",
+                "a",
+                "s",
+            ],
+            b"-ERR values of type stream can't be serialized\r\n",
+        );
+        assert_reply(&mut client, &["GET", "a"], b"$1\r\n3\r\n");
+    }
+}
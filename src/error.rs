@@ -0,0 +1,104 @@
+use crate::resp_type::RespType;
+
+/// Errors shared by the parser, command handling and the server. Each error maps to the RESP error
+/// reply sent to the client, with `code` being the prefix clients match on.
+#[derive(Debug, thiserror::Error)]
+pub enum RedisError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The buffered data ends in the middle of a frame.
+    #[error("incomplete frame")]
+    Incomplete,
+    /// Malformed input after which the stream is still positioned at the start of the next frame.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// Malformed input after which we no longer know where the next frame starts.
+    #[error("Protocol error: {0}")]
+    FatalProtocol(String),
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("unknown command '{0}', with args beginning with: {}", quote_args(.1))]
+    UnknownCommand(String, Vec<String>),
+    /// A subcommand the container command, the second field, doesn't have.
+    #[error("unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("syntax error")]
+    Syntax,
+    #[error("value is not an integer or out of range")]
+    NotInteger,
+    #[error("Authentication required.")]
+    NotAuthenticated,
+    /// The keys of a command in cluster mode belong to different hash slots.
+    #[error("Keys in request don't hash to the same slot")]
+    CrossSlot,
+    #[error("You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl RedisError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::WrongType => "WRONGTYPE",
+            Self::NotAuthenticated => "NOAUTH",
+            Self::CrossSlot => "CROSSSLOT",
+            Self::ReadOnly => "READONLY",
+            _ => "ERR",
+        }
+    }
+
+    /// Whether the connection can be kept alive after the error.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            Self::Io(_) | Self::Incomplete | Self::FatalProtocol(_)
+        )
+    }
+
+    pub fn to_resp(&self) -> RespType {
+        RespType::error(self.code(), self)
+    }
+}
+
+fn quote_args(args: &[String]) -> String {
+    args.iter().map(|arg| format!("'{arg}' ")).collect()
+}
+
+impl From<std::num::ParseIntError> for RedisError {
+    fn from(_: std::num::ParseIntError) -> Self {
+        Self::NotInteger
+    }
+}
+
+/// The message a panic was raised with, for logging panics caught at thread boundaries.
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_resp() {
+        assert_eq!(
+            RedisError::WrongType.to_resp(),
+            RespType::SimpleError(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+            )
+        );
+        assert_eq!(
+            RedisError::from("x".parse::<i64>().unwrap_err()).to_resp(),
+            RespType::SimpleError("ERR value is not an integer or out of range".into())
+        );
+        assert!(RedisError::Protocol("x".into()).is_recoverable());
+        assert!(!RedisError::FatalProtocol("x".into()).is_recoverable());
+    }
+}
@@ -0,0 +1,278 @@
+//! The hash type, a map of fields to values stored under a key, and the commands working on it.
+
+use crate::{
+    cache::{Cache, Change, MemoryUsage, Value},
+    command::HashCommand,
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Hash {
+    fields: HashMap<String, String>,
+}
+
+impl Hash {
+    /// The command that recreates the hash under `key`, used to rewrite the append-only file.
+    pub(crate) fn commands(&self, key: &str) -> Vec<RespType> {
+        let mut args = vec!["HSET".into(), key.into()];
+        for (field, value) in &self.fields {
+            args.extend([field.as_str().into(), value.as_str().into()]);
+        }
+
+        vec![RespType::array(args)]
+    }
+
+    pub(crate) fn iter(&self) -> impl ExactSizeIterator<Item = (&String, &String)> {
+        self.fields.iter()
+    }
+}
+
+impl FromIterator<(String, String)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(fields: I) -> Self {
+        Self {
+            fields: fields.into_iter().collect(),
+        }
+    }
+}
+
+impl MemoryUsage for Hash {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .fields
+                .iter()
+                .map(|(field, value)| {
+                    2 * std::mem::size_of::<String>() + field.capacity() + value.capacity()
+                })
+                .sum::<usize>()
+    }
+
+    fn elements(&self) -> usize {
+        self.fields.len()
+    }
+}
+
+pub(crate) fn execute(command: &HashCommand, cache: &Cache) -> RespType {
+    let result = match command {
+        HashCommand::Set { key, fields } => set(key, fields, cache),
+        HashCommand::Get { key, field } => read(key, cache, |hash| {
+            hash.fields.get(field).map(String::as_str).into()
+        }),
+        HashCommand::MGet { key, fields } => read(key, cache, |hash| {
+            RespType::array(
+                fields
+                    .iter()
+                    .map(|field| hash.fields.get(field).map(String::as_str).into())
+                    .collect(),
+            )
+        }),
+        HashCommand::Del { key, fields } => del(key, fields, cache),
+        HashCommand::Exists { key, field } => read(key, cache, |hash| {
+            i64::from(hash.fields.contains_key(field)).into()
+        }),
+        HashCommand::GetAll { key } => read(key, cache, |hash| {
+            RespType::map(
+                hash.fields
+                    .iter()
+                    .map(|(field, value)| (field.as_str().into(), value.as_str().into()))
+                    .collect(),
+            )
+        }),
+        HashCommand::IncrBy {
+            key,
+            field,
+            increment,
+        } => incr_by(key, field, *increment, cache),
+    };
+
+    result.unwrap_or_else(|err| err.to_resp())
+}
+
+/// Reply from the hash at `key`, or as if it was empty if there's none.
+fn read(key: &str, cache: &Cache, f: impl Fn(&Hash) -> RespType) -> Result<RespType, RedisError> {
+    let empty = Hash::default();
+
+    match cache.read(key, |value| match value {
+        Value::Hash(hash) => Ok(f(hash)),
+        _ => Err(RedisError::WrongType),
+    }) {
+        Some(reply) => reply,
+        None => Ok(f(&empty)),
+    }
+}
+
+/// Set the fields, creating the hash if needed. Replies with the number of fields that were added.
+fn set(key: &str, fields: &[(String, String)], cache: &Cache) -> Result<RespType, RedisError> {
+    cache.update(key, |current| {
+        let mut created = None;
+        let hash = match current {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None => created.insert(Hash::default()),
+        };
+
+        let added = fields
+            .iter()
+            .filter(|(field, value)| hash.fields.insert(field.clone(), value.clone()).is_none())
+            .count();
+
+        let reply = Ok(RespType::from(added as i64));
+        match created {
+            Some(hash) => (reply, Change::Set(Value::Hash(hash))),
+            None => (reply, Change::Modified),
+        }
+    })
+}
+
+/// Remove the fields, replying with how many existed. Hashes left empty are deleted.
+fn del(key: &str, fields: &[String], cache: &Cache) -> Result<RespType, RedisError> {
+    cache.update(key, |current| {
+        let hash = match current {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None => return (Ok(RespType::from(0)), Change::Keep),
+        };
+
+        let removed = fields
+            .iter()
+            .filter(|field| hash.fields.remove(*field).is_some())
+            .count();
+
+        let change = match (removed, hash.fields.is_empty()) {
+            (0, _) => Change::Keep,
+            (_, true) => Change::Delete,
+            (_, false) => Change::Modified,
+        };
+
+        (Ok(RespType::from(removed as i64)), change)
+    })
+}
+
+/// Add `increment` to the integer in the field, which counts as 0 if it doesn't exist. Replies
+/// with the new value.
+fn incr_by(key: &str, field: &str, increment: i64, cache: &Cache) -> Result<RespType, RedisError> {
+    cache.update(key, |current| {
+        let mut created = None;
+        let hash = match current {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None => created.insert(Hash::default()),
+        };
+
+        let current = match hash.fields.get(field).map(|value| value.parse::<i64>()) {
+            Some(Ok(value)) => value,
+            Some(Err(_)) => {
+                let err = RedisError::Other("hash value is not an integer".to_string());
+                return (Err(err), Change::Keep);
+            }
+            None => 0,
+        };
+
+        let Some(value) = current.checked_add(increment) else {
+            let err = RedisError::Other("increment or decrement would overflow".to_string());
+            return (Err(err), Change::Keep);
+        };
+
+        hash.fields.insert(field.to_string(), value.to_string());
+
+        let reply = Ok(RespType::from(value));
+        match created {
+            Some(hash) => (reply, Change::Set(Value::Hash(hash))),
+            None => (reply, Change::Modified),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::ValueType, testing::run};
+
+    #[test]
+    fn test_set_and_get() {
+        let cache = Cache::new(1);
+
+        assert_eq!(run(&cache, &["HSET", "h", "a", "1", "b", "2"]), 2.into());
+        assert_eq!(run(&cache, &["HSET", "h", "a", "3", "c", "4"]), 1.into());
+        assert_eq!(cache.value_type("h"), Some(ValueType::Hash));
+
+        assert_eq!(run(&cache, &["HGET", "h", "a"]), "3".into());
+        assert_eq!(run(&cache, &["HGET", "h", "x"]), RespType::null());
+        assert_eq!(run(&cache, &["HGET", "missing", "a"]), RespType::null());
+        assert_eq!(
+            run(&cache, &["HMGET", "h", "b", "x"]),
+            RespType::array(vec!["2".into(), RespType::null()])
+        );
+        assert_eq!(run(&cache, &["HEXISTS", "h", "c"]), 1.into());
+        assert_eq!(run(&cache, &["HEXISTS", "h", "x"]), 0.into());
+
+        let RespType::Map(_, mut pairs) = run(&cache, &["HGETALL", "h"]) else {
+            panic!("expected a map");
+        };
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a".into(), "3".into()),
+                ("b".into(), "2".into()),
+                ("c".into(), "4".into()),
+            ]
+        );
+        assert_eq!(run(&cache, &["HGETALL", "missing"]), RespType::map(vec![]));
+
+        assert_eq!(
+            run(&cache, &["HSET", "h", "a"]),
+            RedisError::WrongArity("hset".to_string()).to_resp()
+        );
+        cache.set("s", "v", None);
+        assert_eq!(
+            run(&cache, &["HGET", "s", "a"]),
+            RedisError::WrongType.to_resp()
+        );
+        assert_eq!(
+            run(&cache, &["HSET", "s", "a", "1"]),
+            RedisError::WrongType.to_resp()
+        );
+    }
+
+    #[test]
+    fn test_del() {
+        let cache = Cache::new(1);
+        run(&cache, &["HSET", "h", "a", "1", "b", "2"]);
+
+        assert_eq!(run(&cache, &["HDEL", "h", "a", "x"]), 1.into());
+        assert_eq!(run(&cache, &["HDEL", "missing", "a"]), 0.into());
+
+        // The emptied hash is deleted.
+        assert_eq!(run(&cache, &["HDEL", "h", "b"]), 1.into());
+        assert_eq!(cache.value_type("h"), None);
+    }
+
+    #[test]
+    fn test_incr_by() {
+        let cache = Cache::new(1);
+
+        assert_eq!(run(&cache, &["HINCRBY", "h", "n", "5"]), 5.into());
+        assert_eq!(run(&cache, &["HINCRBY", "h", "n", "-7"]), (-2).into());
+        assert_eq!(run(&cache, &["HGET", "h", "n"]), "-2".into());
+
+        run(&cache, &["HSET", "h", "s", "abc"]);
+        assert_eq!(
+            run(&cache, &["HINCRBY", "h", "s", "1"]),
+            RespType::error("ERR", "hash value is not an integer")
+        );
+        assert_eq!(
+            run(&cache, &["HINCRBY", "h", "n", "x"]),
+            RedisError::NotInteger.to_resp()
+        );
+
+        run(&cache, &["HSET", "h", "max", &i64::MAX.to_string()]);
+        assert_eq!(
+            run(&cache, &["HINCRBY", "h", "max", "1"]),
+            RespType::error("ERR", "increment or decrement would overflow")
+        );
+    }
+}
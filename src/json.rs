@@ -0,0 +1,907 @@
+use crate::{
+    cache::{Cache, Change, MemoryUsage, Value},
+    command::{JsonCommand, JsonFormat, SetCondition},
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::fmt::{self, Write};
+
+/// Documents nested deeper than this are rejected to keep the recursive parser off the end of the
+/// stack, the same limit RedisJSON uses.
+const MAX_DEPTH: usize = 128;
+
+/// A JSON document. Numbers keep their original text so documents are returned the way they were
+/// written and values can be compared for equality. Object members keep their insertion order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The command that recreates the document under `key`, used to rewrite the append-only file.
+    pub(crate) fn commands(&self, key: &str) -> Vec<RespType> {
+        vec![RespType::array(vec![
+            "JSON.SET".into(),
+            key.into(),
+            "$".into(),
+            self.to_string().into(),
+        ])]
+    }
+
+    pub(crate) fn parse(input: &str) -> Result<Self, RedisError> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            pos: 0,
+        };
+
+        let value = parser.value(0)?;
+        parser.whitespace();
+        if parser.pos < parser.input.len() {
+            return Err(parser.error("trailing characters"));
+        }
+
+        Ok(value)
+    }
+
+    fn get(&self, step: &Step) -> Option<&Json> {
+        match (self, step) {
+            (Self::Array(values), Step::Index(index)) => values.get(*index),
+            (Self::Object(members), Step::Key(key)) => {
+                members.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, step: &Step) -> Option<&mut Json> {
+        match (self, step) {
+            (Self::Array(values), Step::Index(index)) => values.get_mut(*index),
+            (Self::Object(members), Step::Key(key)) => {
+                members.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    fn at(&self, location: &[Step]) -> Option<&Json> {
+        location
+            .iter()
+            .try_fold(self, |value, step| value.get(step))
+    }
+
+    fn at_mut(&mut self, location: &[Step]) -> Option<&mut Json> {
+        location
+            .iter()
+            .try_fold(self, |value, step| value.get_mut(step))
+    }
+
+    fn remove(&mut self, step: &Step) -> bool {
+        match (self, step) {
+            (Self::Array(values), Step::Index(index)) if *index < values.len() => {
+                values.remove(*index);
+                true
+            }
+            (Self::Object(members), Step::Key(key)) => {
+                let len = members.len();
+                members.retain(|(k, _)| k != key);
+                members.len() != len
+            }
+            _ => false,
+        }
+    }
+
+    /// Serialize with the whitespace options of JSON.GET, which are all empty for compact output.
+    fn format(&self, format: &JsonFormat) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, format: &JsonFormat, depth: usize) {
+        let newline = |out: &mut String, depth: usize| {
+            out.push_str(&format.newline);
+            out.push_str(&format.indent.repeat(depth));
+        };
+
+        match self {
+            Self::Null => out.push_str("null"),
+            Self::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Self::Number(value) => out.push_str(value),
+            Self::String(value) => write_string(out, value),
+            Self::Array(values) if values.is_empty() => out.push_str("[]"),
+            Self::Object(members) if members.is_empty() => out.push_str("{}"),
+            Self::Array(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, depth + 1);
+                    value.write(out, format, depth + 1);
+                }
+                newline(out, depth);
+                out.push(']');
+            }
+            Self::Object(members) => {
+                out.push('{');
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, depth + 1);
+                    write_string(out, key);
+                    out.push(':');
+                    out.push_str(&format.space);
+                    value.write(out, format, depth + 1);
+                }
+                newline(out, depth);
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(&JsonFormat::default()))
+    }
+}
+
+impl MemoryUsage for Json {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + match self {
+                Self::Null | Self::Bool(_) => 0,
+                Self::Number(value) | Self::String(value) => value.capacity(),
+                Self::Array(values) => {
+                    let spare = values.capacity() - values.len();
+                    spare * std::mem::size_of::<Self>()
+                        + values.iter().map(Self::memory_usage).sum::<usize>()
+                }
+                Self::Object(members) => {
+                    let spare = members.capacity() - members.len();
+                    spare * std::mem::size_of::<(String, Self)>()
+                        + members
+                            .iter()
+                            .map(|(key, value)| {
+                                std::mem::size_of::<String>()
+                                    + key.capacity()
+                                    + value.memory_usage()
+                            })
+                            .sum::<usize>()
+                }
+            }
+    }
+
+    /// The members of an object or elements of an array at the root, otherwise 1.
+    fn elements(&self) -> usize {
+        match self {
+            Self::Array(values) => values.len(),
+            Self::Object(members) => members.len(),
+            _ => 1,
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> RedisError {
+        RedisError::Other(format!("invalid JSON, {message} at offset {}", self.pos))
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), RedisError> {
+        if !self.input[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(self.error("expected value"));
+        }
+
+        self.pos += literal.len();
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, RedisError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+
+        self.whitespace();
+        match self.peek() {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.members(b']', |parser| {
+                    values.push(parser.value(depth + 1)?);
+                    Ok(())
+                })?;
+
+                Ok(Json::Array(values))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members: Vec<(String, Json)> = Vec::new();
+                self.members(b'}', |parser| {
+                    parser.whitespace();
+                    if parser.peek() != Some(b'"') {
+                        return Err(parser.error("expected object key"));
+                    }
+
+                    let key = parser.string()?;
+                    parser.whitespace();
+                    parser.expect(":")?;
+                    let value = parser.value(depth + 1)?;
+
+                    // Like most parsers the last of any duplicate keys wins.
+                    match members.iter_mut().find(|(k, _)| *k == key) {
+                        Some(member) => member.1 = value,
+                        None => members.push((key, value)),
+                    }
+
+                    Ok(())
+                })?;
+
+                Ok(Json::Object(members))
+            }
+            _ => Err(self.error("expected value")),
+        }
+    }
+
+    /// Parse comma separated members with `member` until the closing bracket.
+    fn members(
+        &mut self,
+        close: u8,
+        mut member: impl FnMut(&mut Self) -> Result<(), RedisError>,
+    ) -> Result<(), RedisError> {
+        self.whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(());
+        }
+
+        loop {
+            member(self)?;
+            self.whitespace();
+
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("expected ',' or closing bracket")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, RedisError> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let start = parser.pos;
+            while matches!(parser.peek(), Some(b'0'..=b'9')) {
+                parser.pos += 1;
+            }
+            parser.pos > start
+        };
+
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else if !digits(self) {
+            return Err(self.error("invalid number"));
+        }
+
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("invalid number"));
+            }
+        }
+
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("invalid number"));
+            }
+        }
+
+        // Only ASCII was consumed so this can't split a character.
+        let number = std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default();
+        Ok(Json::Number(number.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, RedisError> {
+        self.pos += 1;
+        let mut out = Vec::new();
+
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{08}',
+                        b'f' => '\u{0c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c if c < b' ' => return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+
+        // The input is a &str and escapes produce valid characters so this always succeeds.
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// Decode the hex digits after `\u`, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, RedisError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("invalid unicode escape"));
+        }
+
+        if !self.input[self.pos..].starts_with(b"\\u") {
+            return Err(self.error("unpaired surrogate"));
+        }
+        self.pos += 2;
+
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(self.error("unpaired surrogate"));
+        }
+
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+            .ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, RedisError> {
+        let hex = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+
+        self.pos += 4;
+        Ok(hex)
+    }
+}
+
+/// One step of a path as written, which may match several children.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    /// An array index where negative values count from the end.
+    Index(i64),
+    Wildcard,
+}
+
+/// A concrete step to a single value in a document.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Index(usize),
+    Key(String),
+}
+
+/// A path into a document, either JSONPath starting with `$` or the legacy RedisJSON syntax like
+/// `.a.b` where `.` is the root. JSONPath returns every match while legacy paths return a single
+/// value.
+#[derive(Debug, PartialEq)]
+struct Path {
+    segments: Vec<Segment>,
+    legacy: bool,
+}
+
+impl Path {
+    fn parse(path: &str) -> Result<Self, RedisError> {
+        let error = || RedisError::Other(format!("invalid JSON path '{path}'"));
+
+        let (rest, legacy) = match path.strip_prefix('$') {
+            Some(rest) => (rest.to_string(), false),
+            None if path.starts_with(['.', '[']) => (path.to_string(), true),
+            None => (format!(".{path}"), true),
+        };
+
+        let rest = if legacy && rest == "." { "" } else { &rest };
+        let bytes = rest.as_bytes();
+        let mut segments = Vec::new();
+        let mut pos = 0;
+
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'.' => {
+                    pos += 1;
+                    let start = pos;
+                    while pos < bytes.len() && !matches!(bytes[pos], b'.' | b'[') {
+                        pos += 1;
+                    }
+
+                    match &rest[start..pos] {
+                        "" => return Err(error()),
+                        "*" => segments.push(Segment::Wildcard),
+                        key => segments.push(Segment::Key(key.to_string())),
+                    }
+                }
+                b'[' => {
+                    let end = rest[pos..].find(']').ok_or_else(error)? + pos;
+                    let inner = rest[pos + 1..end].trim();
+
+                    let quoted = inner.len() >= 2
+                        && ((inner.starts_with('\'') && inner.ends_with('\''))
+                            || (inner.starts_with('"') && inner.ends_with('"')));
+
+                    if inner == "*" {
+                        segments.push(Segment::Wildcard);
+                    } else if quoted {
+                        segments.push(Segment::Key(inner[1..inner.len() - 1].to_string()));
+                    } else {
+                        segments.push(Segment::Index(inner.parse().map_err(|_| error())?));
+                    }
+
+                    pos = end + 1;
+                }
+                _ => return Err(error()),
+            }
+        }
+
+        Ok(Self { segments, legacy })
+    }
+
+    fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The locations of every value in `root` the path matches.
+    fn locate(&self, root: &Json) -> Vec<Vec<Step>> {
+        locate(root, &self.segments, Vec::new())
+    }
+}
+
+fn locate(value: &Json, segments: &[Segment], location: Vec<Step>) -> Vec<Vec<Step>> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return vec![location];
+    };
+
+    let steps = match (segment, value) {
+        (Segment::Key(key), Json::Object(members)) if members.iter().any(|(k, _)| k == key) => {
+            vec![Step::Key(key.clone())]
+        }
+        (Segment::Index(index), Json::Array(values)) => {
+            let len = values.len() as i64;
+            let index = if *index < 0 { len + index } else { *index };
+
+            if (0..len).contains(&index) {
+                vec![Step::Index(index as usize)]
+            } else {
+                vec![]
+            }
+        }
+        (Segment::Wildcard, Json::Array(values)) => (0..values.len()).map(Step::Index).collect(),
+        (Segment::Wildcard, Json::Object(members)) => members
+            .iter()
+            .map(|(key, _)| Step::Key(key.clone()))
+            .collect(),
+        _ => vec![],
+    };
+
+    steps
+        .into_iter()
+        .flat_map(|step| {
+            let child = value.get(&step).unwrap_or(&Json::Null);
+            let mut location = location.clone();
+            location.push(step);
+            locate(child, rest, location)
+        })
+        .collect()
+}
+
+fn path_missing(path: &str) -> RespType {
+    RedisError::Other(format!("Path '{path}' does not exist")).to_resp()
+}
+
+pub(crate) fn execute(command: &JsonCommand, cache: &Cache) -> RespType {
+    match command {
+        JsonCommand::Set {
+            key,
+            path,
+            value,
+            condition,
+        } => set(key, path, value, *condition, cache),
+        JsonCommand::Get { key, paths, format } => get(key, paths, format, cache),
+        JsonCommand::Del { key, path } => del(key, path.as_deref().unwrap_or("$"), cache),
+    }
+}
+
+fn set(
+    key: &str,
+    path: &str,
+    value: &str,
+    condition: Option<SetCondition>,
+    cache: &Cache,
+) -> RespType {
+    let (path, value) = match (Path::parse(path), Json::parse(value)) {
+        (Ok(path), Ok(value)) => (path, value),
+        (Err(err), _) | (_, Err(err)) => return err.to_resp(),
+    };
+
+    cache.update(key, |current| {
+        let document = match current {
+            None if !path.is_root() => {
+                let err = RedisError::Other("new objects must be created at the root".to_string());
+                return (err.to_resp(), Change::Keep);
+            }
+            None if condition == Some(SetCondition::Xx) => return (RespType::null(), Change::Keep),
+            None => return (RespType::ok(), Change::Set(Value::Json(value))),
+            Some(Value::Json(document)) => document,
+            Some(_) => return (RedisError::WrongType.to_resp(), Change::Keep),
+        };
+
+        let locations = path.locate(document);
+
+        if !locations.is_empty() {
+            if condition == Some(SetCondition::Nx) {
+                return (RespType::null(), Change::Keep);
+            }
+
+            for location in &locations {
+                if let Some(target) = document.at_mut(location) {
+                    *target = value.clone();
+                }
+            }
+
+            return (RespType::ok(), Change::Modified);
+        }
+
+        // A missing member is added when its parent exists and is an object.
+        let parent = match path.segments.split_last() {
+            Some((Segment::Key(member), parent)) if condition != Some(SetCondition::Xx) => {
+                locate(document, parent, Vec::new())
+                    .into_iter()
+                    .map(|location| (location, member))
+                    .collect::<Vec<_>>()
+            }
+            _ => vec![],
+        };
+
+        let mut added = false;
+        for (location, member) in parent {
+            if let Some(Json::Object(members)) = document.at_mut(&location) {
+                members.push((member.clone(), value.clone()));
+                added = true;
+            }
+        }
+
+        if added {
+            (RespType::ok(), Change::Modified)
+        } else {
+            (RespType::null(), Change::Keep)
+        }
+    })
+}
+
+fn get(key: &str, paths: &[String], format: &JsonFormat, cache: &Cache) -> RespType {
+    let parsed = match paths
+        .iter()
+        .map(|path| Path::parse(path))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(parsed) if parsed.is_empty() => vec![Path::parse(".").unwrap()],
+        Ok(parsed) => parsed,
+        Err(err) => return err.to_resp(),
+    };
+
+    let reply = cache.read(key, |value| {
+        let Value::Json(document) = value else {
+            return Err(RedisError::WrongType.to_resp());
+        };
+
+        // Each path resolves to the single legacy value or an array of every JSONPath match.
+        let mut results = Vec::new();
+        for (path, text) in parsed
+            .iter()
+            .zip(paths.iter().map(String::as_str).chain(["."]))
+        {
+            let locations = path.locate(document);
+            let mut values = locations
+                .iter()
+                .filter_map(|location| document.at(location));
+
+            let result = if path.legacy {
+                values.next().cloned().ok_or_else(|| path_missing(text))?
+            } else {
+                Json::Array(values.cloned().collect())
+            };
+
+            results.push((text.to_string(), result));
+        }
+
+        let result = match results.len() {
+            1 => results.remove(0).1,
+            _ => Json::Object(results),
+        };
+
+        Ok(result.format(format))
+    });
+
+    match reply {
+        None => RespType::null(),
+        Some(Ok(json)) => RespType::from(json),
+        Some(Err(err)) => err,
+    }
+}
+
+fn del(key: &str, path: &str, cache: &Cache) -> RespType {
+    let path = match Path::parse(path) {
+        Ok(path) => path,
+        Err(err) => return err.to_resp(),
+    };
+
+    cache.update(key, |current| {
+        let document = match current {
+            None => return (RespType::from(0), Change::Keep),
+            Some(Value::Json(_)) if path.is_root() => return (RespType::from(1), Change::Delete),
+            Some(Value::Json(document)) => document,
+            Some(_) => return (RedisError::WrongType.to_resp(), Change::Keep),
+        };
+
+        // Removing the last locations first keeps the array indices of earlier ones valid.
+        let mut locations = path.locate(document);
+        locations.sort();
+
+        let mut deleted: i64 = 0;
+        for location in locations.iter().rev() {
+            let Some((step, parent)) = location.split_last() else {
+                continue;
+            };
+
+            if document
+                .at_mut(parent)
+                .is_some_and(|parent| parent.remove(step))
+            {
+                deleted += 1;
+            }
+        }
+
+        match deleted {
+            0 => (RespType::from(0), Change::Keep),
+            _ => (RespType::from(deleted), Change::Modified),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::run;
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let cases = [
+            ("null", "null"),
+            (" [1, -2.5e3, true, false] ", "[1,-2.5e3,true,false]"),
+            (r#"{"a": {"b": []}, "c": {}}"#, r#"{"a":{"b":[]},"c":{}}"#),
+            (
+                r#""tab\t \"q\" \u00e9 \ud83d\ude00""#,
+                "\"tab\\t \\\"q\\\" é 😀\"",
+            ),
+            (r#"{"a": 1, "a": 2}"#, r#"{"a":2}"#),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Json::parse(input).unwrap().to_string(), expected, "{input}");
+        }
+
+        for invalid in [
+            "",
+            "01",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"\\ud83d\"",
+            "nul",
+            "1 2",
+            "-",
+        ] {
+            assert!(Json::parse(invalid).is_err(), "{invalid}");
+        }
+
+        assert!(Json::parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+    }
+
+    #[test]
+    fn test_paths() {
+        let document =
+            Json::parse(r#"{"a": [{"b": 1}, {"b": 2}, {"c": 3}], "d": {"b": 4}}"#).unwrap();
+        let find = |path: &str| {
+            let path = Path::parse(path).unwrap();
+            path.locate(&document)
+                .iter()
+                .map(|location| document.at(location).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(find("$"), vec![document.to_string()]);
+        assert_eq!(find("."), vec![document.to_string()]);
+        assert_eq!(find("$.a[*].b"), vec!["1", "2"]);
+        assert_eq!(find("$.a[-1]"), vec![r#"{"c":3}"#]);
+        assert_eq!(find("$['d'].b"), vec!["4"]);
+        assert_eq!(find("$.*.b"), vec!["4"]);
+        assert_eq!(find("a[1].b"), vec!["2"]);
+        assert_eq!(find("$.a[3]"), Vec::<String>::new());
+
+        for invalid in ["$.", "$[1", "$x", "$.a[b]"] {
+            assert!(Path::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let cache = Cache::new(1);
+
+        assert_eq!(
+            run(&cache, &["JSON.SET", "doc", "$.a", "1"]),
+            RespType::error("ERR", "new objects must be created at the root")
+        );
+        assert_eq!(
+            run(
+                &cache,
+                &["JSON.SET", "doc", "$", r#"{"a": [1, 2], "b": {"c": "x"}}"#]
+            ),
+            RespType::ok()
+        );
+        assert_eq!(
+            run(&cache, &["JSON.SET", "doc", "$", "{}", "NX"]),
+            RespType::null()
+        );
+        assert_eq!(
+            run(&cache, &["JSON.SET", "doc", "$.b.d", "true"]),
+            RespType::ok()
+        );
+        assert_eq!(
+            run(&cache, &["JSON.SET", "doc", "$.x.y", "1"]),
+            RespType::null()
+        );
+        assert_eq!(
+            run(&cache, &["JSON.SET", "doc", "$.a[0]", "5", "XX"]),
+            RespType::ok()
+        );
+
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc"]),
+            RespType::from(r#"{"a":[5,2],"b":{"c":"x","d":true}}"#)
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc", "$..a"]),
+            RespType::error("ERR", "invalid JSON path '$..a'")
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc", "$.a[*]"]),
+            RespType::from("[5,2]")
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc", ".b.c"]),
+            RespType::from(r#""x""#)
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc", ".a", "$.b.d"]),
+            RespType::from(r#"{".a":[5,2],"$.b.d":[true]}"#)
+        );
+        assert_eq!(
+            run(
+                &cache,
+                &["JSON.GET", "doc", "INDENT", "  ", "NEWLINE", "\n", "SPACE", " ", "$.b"]
+            ),
+            RespType::from("[\n  {\n    \"c\": \"x\",\n    \"d\": true\n  }\n]")
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc", ".missing"]),
+            RespType::error("ERR", "Path '.missing' does not exist")
+        );
+        assert_eq!(run(&cache, &["JSON.GET", "missing"]), RespType::null());
+
+        assert_eq!(
+            run(&cache, &["JSON.DEL", "doc", "$.a[*]"]),
+            RespType::from(2)
+        );
+        assert_eq!(
+            run(&cache, &["JSON.DEL", "doc", "$.nope"]),
+            RespType::from(0)
+        );
+        assert_eq!(
+            run(&cache, &["JSON.GET", "doc"]),
+            RespType::from(r#"{"a":[],"b":{"c":"x","d":true}}"#)
+        );
+        assert_eq!(run(&cache, &["JSON.DEL", "doc"]), RespType::from(1));
+        assert_eq!(run(&cache, &["JSON.GET", "doc"]), RespType::null());
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let cache = Cache::new(1);
+        cache.set("s", "v", None);
+        run(&cache, &["JSON.SET", "j", "$", "[]"]);
+
+        let wrong_type = RedisError::WrongType.to_resp();
+        assert_eq!(run(&cache, &["JSON.GET", "s"]), wrong_type);
+        assert_eq!(run(&cache, &["JSON.SET", "s", "$", "1"]), wrong_type);
+        assert_eq!(run(&cache, &["GET", "j"]), wrong_type);
+        assert_eq!(
+            run(&cache, &["DUMP", "j"]),
+            RespType::error("ERR", "values of type ReJSON-RL can't be serialized")
+        );
+        assert_eq!(
+            run(&cache, &["OBJECT", "ENCODING", "j"]),
+            RespType::from("raw")
+        );
+
+        // SET replaces any type.
+        assert_eq!(run(&cache, &["SET", "j", "v"]), RespType::ok());
+        assert_eq!(run(&cache, &["GET", "j"]), RespType::from("v"));
+    }
+}
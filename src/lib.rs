@@ -1,4 +1,26 @@
-pub(crate) mod cache;
-pub(crate) mod command;
-pub(crate) mod resp_type;
+pub mod aof;
+pub(crate) mod blocking;
+pub(crate) mod bloom;
+pub mod cache;
+pub mod client;
+pub mod clock;
+pub mod command;
+pub(crate) mod debug;
+pub(crate) mod dump;
+pub mod error;
+pub(crate) mod glob;
+pub(crate) mod hash;
+pub(crate) mod json;
+pub(crate) mod list;
+pub(crate) mod persistence;
+pub(crate) mod pool;
+pub(crate) mod ratelimit;
+pub mod rdb;
+pub(crate) mod reply;
+pub mod resp_type;
 pub mod server;
+pub mod service;
+pub(crate) mod stats;
+pub(crate) mod stream;
+pub mod testing;
+pub(crate) mod timer;
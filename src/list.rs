@@ -0,0 +1,320 @@
+//! The list type and the commands working on it. BLPOP and BRPOP wait on the server's
+//! [`BlockedClients`] for an element to be pushed.
+
+use crate::{
+    blocking::{BlockedClients, Blocking, Served},
+    cache::{Cache, Change, MemoryUsage, Value, ValueType},
+    command::ListCommand,
+    error::RedisError,
+    resp_type::RespType,
+};
+
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct List {
+    items: VecDeque<String>,
+}
+
+impl List {
+    /// The elements from `start` to `stop`, both inclusive. Negative indexes count from the end,
+    /// and indexes out of range are clamped like LRANGE does.
+    fn range(&self, start: i64, stop: i64) -> impl Iterator<Item = &String> {
+        let len = self.items.len() as i64;
+        let resolve = |index: i64| if index < 0 { len + index } else { index };
+
+        let start = resolve(start).max(0);
+        let stop = resolve(stop).min(len - 1);
+        let count = (stop - start + 1).max(0);
+
+        self.items.iter().skip(start as usize).take(count as usize)
+    }
+
+    /// The command that recreates the list under `key`, used to rewrite the append-only file.
+    pub(crate) fn commands(&self, key: &str) -> Vec<RespType> {
+        let mut args = vec!["RPUSH".into(), key.into()];
+        args.extend(self.items.iter().map(|item| item.as_str().into()));
+
+        vec![RespType::array(args)]
+    }
+
+    pub(crate) fn iter(&self) -> impl ExactSizeIterator<Item = &String> {
+        self.items.iter()
+    }
+
+    fn pop(&mut self, left: bool) -> Option<String> {
+        match left {
+            true => self.items.pop_front(),
+            false => self.items.pop_back(),
+        }
+    }
+}
+
+impl FromIterator<String> for List {
+    fn from_iter<I: IntoIterator<Item = String>>(items: I) -> Self {
+        Self {
+            items: items.into_iter().collect(),
+        }
+    }
+}
+
+impl MemoryUsage for List {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .items
+                .iter()
+                .map(|item| std::mem::size_of::<String>() + item.capacity())
+                .sum::<usize>()
+    }
+
+    fn elements(&self) -> usize {
+        self.items.len()
+    }
+}
+
+pub(crate) fn execute(command: &ListCommand, cache: &Cache) -> RespType {
+    let result = match command {
+        ListCommand::Push { key, values, left } => push(key, values, *left, cache),
+        ListCommand::Len { key } => {
+            let len = cache.read(key, |value| match value {
+                Value::List(list) => list.items.len(),
+                _ => 0,
+            });
+
+            Ok(RespType::from(len.unwrap_or_default() as i64))
+        }
+        ListCommand::Range { key, start, stop } => {
+            let items = cache.read(key, |value| match value {
+                Value::List(list) => list
+                    .range(*start, *stop)
+                    .map(|v| v.as_str().into())
+                    .collect(),
+                _ => Vec::new(),
+            });
+
+            Ok(RespType::array(items.unwrap_or_default()))
+        }
+        ListCommand::Pop { key, count, left } => {
+            pop(key, count.unwrap_or(1), *left, cache).map(|popped| match (popped, count) {
+                (None, _) => RespType::null(),
+                (Some(mut items), None) => items.pop().map(RespType::from).into(),
+                (Some(items), Some(_)) => {
+                    RespType::array(items.into_iter().map(RespType::from).collect())
+                }
+            })
+        }
+        ListCommand::BlockingPop { left, .. } => {
+            let name = if *left { "blpop" } else { "brpop" };
+            Err(RedisError::Other(format!("{name} requires a server")))
+        }
+    };
+
+    result.unwrap_or_else(|err| err.to_resp())
+}
+
+fn push(key: &str, values: &[String], left: bool, cache: &Cache) -> Result<RespType, RedisError> {
+    cache.update(key, |current| {
+        let mut created = None;
+        let list = match current {
+            Some(Value::List(list)) => list,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None => created.insert(List::default()),
+        };
+
+        for value in values {
+            match left {
+                true => list.items.push_front(value.clone()),
+                false => list.items.push_back(value.clone()),
+            }
+        }
+
+        let reply = Ok(RespType::from(list.items.len() as i64));
+        match created {
+            Some(list) => (reply, Change::Set(Value::List(list))),
+            None => (reply, Change::Modified),
+        }
+    })
+}
+
+/// Pop up to `count` elements, or `None` if there's no list. Lists left empty are deleted.
+fn pop(
+    key: &str,
+    count: usize,
+    left: bool,
+    cache: &Cache,
+) -> Result<Option<Vec<String>>, RedisError> {
+    cache.update(key, |current| {
+        let list = match current {
+            Some(Value::List(list)) => list,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None => return (Ok(None), Change::Keep),
+        };
+
+        let popped = (0..count).map_while(|_| list.pop(left)).collect();
+        let change = match list.items.is_empty() {
+            true => Change::Delete,
+            false => Change::Modified,
+        };
+
+        (Ok(Some(popped)), change)
+    })
+}
+
+/// BLPOP and BRPOP: pop an element from the first of the lists that has one, or wait up to
+/// `timeout` for one to be pushed, or forever if it's zero. Replies with the key and the element,
+/// and the non-blocking pop it turned into is what replicas get. Without `blocked` the client may
+/// not block, like in a transaction, and it only tries once.
+pub(crate) fn blocking_pop(
+    command: &ListCommand,
+    cache: &Cache,
+    blocked: Option<&BlockedClients>,
+    client_id: u64,
+) -> Blocking {
+    let ListCommand::BlockingPop {
+        keys,
+        timeout,
+        left,
+    } = command
+    else {
+        return Blocking::Done(Served::reply(RedisError::Syntax.to_resp()));
+    };
+
+    for key in keys {
+        match cache.value_type(key) {
+            Some(ValueType::List) | None => (),
+            Some(_) => return Blocking::Done(Served::reply(RedisError::WrongType.to_resp())),
+        }
+    }
+
+    let left = *left;
+    let timeout = Some(*timeout).filter(|timeout| !timeout.is_zero());
+    let serve = move |key: &str, cache: &Cache| {
+        let value = pop(key, 1, left, cache).ok()??.pop()?;
+        let name = if left { "LPOP" } else { "RPOP" };
+
+        Some(Served {
+            reply: RespType::array(vec![key.into(), value.into()]),
+            write: Some(RespType::array(vec![name.into(), key.into()])),
+        })
+    };
+
+    match blocked {
+        Some(blocked) => blocked.block(client_id, cache, keys, timeout, serve),
+        None => Blocking::Done(
+            keys.iter()
+                .find_map(|key| serve(key, cache))
+                .unwrap_or_else(|| Served::reply(RespType::null())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{command::Command, testing::run, timer::TimerWheel};
+
+    use std::sync::Arc;
+
+    fn array(items: &[&str]) -> RespType {
+        RespType::array(items.iter().map(|item| RespType::from(*item)).collect())
+    }
+
+    #[test]
+    fn test_push_and_range() {
+        let cache = Cache::new(1);
+
+        assert_eq!(run(&cache, &["RPUSH", "l", "a", "b"]), 2.into());
+        assert_eq!(run(&cache, &["LPUSH", "l", "y", "z"]), 4.into());
+        assert_eq!(run(&cache, &["LLEN", "l"]), 4.into());
+        assert_eq!(run(&cache, &["LLEN", "missing"]), 0.into());
+        assert_eq!(cache.value_type("l"), Some(ValueType::List));
+
+        assert_eq!(
+            run(&cache, &["LRANGE", "l", "0", "-1"]),
+            array(&["z", "y", "a", "b"])
+        );
+        assert_eq!(
+            run(&cache, &["LRANGE", "l", "-2", "10"]),
+            array(&["a", "b"])
+        );
+        assert_eq!(run(&cache, &["LRANGE", "l", "-100", "0"]), array(&["z"]));
+        assert_eq!(run(&cache, &["LRANGE", "l", "3", "1"]), array(&[]));
+        assert_eq!(run(&cache, &["LRANGE", "missing", "0", "-1"]), array(&[]));
+
+        cache.set("s", "v", None);
+        assert_eq!(
+            run(&cache, &["RPUSH", "s", "a"]),
+            RedisError::WrongType.to_resp()
+        );
+        assert_eq!(
+            run(&cache, &["LRANGE", "s", "0", "-1"]),
+            RedisError::WrongType.to_resp()
+        );
+    }
+
+    #[test]
+    fn test_pop() {
+        let cache = Cache::new(1);
+        run(&cache, &["RPUSH", "l", "a", "b", "c", "d"]);
+
+        assert_eq!(run(&cache, &["LPOP", "l"]), "a".into());
+        assert_eq!(run(&cache, &["RPOP", "l"]), "d".into());
+        assert_eq!(run(&cache, &["LPOP", "l", "5"]), array(&["b", "c"]));
+
+        // The emptied list is deleted.
+        assert_eq!(cache.value_type("l"), None);
+        assert_eq!(run(&cache, &["LPOP", "l"]), RespType::null());
+        assert_eq!(run(&cache, &["LPOP", "l", "2"]), RespType::null());
+        assert_eq!(
+            run(&cache, &["LPOP", "l", "-1"]),
+            RespType::error("ERR", "value is out of range, must be positive")
+        );
+    }
+
+    #[test]
+    fn test_blocking_pop() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+        let pop = |id, args: &[&str]| {
+            let frames = args.iter().map(|a| RespType::from(*a)).collect::<Vec<_>>();
+            let Command::List(command) = Command::parse(&frames).unwrap() else {
+                panic!("not a list command");
+            };
+            blocking_pop(&command, &cache, Some(&blocked), id)
+        };
+        let wait = |blocking| match blocking {
+            Blocking::Done(served) => served.reply,
+            Blocking::Waiting(waiting) => waiting.wait().unwrap_or_else(RespType::null),
+        };
+
+        // Replicas get the pop it turned into.
+        run(&cache, &["RPUSH", "b", "x"]);
+        let Blocking::Done(served) = pop(1, &["BLPOP", "a", "b", "0"]) else {
+            panic!("expected to be served right away");
+        };
+        assert_eq!(
+            served,
+            Served {
+                reply: array(&["b", "x"]),
+                write: Some(array(&["LPOP", "b"])),
+            }
+        );
+        assert_eq!(wait(pop(1, &["BLPOP", "a", "0.01"])), RespType::null());
+
+        // Blocked clients are served in the order they blocked.
+        let waiting = (1..=2)
+            .map(|id| pop(id, &["BRPOP", "a", "0"]))
+            .collect::<Vec<_>>();
+
+        run(&cache, &["RPUSH", "a", "1", "2"]);
+        let mut writes = Vec::new();
+        blocked.serve_ready(&cache, &cache.lock_keys(&["a"]), |write| {
+            writes.push(write.clone())
+        });
+
+        let replies = waiting.into_iter().map(wait).collect::<Vec<_>>();
+        assert_eq!(replies, vec![array(&["a", "2"]), array(&["a", "1"])]);
+        assert_eq!(writes, vec![array(&["RPOP", "a"]); 2]);
+    }
+}
@@ -0,0 +1,425 @@
+//! Saving the keyspace to an RDB file with SAVE and BGSAVE, and loading it back when the server
+//! starts. Files are written in the same format as Redis, so they can be loaded by either.
+
+use crate::{
+    cache::{Cache, Expiry, Value},
+    dump::{self, Reader, RDB_TYPE_HASH, RDB_TYPE_LIST, RDB_TYPE_STRING, RDB_VERSION},
+    error::RedisError,
+    rdb::{
+        self, RDB_OPCODE_AUX, RDB_OPCODE_EOF, RDB_OPCODE_EXPIRETIME, RDB_OPCODE_EXPIRETIME_MS,
+        RDB_OPCODE_FREQ, RDB_OPCODE_FUNCTION2, RDB_OPCODE_IDLE, RDB_OPCODE_RESIZEDB,
+        RDB_OPCODE_SELECTDB, RDB_OPCODE_SLOT_INFO,
+    },
+};
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// The RDB file of the server and the state of the last save.
+#[derive(Debug)]
+pub(crate) struct Persistence {
+    path: PathBuf,
+    state: Arc<SaveState>,
+}
+
+#[derive(Debug, Default)]
+struct SaveState {
+    bgsave_in_progress: AtomicBool,
+    /// Unix time in seconds of the last successful save, or of when the server started.
+    last_save: AtomicU64,
+    last_bgsave_failed: AtomicBool,
+}
+
+impl Persistence {
+    pub(crate) fn new(dir: &Path, dbfilename: &str, cache: &Cache) -> Self {
+        let state = SaveState::default();
+        state
+            .last_save
+            .store(cache.clock().unix_now().as_secs(), Ordering::Relaxed);
+
+        Self {
+            path: dir.join(dbfilename),
+            state: Arc::new(state),
+        }
+    }
+
+    /// Load the RDB file into the cache, returning the number of keys loaded. A missing file is
+    /// an empty keyspace.
+    pub(crate) fn load(&self, cache: &Cache) -> Result<usize, RedisError> {
+        match fs::read(&self.path) {
+            Ok(data) => decode(&data, cache),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the keyspace to the RDB file, blocking until it's done.
+    pub(crate) fn save(&self, cache: &Cache) -> Result<(), RedisError> {
+        if self.state.bgsave_in_progress.load(Ordering::Relaxed) {
+            return Err(RedisError::Other(
+                "Background save already in progress".to_string(),
+            ));
+        }
+
+        let now = cache.clock().unix_now();
+        write_file(
+            &self.path,
+            &encode(cache.snapshot(), now.as_millis() as u64)?,
+        )?;
+        self.state.last_save.store(now.as_secs(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Take a snapshot of the keyspace and write it to the RDB file from a background thread.
+    pub(crate) fn bgsave(&self, cache: &Cache) -> Result<(), RedisError> {
+        if self.state.bgsave_in_progress.swap(true, Ordering::Relaxed) {
+            return Err(RedisError::Other(
+                "Background save already in progress".to_string(),
+            ));
+        }
+
+        // Only collecting the items needs the shards, writing them happens without any lock.
+        let items = cache.snapshot().collect::<Vec<_>>();
+        // Fail while the client is still waiting rather than in the background.
+        if let Err(err) = items
+            .iter()
+            .try_for_each(|(_, value, _)| dump::rdb_type(value).map(drop))
+        {
+            self.state
+                .bgsave_in_progress
+                .store(false, Ordering::Relaxed);
+            return Err(err);
+        }
+
+        let now = cache.clock().unix_now();
+        let path = self.path.clone();
+        let state = self.state.clone();
+
+        thread::spawn(move || {
+            let result = encode(items.into_iter(), now.as_millis() as u64)
+                .and_then(|data| Ok(write_file(&path, &data)?));
+            match &result {
+                Ok(()) => {
+                    tracing::info!(path = %path.display(), "background saving done");
+                    state.last_save.store(now.as_secs(), Ordering::Relaxed);
+                }
+                Err(err) => {
+                    tracing::error!(path = %path.display(), %err, "background saving failed")
+                }
+            }
+
+            state
+                .last_bgsave_failed
+                .store(result.is_err(), Ordering::Relaxed);
+            state.bgsave_in_progress.store(false, Ordering::Relaxed);
+        });
+
+        Ok(())
+    }
+
+    /// The persistence section of INFO.
+    pub(crate) fn info(&self) -> String {
+        let state = &self.state;
+        let status = if state.last_bgsave_failed.load(Ordering::Relaxed) {
+            "err"
+        } else {
+            "ok"
+        };
+
+        format!(
+            "# Persistence\r\n\
+            loading:0\r\n\
+            rdb_bgsave_in_progress:{}\r\n\
+            rdb_last_save_time:{}\r\n\
+            rdb_last_bgsave_status:{status}\r\n",
+            u8::from(state.bgsave_in_progress.load(Ordering::Relaxed)),
+            state.last_save.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Write `data` to a temporary file next to `path` and rename it, so a crash while saving never
+/// leaves a partial file behind.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp, path)
+}
+
+/// Serialize items as an RDB file. Strings, lists and hashes are supported, any other type fails
+/// the whole file rather than leaving keys out of it.
+pub(crate) fn encode(
+    items: impl Iterator<Item = (String, Value, Option<Expiry>)>,
+    unix_millis: u64,
+) -> Result<Vec<u8>, RedisError> {
+    let items = items.collect::<Vec<_>>();
+    let expires = items
+        .iter()
+        .filter(|(_, _, expiry)| expiry.is_some())
+        .count();
+
+    let mut out = format!("REDIS{RDB_VERSION:04}").into_bytes();
+    for (key, value) in [
+        ("redis-ver", env!("CARGO_PKG_VERSION").to_string()),
+        ("redis-bits", (usize::BITS).to_string()),
+        ("ctime", (unix_millis / 1000).to_string()),
+    ] {
+        out.push(RDB_OPCODE_AUX);
+        dump::write_string(&mut out, key.as_bytes());
+        dump::write_string(&mut out, value.as_bytes());
+    }
+
+    out.push(RDB_OPCODE_SELECTDB);
+    dump::write_length(&mut out, 0);
+    out.push(RDB_OPCODE_RESIZEDB);
+    dump::write_length(&mut out, items.len());
+    dump::write_length(&mut out, expires);
+
+    for (key, value, expiry) in &items {
+        if let Some(expiry) = expiry {
+            out.push(RDB_OPCODE_EXPIRETIME_MS);
+            out.extend_from_slice(&expiry.unix_millis().to_le_bytes());
+        }
+
+        dump::write_value(&mut out, Some(key), value)?;
+    }
+
+    out.push(RDB_OPCODE_EOF);
+    let crc = dump::crc64(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+
+    Ok(out)
+}
+
+/// Load the keys of an RDB file into the cache, returning how many were loaded. Keys that have
+/// already expired are skipped. The whole file is read before any key is set so nothing is loaded
+/// from a corrupt one.
+pub(crate) fn decode(data: &[u8], cache: &Cache) -> Result<usize, RedisError> {
+    let mut report = rdb::Report::default();
+    rdb::check(data, &mut report)?;
+
+    if let Some(db) = report.databases.keys().find(|db| **db != 0) {
+        return Err(RedisError::Other(format!(
+            "the file has keys in database {db} but only one database is supported"
+        )));
+    }
+
+    let unsupported = report
+        .databases
+        .values()
+        .flat_map(|census| census.types.keys())
+        .find(|name| !["string", "list", "hash"].contains(name));
+    if let Some(name) = unsupported {
+        return Err(RedisError::Other(format!(
+            "the file has keys of type {name} which aren't supported"
+        )));
+    }
+
+    let bad_data = || RedisError::Other("Bad data format".to_string());
+    let mut reader = Reader { data, pos: 9 };
+    let now = cache.clock().unix_now().as_millis() as u64;
+    let mut expires_at = None;
+    let mut items = Vec::new();
+
+    loop {
+        match reader.byte().ok_or_else(bad_data)? {
+            RDB_OPCODE_EOF => break,
+            RDB_OPCODE_SELECTDB => {
+                reader.plain_length().ok_or_else(bad_data)?;
+            }
+            RDB_OPCODE_RESIZEDB => {
+                reader.plain_length().ok_or_else(bad_data)?;
+                reader.plain_length().ok_or_else(bad_data)?;
+            }
+            RDB_OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.plain_length().ok_or_else(bad_data)?;
+                }
+            }
+            RDB_OPCODE_AUX => {
+                reader.string().ok_or_else(bad_data)?;
+                reader.string().ok_or_else(bad_data)?;
+            }
+            RDB_OPCODE_FUNCTION2 => {
+                reader.string().ok_or_else(bad_data)?;
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                let millis = reader.bytes(8).ok_or_else(bad_data)?;
+                expires_at = Some(u64::from_le_bytes(millis.try_into().unwrap()));
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let secs = reader.bytes(4).ok_or_else(bad_data)?;
+                expires_at = Some(u64::from(u32::from_le_bytes(secs.try_into().unwrap())) * 1000);
+            }
+            RDB_OPCODE_IDLE => {
+                reader.plain_length().ok_or_else(bad_data)?;
+            }
+            RDB_OPCODE_FREQ => {
+                reader.byte().ok_or_else(bad_data)?;
+            }
+            value_type @ (RDB_TYPE_STRING | RDB_TYPE_LIST | RDB_TYPE_HASH) => {
+                let key = reader.string().ok_or_else(bad_data)?;
+                let value = dump::read_value(&mut reader, value_type).ok_or_else(bad_data)?;
+
+                let expiry = match expires_at.take() {
+                    Some(at) if at <= now => continue,
+                    Some(at) => Some(Expiry::at_unix_millis(cache.clock(), at)),
+                    None => None,
+                };
+
+                // Keys are held as text so any non UTF-8 data in them is replaced.
+                items.push((String::from_utf8_lossy(&key).into_owned(), value, expiry));
+            }
+            value_type => {
+                return Err(RedisError::Other(format!(
+                    "the file has keys of type {} in an encoding which isn't supported",
+                    rdb::type_name(value_type).ok_or_else(bad_data)?
+                )))
+            }
+        }
+    }
+
+    let loaded = items.len();
+    for (key, value, expiry) in items {
+        cache.load(&key, value, expiry);
+    }
+
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_encode_and_decode() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::without_eviction_loop(2, clock.clone());
+        cache.set("k", "v", None);
+        cache.set("int", "-1234", None);
+        cache.set("long", "x".repeat(100), Some(Duration::from_secs(10)));
+        cache.set("short", "v", Some(Duration::from_millis(100)));
+
+        let now = clock.unix_now().as_millis() as u64;
+        let data = encode(cache.snapshot(), now).unwrap();
+
+        let mut report = rdb::Report::default();
+        rdb::check(&data, &mut report).unwrap();
+        assert_eq!(report.version, RDB_VERSION);
+        assert_eq!(report.checksum, rdb::Checksum::Verified);
+        assert_eq!(report.databases[&0].keys(), 4);
+        assert_eq!(report.databases[&0].expires, 2);
+
+        // Keys that expired in the meantime aren't loaded.
+        clock.advance(Duration::from_millis(100));
+        let loaded = Cache::without_eviction_loop(1, clock.clone());
+        assert_eq!(decode(&data, &loaded).unwrap(), 3);
+        assert_eq!(loaded.get("k"), Some("v".to_string()));
+        assert_eq!(loaded.encoding("int"), Some("int"));
+        assert_eq!(loaded.get("long"), Some("x".repeat(100)));
+        // Deadlines are stored in whole milliseconds.
+        let ttl = loaded.ttl("long").flatten().unwrap();
+        assert!(ttl > Duration::from_millis(9898) && ttl <= Duration::from_millis(9900));
+        assert_eq!(loaded.get("short"), None);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let cache = Cache::new(1);
+
+        let mut data = encode(std::iter::empty(), 0).unwrap();
+        let len = data.len();
+        data[len - 1] ^= 0xff;
+        assert!(decode(&data, &cache).is_err());
+
+        let with_crc = |data: &[u8]| {
+            let mut data = data.to_vec();
+            let crc = dump::crc64(&data);
+            data.extend_from_slice(&crc.to_le_bytes());
+            data
+        };
+
+        // A list with one element as a quicklist of ziplists.
+        let list = with_crc(b"REDIS0009\xfe\x00\x0e\x01k\x01\x01v\xff");
+        assert_eq!(
+            decode(&list, &cache).unwrap_err().to_string(),
+            "the file has keys of type list in an encoding which isn't supported"
+        );
+
+        let set = with_crc(b"REDIS0009\xfe\x00\x02\x01k\x01\x01v\xff");
+        assert_eq!(
+            decode(&set, &cache).unwrap_err().to_string(),
+            "the file has keys of type set which aren't supported"
+        );
+
+        // Nothing is loaded from a file that fails after its first key.
+        let partial = with_crc(b"REDIS0009\xfe\x00\x00\x01a\x01v\x0e\x01k\x01\x01v\xff");
+        assert!(decode(&partial, &cache).is_err());
+        assert_eq!(cache.get("a"), None);
+
+        let cache = Cache::new(1);
+        cache.set("k", "v", None);
+        cache.set_with_expiry("s", Value::Stream(Default::default()), None);
+        assert_eq!(
+            encode(cache.snapshot(), 0).unwrap_err().to_string(),
+            "values of type stream can't be serialized"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("persistence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cache = Cache::new(1);
+        let persistence = Persistence::new(&dir, "dump.rdb", &cache);
+        assert_eq!(persistence.load(&cache).unwrap(), 0);
+
+        cache.set("k", "v", None);
+        cache.set("binary", &b"\xff\x00\xfe"[..], None);
+        let list = ["a", "12", "c"].map(String::from).into_iter().collect();
+        cache.set_with_expiry("list", Value::List(list), None);
+        let hash = [("f", "v"), ("n", "1")]
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .into_iter()
+            .collect();
+        cache.set_with_expiry("hash", Value::Hash(hash), None);
+        persistence.save(&cache).unwrap();
+
+        let loaded = Cache::new(1);
+        assert_eq!(persistence.load(&loaded).unwrap(), 4);
+        assert_eq!(loaded.get("k"), Some("v".to_string()));
+        assert_eq!(loaded.get_bytes("binary").unwrap(), &b"\xff\x00\xfe"[..]);
+        for key in ["list", "hash"] {
+            assert_eq!(
+                loaded.read(key, Value::clone),
+                cache.read(key, Value::clone)
+            );
+        }
+
+        // Keys that can't be saved fail the save rather than being left out.
+        cache.set_with_expiry("s", Value::Stream(Default::default()), None);
+        assert!(persistence.save(&cache).is_err());
+        assert!(persistence.bgsave(&cache).is_err());
+        assert_eq!(persistence.load(&loaded).unwrap(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use crate::error::panic_message;
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// How long an idle worker waits for a new job before exiting.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A pool of threads that are reused for new jobs when idle and grows when all of them are busy.
+/// Since every connection occupies a thread for its whole lifetime the pool can't be bounded, but
+/// short-lived connections no longer pay for spawning a thread each.
+pub(crate) struct WorkerPool {
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    /// Workers waiting for a job that haven't been reserved by [`WorkerPool::execute`].
+    idle: Arc<AtomicUsize>,
+    workers: Mutex<Vec<thread::JoinHandle<()>>>,
+    idle_timeout: Duration,
+}
+
+impl WorkerPool {
+    pub(crate) fn new() -> Self {
+        Self::with_idle_timeout(IDLE_TIMEOUT)
+    }
+
+    fn with_idle_timeout(idle_timeout: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        Self {
+            sender: Mutex::new(Some(sender)),
+            receiver: Arc::new(Mutex::new(receiver)),
+            idle: Arc::default(),
+            workers: Mutex::default(),
+            idle_timeout,
+        }
+    }
+
+    /// Run `job` on an idle worker or a new one if all are busy. A panicking job is logged and
+    /// doesn't take its worker down with it.
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        let job = move || {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                tracing::error!(panic = panic_message(&*panic), "worker job panicked");
+            }
+        };
+
+        let reserved = self
+            .idle
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+
+        if reserved {
+            if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+                let _ = sender.send(Box::new(job));
+            }
+
+            return;
+        }
+
+        let receiver = self.receiver.clone();
+        let idle = self.idle.clone();
+        let idle_timeout = self.idle_timeout;
+
+        let worker = thread::spawn(move || {
+            job();
+
+            loop {
+                idle.fetch_add(1, Ordering::SeqCst);
+
+                let job = loop {
+                    let received = receiver.lock().unwrap().recv_timeout(idle_timeout);
+                    match received {
+                        Ok(job) => break job,
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            // If no idle slot is left a job has been reserved for us and is on
+                            // its way so keep waiting.
+                            if idle
+                                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                                    n.checked_sub(1)
+                                })
+                                .is_ok()
+                            {
+                                return;
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                };
+
+                job();
+            }
+        });
+
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(worker);
+    }
+
+    /// Stop accepting jobs and wait for all workers to finish their current job.
+    pub(crate) fn join(&self) {
+        self.sender.lock().unwrap().take();
+
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reuses_idle_workers() {
+        let pool = WorkerPool::new();
+        let threads = Arc::new(Mutex::new(Vec::new()));
+
+        for _ in 0..10 {
+            let threads = threads.clone();
+            pool.execute(move || threads.lock().unwrap().push(thread::current().id()));
+
+            // Give the worker time to become idle again.
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        pool.join();
+
+        let mut threads = threads.lock().unwrap().clone();
+        assert_eq!(threads.len(), 10);
+        threads.dedup();
+        assert_eq!(threads.len(), 1);
+    }
+
+    #[test]
+    fn test_survives_panicking_job() {
+        let pool = WorkerPool::new();
+        let (tx, rx) = mpsc::channel();
+
+        let panicking = tx.clone();
+        pool.execute(move || {
+            panicking.send(thread::current().id()).unwrap();
+            panic!("boom");
+        });
+        thread::sleep(Duration::from_millis(10));
+        pool.execute(move || tx.send(thread::current().id()).unwrap());
+
+        pool.join();
+
+        // The worker that ran the panicking job was reused.
+        let threads = rx.iter().collect::<Vec<_>>();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0], threads[1]);
+    }
+
+    #[test]
+    fn test_grows_when_busy() {
+        let pool = WorkerPool::with_idle_timeout(Duration::from_millis(10));
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(50));
+                tx.send(thread::current().id()).unwrap();
+            });
+        }
+
+        drop(tx);
+        pool.join();
+
+        let mut threads = rx.iter().collect::<Vec<_>>();
+        threads.sort_by_key(|id| format!("{id:?}"));
+        threads.dedup();
+        assert_eq!(threads.len(), 4);
+    }
+}
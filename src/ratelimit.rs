@@ -0,0 +1,164 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Per connection limits configured with [`crate::server::ServerBuilder`]. A limit of zero is the
+/// same as no limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RateLimits {
+    pub(crate) commands_per_sec: Option<u64>,
+    pub(crate) bytes_per_sec: Option<u64>,
+    /// Delay commands over the limit instead of rejecting them.
+    pub(crate) delay: bool,
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one second's worth so a
+/// client can burst after being idle.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Take `n` tokens if they're available.
+    fn try_take(&mut self, n: f64) -> bool {
+        self.refill();
+        if self.tokens < n {
+            return false;
+        }
+
+        self.tokens -= n;
+        true
+    }
+
+    /// Take `n` tokens even if it puts the bucket in debt, returning how long to wait until the
+    /// debt is paid off.
+    fn take(&mut self, n: f64) -> Duration {
+        self.refill();
+        self.tokens -= n;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Enforces [`RateLimits`] for a single connection. Waiting is done by sleeping on the connection's
+/// own thread so other clients are unaffected.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    commands: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    delay: bool,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: &RateLimits) -> Self {
+        Self {
+            commands: limits
+                .commands_per_sec
+                .filter(|rate| *rate > 0)
+                .map(TokenBucket::new),
+            bytes: limits
+                .bytes_per_sec
+                .filter(|rate| *rate > 0)
+                .map(TokenBucket::new),
+            delay: limits.delay,
+        }
+    }
+
+    /// Account for a command, returning whether it may run. Commands over the limit are rejected
+    /// unless configured to be delayed.
+    pub(crate) fn command(&mut self) -> bool {
+        let Some(bucket) = &mut self.commands else {
+            return true;
+        };
+
+        if !self.delay {
+            return bucket.try_take(1.0);
+        }
+
+        thread::sleep(bucket.take(1.0));
+        true
+    }
+
+    /// Account for received bytes. Reads are always delayed rather than rejected, which stops
+    /// reading from the socket until the client is back under the limit.
+    pub(crate) fn received(&mut self, n: usize) {
+        if let Some(bucket) = &mut self.bytes {
+            thread::sleep(bucket.take(n as f64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reject_commands() {
+        let mut limiter = RateLimiter::new(&RateLimits {
+            commands_per_sec: Some(10),
+            ..Default::default()
+        });
+
+        assert!((0..10).all(|_| limiter.command()));
+        assert!(!limiter.command());
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(limiter.command());
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut limiter = RateLimiter::new(&RateLimits {
+            commands_per_sec: Some(100),
+            bytes_per_sec: Some(1000),
+            delay: true,
+        });
+
+        let start = Instant::now();
+        assert!((0..105).all(|_| limiter.command()));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        let start = Instant::now();
+        limiter.received(1100);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let zero = RateLimits {
+            commands_per_sec: Some(0),
+            bytes_per_sec: Some(0),
+            delay: true,
+        };
+
+        for mut limiter in [RateLimiter::default(), RateLimiter::new(&zero)] {
+            let start = Instant::now();
+            assert!((0..10_000).all(|_| limiter.command()));
+            limiter.received(usize::MAX);
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+    }
+}
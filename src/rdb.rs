@@ -0,0 +1,436 @@
+//! Validation of RDB files, the snapshot format written by Redis. Every opcode and length is
+//! checked and values are skipped without being decoded, so files holding types the cache doesn't
+//! support can still be inspected.
+
+use crate::{
+    dump::{crc64, Reader},
+    error::RedisError,
+};
+
+use std::collections::BTreeMap;
+
+/// Files written by versions newer than this are rejected.
+const MAX_RDB_VERSION: u16 = 12;
+/// The first version ending with a CRC64 of the file.
+const CHECKSUM_VERSION: u16 = 5;
+
+pub(crate) const RDB_OPCODE_SLOT_INFO: u8 = 0xf4;
+pub(crate) const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 0xf5;
+pub(crate) const RDB_OPCODE_FUNCTION2: u8 = 0xf6;
+pub(crate) const RDB_OPCODE_MODULE_AUX: u8 = 0xf7;
+pub(crate) const RDB_OPCODE_IDLE: u8 = 0xf8;
+pub(crate) const RDB_OPCODE_FREQ: u8 = 0xf9;
+pub(crate) const RDB_OPCODE_AUX: u8 = 0xfa;
+pub(crate) const RDB_OPCODE_RESIZEDB: u8 = 0xfb;
+pub(crate) const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+pub(crate) const RDB_OPCODE_EXPIRETIME: u8 = 0xfd;
+pub(crate) const RDB_OPCODE_SELECTDB: u8 = 0xfe;
+pub(crate) const RDB_OPCODE_EOF: u8 = 0xff;
+
+/// How the integrity of the file was verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    /// The file is too old to have a checksum.
+    #[default]
+    Absent,
+    /// The file was written with `rdbchecksum no`.
+    Disabled,
+    Verified,
+}
+
+/// The keys in one database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Census {
+    /// The number of keys of each type, by the name TYPE reports.
+    pub types: BTreeMap<&'static str, u64>,
+    pub expires: u64,
+}
+
+impl Census {
+    pub fn keys(&self) -> u64 {
+        self.types.values().sum()
+    }
+}
+
+/// What was found in a file. When checking fails it holds everything read before the error.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub version: u16,
+    /// Auxiliary fields like `redis-ver` and `ctime`.
+    pub aux: Vec<(String, String)>,
+    pub databases: BTreeMap<u64, Census>,
+    pub checksum: Checksum,
+}
+
+/// Check that `data` is a complete and valid RDB file, filling in `report` as it's read. Errors
+/// include the offset of the first invalid byte.
+pub fn check(data: &[u8], report: &mut Report) -> Result<(), RedisError> {
+    let mut checker = Checker {
+        reader: Reader { data, pos: 0 },
+    };
+
+    checker.check(report)
+}
+
+/// The name TYPE reports for keys stored as `value_type`.
+pub(crate) fn type_name(value_type: u8) -> Option<&'static str> {
+    let name = match value_type {
+        0 => "string",
+        1 | 10 | 14 | 18 => "list",
+        2 | 11 | 20 => "set",
+        3 | 5 | 12 | 17 => "zset",
+        4 | 9 | 13 | 16 => "hash",
+        6 | 7 => "module",
+        15 | 19 | 21 => "stream",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+struct Checker<'a> {
+    reader: Reader<'a>,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&self, offset: usize, message: impl std::fmt::Display) -> RedisError {
+        RedisError::Other(format!("{message} at offset {offset}"))
+    }
+
+    /// Run one read, turning a failure into an error pointing at where it started.
+    fn read<T>(
+        &mut self,
+        what: &str,
+        f: impl FnOnce(&mut Reader<'a>) -> Option<T>,
+    ) -> Result<T, RedisError> {
+        let offset = self.reader.pos;
+        f(&mut self.reader)
+            .ok_or_else(|| self.error(offset, format!("invalid or truncated {what}")))
+    }
+
+    fn length(&mut self) -> Result<usize, RedisError> {
+        self.read("length", Reader::plain_length)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RedisError> {
+        self.read("string", Reader::string)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), RedisError> {
+        self.read("value", |reader| reader.bytes(n)).map(|_| ())
+    }
+
+    fn check(&mut self, report: &mut Report) -> Result<(), RedisError> {
+        let header = self.read("header", |reader| reader.bytes(9))?;
+        let version = match header.strip_prefix(b"REDIS") {
+            Some(version) => std::str::from_utf8(version)
+                .ok()
+                .and_then(|version| version.parse::<u16>().ok()),
+            None => None,
+        };
+
+        report.version = match version {
+            Some(version) if (1..=MAX_RDB_VERSION).contains(&version) => version,
+            Some(version) => return Err(self.error(5, format!("unsupported version {version}"))),
+            None => return Err(self.error(0, "invalid header")),
+        };
+
+        let mut db = 0;
+        let mut expires = false;
+
+        loop {
+            let offset = self.reader.pos;
+            let opcode = self.read("opcode", Reader::byte)?;
+
+            match opcode {
+                RDB_OPCODE_EOF => break,
+                RDB_OPCODE_SELECTDB => db = self.length()? as u64,
+                RDB_OPCODE_RESIZEDB => {
+                    self.length()?;
+                    self.length()?;
+                }
+                RDB_OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        self.length()?;
+                    }
+                }
+                RDB_OPCODE_AUX => {
+                    let key = self.string()?;
+                    let value = self.string()?;
+                    report.aux.push((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        String::from_utf8_lossy(&value).into_owned(),
+                    ));
+                }
+                RDB_OPCODE_FUNCTION2 => {
+                    self.string()?;
+                }
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    self.skip(8)?;
+                    expires = true;
+                }
+                RDB_OPCODE_EXPIRETIME => {
+                    self.skip(4)?;
+                    expires = true;
+                }
+                RDB_OPCODE_IDLE => {
+                    self.length()?;
+                }
+                RDB_OPCODE_FREQ => self.skip(1)?,
+                RDB_OPCODE_MODULE_AUX | RDB_OPCODE_FUNCTION_PRE_GA => {
+                    return Err(self.error(offset, format!("unsupported opcode {opcode:#04x}")));
+                }
+                value_type => {
+                    let name = type_name(value_type)
+                        .ok_or_else(|| self.error(offset, format!("unknown type {value_type}")))?;
+                    self.string()?;
+                    self.value(value_type, offset)?;
+
+                    let census = report.databases.entry(db).or_default();
+                    *census.types.entry(name).or_default() += 1;
+                    if std::mem::take(&mut expires) {
+                        census.expires += 1;
+                    }
+                }
+            }
+        }
+
+        if report.version >= CHECKSUM_VERSION {
+            let offset = self.reader.pos;
+            let data = self.reader.data;
+            let expected = self.read("checksum", |reader| reader.bytes(8))?;
+            let expected = u64::from_le_bytes(expected.try_into().expect("read 8 bytes"));
+
+            report.checksum = match expected {
+                0 => Checksum::Disabled,
+                _ if crc64(&data[..offset]) == expected => Checksum::Verified,
+                _ => return Err(self.error(offset, "checksum mismatch")),
+            };
+        }
+
+        if self.reader.pos != self.reader.data.len() {
+            return Err(self.error(self.reader.pos, "trailing data after the end of file"));
+        }
+
+        Ok(())
+    }
+
+    /// Skip a value of `value_type`.
+    fn value(&mut self, value_type: u8, offset: usize) -> Result<(), RedisError> {
+        match value_type {
+            // Linked list and hash table encodings of lists and sets, and quicklists of ziplists.
+            1 | 2 | 14 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                }
+            }
+            // Sorted set with scores as strings prefixed by their length.
+            3 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    let len = self.read("score", Reader::byte)?;
+                    // 253 to 255 are NaN and the infinities, stored without any digits.
+                    if len < 253 {
+                        self.skip(usize::from(len))?;
+                    }
+                }
+            }
+            // Hash table.
+            4 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.string()?;
+                }
+            }
+            // Sorted set with binary scores.
+            5 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.skip(8)?;
+                }
+            }
+            // Quicklist of listpacks, each with its container type.
+            18 => {
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                }
+            }
+            15 | 19 | 21 => self.stream(value_type)?,
+            6 | 7 => return Err(self.error(offset, "module values are not supported")),
+            // Plain strings and the compact encodings stored as a single blob.
+            _ => {
+                self.string()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Skip a stream, whose metadata grew with each of its three versions.
+    fn stream(&mut self, value_type: u8) -> Result<(), RedisError> {
+        let version = match value_type {
+            15 => 1,
+            19 => 2,
+            _ => 3,
+        };
+
+        // Listpacks keyed by their master entry ID.
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.string()?;
+        }
+
+        // Length and last ID, then the first ID, max deleted ID and entries added.
+        let lengths = if version >= 2 { 8 } else { 3 };
+        for _ in 0..lengths {
+            self.length()?;
+        }
+
+        for _ in 0..self.length()? {
+            // Group name, last delivered ID and, since version 2, entries read.
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if version >= 2 {
+                self.length()?;
+            }
+
+            // Pending entries: an ID, delivery time and delivery count.
+            for _ in 0..self.length()? {
+                self.skip(16 + 8)?;
+                self.length()?;
+            }
+
+            for _ in 0..self.length()? {
+                // Consumer name, seen time, active time since version 3 and pending IDs.
+                self.string()?;
+                self.skip(if version >= 3 { 16 } else { 8 })?;
+
+                let pending = self.length()?;
+                self.skip(pending.saturating_mul(16))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dump::{write_length, write_string};
+
+    fn file(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(RDB_OPCODE_EOF);
+
+        let crc = crc64(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+
+        data
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut body = vec![RDB_OPCODE_AUX];
+        write_string(&mut body, b"redis-ver");
+        write_string(&mut body, b"7.2.0");
+
+        body.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB, 3, 1]);
+
+        body.push(RDB_OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        body.push(0);
+        write_string(&mut body, b"greeting");
+        write_string(&mut body, b"hello");
+
+        body.push(1);
+        write_string(&mut body, b"list");
+        write_length(&mut body, 2);
+        write_string(&mut body, b"a");
+        write_string(&mut body, b"12");
+
+        body.push(5);
+        write_string(&mut body, b"scores");
+        write_length(&mut body, 1);
+        write_string(&mut body, b"member");
+        body.extend_from_slice(&1.5f64.to_le_bytes());
+
+        body.extend_from_slice(&[RDB_OPCODE_SELECTDB, 2, 16]);
+        write_string(&mut body, b"fields");
+        write_string(&mut body, b"listpack bytes");
+
+        body
+    }
+
+    #[test]
+    fn test_check() {
+        let mut report = Report::default();
+        check(&file(&sample()), &mut report).unwrap();
+
+        assert_eq!(report.version, 11);
+        assert_eq!(report.checksum, Checksum::Verified);
+        assert_eq!(
+            report.aux,
+            vec![("redis-ver".to_string(), "7.2.0".to_string())]
+        );
+
+        let db0 = &report.databases[&0];
+        assert_eq!(db0.keys(), 3);
+        assert_eq!(db0.expires, 1);
+        assert_eq!(
+            db0.types.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+            vec![("list", 1), ("string", 1), ("zset", 1)]
+        );
+        assert_eq!(report.databases[&2].types["hash"], 1);
+
+        // A zero checksum means it wasn't computed.
+        let mut data = file(&sample());
+        let len = data.len();
+        data[len - 8..].fill(0);
+        let mut report = Report::default();
+        check(&data, &mut report).unwrap();
+        assert_eq!(report.checksum, Checksum::Disabled);
+    }
+
+    #[test]
+    fn test_check_errors() {
+        let check = |data: &[u8]| check(data, &mut Report::default()).unwrap_err().to_string();
+
+        assert_eq!(check(b"RDB0011"), "invalid or truncated header at offset 0");
+        assert_eq!(
+            check(b"REDIS0099\xff"),
+            "unsupported version 99 at offset 5"
+        );
+
+        let mut data = file(&sample());
+        let offset = data.len() - 8;
+        data[12] ^= 1;
+        assert_eq!(
+            check(&data),
+            format!("checksum mismatch at offset {offset}")
+        );
+
+        // Cut in the middle of the value of the last key.
+        let data = file(&sample());
+        let body = sample();
+        let truncated = &data[..9 + body.len() - 4];
+        assert_eq!(
+            check(truncated),
+            format!(
+                "invalid or truncated string at offset {}",
+                9 + body.len() - 15
+            )
+        );
+
+        assert_eq!(check(&file(&[42])), "unknown type 42 at offset 9");
+
+        let mut data = file(&[]);
+        data.push(0);
+        assert_eq!(
+            check(&data),
+            "trailing data after the end of file at offset 18"
+        );
+    }
+}
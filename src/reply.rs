@@ -0,0 +1,264 @@
+use std::{
+    io::{self, IoSlice, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Writes at least this large skip the buffer. They're sent together with whatever is buffered in
+/// a single vectored write so large values aren't copied.
+const DIRECT_WRITE_SIZE: usize = 16 * 1024;
+
+/// Replies are flushed early once this much is buffered, to bound the memory used by a connection
+/// sending a huge pipeline.
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// Max number of large buffers kept for reuse.
+const MAX_POOLED: usize = 16;
+
+/// Reply buffers that grew past the configured size, shared between connections. A connection
+/// hands its buffer back once flushed and keeps a small one, so a burst of big pipelines doesn't
+/// pin memory in every connection, while connections sending them keep reusing the same few
+/// allocations.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    /// The size of the buffer each connection keeps to itself.
+    size: usize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            size,
+        }
+    }
+
+    fn take(&self) -> Option<Vec<u8>> {
+        self.buffers.lock().unwrap().pop()
+    }
+
+    fn give(&self, mut buf: Vec<u8>) {
+        buf.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+        }
+    }
+}
+
+/// Buffers the replies to a batch of pipelined commands so they're sent with as few syscalls as
+/// possible, normally a single one when the batch is flushed. Every syscall is counted in `writes`.
+pub(crate) struct ReplyWriter<'a, W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    writes: &'a AtomicU64,
+    pool: &'a BufferPool,
+}
+
+impl<'a, W: Write> ReplyWriter<'a, W> {
+    pub(crate) fn new(inner: W, writes: &'a AtomicU64, pool: &'a BufferPool) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(pool.size),
+            writes,
+            pool,
+        }
+    }
+
+    /// Make room for `additional` bytes, switching to a large buffer from the pool if the one of
+    /// the connection is too small.
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.buf.len() + additional;
+        if needed <= self.buf.capacity() || self.buf.capacity() > self.pool.size {
+            return;
+        }
+
+        if let Some(mut large) = self.pool.take() {
+            large.extend_from_slice(&self.buf);
+            self.buf = large;
+        }
+    }
+
+    /// Write the buffer followed by `data`, retrying until both are fully written.
+    fn write_with_buffer(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let mut buffered = 0;
+
+        while buffered < self.buf.len() || !data.is_empty() {
+            let slices = [IoSlice::new(&self.buf[buffered..]), IoSlice::new(data)];
+            self.writes.fetch_add(1, Ordering::Relaxed);
+
+            let mut written = match self.inner.write_vectored(&slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => written,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            let from_buffer = written.min(self.buf.len() - buffered);
+            buffered += from_buffer;
+            written -= from_buffer;
+            data = &data[written..];
+        }
+
+        if self.buf.capacity() > self.pool.size {
+            let large = std::mem::replace(&mut self.buf, Vec::with_capacity(self.pool.size));
+            self.pool.give(large);
+        } else {
+            self.buf.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ReplyWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() >= DIRECT_WRITE_SIZE {
+            self.write_with_buffer(data)?;
+            return Ok(data.len());
+        }
+
+        self.reserve(data.len());
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= MAX_BUFFERED {
+            self.write_with_buffer(&[])?;
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write_with_buffer(&[])?;
+        }
+
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::resp_type::{Protocol, RespType};
+
+    /// Records each write and accepts at most `limit` bytes per call, like a full socket buffer.
+    struct Recorder {
+        writes: Vec<Vec<u8>>,
+        limit: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let n = data.len().min(self.limit);
+            self.writes.push(data[..n].to_vec());
+            Ok(n)
+        }
+
+        fn write_vectored(&mut self, slices: &[IoSlice<'_>]) -> io::Result<usize> {
+            let data = slices.iter().flat_map(|slice| slice.iter().copied());
+            let data = data.take(self.limit).collect::<Vec<_>>();
+            let n = data.len();
+            self.writes.push(data);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_is_one_write() {
+        let writes = AtomicU64::new(0);
+        let pool = BufferPool::new(1024);
+        let mut recorder = Recorder {
+            writes: Vec::new(),
+            limit: usize::MAX,
+        };
+        let mut expected = Vec::new();
+
+        let mut writer = ReplyWriter::new(&mut recorder, &writes, &pool);
+        for i in 0..100 {
+            let reply = RespType::array(vec![i.into(), format!("value:{i}").into()]);
+            reply.encode(&mut writer, Protocol::Resp2).unwrap();
+            expected.extend(reply.to_bytes(Protocol::Resp2));
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(recorder.writes, vec![expected]);
+    }
+
+    #[test]
+    fn test_large_values_are_written_with_the_buffer() {
+        let writes = AtomicU64::new(0);
+        let pool = BufferPool::new(1024);
+        let mut recorder = Recorder {
+            writes: Vec::new(),
+            limit: usize::MAX,
+        };
+
+        let value = "x".repeat(DIRECT_WRITE_SIZE);
+        let replies = [RespType::ok(), RespType::from(value.as_str()), 1.into()];
+
+        let mut writer = ReplyWriter::new(&mut recorder, &writes, &pool);
+        for reply in &replies {
+            reply.encode(&mut writer, Protocol::Resp2).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // The value is written along with the buffered OK and bulk string header, the trailing CRLF
+        // and integer reply are flushed at the end.
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+        assert_eq!(recorder.writes[0].len(), 5 + 8 + DIRECT_WRITE_SIZE);
+        assert_eq!(recorder.writes[1], b"\r\n:1\r\n");
+
+        // Short writes are continued where they left off.
+        let mut short = Recorder {
+            writes: Vec::new(),
+            limit: 1000,
+        };
+        let mut writer = ReplyWriter::new(&mut short, &writes, &pool);
+        for reply in &replies {
+            reply.encode(&mut writer, Protocol::Resp2).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(short.writes.concat(), recorder.writes.concat());
+    }
+
+    #[test]
+    fn test_large_buffers_are_pooled() {
+        let writes = AtomicU64::new(0);
+        let pool = BufferPool::new(1024);
+        let mut sink = Vec::new();
+
+        let mut writer = ReplyWriter::new(&mut sink, &writes, &pool);
+        let reply = RespType::from("x".repeat(100).as_str());
+        for _ in 0..100 {
+            reply.encode(&mut writer, Protocol::Resp2).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // The connection is back to a small buffer and the large one is kept for reuse.
+        assert_eq!(writer.buf.capacity(), 1024);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+        let large = pool.buffers.lock().unwrap()[0].as_ptr();
+
+        reply.encode(&mut writer, Protocol::Resp2).unwrap();
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+        for _ in 0..100 {
+            reply.encode(&mut writer, Protocol::Resp2).unwrap();
+        }
+        assert_eq!(writer.buf.as_ptr(), large);
+        assert!(pool.buffers.lock().unwrap().is_empty());
+
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(sink.len(), 201 * reply.to_bytes(Protocol::Resp2).len());
+    }
+}
@@ -1,87 +1,1457 @@
-use std::{
-    collections::{HashMap, HashSet},
-    io::{BufRead, BufReader},
-    net::TcpStream,
-};
+use crate::{client::split_args, error::RedisError};
+use bytes::{Buf, Bytes, BytesMut};
+use std::io::{BufRead, Read, Write};
+
+/// The protocol version a connection negotiated with HELLO. Replies are serialized according to it
+/// so RESP3 types fall back to their RESP2 equivalents for older clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
 
 // https://redis.io/docs/reference/protocol-spec/#resp-protocol-description
 #[allow(dead_code)] // TODO: We might actually need them...
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RespType {
-    SimpleString(String),                    // + (data)
-    SimpleError(String),                     // - (data)
-    Integer(i64),                            // : (data)
-    BulkString(usize, String),               // $ (length, data)
-    Array(Vec<RespType>),                    // * (data)
-    Null,                                    // _ (empty)
-    Boolean(bool),                           // # (data)
-    Double(f64),                             // , (data)
-    BigNumber(f64),                          // ( (data)
-    BulkError(usize, String),                // ! (length, data)
-    VerbatimString(usize, String, String),   // = (length, encoding, data)
-    Map(usize, HashMap<RespType, RespType>), // % (length, data)
-    Set(usize, HashSet<RespType>),           // ~ (length, data)
-    Push(usize),                             // > TODO
+    SimpleString(String),                                // + (data)
+    SimpleError(String),                                 // - (data)
+    Integer(i64),                                        // : (data)
+    BulkString(usize, Bytes),                            // $ (length, data)
+    Array(Vec<RespType>),                                // * (data)
+    Null,                                                // _ (empty)
+    Boolean(bool),                                       // # (data)
+    Double(f64),                                         // , (data)
+    BigNumber(String),                                   // ( (data)
+    BulkError(usize, String),                            // ! (length, data)
+    VerbatimString(usize, String, String),               // = (length, encoding, data)
+    Map(usize, Vec<(RespType, RespType)>),               // % (length, data)
+    Set(usize, Vec<RespType>),                           // ~ (length, data)
+    Push(usize, Vec<RespType>),                          // > (length, data)
+    Attribute(Vec<(RespType, RespType)>, Box<RespType>), // | (attributes, data)
 }
 
 impl RespType {
-    pub fn parse(reader: &mut BufReader<TcpStream>) -> Result<Self, std::io::Error> {
-        let mut command = String::new();
-        reader.read_line(&mut command)?;
-
-        match command.chars().next() {
-            Some('$') => Self::parse_bulk_string(&command, reader),
-            Some('*') => Self::parse_array(&command, reader),
-            Some(c) => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("resp type '{c:?}' not implemented"),
-            )),
-            None => Err(std::io::Error::new(
+    /// Numerically compare two normalized big numbers.
+    fn cmp_big_numbers(a: &str, b: &str) -> std::cmp::Ordering {
+        match (a.strip_prefix('-'), b.strip_prefix('-')) {
+            (Some(a), Some(b)) => (b.len(), b).cmp(&(a.len(), a)),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => (a.len(), a).cmp(&(b.len(), b)),
+        }
+    }
+
+    /// The position of the variant, used to order values of different types.
+    fn discriminant(&self) -> u8 {
+        match self {
+            Self::SimpleString(_) => 0,
+            Self::SimpleError(_) => 1,
+            Self::Integer(_) => 2,
+            Self::BulkString(..) => 3,
+            Self::Array(_) => 4,
+            Self::Null => 5,
+            Self::Boolean(_) => 6,
+            Self::Double(_) => 7,
+            Self::BigNumber(_) => 8,
+            Self::BulkError(..) => 9,
+            Self::VerbatimString(..) => 10,
+            Self::Map(..) => 11,
+            Self::Set(..) => 12,
+            Self::Push(..) => 13,
+            Self::Attribute(..) => 14,
+        }
+    }
+}
+
+// Floats are compared and hashed by their bit pattern (`f64::total_cmp`) so every value, including
+// NaN, can be used as a map key or set member.
+impl Ord for RespType {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self, other) {
+            (Self::SimpleString(a), Self::SimpleString(b)) => a.cmp(b),
+            (Self::SimpleError(a), Self::SimpleError(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::BulkString(_, a), Self::BulkString(_, b)) => a.cmp(b),
+            (Self::Array(a), Self::Array(b)) => a.cmp(b),
+            (Self::Boolean(a), Self::Boolean(b)) => a.cmp(b),
+            (Self::Double(a), Self::Double(b)) => a.total_cmp(b),
+            (Self::BigNumber(a), Self::BigNumber(b)) => Self::cmp_big_numbers(a, b),
+            (Self::BulkError(_, a), Self::BulkError(_, b)) => a.cmp(b),
+            (Self::VerbatimString(_, a, b), Self::VerbatimString(_, c, d)) => (a, b).cmp(&(c, d)),
+            (Self::Map(_, a), Self::Map(_, b)) => a.cmp(b),
+            (Self::Set(_, a), Self::Set(_, b)) => a.cmp(b),
+            (Self::Push(_, a), Self::Push(_, b)) => a.cmp(b),
+            (Self::Attribute(a, b), Self::Attribute(c, d)) => (a, b).cmp(&(c, d)),
+            _ => self.discriminant().cmp(&other.discriminant()),
+        }
+    }
+}
+
+impl PartialOrd for RespType {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RespType {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for RespType {}
+
+impl std::hash::Hash for RespType {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.discriminant().hash(state);
+
+        match self {
+            Self::SimpleString(v)
+            | Self::SimpleError(v)
+            | Self::BulkError(_, v)
+            | Self::BigNumber(v) => v.hash(state),
+            Self::Integer(v) => v.hash(state),
+            Self::BulkString(_, v) => v.hash(state),
+            Self::Array(v) | Self::Set(_, v) | Self::Push(_, v) => v.hash(state),
+            Self::Null => (),
+            Self::Boolean(v) => v.hash(state),
+            Self::Double(v) => v.to_bits().hash(state),
+            Self::VerbatimString(_, encoding, v) => (encoding, v).hash(state),
+            Self::Map(_, v) => v.hash(state),
+            Self::Attribute(attributes, v) => (attributes, v).hash(state),
+        }
+    }
+}
+
+// Helpers to build replies.
+impl RespType {
+    pub fn ok() -> Self {
+        Self::SimpleString("OK".to_string())
+    }
+
+    pub fn simple(value: impl Into<String>) -> Self {
+        Self::SimpleString(value.into())
+    }
+
+    /// An error reply where `code` is the error prefix clients match on, e.g. `ERR` or `WRONGTYPE`.
+    pub fn error(code: &str, msg: impl std::fmt::Display) -> Self {
+        Self::SimpleError(format!("{code} {msg}"))
+    }
+
+    /// The null reply. How it's written depends on the protocol the client negotiated.
+    pub fn null() -> Self {
+        Self::Null
+    }
+
+    pub fn bulk(value: impl Into<Bytes>) -> Self {
+        let value = value.into();
+        Self::BulkString(value.len(), value)
+    }
+
+    /// A big number from its decimal representation, normalized so equal numbers have the same
+    /// representation. Returns `None` if the value isn't an integer.
+    pub fn big_number(value: &str) -> Option<Self> {
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let digits = match digits.trim_start_matches('0') {
+            "" => return Some(Self::BigNumber("0".to_string())),
+            digits => digits,
+        };
+
+        let sign = if negative { "-" } else { "" };
+        Some(Self::BigNumber(format!("{sign}{digits}")))
+    }
+
+    pub fn array(values: Vec<RespType>) -> Self {
+        Self::Array(values)
+    }
+
+    pub fn map(values: Vec<(RespType, RespType)>) -> Self {
+        Self::Map(values.len(), values)
+    }
+
+    /// Out-of-band data like pub/sub messages, which RESP2 clients receive as an array.
+    pub fn push(values: Vec<RespType>) -> Self {
+        Self::Push(values.len(), values)
+    }
+
+    pub fn verbatim(encoding: &str, value: impl Into<String>) -> Self {
+        let value = value.into();
+        Self::VerbatimString(value.len(), encoding.to_string(), value)
+    }
+}
+
+impl From<i64> for RespType {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<&str> for RespType {
+    fn from(value: &str) -> Self {
+        Self::bulk(Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+impl From<String> for RespType {
+    fn from(value: String) -> Self {
+        Self::bulk(value)
+    }
+}
+
+impl<T: Into<RespType>> From<Option<T>> for RespType {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Null, Into::into)
+    }
+}
+
+/// Caps enforced while parsing so a malicious size header can't make us allocate huge buffers or
+/// recurse forever. Exceeding any of them is a fatal protocol error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Max size of a single bulk string, `proto-max-bulk-len` in Redis.
+    pub max_bulk_len: usize,
+    /// Max number of elements in an array.
+    pub max_multibulk_len: usize,
+    /// Max nesting of aggregate types.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+            max_depth: 32,
+        }
+    }
+}
+
+/// Max length of a type line, i.e. everything but bulk string data.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Max number of bytes read from a reader into a [`RespParser`] at a time. The space is zeroed
+/// before every read so it's kept around what a socket typically returns.
+const MAX_READ_SIZE: usize = 64 * 1024;
+
+/// Default min number of bytes read into a [`RespParser`] at a time, unless a frame needs less.
+pub const DEFAULT_READ_SIZE: usize = 4096;
+
+/// Max number of elements to pre-allocate room for, the rest is allocated as they're parsed.
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+
+/// The first byte of every RESP type. A request starting with anything else is an inline command.
+const TYPE_BYTES: &[u8] = b"+-:(_#,$!=*%~>|";
+
+impl RespType {
+    /// Parse the next frame from the reader. An empty reader results in a `ConnectionReset` error
+    /// and a frame that ends prematurely in `RedisError::Incomplete`.
+    pub fn parse(reader: &mut impl BufRead) -> Result<Self, RedisError> {
+        Self::parse_with_limits(reader, &Limits::default())
+    }
+
+    pub fn parse_with_limits(
+        reader: &mut impl BufRead,
+        limits: &Limits,
+    ) -> Result<Self, RedisError> {
+        let command = Self::read_line(reader)?;
+
+        if command.is_empty() {
+            return Err(RedisError::Io(std::io::Error::new(
                 std::io::ErrorKind::ConnectionReset,
                 "empty command",
-            )),
+            )));
         }
+
+        Self::parse_frame(&command, reader, limits, 0)
     }
 
-    fn parse_size(command: &str) -> Result<usize, std::io::Error> {
-        command
-            .trim_end()
-            .chars()
-            .skip(1)
-            .collect::<String>()
+    fn read_line(reader: &mut impl BufRead) -> Result<String, RedisError> {
+        let mut command = String::new();
+        let read = reader
+            .by_ref()
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut command)?;
+
+        if read == MAX_LINE_LENGTH && !command.ends_with('\n') {
+            return Err(RedisError::FatalProtocol("too big line".to_string()));
+        }
+
+        Ok(command)
+    }
+
+    fn parse_frame(
+        command: &str,
+        reader: &mut impl BufRead,
+        limits: &Limits,
+        depth: usize,
+    ) -> Result<Self, RedisError> {
+        let Some(c) = command.chars().next().filter(|_| command.ends_with('\n')) else {
+            return Err(RedisError::Incomplete);
+        };
+
+        match c {
+            '+' => Ok(Self::SimpleString(Self::line_data(command).to_string())),
+            '-' => Ok(Self::SimpleError(Self::line_data(command).to_string())),
+            ':' => Self::parse_integer(command),
+            '(' => Self::parse_big_number(command),
+            '_' => Ok(Self::Null),
+            '#' => Self::parse_boolean(command),
+            ',' => Self::parse_double(command),
+            '$' | '!' | '=' => Self::parse_bulk_string(command, reader, limits),
+            '*' | '%' | '~' | '>' => Self::parse_aggregate(command, reader, limits, depth),
+            '|' => {
+                let attributes = Self::parse_aggregate(command, reader, limits, depth)?;
+                let command = Self::read_line(reader)?;
+                let value = Self::parse_frame(&command, reader, limits, depth)?;
+
+                Ok(Self::with_attributes(attributes, value))
+            }
+            c => Err(RedisError::Protocol(format!(
+                "resp type {c:?} not implemented"
+            ))),
+        }
+    }
+
+    /// The data following the type byte with the trailing CRLF removed.
+    fn line_data(command: &str) -> &str {
+        command[1..].trim_end_matches(['\r', '\n'])
+    }
+
+    /// Parse the size of a bulk string or array where `-1` denotes the RESP2 null value. Sizes above
+    /// `max` are fatal since the data they announce would otherwise be parsed as new frames.
+    fn parse_size(command: &str, max: usize) -> Result<Option<usize>, RedisError> {
+        let data = Self::line_data(command);
+        if data == "-1" {
+            return Ok(None);
+        }
+
+        let size = data
             .parse::<usize>()
-            .map_err(|err| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("failed to parse size: {err}"),
-                )
-            })
+            .map_err(|err| RedisError::Protocol(format!("failed to parse size: {err}")))?;
+
+        if size > max {
+            return Err(RedisError::FatalProtocol(format!(
+                "size {size} exceeds the limit of {max}"
+            )));
+        }
+
+        Ok(Some(size))
+    }
+
+    fn parse_big_number(command: &str) -> Result<Self, RedisError> {
+        Self::big_number(Self::line_data(command))
+            .ok_or_else(|| RedisError::Protocol("failed to parse big number".to_string()))
+    }
+
+    fn parse_integer(command: &str) -> Result<Self, RedisError> {
+        Self::line_data(command)
+            .parse::<i64>()
+            .map(Self::Integer)
+            .map_err(|err| RedisError::Protocol(format!("failed to parse integer: {err}")))
     }
 
     fn parse_bulk_string(
         command: &str,
-        reader: &mut BufReader<TcpStream>,
-    ) -> Result<Self, std::io::Error> {
-        let size = Self::parse_size(command)?;
+        reader: &mut impl BufRead,
+        limits: &Limits,
+    ) -> Result<Self, RedisError> {
+        let Some(size) = Self::parse_size(command, limits.max_bulk_len)? else {
+            return Ok(Self::Null);
+        };
+
+        // Read the declared number of bytes plus the trailing CRLF so the data may contain anything,
+        // including CRLF itself. The buffer grows as data is read rather than trusting the size.
+        let mut bulk_string = Vec::new();
+        reader
+            .by_ref()
+            .take((size as u64).saturating_add(2))
+            .read_to_end(&mut bulk_string)?;
+
+        if bulk_string.len() < size.saturating_add(2) {
+            return Err(RedisError::Incomplete);
+        }
+
+        if !bulk_string.ends_with(b"\r\n") {
+            return Err(RedisError::FatalProtocol(
+                "bulk string not terminated by CRLF".to_string(),
+            ));
+        }
+
+        bulk_string.truncate(size);
+
+        Self::bulk_frame(command, Bytes::from(bulk_string))
+    }
+
+    /// Build the frame for a length prefixed type, i.e. a bulk string, bulk error or verbatim
+    /// string, once its data has been read.
+    fn bulk_frame(command: &str, data: Bytes) -> Result<Self, RedisError> {
+        if command.starts_with('$') {
+            return Ok(Self::BulkString(data.len(), data));
+        }
+
+        let data = String::from_utf8(data.to_vec())
+            .map_err(|err| RedisError::Protocol(format!("invalid frame: {err}")))?;
+
+        if command.starts_with('!') {
+            return Ok(Self::BulkError(data.len(), data));
+        }
 
-        // TODO: Should we just read size and skip \r\n?
-        let mut bulk_string = String::new();
-        reader.read_line(&mut bulk_string)?;
+        // Verbatim strings are prefixed by a three byte encoding and a colon.
+        match data.split_once(':') {
+            Some((encoding, value)) if encoding.len() == 3 => Ok(Self::verbatim(encoding, value)),
+            _ => Err(RedisError::Protocol(
+                "verbatim string missing encoding".to_string(),
+            )),
+        }
+    }
 
-        Ok(Self::BulkString(size, bulk_string))
+    fn parse_boolean(command: &str) -> Result<Self, RedisError> {
+        match Self::line_data(command) {
+            "t" => Ok(Self::Boolean(true)),
+            "f" => Ok(Self::Boolean(false)),
+            data => Err(RedisError::Protocol(format!(
+                "failed to parse boolean: {data:?}"
+            ))),
+        }
     }
 
-    fn parse_array(
+    fn parse_double(command: &str) -> Result<Self, RedisError> {
+        // Rust parses `inf`, `-inf` and `nan` the same way Redis writes them.
+        Self::line_data(command)
+            .parse::<f64>()
+            .map(Self::Double)
+            .map_err(|err| RedisError::Protocol(format!("failed to parse double: {err}")))
+    }
+
+    /// Parse an array, map or set. The element count is validated before anything is read.
+    fn parse_aggregate(
+        command: &str,
+        reader: &mut impl BufRead,
+        limits: &Limits,
+        depth: usize,
+    ) -> Result<Self, RedisError> {
+        let Some(count) = Self::aggregate_len(command, limits, depth)? else {
+            return Ok(Self::Null);
+        };
+
+        let mut values = Vec::with_capacity(count.min(MAX_PREALLOCATED_ELEMENTS));
+
+        for _ in 0..count {
+            let command = Self::read_line(reader)?;
+            values.push(Self::parse_frame(&command, reader, limits, depth + 1)?);
+        }
+
+        Ok(Self::build_aggregate(command, values))
+    }
+
+    /// The number of elements to read for an aggregate type, where a map of size N consists of N
+    /// keys and N values.
+    fn aggregate_len(
         command: &str,
-        reader: &mut BufReader<TcpStream>,
-    ) -> Result<Self, std::io::Error> {
-        let size = Self::parse_size(command)?;
-        let mut values = Vec::with_capacity(size);
+        limits: &Limits,
+        depth: usize,
+    ) -> Result<Option<usize>, RedisError> {
+        let Some(size) = Self::parse_size(command, limits.max_multibulk_len)? else {
+            return Ok(None);
+        };
+
+        if depth >= limits.max_depth {
+            return Err(RedisError::FatalProtocol(
+                "too deeply nested frame".to_string(),
+            ));
+        }
+
+        if command.starts_with(['%', '|']) {
+            size.checked_mul(2)
+                .map(Some)
+                .ok_or_else(|| RedisError::FatalProtocol("too big map".to_string()))
+        } else {
+            Ok(Some(size))
+        }
+    }
+
+    fn build_aggregate(command: &str, values: Vec<RespType>) -> Self {
+        match command.as_bytes()[0] {
+            b'%' | b'|' => {
+                let mut values = values.into_iter();
+                let mut pairs = Vec::with_capacity(values.len() / 2);
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    pairs.push((key, value));
+                }
+
+                Self::Map(pairs.len(), pairs)
+            }
+            b'~' => Self::Set(values.len(), values),
+            b'>' => Self::Push(values.len(), values),
+            _ => Self::Array(values),
+        }
+    }
+
+    /// Attach parsed attributes to the value following them. The attributes are parsed as a map.
+    fn with_attributes(attributes: Self, value: Self) -> Self {
+        match attributes {
+            Self::Map(_, attributes) => Self::Attribute(attributes, Box::new(value)),
+            _ => value,
+        }
+    }
+
+    /// Decode a complete frame starting at `pos` of `buf`, advancing `pos` past it. Bulk strings
+    /// share memory with `buf`.
+    pub(crate) fn decode_from(
+        buf: &Bytes,
+        pos: &mut usize,
+        limits: &Limits,
+    ) -> Result<Self, RedisError> {
+        Self::decode(buf, pos, Some(buf), limits, 0)
+    }
+
+    /// Decode a frame starting at `pos` of an in-memory buffer, advancing `pos` past it. Bulk
+    /// strings are sliced out of `source` without copying when given, otherwise they're left empty
+    /// which is enough to check if a complete frame is buffered.
+    fn decode(
+        buf: &[u8],
+        pos: &mut usize,
+        source: Option<&Bytes>,
+        limits: &Limits,
+        depth: usize,
+    ) -> Result<Self, RedisError> {
+        let command = loop {
+            let Some(end) = buf[*pos..].iter().position(|b| *b == b'\n') else {
+                if buf.len() - *pos >= MAX_LINE_LENGTH {
+                    return Err(RedisError::FatalProtocol("too big line".to_string()));
+                }
+
+                return Err(RedisError::Incomplete);
+            };
+
+            let line = &buf[*pos..*pos + end + 1];
+            *pos += end + 1;
+
+            let command = std::str::from_utf8(line)
+                .map_err(|err| RedisError::Protocol(format!("invalid frame: {err}")))?;
+
+            // Empty lines between inline commands are skipped like Redis does.
+            if depth > 0 || !command.trim().is_empty() {
+                break command;
+            }
+        };
+
+        match command.as_bytes()[0] {
+            b'$' | b'!' | b'=' => {
+                let Some(size) = Self::parse_size(command, limits.max_bulk_len)? else {
+                    return Ok(Self::Null);
+                };
+
+                if buf.len() - *pos < size.saturating_add(2) {
+                    // Point past the end of the bulk string so the caller knows how much more it
+                    // needs to buffer.
+                    *pos = pos.saturating_add(size).saturating_add(2);
+                    return Err(RedisError::Incomplete);
+                }
 
-        for _ in 0..size {
-            values.push(Self::parse(reader)?);
+                if &buf[*pos + size..*pos + size + 2] != b"\r\n" {
+                    return Err(RedisError::FatalProtocol(
+                        "bulk string not terminated by CRLF".to_string(),
+                    ));
+                }
+
+                let Some(source) = source else {
+                    *pos += size + 2;
+                    return Ok(Self::Null);
+                };
+
+                let data = source.slice(*pos..*pos + size);
+                *pos += size + 2;
+
+                Self::bulk_frame(command, data)
+            }
+            b'*' | b'%' | b'~' | b'>' | b'|' => {
+                let Some(count) = Self::aggregate_len(command, limits, depth)? else {
+                    return Ok(Self::Null);
+                };
+
+                let mut values = Vec::with_capacity(count.min(MAX_PREALLOCATED_ELEMENTS));
+
+                for _ in 0..count {
+                    values.push(Self::decode(buf, pos, source, limits, depth + 1)?);
+                }
+
+                let aggregate = Self::build_aggregate(command, values);
+                if command.starts_with('|') {
+                    let value = Self::decode(buf, pos, source, limits, depth)?;
+                    return Ok(Self::with_attributes(aggregate, value));
+                }
+
+                Ok(aggregate)
+            }
+            // Commands typed by hand, e.g. over telnet, come as a line of space separated arguments.
+            first if depth == 0 && !TYPE_BYTES.contains(&first) => Ok(Self::array(
+                split_args(command)
+                    .into_iter()
+                    .map(|arg| Self::bulk(arg.into_bytes()))
+                    .collect(),
+            )),
+            // All other types are contained in a single line.
+            _ => Self::parse_frame(command, &mut std::io::empty(), limits, depth),
         }
+    }
+
+    /// Serialize the type into its wire format for the given protocol version. RESP3 only types
+    /// are written as their closest RESP2 equivalent when the client speaks RESP2.
+    pub fn encode(
+        &self,
+        writer: &mut impl Write,
+        protocol: Protocol,
+    ) -> Result<(), std::io::Error> {
+        match (self, protocol) {
+            (Self::SimpleString(data), _) => write!(writer, "+{data}\r\n"),
+            (Self::SimpleError(data), _) => write!(writer, "-{data}\r\n"),
+            (Self::Integer(data), _) => write!(writer, ":{data}\r\n"),
+            (Self::BulkString(_, data), _) => Self::encode_bulk(writer, b'$', data),
+            (Self::Array(values), _) => Self::encode_aggregate(writer, b'*', values, protocol),
+            (Self::Null, Protocol::Resp2) => write!(writer, "$-1\r\n"),
+            (Self::Null, Protocol::Resp3) => write!(writer, "_\r\n"),
+            (Self::Boolean(data), Protocol::Resp2) => write!(writer, ":{}\r\n", u8::from(*data)),
+            (Self::Boolean(data), Protocol::Resp3) => {
+                write!(writer, "#{}\r\n", if *data { 't' } else { 'f' })
+            }
+            (Self::Double(data), Protocol::Resp2) => {
+                Self::encode_bulk(writer, b'$', Self::format_double(*data).as_bytes())
+            }
+            (Self::Double(data), Protocol::Resp3) => {
+                write!(writer, ",{}\r\n", Self::format_double(*data))
+            }
+            (Self::BigNumber(data), Protocol::Resp2) => {
+                Self::encode_bulk(writer, b'$', data.as_bytes())
+            }
+            (Self::BigNumber(data), Protocol::Resp3) => write!(writer, "({data}\r\n"),
+            (Self::BulkError(_, data), Protocol::Resp2) => write!(writer, "-{data}\r\n"),
+            (Self::BulkError(_, data), Protocol::Resp3) => {
+                Self::encode_bulk(writer, b'!', data.as_bytes())
+            }
+            (Self::VerbatimString(_, _, data), Protocol::Resp2) => {
+                Self::encode_bulk(writer, b'$', data.as_bytes())
+            }
+            (Self::VerbatimString(_, encoding, data), Protocol::Resp3) => {
+                // Written in parts so the data isn't copied into a prefixed string first.
+                write!(
+                    writer,
+                    "={}\r\n{encoding}:",
+                    encoding.len() + 1 + data.len()
+                )?;
+                writer.write_all(data.as_bytes())?;
+                writer.write_all(b"\r\n")
+            }
+            (Self::Map(_, values), _) => {
+                match protocol {
+                    Protocol::Resp2 => write!(writer, "*{}\r\n", values.len() * 2)?,
+                    Protocol::Resp3 => write!(writer, "%{}\r\n", values.len())?,
+                }
+
+                values.iter().try_for_each(|(key, value)| {
+                    key.encode(writer, protocol)?;
+                    value.encode(writer, protocol)
+                })
+            }
+            (Self::Set(_, values), Protocol::Resp2) => {
+                Self::encode_aggregate(writer, b'*', values, protocol)
+            }
+            (Self::Set(_, values), Protocol::Resp3) => {
+                Self::encode_aggregate(writer, b'~', values, protocol)
+            }
+            (Self::Push(_, values), Protocol::Resp2) => {
+                Self::encode_aggregate(writer, b'*', values, protocol)
+            }
+            (Self::Push(_, values), Protocol::Resp3) => {
+                Self::encode_aggregate(writer, b'>', values, protocol)
+            }
+            // Attributes are out-of-band data that RESP2 clients can't receive.
+            (Self::Attribute(_, value), Protocol::Resp2) => value.encode(writer, protocol),
+            (Self::Attribute(attributes, value), Protocol::Resp3) => {
+                write!(writer, "|{}\r\n", attributes.len())?;
+                attributes.iter().try_for_each(|(key, value)| {
+                    key.encode(writer, protocol)?;
+                    value.encode(writer, protocol)
+                })?;
+
+                value.encode(writer, protocol)
+            }
+        }
+    }
+
+    pub fn to_bytes(&self, protocol: Protocol) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // safety: Writing to a Vec can't fail.
+        self.encode(&mut buf, protocol).unwrap();
+
+        buf
+    }
+
+    fn encode_bulk(writer: &mut impl Write, prefix: u8, data: &[u8]) -> Result<(), std::io::Error> {
+        write!(writer, "{}{}\r\n", prefix as char, data.len())?;
+        writer.write_all(data)?;
+        writer.write_all(b"\r\n")
+    }
+
+    fn encode_aggregate(
+        writer: &mut impl Write,
+        prefix: u8,
+        values: &[RespType],
+        protocol: Protocol,
+    ) -> Result<(), std::io::Error> {
+        write!(writer, "{}{}\r\n", prefix as char, values.len())?;
+        values
+            .iter()
+            .try_for_each(|value| value.encode(writer, protocol))
+    }
+
+    fn format_double(value: f64) -> String {
+        if value.is_nan() {
+            "nan".to_string()
+        } else if value.is_infinite() {
+            if value.is_sign_positive() {
+                "inf"
+            } else {
+                "-inf"
+            }
+            .to_string()
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+/// An incremental parser that buffers received bytes until they form complete frames, allowing
+/// frames split across reads to be handled without blocking on the socket. Bulk strings in the
+/// returned frames share memory with the receive buffer.
+#[derive(Debug)]
+pub struct RespParser {
+    buf: BytesMut,
+    limits: Limits,
+    /// Bytes known to be missing from the frame being received, used to make room for a large bulk
+    /// string at once instead of growing the buffer as it arrives.
+    missing: usize,
+    /// Min number of bytes read at a time.
+    read_size: usize,
+    /// Whether room was reserved for more than a read, which the buffer keeps until released by
+    /// [`RespParser::shrink`].
+    grown: bool,
+    /// Bytes taken off the buffer by frames and discarded protocol errors.
+    consumed: u64,
+}
+
+impl Default for RespParser {
+    fn default() -> Self {
+        Self::with_limits(Limits::default())
+    }
+}
+
+impl RespParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            limits,
+            missing: 0,
+            read_size: DEFAULT_READ_SIZE,
+            grown: false,
+            consumed: 0,
+        }
+    }
+
+    /// Read at least `bytes` at a time in [`RespParser::read_from`], 4 KB by default.
+    pub fn with_read_size(mut self, bytes: usize) -> Self {
+        self.read_size = bytes.max(1);
+        self
+    }
+
+    /// Release the buffer if it's empty and has grown to fit a large bulk string. Bulk strings
+    /// share the buffer's memory, so a connection that once received a huge value would otherwise
+    /// keep all of it for as long as it's open.
+    pub fn shrink(&mut self) {
+        if self.grown && self.buf.is_empty() {
+            self.buf = BytesMut::new();
+            self.grown = false;
+        }
+    }
+
+    /// Append received bytes to the internal buffer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Read from `reader` straight into the internal buffer, returning the number of bytes read
+    /// like [`Read::read`]. Room for the rest of a partially received bulk string is reserved up
+    /// front, so even a value of hundreds of megabytes is received into its final place without
+    /// being copied as the buffer grows. Like Redis the room is reserved as soon as the size is
+    /// read, which the bulk string limit caps.
+    pub fn read_from(&mut self, reader: &mut impl Read) -> std::io::Result<usize> {
+        self.grown |= self.missing > self.read_size;
+        self.buf.reserve(self.missing);
+
+        let len = self.buf.len();
+        let chunk = self
+            .missing
+            .clamp(self.read_size, MAX_READ_SIZE.max(self.read_size));
+        self.buf.resize(len + chunk, 0);
+
+        let read = reader.read(&mut self.buf[len..]);
+        self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
+
+        read
+    }
+
+    /// Whether no bytes are buffered, i.e. nothing has been received of the next frame.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The number of bytes the frames returned so far were received in, including any discarded
+    /// by protocol errors. Frames don't always encode back to the bytes they were parsed from,
+    /// like inline commands, so this is what a replica's offset has to be based on.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Returns `Ok(None)` if the buffer doesn't hold a complete frame yet. Bytes consumed by a
+    /// recoverable protocol error are discarded so the next call continues with the next frame.
+    pub fn next_frame(&mut self) -> Result<Option<RespType>, RedisError> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        // The first pass only checks that a complete frame is buffered, the second one slices the
+        // frame's bulk strings out of the split off buffer.
+        let mut consumed = 0;
+        self.missing = 0;
+        match RespType::decode(&self.buf, &mut consumed, None, &self.limits, 0) {
+            Ok(_) => (),
+            Err(RedisError::Incomplete) => {
+                // An incomplete bulk string leaves `consumed` past the end of the buffer.
+                self.missing = consumed.saturating_sub(self.buf.len());
+                return Ok(None);
+            }
+            Err(err) => {
+                if err.is_recoverable() {
+                    self.buf.advance(consumed);
+                    self.consumed += consumed as u64;
+                }
+
+                return Err(err);
+            }
+        }
+
+        let frame = self.buf.split_to(consumed).freeze();
+        self.consumed += consumed as u64;
+        RespType::decode(&frame, &mut 0, Some(&frame), &self.limits, 0).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<RespType, RedisError> {
+        RespType::parse(&mut std::io::Cursor::new(input))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(parse(b"+OK\r\n"), Ok(RespType::SimpleString(s)) if s == "OK"));
+        assert!(matches!(parse(b"-ERR no\r\n"), Ok(RespType::SimpleError(s)) if s == "ERR no"));
+        assert!(matches!(parse(b":-42\r\n"), Ok(RespType::Integer(-42))));
+        assert!(matches!(parse(b"$-1\r\n"), Ok(RespType::Null)));
+        assert!(matches!(parse(b"*-1\r\n"), Ok(RespType::Null)));
+        assert!(
+            matches!(parse(b"$4\r\na\r\nb\r\n"), Ok(RespType::BulkString(4, s)) if s == b"a\r\nb".as_slice())
+        );
+        assert!(matches!(parse(b"$0\r\n\r\n"), Ok(RespType::BulkString(0, s)) if s.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_nested_array() {
+        let Ok(RespType::Array(values)) = parse(b"*2\r\n*1\r\n$3\r\nGET\r\n:1\r\n") else {
+            panic!("expected array");
+        };
+
+        assert!(matches!(&values[0], RespType::Array(inner) if inner.len() == 1));
+        assert!(matches!(values[1], RespType::Integer(1)));
+    }
+
+    #[test]
+    fn test_parse_recoverable() {
+        let mut reader = std::io::Cursor::new(b"$x\r\n?foo\r\n+OK\r\n".as_slice());
+
+        for _ in 0..2 {
+            let err = RespType::parse(&mut reader).unwrap_err();
+            assert!(err.is_recoverable());
+        }
+
+        assert!(matches!(
+            RespType::parse(&mut reader),
+            Ok(RespType::SimpleString(_))
+        ));
+        assert!(!parse(b"$1\r\nabc\r\n").unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn test_incremental_parser() {
+        let mut parser = RespParser::new();
+        let input = b"*2\r\n$4\r\nECHO\r\n$5\r\nhello\r\n+OK\r\n";
+
+        for chunk in input[..input.len() - 5].chunks(3) {
+            parser.feed(chunk);
+        }
+
+        assert!(
+            matches!(parser.next_frame(), Ok(Some(RespType::Array(values))) if values.len() == 2)
+        );
+        assert!(matches!(parser.next_frame(), Ok(None)));
+
+        parser.feed(&input[input.len() - 5..]);
+        parser.feed(b"*1\r\n?bad\r\n:1\r\n");
+
+        assert!(matches!(
+            parser.next_frame(),
+            Ok(Some(RespType::SimpleString(_)))
+        ));
+        assert!(parser.next_frame().is_err());
+        assert!(matches!(
+            parser.next_frame(),
+            Ok(Some(RespType::Integer(1)))
+        ));
+        assert!(matches!(parser.next_frame(), Ok(None)));
+    }
+
+    #[test]
+    fn test_inline_commands() {
+        let mut parser = RespParser::new();
+        parser.feed(b"PING\r\n\r\n  set k \"a b\"\nGET");
+
+        let command = |args: &[&str]| {
+            RespType::array(
+                args.iter()
+                    .map(|arg| RespType::bulk(arg.to_string()))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(parser.next_frame().unwrap(), Some(command(&["PING"])));
+        assert_eq!(parser.consumed(), 6);
+        assert_eq!(
+            parser.next_frame().unwrap(),
+            Some(command(&["set", "k", "a b"]))
+        );
+        assert_eq!(parser.next_frame().unwrap(), None);
+
+        parser.feed(b" k\r\n");
+        assert_eq!(parser.next_frame().unwrap(), Some(command(&["GET", "k"])));
+        assert_eq!(parser.next_frame().unwrap(), None);
+        assert_eq!(parser.consumed(), 29);
+    }
+
+    #[test]
+    fn test_read_from_reserves_bulk_strings() {
+        let value = vec![b'x'; 3 * MAX_READ_SIZE];
+        let frame = RespType::array(vec![
+            "SET".into(),
+            "k".into(),
+            RespType::bulk(value.clone()),
+        ])
+        .to_bytes(Protocol::Resp2);
+
+        // A reader handing out the frame in small pieces like a socket would.
+        struct Chunked<'a>(&'a [u8]);
+        impl Read for Chunked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(1000);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut reader = Chunked(&frame);
+        let mut parser = RespParser::new();
+        parser.read_from(&mut reader).unwrap();
+        assert!(matches!(parser.next_frame(), Ok(None)));
+        assert_eq!(parser.missing, frame.len() - 1000);
+
+        // Room for the whole value is reserved by the next read so the buffer isn't moved after it.
+        let mut start = None;
+        let frame = loop {
+            assert!(parser.read_from(&mut reader).unwrap() > 0);
+            assert_eq!(
+                *start.get_or_insert(parser.buf.as_ptr()),
+                parser.buf.as_ptr()
+            );
+
+            if let Some(frame) = parser.next_frame().unwrap() {
+                break frame;
+            }
+        };
+
+        let RespType::Array(values) = frame else {
+            panic!("expected an array");
+        };
+        assert!(matches!(&values[2], RespType::BulkString(_, data) if *data == value));
+        assert_eq!(parser.read_from(&mut reader).unwrap(), 0);
+
+        // The memory is released once the parser is idle.
+        parser.shrink();
+        assert_eq!(parser.buf.capacity(), 0);
+    }
+
+    #[test]
+    fn test_incremental_parser_shares_buffer() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*2\r\n$3\r\nSET\r\n$5\r\nvalue\r\n");
+
+        let Ok(Some(RespType::Array(values))) = parser.next_frame() else {
+            panic!("expected array");
+        };
+        let (RespType::BulkString(_, first), RespType::BulkString(_, second)) =
+            (&values[0], &values[1])
+        else {
+            panic!("expected bulk strings");
+        };
+
+        // Both values point into the same frame, 9 bytes apart (`SET\r\n$5\r\n`).
+        assert_eq!(second.as_ptr() as usize - first.as_ptr() as usize, 9);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+            max_depth: 2,
+        };
+        let parse =
+            |input: &[u8]| RespType::parse_with_limits(&mut std::io::Cursor::new(input), &limits);
+
+        assert!(parse(b"$4\r\nabcd\r\n").is_ok());
+        assert!(parse(b"*2\r\n*1\r\n:1\r\n:2\r\n").is_ok());
+
+        for input in [
+            b"$5\r\nabcde\r\n".as_slice(),
+            b"$4294967295\r\n",
+            b"*3\r\n:1\r\n:2\r\n:3\r\n",
+            b"*1\r\n*1\r\n*1\r\n:1\r\n",
+        ] {
+            let err = parse(input).unwrap_err();
+            assert!(!err.is_recoverable(), "{err}");
+
+            let mut parser = RespParser::with_limits(limits);
+            parser.feed(input);
+            assert!(parser.next_frame().is_err());
+        }
+
+        let mut parser = RespParser::new();
+        parser.feed(&vec![b'+'; MAX_LINE_LENGTH]);
+        assert!(parser.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_parse_map_and_set() {
+        let map = parse(b"%2\r\n+a\r\n:1\r\n+b\r\n~2\r\n:1\r\n:2\r\n").unwrap();
+        let expected = RespType::Map(
+            2,
+            vec![
+                (RespType::SimpleString("a".into()), RespType::Integer(1)),
+                (
+                    RespType::SimpleString("b".into()),
+                    RespType::Set(2, vec![RespType::Integer(1), RespType::Integer(2)]),
+                ),
+            ],
+        );
+        assert_eq!(map, expected);
+        assert_eq!(
+            map.to_bytes(Protocol::Resp3),
+            b"%2\r\n+a\r\n:1\r\n+b\r\n~2\r\n:1\r\n:2\r\n"
+        );
+
+        let mut parser = RespParser::new();
+        parser.feed(&map.to_bytes(Protocol::Resp3));
+        assert_eq!(parser.next_frame().unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let input = b"|1\r\n+key-popularity\r\n%1\r\n$1\r\na\r\n:1\r\n*1\r\n:2\r\n";
+        let value = parse(input).unwrap();
+
+        let RespType::Attribute(attributes, inner) = &value else {
+            panic!("expected attribute");
+        };
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(**inner, RespType::Array(vec![RespType::Integer(2)]));
+
+        assert_eq!(value.to_bytes(Protocol::Resp3), input);
+        assert_eq!(value.to_bytes(Protocol::Resp2), b"*1\r\n:2\r\n");
+
+        let mut parser = RespParser::new();
+        parser.feed(input);
+        assert_eq!(parser.next_frame().unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_big_number() {
+        let value = "3492890328409238509324850943850943825024385";
+        let parsed = parse(format!("({value}\r\n").as_bytes()).unwrap();
+
+        assert_eq!(parsed, RespType::BigNumber(value.to_string()));
+        assert_eq!(
+            parsed.to_bytes(Protocol::Resp3),
+            format!("({value}\r\n").as_bytes()
+        );
+        assert_eq!(
+            parsed.to_bytes(Protocol::Resp2),
+            format!("${}\r\n{value}\r\n", value.len()).as_bytes()
+        );
+
+        assert_eq!(RespType::big_number("-007"), RespType::big_number("-7"));
+        assert_eq!(RespType::big_number("-0"), RespType::big_number("0"));
+        assert!(RespType::big_number("1.5").is_none());
+        assert!(parse(b"(12a\r\n").is_err());
+
+        let mut values = ["-100", "-99", "5", "0", "123456789012345678901234567890"]
+            .map(|v| RespType::big_number(v).unwrap());
+        values.sort();
+        assert_eq!(
+            values.map(|v| match v {
+                RespType::BigNumber(v) => v,
+                _ => unreachable!(),
+            }),
+            ["-100", "-99", "0", "5", "123456789012345678901234567890"]
+        );
+    }
+
+    #[test]
+    fn test_hash_and_ord() {
+        let values = [
+            RespType::Double(f64::NAN),
+            RespType::Double(f64::NAN),
+            RespType::Integer(1),
+            RespType::BulkString(1, Bytes::from_static(b"a")),
+        ];
+
+        let set = values.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(set.len(), 3);
+
+        let mut sorted = values.to_vec();
+        sorted.sort();
+        assert_eq!(sorted[0], RespType::Integer(1));
+    }
+
+    /// A tiny xorshift generator so randomized tests are deterministic without extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, alphabet: &[u8], max_len: usize) -> Vec<u8> {
+            let len = self.below(max_len + 1);
+            (0..len)
+                .map(|_| alphabet[self.below(alphabet.len())])
+                .collect()
+        }
+
+        fn string(&mut self, max_len: usize) -> String {
+            String::from_utf8(self.bytes(b"abcXYZ019 -_:.", max_len)).unwrap()
+        }
+    }
+
+    /// Generate a random frame. Only types that survive a round trip are generated, so RESP3 only
+    /// types are left out for RESP2.
+    fn arbitrary(rng: &mut Rng, depth: usize, protocol: Protocol) -> RespType {
+        let types = match protocol {
+            Protocol::Resp2 => 6,
+            Protocol::Resp3 => 16,
+        };
+
+        // Aggregates, i.e. 5 and 11 and above, are only generated while there's depth left.
+        let kind = loop {
+            let kind = rng.below(types);
+            if depth > 0 || (kind != 5 && kind < 11) {
+                break kind;
+            }
+        };
+
+        let aggregate = |rng: &mut Rng| {
+            let len = rng.below(4);
+            (0..len)
+                .map(|_| arbitrary(rng, depth - 1, protocol))
+                .collect::<Vec<_>>()
+        };
+
+        match kind {
+            0 => RespType::SimpleString(rng.string(8)),
+            1 => RespType::error("ERR", rng.string(8)),
+            2 => RespType::Integer(rng.next() as i64 >> rng.below(64)),
+            3 => RespType::bulk(rng.bytes(b"ab\r\n\x00\xff", 16)),
+            4 => RespType::Null,
+            5 => RespType::array(aggregate(rng)),
+            6 => RespType::Boolean(rng.below(2) == 0),
+            7 => RespType::Double(match rng.below(4) {
+                0 => f64::INFINITY,
+                1 => f64::NEG_INFINITY,
+                _ => (rng.next() as i64 >> rng.below(64)) as f64 / 1024.0,
+            }),
+            8 => {
+                let digits = rng.bytes(b"0123456789", 40);
+                let number = format!("-{}", String::from_utf8(digits).unwrap());
+                RespType::big_number(&number).unwrap_or(RespType::BigNumber("0".to_string()))
+            }
+            9 => {
+                let message = format!("ERR {}", rng.string(8));
+                RespType::BulkError(message.len(), message)
+            }
+            10 => RespType::verbatim("txt", rng.string(16)),
+            11 => {
+                let values = aggregate(rng);
+                RespType::Set(values.len(), values)
+            }
+            12 => RespType::push(aggregate(rng)),
+            13 | 14 => {
+                let keys = aggregate(rng);
+                let pairs = keys
+                    .into_iter()
+                    .map(|key| (key, arbitrary(rng, depth - 1, protocol)))
+                    .collect();
+                RespType::map(pairs)
+            }
+            _ => {
+                let attributes = vec![(
+                    RespType::SimpleString(rng.string(8)),
+                    arbitrary(rng, depth - 1, protocol),
+                )];
+                RespType::Attribute(attributes, Box::new(arbitrary(rng, depth - 1, protocol)))
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            for _ in 0..5_000 {
+                let frame = arbitrary(&mut rng, 3, protocol);
+                let encoded = frame.to_bytes(protocol);
+
+                assert_eq!(parse(&encoded).unwrap(), frame, "{encoded:?}");
+
+                let mut parser = RespParser::new();
+                parser.feed(&encoded);
+                assert_eq!(parser.next_frame().unwrap(), Some(frame));
+                assert_eq!(parser.next_frame().unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_captured_streams() {
+        // Replies captured from a real Redis 7.2 server, mostly via DEBUG PROTOCOL.
+        let streams: &[&[u8]] = &[
+            b"+OK\r\n",
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            b"*3\r\n$3\r\nfoo\r\n$-1\r\n$3\r\nbar\r\n",
+            b"*-1\r\n",
+            b"*0\r\n",
+            b"%7\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n$5\r\n7.2.4\r\n\
+              $5\r\nproto\r\n:3\r\n$2\r\nid\r\n:5\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+              $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            b"|1\r\n+key-popularity\r\n%2\r\n$1\r\na\r\n,0.1923\r\n$1\r\nb\r\n,0.0012\r\n\
+              *2\r\n:2039123\r\n:9543892\r\n",
+            b"(1234567999999999999999999999999999999\r\n",
+            b"#t\r\n",
+            b"#f\r\n",
+            b",3.141\r\n",
+            b",inf\r\n",
+            b"_\r\n",
+            b"~3\r\n:0\r\n:1\r\n:2\r\n",
+            b"=15\r\ntxt:Some string\r\n",
+            b"!21\r\nSYNTAX invalid syntax\r\n",
+            b"$12\r\nhello\r\nworld\r\n",
+        ];
+
+        let all = streams.concat();
+
+        // Decoding the whole stream at once, one frame at a time and byte by byte must all agree.
+        let mut reader = all.as_slice();
+        let mut parser = RespParser::new();
+        let mut trickled = RespParser::new();
+        let mut bytes = all.iter();
+
+        for stream in streams {
+            let expected = parse(stream).unwrap();
+
+            assert_eq!(RespType::parse(&mut reader).unwrap(), expected);
+
+            parser.feed(stream);
+            assert_eq!(parser.next_frame().unwrap().as_ref(), Some(&expected));
+
+            let frame = loop {
+                if let Some(frame) = trickled.next_frame().unwrap() {
+                    break frame;
+                }
+
+                trickled.feed(&[*bytes.next().unwrap()]);
+            };
+            assert_eq!(frame, expected);
+
+            // Re-encoding keeps the value even if the representation changes, e.g. `$-1` to `_`.
+            assert_eq!(
+                parse(&expected.to_bytes(Protocol::Resp3)).unwrap(),
+                expected
+            );
+        }
+
+        assert!(reader.is_empty());
+        assert!(bytes.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        let alphabet = b"*$%~|+-:_#,(!=>\r\n\r\n0123456789-1a\xff";
+        let limits = Limits {
+            max_bulk_len: usize::MAX,
+            max_multibulk_len: usize::MAX,
+            max_depth: 8,
+        };
+
+        for _ in 0..20_000 {
+            let input = rng.bytes(alphabet, 31);
+
+            let _ = RespType::parse_with_limits(&mut std::io::Cursor::new(&input), &limits);
+
+            let mut parser = RespParser::with_limits(limits);
+            parser.feed(&input);
+            loop {
+                match parser.next_frame() {
+                    Ok(Some(_)) => (),
+                    Err(err) if err.is_recoverable() => (),
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"$3\r\nabcd\r\n").is_err());
+        assert!(parse(b"$10\r\nabc\r\n").is_err());
+        assert!(parse(b"$x\r\n").is_err());
+        assert!(parse(b":1.5\r\n").is_err());
+        assert!(matches!(
+            parse(b"*2\r\n:1\r\n"),
+            Err(RedisError::Incomplete)
+        ));
+        assert!(matches!(parse(b"+OK"), Err(RedisError::Incomplete)));
+        assert!(matches!(
+            parse(b""),
+            Err(RedisError::Io(err)) if err.kind() == std::io::ErrorKind::ConnectionReset
+        ));
+    }
+
+    #[test]
+    fn test_encode() {
+        let value = RespType::Array(vec![
+            RespType::SimpleString("OK".to_string()),
+            RespType::Integer(-3),
+            RespType::BulkString(5, Bytes::from_static(b"hello")),
+            RespType::Array(vec![RespType::Null, RespType::Boolean(true)]),
+            RespType::SimpleError("ERR oops".to_string()),
+        ]);
+
+        assert_eq!(
+            value.to_bytes(Protocol::Resp2),
+            b"*5\r\n+OK\r\n:-3\r\n$5\r\nhello\r\n*2\r\n$-1\r\n:1\r\n-ERR oops\r\n"
+        );
+        assert_eq!(
+            value.to_bytes(Protocol::Resp3),
+            b"*5\r\n+OK\r\n:-3\r\n$5\r\nhello\r\n*2\r\n_\r\n#t\r\n-ERR oops\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_resp3() {
+        assert_eq!(RespType::Double(1.5).to_bytes(Protocol::Resp3), b",1.5\r\n");
+        assert_eq!(
+            RespType::Double(f64::NEG_INFINITY).to_bytes(Protocol::Resp3),
+            b",-inf\r\n"
+        );
+        assert_eq!(
+            RespType::big_number("100000000000000000000")
+                .unwrap()
+                .to_bytes(Protocol::Resp3),
+            b"(100000000000000000000\r\n"
+        );
+        assert_eq!(
+            RespType::BulkError(0, "SYNTAX invalid".to_string()).to_bytes(Protocol::Resp3),
+            b"!14\r\nSYNTAX invalid\r\n"
+        );
+        assert_eq!(
+            RespType::VerbatimString(0, "txt".to_string(), "Some string".to_string())
+                .to_bytes(Protocol::Resp3),
+            b"=15\r\ntxt:Some string\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_resp2_fallback() {
+        let map = RespType::Map(
+            1,
+            vec![(
+                RespType::SimpleString("ratio".to_string()),
+                RespType::Double(2.5),
+            )],
+        );
+
+        assert_eq!(
+            map.to_bytes(Protocol::Resp2),
+            b"*2\r\n+ratio\r\n$3\r\n2.5\r\n"
+        );
+        assert_eq!(map.to_bytes(Protocol::Resp3), b"%1\r\n+ratio\r\n,2.5\r\n");
+        assert_eq!(
+            RespType::Set(1, vec![RespType::Integer(1)]).to_bytes(Protocol::Resp2),
+            b"*1\r\n:1\r\n"
+        );
 
-        Ok(Self::Array(values))
+        let push = RespType::push(vec!["message".into(), RespType::Integer(1)]);
+        assert_eq!(
+            push.to_bytes(Protocol::Resp2),
+            b"*2\r\n$7\r\nmessage\r\n:1\r\n"
+        );
+        assert_eq!(
+            push.to_bytes(Protocol::Resp3),
+            b">2\r\n$7\r\nmessage\r\n:1\r\n"
+        );
+        assert_eq!(parse(&push.to_bytes(Protocol::Resp3)).unwrap(), push);
     }
 }
@@ -1,171 +1,2663 @@
-use crate::resp_type::RespType;
-use crate::{cache::Cache, command::Command};
+use crate::resp_type::{Limits, Protocol, RespParser, RespType, DEFAULT_READ_SIZE};
+use crate::{
+    aof::{self, AppendFsync, AppendOnlyFile},
+    blocking::{BlockedClients, Blocking, Served},
+    cache::{key_hash_slot, Cache},
+    clock::{Clock, SystemClock},
+    command::{
+        self, ClientCommand, Command, CommandHandler, CommandRegistry, ConfigCommand,
+        ExpireCommand, KillFilter, ListCommand, ReplconfCommand, SetCommand, StreamCommand, Ttl,
+    },
+    error::{panic_message, RedisError},
+    list,
+    persistence::Persistence,
+    pool::WorkerPool,
+    ratelimit::{RateLimiter, RateLimits},
+    reply::{BufferPool, ReplyWriter},
+    stats::{CommandStats, IoStats},
+    stream,
+    timer::TimerWheel,
+};
+use bytes::Bytes;
+#[cfg(unix)]
+use tokio::net::TcpSocket;
+
+mod config;
+mod pubsub;
+mod replication;
+mod simulation;
+mod transaction;
+
+use config::Config;
+use pubsub::{Outbox, PubSub};
+use replication::{FullSync, Replication};
+pub use simulation::Simulation;
+use transaction::Transaction;
 
-use std::time::Duration;
 use std::{
-    io::{BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard, Weak,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-pub struct Server {
-    listener: TcpListener,
-    cache: Arc<Mutex<Cache>>,
+type BeforeHook = Box<dyn Fn(&ClientState, &Command) -> Result<(), RedisError> + Send + Sync>;
+type AfterHook = Box<dyn Fn(&Command, &RespType, Duration) + Send + Sync>;
+
+/// Callbacks run around every command. A before hook returning an error rejects the command and the
+/// error is sent as the reply. After hooks get the reply and how long the command took to execute.
+#[derive(Default)]
+struct CommandHooks {
+    before: RwLock<Vec<BeforeHook>>,
+    after: RwLock<Vec<AfterHook>>,
 }
 
-impl Server {
-    pub fn new(addr: &str) -> Arc<Self> {
-        Arc::new(Self {
-            listener: TcpListener::bind(addr).unwrap(),
-            cache: Arc::new(Mutex::new(Cache::new(1))),
-        })
+impl CommandHooks {
+    fn before(&self, client: &ClientState, command: &Command) -> Result<(), RedisError> {
+        for hook in self.before.read().unwrap().iter() {
+            hook(client, command)?;
+        }
+
+        Ok(())
     }
 
-    pub fn serve_forever(&self) {
-        for stream in self.listener.incoming() {
-            let c = self.cache.clone();
-            thread::spawn(|| handle_request(stream, c));
+    fn after(&self, command: &Command, reply: &RespType, elapsed: Duration) {
+        for hook in self.after.read().unwrap().iter() {
+            hook(command, reply, elapsed);
         }
     }
 }
 
-fn handle_request(stream: Result<TcpStream, std::io::Error>, cache: Arc<Mutex<Cache>>) {
-    match stream {
-        Ok(stream) => match process_request(stream, cache) {
-            Ok(_) => (),
-            Err(err) => println!("error handlign request: {err:?}"),
-        },
-        Err(e) => {
-            println!("error: {}", e);
+/// A connected client as reported by CLIENT LIST.
+#[derive(Debug)]
+struct ClientInfo {
+    stream: TcpStream,
+    addr: String,
+    name: String,
+    connected_at: Instant,
+    last_interaction: Instant,
+    last_command: String,
+}
+
+/// Every connected client keyed by its ID. IDs are assigned in accept order and never reused.
+#[derive(Debug, Default)]
+struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, ClientInfo>>,
+}
+
+impl ClientRegistry {
+    fn register(&self, stream: &TcpStream) -> Result<u64, RedisError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let info = ClientInfo {
+            stream: stream.try_clone()?,
+            addr: stream.peer_addr()?.to_string(),
+            name: String::new(),
+            connected_at: now,
+            last_interaction: now,
+            last_command: "NULL".to_string(),
+        };
+
+        self.clients.lock().unwrap().insert(id, info);
+
+        Ok(id)
+    }
+
+    fn remove(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut ClientInfo)) {
+        if let Some(info) = self.clients.lock().unwrap().get_mut(&id) {
+            f(info);
+        }
+    }
+
+    /// A handle to the client's socket and its address.
+    fn connection(&self, id: u64) -> Option<(TcpStream, String)> {
+        let clients = self.clients.lock().unwrap();
+        let info = clients.get(&id)?;
+
+        Some((info.stream.try_clone().ok()?, info.addr.clone()))
+    }
+
+    fn name(&self, id: u64) -> Option<String> {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|info| info.name.clone())
+    }
+
+    fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut ids = clients.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids.into_iter()
+            .map(|id| {
+                let info = &clients[&id];
+                format!(
+                    "id={} addr={} name={} age={} idle={} cmd={}\n",
+                    id,
+                    info.addr,
+                    info.name,
+                    info.connected_at.elapsed().as_secs(),
+                    info.last_interaction.elapsed().as_secs(),
+                    info.last_command,
+                )
+            })
+            .collect()
+    }
+
+    /// Disconnect the matching clients and return their IDs. The caller's own connection is only
+    /// closed for reading so the reply can still be written.
+    fn kill(&self, filter: &KillFilter, caller: u64) -> Vec<u64> {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = Vec::new();
+
+        for (id, info) in clients.iter() {
+            let matches = match filter {
+                KillFilter::Id(filter) => id == filter,
+                KillFilter::Addr(addr) => &info.addr == addr,
+            };
+
+            if matches {
+                let how = if *id == caller {
+                    Shutdown::Read
+                } else {
+                    Shutdown::Both
+                };
+
+                let _ = info.stream.shutdown(how);
+                killed.push(*id);
+            }
+        }
+
+        killed
+    }
+
+    fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    fn close_all(&self) {
+        for info in self.clients.lock().unwrap().values() {
+            let _ = info.stream.shutdown(Shutdown::Both);
         }
     }
 }
 
-fn process_request(
-    stream: TcpStream,
-    cache: Arc<Mutex<Cache>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut writer = stream.try_clone()?;
-    let mut reader = BufReader::new(stream);
+/// State shared by every connection.
+struct Shared {
+    cache: Cache,
+    blocked: BlockedClients,
+    commands: CommandRegistry,
+    hooks: CommandHooks,
+    clients: ClientRegistry,
+    stats: CommandStats,
+    io: IoStats,
+    limits: RateLimits,
+    /// Drives blocking command and idle client timeouts.
+    timers: Arc<TimerWheel>,
+    /// Limits for parsing requests, including the max size of a value.
+    proto_limits: Limits,
+    /// Commands with a longer key are rejected.
+    max_key_len: usize,
+    /// Reject commands with keys in different hash slots, like Redis Cluster.
+    cluster_enabled: bool,
+    /// Min number of bytes read from a connection at a time.
+    read_buffer_size: usize,
+    /// Large reply buffers shared by connections.
+    reply_buffers: BufferPool,
+    /// The RDB file written by SAVE and BGSAVE.
+    persistence: Persistence,
+    /// The append-only file, set once it has been loaded if it's enabled.
+    aof: OnceLock<AppendOnlyFile>,
+    replication: Replication,
+    pubsub: PubSub,
+    /// Held for reading while a command runs and for writing while EXEC runs a transaction, see
+    /// [`transaction`].
+    exec_lock: RwLock<()>,
+    /// Parameters reported by CONFIG GET, including the idle timeout after which connections are
+    /// closed.
+    config: Config,
+    /// When the server was built, for the uptime reported by INFO.
+    started: Instant,
+}
 
-    loop {
-        let resp_type = match RespType::parse(&mut reader) {
-            Ok(rt) => rt,
-            Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => return Ok(()),
-            err @ Err(_) => err?,
+pub struct Server {
+    /// Taken by the accept loops when they start and dropped when they stop, closing the sockets.
+    listeners: Mutex<Vec<TcpListener>>,
+    /// Number of listeners and how many of their accept loops have stopped.
+    acceptors: usize,
+    stopped_acceptors: AtomicUsize,
+    local_addr: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: AtomicBool,
+    workers: WorkerPool,
+}
+
+/// Configures and binds a [`Server`]. Binding to port 0 picks a free ephemeral port which can be
+/// read back with [`Server::local_addr`].
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    addr: String,
+    shards: u64,
+    acceptors: usize,
+    limits: RateLimits,
+    idle_timeout: Option<Duration>,
+    proto_limits: Limits,
+    max_key_len: usize,
+    cluster_enabled: bool,
+    clock: Arc<dyn Clock>,
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+    dir: PathBuf,
+    dbfilename: String,
+    replicaof: Option<(String, u16)>,
+    appendonly: bool,
+    appendfsync: AppendFsync,
+    appendfilename: String,
+}
+
+/// Default size of the reply buffer each connection keeps.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Default max key size. Redis only limits keys like any other bulk string, but no reasonable key
+/// gets close to this.
+const DEFAULT_MAX_KEY_LEN: usize = 64 * 1024;
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:6379".to_string(),
+            shards: 1,
+            acceptors: 1,
+            limits: RateLimits::default(),
+            idle_timeout: None,
+            proto_limits: Limits::default(),
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+            cluster_enabled: false,
+            clock: Arc::new(SystemClock),
+            read_buffer_size: DEFAULT_READ_SIZE,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            replicaof: None,
+            appendonly: false,
+            appendfsync: AppendFsync::default(),
+            appendfilename: "appendonly.aof".to_string(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    pub fn shards(mut self, shards: u64) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// The clock keys expire by, the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Bind `acceptors` sockets to the address with SO_REUSEPORT, each with its own accept loop, so
+    /// the kernel spreads bursts of new connections between them. Only supported on Unix.
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Limit the number of commands each connection may send per second. Clients exempted with
+    /// CLIENT NO-RATELIMIT aren't limited, and neither is anyone with a limit of zero.
+    pub fn max_commands_per_sec(mut self, commands: u64) -> Self {
+        self.limits.commands_per_sec = Some(commands);
+        self
+    }
+
+    /// Limit the number of bytes read from each connection per second. Reading is paused while a
+    /// client is over the limit. Zero means no limit.
+    pub fn max_bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.limits.bytes_per_sec = Some(bytes);
+        self
+    }
+
+    /// Delay commands over the limit set by [`ServerBuilder::max_commands_per_sec`] until the
+    /// client is back under it instead of rejecting them.
+    pub fn delay_rate_limited(mut self, delay: bool) -> Self {
+        self.limits.delay = delay;
+        self
+    }
+
+    /// Close connections that haven't sent anything for `timeout`, like the `timeout` config in
+    /// Redis. Clients blocked by a command aren't idle.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Max size in bytes of any bulk string in a request, and so of any value, like
+    /// `proto-max-bulk-len` in Redis. Larger bulk strings are rejected as soon as their length is
+    /// read, without buffering the data, and the connection is closed. Defaults to 512 MB.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.proto_limits.max_bulk_len = bytes;
+        self
+    }
+
+    /// Max size in bytes of a key. Commands with a longer key are rejected. Defaults to 64 KB.
+    pub fn max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_len = bytes;
+        self
+    }
+
+    /// Check that the keys of every command hash to the same slot like a Redis Cluster node does,
+    /// rejecting them with a CROSSSLOT error otherwise. There's no other cluster support, the node
+    /// still owns every slot.
+    pub fn cluster_enabled(mut self, enabled: bool) -> Self {
+        self.cluster_enabled = enabled;
+        self
+    }
+
+    /// Min number of bytes read from a connection at a time, 4 KB by default. Room for large bulk
+    /// strings is made as needed and released once the connection is idle.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes.max(1);
+        self
+    }
+
+    /// Size of the buffer each connection keeps for replies, 16 KB by default. Pipelines with more
+    /// replies than fit borrow a larger buffer from a pool shared by all connections and give it
+    /// back once the replies are sent.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// The directory of the RDB file, the working directory by default.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// The name of the RDB file written by SAVE and BGSAVE, `dump.rdb` by default. If the file
+    /// exists when the server is built its keys are loaded, and building fails if it can't be read.
+    pub fn dbfilename(mut self, name: impl Into<String>) -> Self {
+        self.dbfilename = name.into();
+        self
+    }
+
+    /// Start as a replica of the master at `host` and `port`, like `--replicaof` in Redis. The
+    /// replica loads a snapshot from the master, applies every write the master makes afterwards
+    /// and rejects writes from its own clients.
+    pub fn replicaof(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.replicaof = Some((host.into(), port));
+        self
+    }
+
+    /// Log every write to the append-only file, like `appendonly yes` in Redis. When the server is
+    /// built the file is loaded instead of the RDB file, and building fails if it's corrupt. A
+    /// command cut short at the end, like by a crash, is dropped.
+    pub fn appendonly(mut self, enabled: bool) -> Self {
+        self.appendonly = enabled;
+        self
+    }
+
+    /// When the append-only file is synced to disk, `everysec` by default.
+    pub fn appendfsync(mut self, fsync: AppendFsync) -> Self {
+        self.appendfsync = fsync;
+        self
+    }
+
+    /// The name of the append-only file in the directory set with [`ServerBuilder::dir`],
+    /// `appendonly.aof` by default.
+    pub fn appendfilename(mut self, name: impl Into<String>) -> Self {
+        self.appendfilename = name.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<Server>, RedisError> {
+        let listeners = match self.acceptors {
+            1 => vec![TcpListener::bind(&self.addr)?],
+            acceptors => bind_reuseport(&self.addr, acceptors)?,
         };
+        let local_addr = listeners[0].local_addr()?;
+
+        let aof = self
+            .appendonly
+            .then(|| (self.dir.join(&self.appendfilename), self.appendfsync));
+        let cache = Cache::with_clock(self.shards, self.clock.clone());
+        let shared = self.shared(cache, Some(local_addr));
+
+        if aof.is_none() {
+            let loaded = shared.persistence.load(&shared.cache)?;
+            if loaded > 0 {
+                tracing::info!(keys = loaded, "loaded the RDB file");
+            }
+        }
+
+        let server = Arc::new(Server {
+            local_addr,
+            acceptors: listeners.len(),
+            stopped_acceptors: AtomicUsize::new(0),
+            listeners: Mutex::new(listeners),
+            shared: Arc::new(shared),
+            shutdown: AtomicBool::new(false),
+            workers: WorkerPool::new(),
+        });
+
+        // The file is replayed before it's opened for appending so the replay isn't logged again.
+        if let Some((path, fsync)) = aof {
+            server.load_aof(&path)?;
+            let _ = server.shared.aof.set(AppendOnlyFile::open(&path, fsync)?);
+        }
 
-        let command = process_resp_type(&resp_type)?;
-        process_command(command, cache.clone(), &mut writer)?;
+        Ok(server)
+    }
+
+    /// Build a [`Simulation`] of the server instead, which doesn't listen on any address and runs
+    /// everything on the calling thread.
+    pub fn simulate(self) -> Simulation {
+        let cache = Cache::without_eviction_loop(self.shards, self.clock.clone());
+        Simulation::new(self.shared(cache, None))
+    }
+
+    fn shared(self, cache: Cache, local_addr: Option<SocketAddr>) -> Shared {
+        let config = [
+            (
+                "bind",
+                local_addr.map_or(String::new(), |addr| addr.ip().to_string()),
+            ),
+            ("port", local_addr.map_or(0, |addr| addr.port()).to_string()),
+            ("databases", "1".to_string()),
+            ("save", String::new()),
+            (
+                "appendonly",
+                if self.appendonly { "yes" } else { "no" }.to_string(),
+            ),
+            ("appendfsync", self.appendfsync.name().to_string()),
+            ("appendfilename", self.appendfilename.clone()),
+            ("dir", self.dir.display().to_string()),
+            ("dbfilename", self.dbfilename.clone()),
+            (
+                "replicaof",
+                self.replicaof
+                    .as_ref()
+                    .map_or(String::new(), |(host, port)| format!("{host} {port}")),
+            ),
+            (
+                "cluster-enabled",
+                if self.cluster_enabled { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "proto-max-bulk-len",
+                self.proto_limits.max_bulk_len.to_string(),
+            ),
+        ];
+
+        let timers = Arc::new(TimerWheel::new());
+        let persistence = Persistence::new(&self.dir, &self.dbfilename, &cache);
+
+        Shared {
+            blocked: BlockedClients::new(&cache, timers.clone()),
+            cache,
+            commands: CommandRegistry::default(),
+            hooks: CommandHooks::default(),
+            clients: ClientRegistry::default(),
+            stats: CommandStats::default(),
+            io: IoStats::default(),
+            limits: self.limits,
+            timers,
+            proto_limits: self.proto_limits,
+            max_key_len: self.max_key_len,
+            cluster_enabled: self.cluster_enabled,
+            read_buffer_size: self.read_buffer_size,
+            reply_buffers: BufferPool::new(self.write_buffer_size),
+            persistence,
+            aof: OnceLock::new(),
+            replication: Replication::new(self.replicaof),
+            pubsub: PubSub::default(),
+            exec_lock: RwLock::default(),
+            config: Config::new(config, self.idle_timeout),
+            started: Instant::now(),
+        }
     }
 }
 
-fn process_resp_type(
-    resp_type: &RespType,
-) -> Result<Command, Box<dyn std::error::Error + Send + Sync>> {
-    match resp_type {
-        RespType::Array(arr) if !arr.is_empty() => {
-            // safety: We just checked for length.
-            let command = &arr[0];
-
-            match process_resp_type(command)? {
-                Command::Literal(s) if s.to_lowercase() == "ping" => Ok(Command::Ping),
-                Command::Literal(s) if s.to_lowercase() == "echo" => {
-                    let arg = process_resp_type(&arr[1])?.literal_value()?;
-                    Ok(Command::Echo(arg))
-                }
-                Command::Literal(s) if s.to_lowercase() == "set" => {
-                    let key = process_resp_type(&arr[1])?.literal_value()?;
-                    let value = process_resp_type(&arr[2])?.literal_value()?;
-
-                    match (arr.get(3), arr.get(4)) {
-                        (Some(a), Some(b)) => {
-                            let arg = process_resp_type(a)?.literal_value()?;
-                            if arg.to_lowercase() != "px" {
-                                return Ok(Command::Set(key, value, None));
-                            }
-
-                            let arg_value =
-                                process_resp_type(b)?.literal_value()?.parse::<u64>()?;
-
-                            Ok(Command::Set(
-                                key,
-                                value,
-                                Some(Duration::from_millis(arg_value)),
-                            ))
-                        }
-                        _ => Ok(Command::Set(key, value, None)),
-                    }
+/// Bind `count` listeners to the same address with SO_REUSEPORT. If the port is 0 the rest are
+/// bound to the ephemeral port picked for the first one.
+#[cfg(unix)]
+fn bind_reuseport(addr: &str, count: usize) -> Result<Vec<TcpListener>, RedisError> {
+    let mut addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| RedisError::Other(format!("invalid address '{addr}'")))?;
+
+    // Listening registers the socket with a reactor so a runtime is needed, it's deregistered
+    // again when converted to a std listener.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    let _runtime = runtime.enter();
+
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+
+        let listener = socket.listen(1024)?.into_std()?;
+        listener.set_nonblocking(false)?;
+        addr = listener.local_addr()?;
+
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+fn bind_reuseport(_: &str, _: usize) -> Result<Vec<TcpListener>, RedisError> {
+    Err(RedisError::Other(
+        "multiple acceptors require SO_REUSEPORT which isn't supported on this platform"
+            .to_string(),
+    ))
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn new(addr: &str) -> Result<Arc<Self>, RedisError> {
+        Self::builder().addr(addr).build()
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The keyspace served, e.g. to seed or inspect data without going through a connection.
+    pub fn cache(&self) -> &Cache {
+        &self.shared.cache
+    }
+
+    /// Add a command that isn't built in. Registering the name of a built-in command has no effect
+    /// since those are always resolved first.
+    pub fn register_command(&self, name: &str, handler: impl CommandHandler + 'static) {
+        self.shared.commands.register(name, Arc::new(handler));
+    }
+
+    /// Add a hook called before every command is executed. Returning an error rejects the command
+    /// with that error as the reply.
+    pub fn before_command(
+        &self,
+        hook: impl Fn(&ClientState, &Command) -> Result<(), RedisError> + Send + Sync + 'static,
+    ) {
+        self.shared
+            .hooks
+            .before
+            .write()
+            .unwrap()
+            .push(Box::new(hook));
+    }
+
+    /// Add a hook called after every executed command with its reply and execution time.
+    pub fn after_command(
+        &self,
+        hook: impl Fn(&Command, &RespType, Duration) + Send + Sync + 'static,
+    ) {
+        self.shared
+            .hooks
+            .after
+            .write()
+            .unwrap()
+            .push(Box::new(hook));
+    }
+
+    /// Execute a file of commands in RESP, like the input of `redis-cli --pipe`, before serving
+    /// clients. The replies are discarded except for counting errors. A protocol error stops the
+    /// load since the rest of the file can't be trusted.
+    pub fn load(&self, data: &[u8]) -> Result<LoadSummary, RedisError> {
+        let mut parser = RespParser::with_limits(self.shared.proto_limits);
+        parser.feed(data);
+
+        let mut client = ClientState {
+            rate_limit_exempt: true,
+            ..Default::default()
+        };
+        let mut summary = LoadSummary::default();
+        let mut reply = Vec::new();
+
+        while let Some(frame) = parser.next_frame()? {
+            reply.clear();
+            process_frame(&frame, &self.shared, &mut client, &mut reply)?;
+
+            summary.commands += 1;
+            if matches!(reply.first(), Some(b'-' | b'!')) {
+                summary.errors += 1;
+            }
+        }
+
+        // Anything left over is a command cut short.
+        if !parser.is_empty() {
+            return Err(RedisError::Protocol(
+                "truncated command at end of file".to_string(),
+            ));
+        }
+
+        Ok(summary)
+    }
+
+    /// Replay the append-only file at `path` if it exists, truncating a command cut short at its
+    /// end.
+    fn load_aof(&self, path: &Path) -> Result<(), RedisError> {
+        let data = match fs::read(path) {
+            Ok(data) => Bytes::from(data),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let len = aof::loadable_len(&data)?;
+        if len < data.len() {
+            tracing::warn!(
+                path = %path.display(),
+                bytes = data.len() - len,
+                "dropping a truncated command at the end of the append only file"
+            );
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(len as u64)?;
+        }
+
+        let summary = self.load(&data[..len])?;
+        tracing::info!(
+            commands = summary.commands,
+            errors = summary.errors,
+            "loaded the append only file"
+        );
+
+        Ok(())
+    }
+
+    /// Run the accept loop on a background thread and return a handle to stop it.
+    pub fn start(self: &Arc<Self>) -> ServerHandle {
+        let server = self.clone();
+        let acceptor = thread::spawn(move || server.serve_forever());
+
+        ServerHandle {
+            server: self.clone(),
+            acceptor,
+        }
+    }
+
+    /// Call `ping` every `interval` from the timer thread for as long as the server accepts
+    /// connections. It's meant for a service manager's watchdog, which then notices if the timers
+    /// that drive timeouts stop firing or the accept loops exit.
+    pub fn heartbeat(
+        self: &Arc<Self>,
+        interval: Duration,
+        ping: impl Fn() + Send + Sync + 'static,
+    ) {
+        schedule_heartbeat(Arc::downgrade(self), interval, Arc::new(ping));
+    }
+
+    /// Accept connections until the server is shut down. Each connection is served by its own
+    /// thread, reusing threads from connections that have been closed.
+    pub fn serve_forever(&self) {
+        let mut listeners = std::mem::take(&mut *self.listeners.lock().unwrap());
+        let Some(listener) = listeners.pop() else {
+            tracing::error!("server is already running");
+            return;
+        };
+
+        // Additional listeners bound with SO_REUSEPORT get an accept loop each.
+        thread::scope(|scope| {
+            scope.spawn(|| replication::run_link(&self.shared, self.local_addr.port()));
+
+            for listener in listeners {
+                scope.spawn(|| self.accept_loop(listener));
+            }
+
+            self.accept_loop(listener);
+        });
+    }
+
+    fn accept_loop(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::error!(%err, "failed to accept connection");
+                    continue;
                 }
-                Command::Literal(s) if s.to_lowercase() == "get" => {
-                    let key = process_resp_type(&arr[1])?.literal_value()?;
-                    Ok(Command::Get(key))
+            };
+
+            let id = match self.shared.clients.register(&stream) {
+                Ok(id) => id,
+                Err(err) => {
+                    tracing::error!(%err, "failed to register connection");
+                    continue;
                 }
-                v => Ok(v),
+            };
+
+            let shared = self.shared.clone();
+            self.workers.execute(move || {
+                handle_request(id, stream, &shared);
+                shared.clients.remove(id);
+                shared.replication.remove_replica(id);
+                shared.pubsub.remove_client(id);
+            });
+        }
+
+        self.stopped_acceptors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn close_connections(&self) {
+        self.shared.clients.close_all();
+        self.shared.blocked.unblock_all();
+    }
+}
+
+/// Schedule the next ping of [`Server::heartbeat`], which schedules the one after it once it runs.
+fn schedule_heartbeat(server: Weak<Server>, interval: Duration, ping: Arc<dyn Fn() + Send + Sync>) {
+    let Some(timers) = server.upgrade().map(|server| server.shared.timers.clone()) else {
+        return;
+    };
+
+    timers.schedule(Instant::now() + interval, move || {
+        let Some(alive) = server.upgrade() else {
+            return;
+        };
+        if alive.shutdown.load(Ordering::SeqCst)
+            || alive.stopped_acceptors.load(Ordering::SeqCst) > 0
+        {
+            return;
+        }
+        drop(alive);
+
+        ping();
+        schedule_heartbeat(server, interval, ping);
+    });
+}
+
+/// The result of [`Server::load`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSummary {
+    pub commands: u64,
+    /// Commands that replied with an error.
+    pub errors: u64,
+}
+
+/// Handle to a server running in the background, see [`Server::start`].
+pub struct ServerHandle {
+    server: Arc<Server>,
+    acceptor: thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr
+    }
+
+    /// Stop accepting connections and disconnect all clients. Use [`ServerHandle::join`] to wait
+    /// for everything to wind down.
+    pub fn shutdown(&self) {
+        self.server.shutdown.store(true, Ordering::SeqCst);
+        self.server.shared.replication.shutdown();
+
+        // The accept loops only check the flag when a connection arrives so wake them up. With
+        // multiple listeners the kernel picks the one receiving each connection, so keep connecting
+        // until all of them have stopped. The attempts are bounded in case the server was never
+        // started.
+        let server = &self.server;
+        for _ in 0..100 {
+            if server.stopped_acceptors.load(Ordering::SeqCst) >= server.acceptors {
+                break;
             }
+
+            for _ in 0..server.acceptors {
+                let _ = TcpStream::connect(server.local_addr);
+            }
+
+            thread::sleep(Duration::from_millis(1));
         }
-        RespType::BulkString(_, command) => Ok(Command::Literal(command.trim_end().to_string())),
-        _ => todo!(),
+
+        self.server.close_connections();
+    }
+
+    /// Wait for the accept loop and every connection thread to finish.
+    pub fn join(self) {
+        let _ = self.acceptor.join();
+
+        // A connection accepted while shutting down might have been registered after the others
+        // were closed.
+        self.server.close_connections();
+
+        self.server.workers.join();
     }
 }
 
-fn process_command(
-    command: Command,
-    cache: Arc<Mutex<Cache>>,
-    writer: &mut TcpStream,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    match command {
-        Command::Literal(value) => {
-            let err = format!("-ERROR '{value}' not implemented\r\n");
-            let buf = err.as_bytes();
-            writer.write_all(buf)?;
-        }
-        Command::Ping => {
-            let buf = "+PONG\r\n".as_bytes();
-            writer.write_all(buf)?;
-        }
-        Command::Echo(response) => {
-            let size = response.len();
-            let reply = format!("${size}\r\n{response}\r\n");
-            let buf = reply.as_bytes();
-
-            writer.write_all(buf)?;
-        }
-        Command::Set(key, value, ttl) => {
-            let mut c = cache.lock().unwrap();
-            c.set(&key, &value, ttl);
-
-            let buf = "+OK\r\n".as_bytes();
-            writer.write_all(buf)?;
-        }
-        Command::Get(key) => {
-            let c = cache.lock().unwrap();
-            match c.get(&key) {
-                Some(value) => {
-                    let size = value.len();
-                    let reply = format!("${size}\r\n{value}\r\n");
-                    let buf = reply.as_bytes();
-
-                    writer.write_all(buf)?;
+fn handle_request(id: u64, stream: TcpStream, shared: &Shared) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let _span = tracing::info_span!("connection", id, %peer).entered();
+
+    tracing::debug!("client connected");
+
+    match process_request(id, stream, shared) {
+        Ok(()) => tracing::debug!("client disconnected"),
+        Err(err) => tracing::warn!(%err, "error handling request"),
+    }
+}
+
+/// State tied to a single connection.
+#[derive(Debug, Default)]
+pub struct ClientState {
+    id: u64,
+    protocol: Protocol,
+    limiter: RateLimiter,
+    /// Set with CLIENT NO-RATELIMIT.
+    rate_limit_exempt: bool,
+    /// The connection is the link to our master, whose writes are applied even on a replica.
+    master: bool,
+    /// The port a replica on this connection listens on, from REPLCONF.
+    replica_port: u16,
+    /// A full resynchronization to send once the reply to PSYNC is written.
+    sync: Option<FullSync>,
+    /// The channels the client is subscribed to.
+    subscriptions: HashSet<String>,
+    /// Everything sent to the connection goes through here once it has subscribed to a channel,
+    /// see [`pubsub`].
+    outbox: Option<Outbox>,
+    /// The commands queued since MULTI.
+    transaction: Option<Transaction>,
+    /// Blocking commands give up right away instead, set while EXEC runs.
+    deny_blocking: bool,
+}
+
+impl ClientState {
+    /// The unique ID of the connection, as returned by CLIENT ID.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+fn process_request(id: u64, stream: TcpStream, shared: &Shared) -> Result<(), RedisError> {
+    // Replies are buffered and only flushed when all pipelined commands that have been received are
+    // processed, writing the replies for a whole pipeline with a single syscall.
+    let mut writer = ReplyWriter::new(
+        stream.try_clone()?,
+        &shared.io.writes,
+        &shared.reply_buffers,
+    );
+    // Closes the connection from the timer thread when the client is idle for too long. It's only
+    // cloned once there's a timeout, which CONFIG SET may add at any time.
+    let mut closer = None;
+    let mut reader = stream;
+    let mut parser =
+        RespParser::with_limits(shared.proto_limits).with_read_size(shared.read_buffer_size);
+    let mut client = ClientState {
+        id,
+        limiter: RateLimiter::new(&shared.limits),
+        ..Default::default()
+    };
+    loop {
+        let resp_type = match parser.next_frame() {
+            Ok(Some(rt)) => rt,
+            Ok(None) => {
+                writer.flush()?;
+                parser.shrink();
+
+                // The client is only idle while we wait for it to send something, not while it's
+                // blocked by a command.
+                let idle_timer = match shared.config.idle_timeout() {
+                    Some(timeout) => {
+                        let closer = match &closer {
+                            Some(closer) => Arc::clone(closer),
+                            None => closer.insert(Arc::new(reader.try_clone()?)).clone(),
+                        };
+
+                        Some(shared.timers.schedule(Instant::now() + timeout, move || {
+                            let _ = closer.shutdown(Shutdown::Both);
+                        }))
+                    }
+                    None => None,
+                };
+
+                let n = parser.read_from(&mut reader);
+                shared.io.reads.fetch_add(1, Ordering::Relaxed);
+                if let Some(timer) = idle_timer {
+                    shared.timers.cancel(timer);
+                }
+
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+
+                if !client.rate_limit_exempt {
+                    client.limiter.received(n);
                 }
-                None => {
-                    let buf = "$-1\r\n".as_bytes();
-                    writer.write_all(buf)?;
+
+                continue;
+            }
+            Err(err) => {
+                write_reply(&err.to_resp(), &client, &mut writer)?;
+
+                // The stream can't be trusted after a fatal protocol error so close it.
+                if !err.is_recoverable() {
+                    writer.flush()?;
+                    return Ok(());
                 }
+
+                continue;
+            }
+        };
+
+        process_frame(&resp_type, shared, &mut client, &mut writer)?;
+    }
+}
+
+/// Run the command in a request frame and write its reply.
+fn process_frame(
+    resp_type: &RespType,
+    shared: &Shared,
+    client: &mut ClientState,
+    writer: &mut impl Write,
+) -> Result<(), RedisError> {
+    let command = match process_resp_type(resp_type) {
+        Ok(command) => command,
+        Err(RedisError::UnknownCommand(name, args)) if shared.commands.get(&name).is_some() => {
+            Command::Custom(name, args)
+        }
+        Err(err) => {
+            let name = match &err {
+                RedisError::WrongArity(name) => Some(name.as_str()),
+                _ => None,
             };
+
+            // A transaction with a command that couldn't be queued is discarded by EXEC.
+            if let Some(transaction) = &mut client.transaction {
+                transaction.fail();
+            }
+
+            let reply = err.to_resp();
+            shared.stats.record_rejected(name, &reply);
+            return write_reply(&reply, client, writer);
         }
-    }
+    };
 
-    Ok(())
+    process_command(command, resp_type, shared, client, writer)
 }
-#[allow(dead_code)]
-fn dump_stream(stream: &std::net::TcpStream) {
-    let mut tmp = stream.try_clone().unwrap();
-    let mut received: Vec<u8> = vec![];
-    let mut rx_bytes = [0u8; 1024];
-    let bytes_read = tmp.read(&mut rx_bytes).unwrap();
-    received.extend_from_slice(&rx_bytes[..bytes_read]);
+
+fn process_resp_type(resp_type: &RespType) -> Result<Command, RedisError> {
+    match resp_type {
+        RespType::Array(arr) if !arr.is_empty() => Command::parse(arr),
+        // A bare string is only echoed back as an unknown command, so any non UTF-8 data in it is
+        // replaced.
+        RespType::BulkString(_, command) => Ok(Command::Literal(
+            String::from_utf8_lossy(command).into_owned(),
+        )),
+        RespType::SimpleString(command) => Ok(Command::Literal(command.to_string())),
+        RespType::Attribute(_, value) => process_resp_type(value),
+        _ => Err(RedisError::Protocol(format!(
+            "unexpected {resp_type:?} in command"
+        ))),
+    }
+}
+
+fn process_command(
+    command: Command,
+    frame: &RespType,
+    shared: &Shared,
+    client: &mut ClientState,
+    writer: &mut impl Write,
+) -> Result<(), RedisError> {
+    let span = command_span(&command);
+    let _span = span.enter();
+
+    // Replicas acknowledging their offset don't get a reply.
+    if let Command::Replconf(ReplconfCommand::Ack(offset)) = command {
+        shared.replication.ack(client.id, offset);
+        return Ok(());
+    }
+
+    shared.clients.update(client.id, |info| {
+        info.last_interaction = Instant::now();
+        info.last_command = command.name().to_string();
+    });
+
+    // From the first subscription on, the connection is written by its outbox thread. What's
+    // buffered is sent first so replies stay in order.
+    if matches!(command, Command::Subscribe(_)) && client.outbox.is_none() {
+        writer.flush()?;
+        client.outbox = pubsub::start_outbox(shared, client.id);
+    }
+
+    let confirms_channels = client.transaction.is_none()
+        && matches!(command, Command::Subscribe(_) | Command::Unsubscribe(_));
+
+    // The limiter may sleep, so it's consulted before the exec lock is taken. A command over the
+    // limit fails its transaction like one that couldn't be queued.
+    let limited = !client.rate_limit_exempt && !client.limiter.command();
+    if let (true, Some(transaction)) = (limited, &mut client.transaction) {
+        transaction.fail();
+    }
+
+    // Commands after MULTI are queued until EXEC, which takes the exec lock for writing itself.
+    // Other commands hold it for reading, which blocking commands let go of before they wait.
+    let reply = match &mut client.transaction {
+        _ if limited => {
+            tracing::debug!("command rate limited");
+
+            let reply = RedisError::Other("rate limit exceeded".to_string()).to_resp();
+            shared.stats.record_rejected(Some(command.name()), &reply);
+
+            reply
+        }
+        Some(transaction)
+            if !matches!(command, Command::Multi | Command::Exec | Command::Discard) =>
+        {
+            transaction.queue(command, frame)
+        }
+        _ if matches!(command, Command::Exec) => {
+            run_guarded(&command, frame, shared, client, &span, None)
+        }
+        // The keyspace the append-only file is rewritten from, or a replica is sent, must not
+        // change while it's copied, and no write may be logged to the old file or left out of
+        // the replica's stream after it.
+        _ if matches!(command, Command::BgRewriteAof | Command::Psync(..)) => {
+            let _exclusive = shared
+                .exec_lock
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            run_guarded(&command, frame, shared, client, &span, None)
+        }
+        _ => {
+            let exec_guard = shared
+                .exec_lock
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            run_guarded(&command, frame, shared, client, &span, Some(exec_guard))
+        }
+    };
+
+    match (&client.outbox, reply) {
+        // SUBSCRIBE and UNSUBSCRIBE confirm each channel with a message of its own.
+        (Some(outbox), RespType::Array(confirmations)) if confirms_channels => {
+            let data = confirmations
+                .iter()
+                .flat_map(|confirmation| confirmation.to_bytes(client.protocol))
+                .collect::<Vec<_>>();
+
+            // The outbox thread only stops when the connection fails, which the reader notices too.
+            let _ = outbox.send(data.into());
+        }
+        (_, reply) => write_reply(&reply, client, writer)?,
+    }
+
+    if let Some(sync) = client.sync.take() {
+        replication::start_feed(sync, writer)?;
+    }
+
+    Ok(())
+}
+
+/// Write a reply, or once the client has subscribed hand it to the outbox thread so it stays in
+/// order with the replies sent from there.
+fn write_reply(
+    reply: &RespType,
+    client: &ClientState,
+    writer: &mut impl Write,
+) -> Result<(), RedisError> {
+    match &client.outbox {
+        Some(outbox) => {
+            // The outbox thread only stops when the connection fails, which the reader notices too.
+            let _ = outbox.send(reply.to_bytes(client.protocol).into());
+        }
+        None => reply.encode(writer, client.protocol)?,
+    }
+
+    Ok(())
+}
+
+fn command_span(command: &Command) -> tracing::Span {
+    tracing::debug_span!(
+        "command",
+        name = command.name(),
+        keys = command.keys().len(),
+        duration_us = tracing::field::Empty,
+    )
+}
+
+/// Whether the keys hash to more than one cluster slot.
+fn cross_slot(keys: &[&str]) -> bool {
+    keys.split_first().is_some_and(|(first, rest)| {
+        let slot = key_hash_slot(first);
+        rest.iter().any(|key| key_hash_slot(key) != slot)
+    })
+}
+
+/// Run the command, turning a panic into an error reply. Hooks and handlers may be user code so a
+/// panic in them shouldn't drop the connection.
+fn run_guarded(
+    command: &Command,
+    frame: &RespType,
+    shared: &Shared,
+    client: &mut ClientState,
+    span: &tracing::Span,
+    exec_guard: Option<RwLockReadGuard<'_, ()>>,
+) -> RespType {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        run_command(command, frame, shared, client, span, exec_guard)
+    }))
+    .unwrap_or_else(|panic| {
+        tracing::error!(panic = panic_message(&*panic), "command panicked");
+        RedisError::Other("internal error".to_string()).to_resp()
+    })
+}
+
+/// Run the hooks and execute the command, recording its stats. A blocking command that has to wait
+/// releases `exec_guard`, the caller's hold of the exec lock, first.
+fn run_command(
+    command: &Command,
+    frame: &RespType,
+    shared: &Shared,
+    client: &mut ClientState,
+    span: &tracing::Span,
+    exec_guard: Option<RwLockReadGuard<'_, ()>>,
+) -> RespType {
+    let keys = command.keys();
+    let too_long = keys.iter().any(|key| key.len() > shared.max_key_len);
+    let cross_slot = shared.cluster_enabled && cross_slot(&keys);
+
+    let allowed = if too_long {
+        Err(RedisError::Other(format!(
+            "key is too large, the limit is {} bytes",
+            shared.max_key_len
+        )))
+    } else if cross_slot {
+        Err(RedisError::CrossSlot)
+    } else if command.is_write() && !client.master && shared.replication.is_replica() {
+        Err(RedisError::ReadOnly)
+    } else if !client.subscriptions.is_empty()
+        && client.protocol == Protocol::Resp2
+        && !command.allowed_when_subscribed()
+    {
+        Err(RedisError::Other(format!(
+            "Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context",
+            command.name()
+        )))
+    } else {
+        shared.hooks.before(client, command)
+    };
+
+    match allowed {
+        Ok(()) => {
+            // MIGRATE isn't propagated since replicas would migrate the keys as well.
+            let propagated = command.is_write() && !matches!(command, Command::Migrate(_));
+            // Other writers to the same shards wait until this write is propagated, so it can't
+            // be applied first but propagated last.
+            let write_order = propagated.then(|| shared.cache.lock_keys(&keys));
+
+            let start = Instant::now();
+            let (reply, waiting) = match execute(command, shared, client) {
+                Blocking::Done(Served { reply, write }) => {
+                    let failed =
+                        matches!(reply, RespType::SimpleError(_) | RespType::BulkError(..));
+                    if propagated && !failed {
+                        propagate_write(command, frame, write, shared);
+                    }
+
+                    (reply, None)
+                }
+                Blocking::Waiting(waiting) => (RespType::null(), Some(waiting)),
+            };
+
+            // Clients blocked on the keys written are served after the write is propagated, so
+            // replicas get their pops after it.
+            if let Some(locks) = &write_order {
+                shared
+                    .blocked
+                    .serve_ready(&shared.cache, locks, |write| propagate(shared, write));
+            }
+
+            // The write serving a waiting client needs the locks, and propagates what it wrote.
+            drop(write_order);
+            drop(exec_guard);
+            let reply = match waiting {
+                Some(waiting) => waiting.wait().unwrap_or_else(RespType::null),
+                None => reply,
+            };
+            let elapsed = start.elapsed();
+
+            span.record("duration_us", elapsed.as_micros() as u64);
+            tracing::debug!("command executed");
+            shared.stats.record_call(command.name(), &reply, elapsed);
+            shared.hooks.after(command, &reply, elapsed);
+
+            reply
+        }
+        Err(err) => {
+            tracing::debug!(%err, "command rejected");
+
+            let reply = err.to_resp();
+            shared.stats.record_rejected(Some(command.name()), &reply);
+
+            reply
+        }
+    }
+}
+
+/// Propagate a write command that succeeded. Replicas get the ID XADD generated rather than
+/// generating their own, and a blocking command only propagates the `write` it turned into, if
+/// any, rather than blocking on replicas.
+fn propagate_write(command: &Command, frame: &RespType, write: Option<RespType>, shared: &Shared) {
+    match write {
+        Some(write) => propagate(shared, &write),
+        None if command.may_block() => (),
+        None => propagate(shared, frame),
+    }
+
+    // A relative TTL would start over when the file is loaded, so the deadline it resulted in is
+    // logged as well.
+    if let Some(aof) = shared.aof.get() {
+        if let Some(deadline) = absolute_expiry(command, &shared.cache) {
+            aof.append(&deadline);
+        }
+    }
+}
+
+/// Send a write to the replicas and log it to the append-only file.
+fn propagate(shared: &Shared, frame: &RespType) {
+    shared.replication.propagate(frame);
+
+    if let Some(aof) = shared.aof.get() {
+        aof.append(frame);
+    }
+}
+
+/// PEXPIREAT with the deadline a command setting a relative TTL gave its key, if it still has one.
+fn absolute_expiry(command: &Command, cache: &Cache) -> Option<RespType> {
+    let key = match command {
+        Command::Set(SetCommand {
+            key,
+            ttl: Some(Ttl::Relative(_)),
+            ..
+        })
+        | Command::Expire(ExpireCommand::Expire {
+            key,
+            ttl: Ttl::Relative(_),
+            ..
+        }) => key,
+        _ => return None,
+    };
+
+    let deadline = cache.clock().unix_now() + cache.ttl(key)??;
+
+    Some(RespType::array(vec![
+        "PEXPIREAT".into(),
+        key.as_str().into(),
+        deadline.as_millis().to_string().into(),
+    ]))
+}
+
+/// Execute the command, which blocking commands may have to wait to be served for. Commands that
+/// don't block are done right away, with the XADD replicas get in place of one generating an ID.
+fn execute(command: &Command, shared: &Shared, client: &mut ClientState) -> Blocking {
+    // In a transaction clients can't block, so blocking commands only try once.
+    let blocked = (!client.deny_blocking).then_some(&shared.blocked);
+
+    match command {
+        Command::List(pop @ ListCommand::BlockingPop { .. }) => {
+            list::blocking_pop(pop, &shared.cache, blocked, client.id)
+        }
+        Command::Stream(read @ StreamCommand::Read { .. }) => {
+            stream::read(read, &shared.cache, blocked, client.id, client.protocol)
+        }
+        _ => {
+            let reply = execute_command(command, shared, client);
+            let write = match command {
+                Command::Stream(stream) => stream::propagated(stream, &reply),
+                _ => None,
+            };
+
+            Blocking::Done(Served { reply, write })
+        }
+    }
+}
+
+fn execute_command(command: &Command, shared: &Shared, client: &mut ClientState) -> RespType {
+    match command {
+        Command::Custom(name, args) => match shared.commands.get(name) {
+            Some(handler) => shared
+                .commands
+                .execute(handler.as_ref(), name, args, &shared.cache),
+            None => RedisError::UnknownCommand(name.clone(), args.clone()).to_resp(),
+        },
+        Command::Client(subcommand) => execute_client_command(subcommand, shared, client),
+        Command::Info(section) => info(section.as_deref(), shared),
+        Command::Replconf(ReplconfCommand::Options { listening_port }) => {
+            if let Some(port) = listening_port {
+                client.replica_port = *port;
+            }
+            RespType::ok()
+        }
+        // GETACK only means something coming from our master, which is handled by the link.
+        Command::Replconf(_) => RespType::ok(),
+        Command::Psync(..) => shared.replication.psync(shared, client),
+        Command::ReplicaOf(master) => {
+            shared.replication.set_master(master.clone());
+            RespType::ok()
+        }
+        Command::Multi => transaction::multi(client),
+        Command::Exec => transaction::exec(shared, client),
+        Command::Discard => transaction::discard(client),
+        Command::Subscribe(channels) => shared.pubsub.subscribe(client, channels),
+        Command::Unsubscribe(channels) => shared.pubsub.unsubscribe(client, channels),
+        Command::Publish(channel, message) => {
+            (shared.pubsub.publish(channel, message) as i64).into()
+        }
+        // A subscribed RESP2 client can't be sent a plain PONG since it would look like a message.
+        Command::Ping if !client.subscriptions.is_empty() && client.protocol == Protocol::Resp2 => {
+            RespType::array(vec!["pong".into(), "".into()])
+        }
+        Command::Save => match shared.persistence.save(&shared.cache) {
+            Ok(()) => RespType::ok(),
+            Err(err) => err.to_resp(),
+        },
+        Command::BgSave => match shared.persistence.bgsave(&shared.cache) {
+            Ok(()) => RespType::simple("Background saving started"),
+            Err(err) => err.to_resp(),
+        },
+        Command::BgRewriteAof => {
+            let Some(aof) = shared.aof.get() else {
+                return RedisError::Other("the append only file is disabled".to_string()).to_resp();
+            };
+
+            match aof.rewrite(shared.cache.snapshot().collect()) {
+                Ok(()) => RespType::simple("Background append only file rewriting started"),
+                Err(err) => err.to_resp(),
+            }
+        }
+        Command::Config(ConfigCommand::Get(patterns)) => shared.config.get(patterns),
+        Command::Config(ConfigCommand::Set(pairs)) => match shared.config.set(pairs) {
+            Ok(()) => RespType::ok(),
+            Err(err) => err.to_resp(),
+        },
+        Command::Command(command) => command::command_reply(command, &shared.commands),
+        command => command::execute_with_protocol(command, &shared.cache, &mut client.protocol),
+    }
+}
+
+/// Build the INFO reply. Without a section the default sections are included, `all` and
+/// `everything` include every section.
+fn info(section: Option<&str>, shared: &Shared) -> RespType {
+    let section = section.map(|s| s.to_lowercase());
+    let (default, all) = match section.as_deref() {
+        None | Some("default") => (true, false),
+        Some("all") | Some("everything") => (true, true),
+        Some(_) => (false, false),
+    };
+    let wants = |name: &str| section.as_deref() == Some(name);
+
+    let mut sections = Vec::new();
+    if default || wants("server") {
+        let uptime = shared.started.elapsed().as_secs();
+        sections.push(format!(
+            "# Server\r\nredis_version:{}\r\nredis_mode:{}\r\narch_bits:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{uptime}\r\nuptime_in_days:{}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            if shared.cluster_enabled { "cluster" } else { "standalone" },
+            usize::BITS,
+            std::process::id(),
+            shared.config.value("port").unwrap_or_default(),
+            uptime / 86400,
+        ));
+    }
+
+    if default || wants("clients") {
+        sections.push(format!(
+            "# Clients\r\nconnected_clients:{}\r\nblocked_clients:{}\r\n",
+            shared.clients.len(),
+            shared.blocked.len(),
+        ));
+    }
+
+    // Estimated by walking the keyspace since the memory used isn't tracked as keys change.
+    if default || wants("memory") {
+        let mut used_memory = 0;
+        shared.cache.scan(|_, _, bytes| used_memory += bytes);
+        sections.push(format!("# Memory\r\nused_memory:{used_memory}\r\n"));
+    }
+
+    if default || wants("persistence") {
+        let aof = shared
+            .aof
+            .get()
+            .map_or("aof_enabled:0\r\n".to_string(), AppendOnlyFile::info);
+        sections.push(shared.persistence.info() + &aof);
+    }
+
+    if default || wants("stats") {
+        sections.push(format!(
+            "{}total_connections_received:{}\r\n{}{}pubsub_channels:{}\r\n",
+            command::stats_info(&shared.cache),
+            shared.clients.next_id.load(Ordering::Relaxed),
+            shared.stats.stats_info(),
+            shared.io.stats_info(),
+            shared.pubsub.channels(),
+        ));
+    }
+
+    if default || wants("replication") {
+        sections.push(shared.replication.info());
+    }
+
+    if all || wants("commandstats") {
+        sections.push(shared.stats.commandstats_info());
+    }
+
+    if all || wants("latencystats") {
+        sections.push(shared.stats.latencystats_info());
+    }
+
+    if default || wants("errorstats") {
+        sections.push(shared.stats.errorstats_info());
+    }
+
+    if default || wants("keyspace") {
+        sections.push(command::keyspace_info(&shared.cache));
+    }
+
+    if default || wants("shards") {
+        let mut info = "# Shards\r\n".to_string();
+        for (shard, keys) in shared.cache.shard_keys().into_iter().enumerate() {
+            info += &format!("shard{shard}:keys={keys}\r\n");
+        }
+        sections.push(info);
+    }
+
+    RespType::verbatim("txt", sections.join("\r\n"))
+}
+
+fn execute_client_command(
+    subcommand: &ClientCommand,
+    shared: &Shared,
+    client: &mut ClientState,
+) -> RespType {
+    match subcommand {
+        ClientCommand::Id => (client.id as i64).into(),
+        ClientCommand::List => RespType::verbatim("txt", shared.clients.list()),
+        ClientCommand::GetName => match shared.clients.name(client.id) {
+            Some(name) if !name.is_empty() => name.into(),
+            _ => RespType::null(),
+        },
+        ClientCommand::SetName(name) => {
+            shared
+                .clients
+                .update(client.id, |info| info.name = name.clone());
+            RespType::ok()
+        }
+        ClientCommand::Kill(filter, legacy) => {
+            let killed = shared.clients.kill(filter, client.id);
+            // A blocked client only notices its connection was closed once it's woken up.
+            for id in &killed {
+                shared.blocked.unblock(*id);
+            }
+
+            match (legacy, killed.len()) {
+                (true, 0) => RedisError::Other("No such client".to_string()).to_resp(),
+                (true, _) => RespType::ok(),
+                (false, killed) => (killed as i64).into(),
+            }
+        }
+        ClientCommand::NoRateLimit(exempt) => {
+            client.rate_limit_exempt = *exempt;
+            RespType::ok()
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn dump_stream(stream: &std::net::TcpStream) {
+    let mut tmp = stream.try_clone().unwrap();
+    let mut received: Vec<u8> = vec![];
+    let mut rx_bytes = [0u8; 1024];
+    let bytes_read = tmp.read(&mut rx_bytes).unwrap();
+    received.extend_from_slice(&rx_bytes[..bytes_read]);
 
     println!("{}", std::str::from_utf8(&received).unwrap());
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::ValueType;
+    use crate::client::Client;
+    use crate::testing::{assert_next_reply, assert_reply, TestServer};
+
+    #[test]
+    fn test_ephemeral_port() {
+        let a = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let b = Server::builder().addr("127.0.0.1:0").build().unwrap();
+
+        assert_ne!(a.local_addr(), b.local_addr());
+        assert_ne!(a.local_addr().port(), 0);
+        assert!(Server::new(&a.local_addr().to_string()).is_err());
+    }
+
+    struct Double;
+
+    impl CommandHandler for Double {
+        fn arity(&self) -> i64 {
+            2
+        }
+
+        fn execute(&self, args: &[String], _: &Cache) -> RespType {
+            match args[0].parse::<i64>() {
+                Ok(n) => (n * 2).into(),
+                Err(err) => RedisError::from(err).to_resp(),
+            }
+        }
+    }
+
+    struct Panic;
+
+    impl CommandHandler for Panic {
+        fn arity(&self) -> i64 {
+            1
+        }
+
+        fn execute(&self, _: &[String], _: &Cache) -> RespType {
+            panic!("handler bug")
+        }
+    }
+
+    #[test]
+    fn test_register_command() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        server.register_command("DOUBLE", Double);
+        let handle = server.start();
+
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        assert_eq!(
+            client.command(&["double", "21"]).unwrap(),
+            RespType::Integer(42)
+        );
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let handle = server.start();
+
+        let pings = Arc::new(AtomicU64::new(0));
+        let counted = pings.clone();
+        server.heartbeat(Duration::from_millis(5), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        while pings.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The pings stop with the accept loops.
+        handle.shutdown();
+        handle.join();
+        thread::sleep(Duration::from_millis(20));
+        let stopped = pings.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pings.load(Ordering::SeqCst), stopped);
+    }
+
+    #[test]
+    fn test_panicking_command() {
+        let server = TestServer::start_with(|server| server.register_command("PANIC", Panic));
+        let mut client = server.client();
+
+        assert_reply(&mut client, &["PANIC"], b"-ERR internal error\r\n");
+        assert_reply(&mut client, &["PING"], b"+PONG\r\n");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .max_commands_per_sec(3)
+            .build()
+            .unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_reply(&mut client, &["PING"], b"+PONG\r\n");
+        assert_reply(&mut client, &["PING"], b"+PONG\r\n");
+        assert_reply(&mut client, &["CLIENT", "NO-RATELIMIT", "on"], b"+OK\r\n");
+        for _ in 0..5 {
+            assert_reply(&mut client, &["PING"], b"+PONG\r\n");
+        }
+
+        assert_reply(&mut client, &["CLIENT", "NO-RATELIMIT", "off"], b"+OK\r\n");
+        assert_reply(&mut client, &["PING"], b"-ERR rate limit exceeded\r\n");
+
+        // A command rejected in a transaction discards it.
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["SET", "k", "v"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["SET", "k", "v"], b"+QUEUED\r\n");
+        assert_reply(
+            &mut client,
+            &["SET", "k", "v"],
+            b"-ERR rate limit exceeded\r\n",
+        );
+        thread::sleep(Duration::from_millis(400));
+        assert_reply(
+            &mut client,
+            &["EXEC"],
+            b"-EXECABORT Transaction discarded because of previous errors.\r\n",
+        );
+        assert_eq!(handle.server.shared.cache.get("k"), None);
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .idle_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        // Each command resets the timeout.
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(30));
+            assert_reply(&mut client, &["PING"], b"+PONG\r\n");
+        }
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(client.command(&["PING"]).is_err());
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_max_key_and_value_size() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .max_key_size(4)
+            .max_value_size(8)
+            .build()
+            .unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_reply(&mut client, &["SET", "abcd", "12345678"], b"+OK\r\n");
+        assert_reply(
+            &mut client,
+            &["SET", "abcde", "v"],
+            b"-ERR key is too large, the limit is 4 bytes\r\n",
+        );
+
+        // Only the header of the oversized bulk string is sent, it's rejected without waiting for
+        // the data and the connection is closed.
+        client
+            .send_raw(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$9\r\n")
+            .unwrap();
+        assert_eq!(
+            client.read_reply().unwrap(),
+            RespType::SimpleError("ERR Protocol error: size 9 exceeds the limit of 8".into())
+        );
+        assert!(client.read_reply().is_err());
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_xread_block() {
+        let server = TestServer::start();
+        let mut reader = server.client();
+        let mut writer = server.client();
+
+        assert_reply(
+            &mut writer,
+            &["XADD", "s", "1-1", "f", "v"],
+            b"$3\r\n1-1\r\n",
+        );
+        reader
+            .send(&["XREAD", "BLOCK", "0", "STREAMS", "s", "$"])
+            .unwrap();
+
+        // The reader is served by the next entry added by another client.
+        while server.server().shared.blocked.len() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_reply(
+            &mut writer,
+            &["XADD", "s", "2-0", "f", "new"],
+            b"$3\r\n2-0\r\n",
+        );
+
+        assert_eq!(
+            reader.read_reply().unwrap(),
+            RespType::array(vec![RespType::array(vec![
+                RespType::bulk("s"),
+                RespType::array(vec![RespType::array(vec![
+                    RespType::bulk("2-0"),
+                    RespType::array(vec![RespType::bulk("f"), RespType::bulk("new")]),
+                ])]),
+            ])])
+        );
+
+        assert_reply(
+            &mut reader,
+            &["XREAD", "BLOCK", "10", "STREAMS", "s", "$"],
+            b"$-1\r\n",
+        );
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let server = TestServer::start();
+        let mut subscriber = server.client();
+        let mut publisher = server.client();
+
+        assert_reply(&mut publisher, &["SET", "k", "v"], b"+OK\r\n");
+        subscriber.send(&["GET", "k"]).unwrap();
+        subscriber.send(&["SUBSCRIBE", "a", "b"]).unwrap();
+        assert_eq!(subscriber.read_reply().unwrap(), RespType::bulk("v"));
+        for (channel, count) in [("a", 1), ("b", 2)] {
+            assert_eq!(
+                subscriber.read_reply().unwrap(),
+                RespType::array(vec![
+                    RespType::bulk("subscribe"),
+                    RespType::bulk(channel),
+                    count.into()
+                ])
+            );
+        }
+
+        assert_reply(&mut publisher, &["PUBLISH", "a", "hello"], b":1\r\n");
+        assert_reply(&mut publisher, &["PUBLISH", "c", "hello"], b":0\r\n");
+        assert_eq!(
+            subscriber.read_reply().unwrap(),
+            RespType::array(vec![
+                RespType::bulk("message"),
+                RespType::bulk("a"),
+                RespType::bulk("hello"),
+            ])
+        );
+
+        // Only subscription commands and PING are allowed while subscribed.
+        assert_reply(
+            &mut subscriber,
+            &["GET", "k"],
+            b"-ERR Can't execute 'get': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context\r\n",
+        );
+        assert_reply(
+            &mut subscriber,
+            &["PING"],
+            b"*2\r\n$4\r\npong\r\n$0\r\n\r\n",
+        );
+
+        // Commands that fail to parse are replied to in order too.
+        subscriber
+            .send_raw(b"*1\r\n$3\r\nFOO\r\n*1\r\n$7\r\nPUBLISH\r\n*1\r\n$4\r\nPING\r\n")
+            .unwrap();
+        assert_next_reply(
+            &mut subscriber,
+            b"-ERR unknown command 'FOO', with args beginning with: \r\n",
+        );
+        assert_next_reply(
+            &mut subscriber,
+            b"-ERR wrong number of arguments for 'publish' command\r\n",
+        );
+        assert_next_reply(&mut subscriber, b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+
+        subscriber.send(&["UNSUBSCRIBE"]).unwrap();
+        subscriber.read_reply().unwrap();
+        subscriber.read_reply().unwrap();
+        assert_reply(&mut subscriber, &["GET", "k"], b"$1\r\nv\r\n");
+
+        // Disconnected subscribers are removed.
+        assert_reply(
+            &mut subscriber,
+            &["SUBSCRIBE", "a"],
+            b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n",
+        );
+        drop(subscriber);
+        while server.server().shared.pubsub.channels() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_reply(&mut publisher, &["PUBLISH", "a", "hello"], b":0\r\n");
+    }
+
+    #[test]
+    fn test_cross_slot() {
+        let mut sim = Server::builder().cluster_enabled(true).simulate();
+        let client = sim.connect();
+
+        let migrate = ["MIGRATE", "127.0.0.1", "1", "", "0", "10", "KEYS"];
+        sim.send(client, &[&migrate[..], &["foo", "bar"]].concat());
+        sim.send(client, &["SET", "foo", "v"]);
+        sim.run();
+
+        assert_eq!(
+            sim.reply(client),
+            Some(RespType::error(
+                "CROSSSLOT",
+                "Keys in request don't hash to the same slot"
+            ))
+        );
+        assert_eq!(sim.reply(client), Some(RespType::ok()));
+
+        sim.send(client, &["MULTI"]);
+        sim.send(client, &["SET", "foo", "1"]);
+        sim.send(client, &["SET", "bar", "1"]);
+        sim.send(client, &["EXEC"]);
+        sim.send(client, &["MULTI"]);
+        sim.send(client, &["SET", "{foo}.a", "1"]);
+        sim.send(client, &["SET", "{foo}.b", "1"]);
+        sim.send(client, &["EXEC"]);
+        sim.send(client, &["GET", "bar"]);
+        sim.run();
+
+        let queued = [
+            RespType::ok(),
+            RespType::simple("QUEUED"),
+            RespType::simple("QUEUED"),
+        ];
+        for reply in &queued {
+            assert_eq!(sim.reply(client).as_ref(), Some(reply));
+        }
+        assert_eq!(
+            sim.reply(client),
+            Some(RespType::error(
+                "CROSSSLOT",
+                "Keys in request don't hash to the same slot"
+            ))
+        );
+        for reply in &queued {
+            assert_eq!(sim.reply(client).as_ref(), Some(reply));
+        }
+        assert_eq!(
+            sim.reply(client),
+            Some(RespType::array(vec![RespType::ok(), RespType::ok()]))
+        );
+        assert_eq!(sim.reply(client), Some(RespType::null()));
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut sim = Server::builder().simulate();
+        let (a, b) = (sim.connect(), sim.connect());
+
+        sim.send(a, &["EXEC"]);
+        sim.send(a, &["MULTI"]);
+        sim.send(a, &["MULTI"]);
+        sim.send(a, &["SET", "k", "1"]);
+        sim.send(b, &["SET", "k", "0"]);
+        sim.send(a, &["GET", "k"]);
+        sim.send(a, &["BLPOP", "list", "0"]);
+        sim.send(a, &["RPUSH", "k", "x"]);
+        sim.send(b, &["GET", "k"]);
+        sim.send(a, &["EXEC"]);
+        sim.send(b, &["GET", "k"]);
+        sim.run();
+
+        assert_eq!(
+            sim.reply(a),
+            Some(RespType::error("ERR", "EXEC without MULTI"))
+        );
+        assert_eq!(sim.reply(a), Some(RespType::ok()));
+        assert_eq!(
+            sim.reply(a),
+            Some(RespType::error("ERR", "MULTI calls can not be nested"))
+        );
+        for _ in 0..4 {
+            assert_eq!(sim.reply(a), Some(RespType::simple("QUEUED")));
+        }
+
+        // Nothing runs until EXEC, and errors are replied in place of the failed command. Blocking
+        // commands don't block in a transaction.
+        assert_eq!(sim.reply(b), Some(RespType::ok()));
+        assert_eq!(sim.reply(b), Some("0".into()));
+        assert_eq!(
+            sim.reply(a),
+            Some(RespType::array(vec![
+                RespType::ok(),
+                "1".into(),
+                RespType::null(),
+                RedisError::WrongType.to_resp(),
+            ]))
+        );
+        assert_eq!(sim.reply(b), Some("1".into()));
+
+        // A command that can't be queued discards the transaction.
+        sim.send(a, &["MULTI"]);
+        sim.send(a, &["SET", "k", "2"]);
+        sim.send(a, &["GET"]);
+        sim.send(a, &["EXEC"]);
+        sim.send(a, &["MULTI"]);
+        sim.send(a, &["SET", "k", "3"]);
+        sim.send(a, &["DISCARD"]);
+        sim.send(a, &["DISCARD"]);
+        sim.send(a, &["GET", "k"]);
+        sim.run();
+
+        let replies = std::iter::from_fn(|| sim.reply(a)).collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            vec![
+                RespType::ok(),
+                RespType::simple("QUEUED"),
+                RedisError::WrongArity("get".to_string()).to_resp(),
+                RespType::error(
+                    "EXECABORT",
+                    "Transaction discarded because of previous errors."
+                ),
+                RespType::ok(),
+                RespType::simple("QUEUED"),
+                RespType::ok(),
+                RespType::error("ERR", "DISCARD without MULTI"),
+                "1".into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiple_acceptors() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .acceptors(4)
+            .build()
+            .unwrap();
+        let handle = server.start();
+
+        let mut clients = (0..20)
+            .map(|_| Client::connect(handle.local_addr()).unwrap())
+            .collect::<Vec<_>>();
+
+        for client in &mut clients {
+            assert_reply(client, &["PING"], b"+PONG\r\n");
+        }
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_command_hooks() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let executed = Arc::new(Mutex::new(Vec::new()));
+
+        server.before_command(|_, command| match command {
+            Command::Echo(_) => Err(RedisError::Other("ECHO is disabled".into())),
+            _ => Ok(()),
+        });
+
+        let recorder = executed.clone();
+        server.after_command(move |command, reply, _| {
+            recorder
+                .lock()
+                .unwrap()
+                .push(format!("{command:?} {reply:?}"))
+        });
+
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_eq!(
+            client.command(&["ECHO", "hi"]).unwrap(),
+            RespType::error("ERR", "ECHO is disabled")
+        );
+        assert_eq!(client.command(&["PING"]).unwrap(), RespType::simple("PONG"));
+        assert_eq!(
+            *executed.lock().unwrap(),
+            vec![r#"Ping SimpleString("PONG")"#]
+        );
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_client_commands() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let handle = server.start();
+        let mut a = Client::connect(handle.local_addr()).unwrap();
+        let mut b = Client::connect(handle.local_addr()).unwrap();
+
+        let RespType::Integer(a_id) = a.command(&["CLIENT", "ID"]).unwrap() else {
+            panic!("expected integer ID");
+        };
+        let RespType::Integer(b_id) = b.command(&["client", "id"]).unwrap() else {
+            panic!("expected integer ID");
+        };
+        assert!(b_id > a_id);
+
+        assert_eq!(
+            a.command(&["CLIENT", "SETNAME", "first"]).unwrap(),
+            RespType::ok()
+        );
+        assert_eq!(
+            a.command(&["CLIENT", "GETNAME"]).unwrap(),
+            RespType::from("first")
+        );
+
+        let RespType::BulkString(_, list) = a.command(&["CLIENT", "LIST"]).unwrap() else {
+            panic!("expected bulk string");
+        };
+        let list = String::from_utf8_lossy(&list).into_owned();
+        assert!(list.contains(&format!("id={a_id} ")));
+        assert!(list.contains("name=first"));
+        assert!(list.contains(&format!("id={b_id} ")));
+
+        assert_eq!(
+            a.command(&["CLIENT", "KILL", "ID", &b_id.to_string()])
+                .unwrap(),
+            RespType::Integer(1)
+        );
+        assert!(b.command(&["PING"]).is_err());
+        assert_eq!(
+            a.command(&["CLIENT", "KILL", "127.0.0.1:1"]).unwrap(),
+            RespType::error("ERR", "No such client")
+        );
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_load() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+
+        let mut data = Vec::new();
+        for command in [
+            &["SET", "a", "1"][..],
+            &["GET"],
+            &["SET", "b", "2", "EX", "100"],
+        ] {
+            let args = command.iter().map(|arg| RespType::from(*arg)).collect();
+            data.extend(RespType::array(args).to_bytes(Protocol::Resp2));
+        }
+
+        assert_eq!(
+            server.load(&data).unwrap(),
+            LoadSummary {
+                commands: 3,
+                errors: 1,
+            }
+        );
+        assert_eq!(server.cache().get("a"), Some("1".to_string()));
+        assert!(matches!(server.cache().ttl("b"), Some(Some(_))));
+
+        data.truncate(data.len() - 2);
+        assert!(server.load(&data).is_err());
+    }
+
+    #[test]
+    fn test_save_and_restart() {
+        let dir = std::env::temp_dir().join(format!("save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let builder = Server::builder().addr("127.0.0.1:0").dir(&dir);
+
+        let server = builder.clone().build().unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_reply(&mut client, &["SET", "a", "1"], b"+OK\r\n");
+        assert_reply(&mut client, &["SAVE"], b"+OK\r\n");
+        assert_reply(&mut client, &["SET", "b", "2", "EX", "100"], b"+OK\r\n");
+        assert_reply(&mut client, &["BGSAVE"], b"+Background saving started\r\n");
+
+        let saving = |client: &mut Client| {
+            let RespType::BulkString(_, info) = client.command(&["INFO", "persistence"]).unwrap()
+            else {
+                panic!("expected bulk string");
+            };
+            String::from_utf8_lossy(&info).contains("rdb_bgsave_in_progress:1")
+        };
+        while saving(&mut client) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        handle.shutdown();
+        handle.join();
+
+        let server = builder.build().unwrap();
+        assert_eq!(server.cache().get("a"), Some("1".to_string()));
+        assert!(matches!(server.cache().ttl("b"), Some(Some(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aof_and_restart() {
+        let dir = std::env::temp_dir().join(format!("aof-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let builder = Server::builder()
+            .addr("127.0.0.1:0")
+            .dir(&dir)
+            .appendonly(true)
+            .appendfsync(AppendFsync::Always);
+        let restart = |handle: ServerHandle| {
+            handle.shutdown();
+            handle.join();
+            builder.clone().build().unwrap()
+        };
+
+        let server = builder.clone().build().unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_reply(&mut client, &["SET", "a", "1"], b"+OK\r\n");
+        assert_reply(&mut client, &["INCR", "a"], b":2\r\n");
+        assert_reply(&mut client, &["SET", "b", "2", "EX", "100"], b"+OK\r\n");
+        assert_reply(&mut client, &["RPUSH", "l", "x", "y"], b":2\r\n");
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["HSET", "h", "f", "v"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["DEL", "l"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["EXEC"], b"*2\r\n:1\r\n:1\r\n");
+
+        let server = restart(handle);
+        let ttl = server.cache().ttl("b").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        assert_reply(&mut client, &["GET", "a"], b"$1\r\n2\r\n");
+        assert_reply(&mut client, &["HGET", "h", "f"], b"$1\r\nv\r\n");
+        assert_reply(&mut client, &["EXISTS", "l"], b":0\r\n");
+
+        // The rewritten file only holds the keys that are left.
+        assert_reply(&mut client, &["RPUSH", "l", "z"], b":1\r\n");
+        let size = || std::fs::metadata(dir.join("appendonly.aof")).unwrap().len();
+        let before = size();
+        assert_reply(
+            &mut client,
+            &["BGREWRITEAOF"],
+            b"+Background append only file rewriting started\r\n",
+        );
+        while size() >= before {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_reply(&mut client, &["SET", "c", "3"], b"+OK\r\n");
+
+        let server = restart(handle);
+        let cache = server.cache();
+        assert_eq!(cache.get("a"), Some("2".to_string()));
+        assert_eq!(cache.get("c"), Some("3".to_string()));
+        assert!(matches!(cache.ttl("b"), Some(Some(_))));
+        assert_eq!(cache.value_type("l"), Some(ValueType::List));
+        assert_eq!(cache.value_type("h"), Some(ValueType::Hash));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aof_order() {
+        let dir = std::env::temp_dir().join(format!("aof-order-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let builder = Server::builder()
+            .addr("127.0.0.1:0")
+            .dir(&dir)
+            .appendonly(true);
+
+        // Pushes racing on a key must be logged in the order they were applied.
+        let handle = builder.clone().build().unwrap().start();
+        race_pushes(&handle, "k");
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        let range = ["LRANGE", "k", "0", "-1"];
+        let pushed = client.command(&range).unwrap();
+        handle.shutdown();
+        handle.join();
+
+        let handle = builder.build().unwrap().start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        assert_eq!(client.command(&range).unwrap(), pushed);
+        handle.shutdown();
+        handle.join();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_served_pop_in_transaction() {
+        let dir = std::env::temp_dir().join(format!("aof-pop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let handle = Server::builder()
+            .addr("127.0.0.1:0")
+            .dir(&dir)
+            .appendonly(true)
+            .appendfsync(AppendFsync::Always)
+            .build()
+            .unwrap()
+            .start();
+
+        let mut popper = Client::connect(handle.local_addr()).unwrap();
+        popper.send(&["BLPOP", "list", "0"]).unwrap();
+        while handle.server.shared.blocked.len() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The pop served by a push in a transaction is logged as part of it.
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["RPUSH", "list", "a"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["SET", "k", "v"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["EXEC"], b"*2\r\n:1\r\n+OK\r\n");
+        assert_eq!(
+            popper.read_reply().unwrap(),
+            RespType::array(vec!["list".into(), "a".into()])
+        );
+
+        let mut parser = RespParser::new();
+        parser.feed(&std::fs::read(dir.join("appendonly.aof")).unwrap());
+        let mut commands = Vec::new();
+        while let Some(RespType::Array(args)) = parser.next_frame().unwrap() {
+            let RespType::BulkString(_, name) = &args[0] else {
+                panic!("unexpected command {args:?}");
+            };
+            commands.push(String::from_utf8_lossy(name).to_uppercase());
+        }
+        assert_eq!(commands, ["MULTI", "RPUSH", "LPOP", "SET", "EXEC"]);
+
+        handle.shutdown();
+        handle.join();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replication() {
+        let master = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let master = master.start();
+        let mut client = Client::connect(master.local_addr()).unwrap();
+        assert_reply(&mut client, &["SET", "before", "1"], b"+OK\r\n");
+        assert_reply(&mut client, &["HSET", "hash", "f", "v"], b":1\r\n");
+
+        let replica = Server::builder()
+            .addr("127.0.0.1:0")
+            .replicaof("127.0.0.1", master.local_addr().port())
+            .build()
+            .unwrap();
+        let replica = replica.start();
+        let mut replica_client = Client::connect(replica.local_addr()).unwrap();
+
+        let info = |client: &mut Client| {
+            let RespType::BulkString(_, info) = client.command(&["INFO", "replication"]).unwrap()
+            else {
+                panic!("expected bulk string");
+            };
+            String::from_utf8_lossy(&info).into_owned()
+        };
+        let wait_for = |client: &mut Client, f: &dyn Fn(&mut Client) -> bool| {
+            for _ in 0..500 {
+                if f(client) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("timed out waiting for the replica");
+        };
+
+        wait_for(&mut replica_client, &|client| {
+            info(client).contains("master_link_status:up")
+        });
+        assert!(info(&mut replica_client).contains("role:slave\r\n"));
+        assert!(info(&mut client).contains("role:master\r\nconnected_slaves:1\r\n"));
+        assert_reply(&mut replica_client, &["GET", "before"], b"$1\r\n1\r\n");
+        assert_reply(&mut replica_client, &["HGET", "hash", "f"], b"$1\r\nv\r\n");
+
+        // Writes made after the snapshot are propagated, and only the master accepts them.
+        assert_reply(&mut client, &["SET", "after", "2"], b"+OK\r\n");
+        wait_for(&mut replica_client, &|client| {
+            client.command(&["GET", "after"]).unwrap() == RespType::from("2")
+        });
+        assert_reply(
+            &mut replica_client,
+            &["SET", "k", "v"],
+            b"-READONLY You can't write against a read only replica.\r\n",
+        );
+
+        // A blocking pop reaches the replica as the pop it turned into, after the push serving it.
+        let mut popper = Client::connect(master.local_addr()).unwrap();
+        popper.send(&["BLPOP", "list", "0"]).unwrap();
+        while master.server.shared.blocked.len() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_reply(&mut client, &["RPUSH", "list", "a", "b"], b":2\r\n");
+        assert_eq!(
+            popper.read_reply().unwrap(),
+            RespType::array(vec!["list".into(), "a".into()])
+        );
+        wait_for(&mut replica_client, &|client| {
+            client.command(&["LRANGE", "list", "0", "-1"]).unwrap()
+                == RespType::array(vec!["b".into()])
+        });
+
+        // Transactions reach the replica as transactions.
+        client.send(&["MULTI"]).unwrap();
+        client.send(&["SET", "in-multi", "1"]).unwrap();
+        client.send(&["EXEC"]).unwrap();
+        for _ in 0..3 {
+            client.read_reply().unwrap();
+        }
+        wait_for(&mut replica_client, &|client| {
+            client.command(&["GET", "in-multi"]).unwrap() == RespType::from("1")
+        });
+
+        // The replica acknowledges its offset every second without being asked.
+        wait_for(&mut client, &|client| {
+            let info = info(client);
+            let offset = info
+                .lines()
+                .find_map(|line| line.strip_prefix("master_repl_offset:"))
+                .unwrap();
+            info.contains(&format!(",offset={offset}\r\n"))
+        });
+
+        // Once promoted the replica keeps its data and accepts writes.
+        assert_reply(&mut replica_client, &["REPLICAOF", "NO", "ONE"], b"+OK\r\n");
+        assert!(info(&mut replica_client).contains("role:master\r\n"));
+        assert_reply(&mut replica_client, &["SET", "k", "v"], b"+OK\r\n");
+        assert_reply(&mut replica_client, &["GET", "after"], b"$1\r\n2\r\n");
+
+        replica.shutdown();
+        replica.join();
+        master.shutdown();
+        master.join();
+    }
+
+    /// Push to `key` from several clients at once. Every other write pauses before it's propagated
+    /// to give the others a chance to overtake it.
+    fn race_pushes(handle: &ServerHandle, key: &str) {
+        let paused = AtomicBool::new(false);
+        handle.server.shared.cache.on_write(move |_| {
+            if !paused.fetch_xor(true, Ordering::Relaxed) {
+                thread::sleep(Duration::from_micros(200));
+            }
+        });
+
+        let addr = handle.local_addr();
+        let writers = (0..4)
+            .map(|writer| {
+                let key = key.to_string();
+                thread::spawn(move || {
+                    let mut client = Client::connect(addr).unwrap();
+                    for i in 0..50 {
+                        let value = format!("{writer}-{i}");
+                        let reply = client.command(&["RPUSH", &key, &value]).unwrap();
+                        assert!(matches!(reply, RespType::Integer(_)), "{reply:?}");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replication_order() {
+        let master = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let master = master.start();
+        let replica = Server::builder()
+            .addr("127.0.0.1:0")
+            .replicaof("127.0.0.1", master.local_addr().port())
+            .build()
+            .unwrap();
+        let replica = replica.start();
+        let mut replica_client = Client::connect(replica.local_addr()).unwrap();
+
+        let wait_for = |client: &mut Client, key: &str, value: RespType| {
+            for _ in 0..500 {
+                if client.command(&["GET", key]).unwrap() == value {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("timed out waiting for the replica");
+        };
+
+        let mut client = Client::connect(master.local_addr()).unwrap();
+        assert_reply(&mut client, &["SET", "synced", "1"], b"+OK\r\n");
+        wait_for(&mut replica_client, "synced", "1".into());
+
+        // Pushes racing on a key must reach the replica in the order they were applied.
+        race_pushes(&master, "k");
+        assert_reply(&mut client, &["SET", "done", "1"], b"+OK\r\n");
+        wait_for(&mut replica_client, "done", "1".into());
+        let range = ["LRANGE", "k", "0", "-1"];
+        assert_eq!(
+            replica_client.command(&range).unwrap(),
+            client.command(&range).unwrap()
+        );
+
+        replica.shutdown();
+        replica.join();
+        master.shutdown();
+        master.join();
+    }
+
+    #[test]
+    fn test_psync_unsupported_type() {
+        let master = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let master = master.start();
+        let mut client = Client::connect(master.local_addr()).unwrap();
+
+        // A snapshot without the stream would leave the replica silently missing it.
+        assert_reply(
+            &mut client,
+            &["XADD", "s", "1-1", "f", "v"],
+            b"$3\r\n1-1\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["PSYNC", "?", "-1"],
+            b"-ERR values of type stream can't be serialized\r\n",
+        );
+        assert!(master
+            .server
+            .shared
+            .replication
+            .info()
+            .contains("connected_slaves:0"));
+
+        master.shutdown();
+        master.join();
+    }
+
+    #[test]
+    fn test_info_commandstats() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+
+        client.command(&["GET", "k"]).unwrap();
+        client.command(&["GET"]).unwrap();
+        client.command(&["NOPE"]).unwrap();
+        client.command(&["SET", "k", "v"]).unwrap();
+
+        let RespType::BulkString(_, info) = client.command(&["INFO", "everything"]).unwrap() else {
+            panic!("expected bulk string");
+        };
+        let info = String::from_utf8_lossy(&info).into_owned();
+
+        assert!(info.contains("cmdstat_get:calls=1,"));
+        assert!(info.contains("rejected_calls=1,failed_calls=0\r\n"));
+        assert!(info.contains("errorstat_ERR:count=2\r\n"));
+        assert!(info.contains("# Keyspace\r\ndb0:keys=1,expires=0,avg_ttl=0\r\n"));
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_config_get() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        assert_reply(
+            &mut client,
+            &["CONFIG", "GET", "save"],
+            b"*2\r\n$4\r\nsave\r\n$0\r\n\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["CONFIG", "GET", "append*", "nope"],
+            b"*6\r\n$14\r\nappendfilename\r\n$14\r\nappendonly.aof\r\n$11\r\nappendfsync\r\n$8\r\neverysec\r\n$10\r\nappendonly\r\n$2\r\nno\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["CONFIG", "GET"],
+            b"-ERR wrong number of arguments for 'config|get' command\r\n",
+        );
+    }
+
+    #[test]
+    fn test_config_set() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        assert_reply(
+            &mut client,
+            &["CONFIG", "SET", "TIMEOUT", "300"],
+            b"+OK\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["CONFIG", "GET", "timeout"],
+            b"*2\r\n$7\r\ntimeout\r\n$3\r\n300\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["CONFIG", "SET", "port", "1"],
+            b"-ERR CONFIG SET failed (possibly related to argument 'port') - can't set immutable config\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["CONFIG", "SET", "timeout"],
+            b"-ERR wrong number of arguments for 'config|set' command\r\n",
+        );
+    }
+
+    #[test]
+    fn test_config_set_idle_timeout() {
+        let server = TestServer::start();
+        let mut idle = server.client();
+        let mut client = server.client();
+
+        // Connections that are already open get the new timeout the next time they wait.
+        assert_reply(&mut client, &["CONFIG", "SET", "timeout", "1"], b"+OK\r\n");
+        assert_reply(&mut idle, &["PING"], b"+PONG\r\n");
+        thread::sleep(Duration::from_millis(1500));
+        assert!(idle.command(&["PING"]).is_err());
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .shards(2)
+            .build()
+            .unwrap();
+        let handle = server.start();
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        let _other = Client::connect(handle.local_addr()).unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            client.command(&["SET", key, "v"]).unwrap();
+        }
+
+        let RespType::BulkString(_, info) = client.command(&["INFO"]).unwrap() else {
+            panic!("expected bulk string");
+        };
+        let info = String::from_utf8_lossy(&info).into_owned();
+        let field = |name: &str| {
+            info.lines()
+                .find_map(|line| line.strip_prefix(&format!("{name}:")))
+                .unwrap_or_else(|| panic!("missing {name}"))
+                .to_string()
+        };
+
+        assert_eq!(field("tcp_port"), handle.local_addr().port().to_string());
+        assert_eq!(field("connected_clients"), "2");
+        assert_eq!(field("total_connections_received"), "2");
+        assert_eq!(field("total_commands_processed"), "4");
+        assert!(field("used_memory").parse::<usize>().unwrap() > 0);
+
+        let shard_keys = ["shard0", "shard1"].map(|shard| {
+            field(shard)
+                .strip_prefix("keys=")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        });
+        assert_eq!(shard_keys.iter().sum::<u64>(), 4);
+
+        assert_reply(&mut client, &["DBSIZE"], b":4\r\n");
+
+        handle.shutdown();
+        handle.join();
+    }
+
+    #[test]
+    fn test_start_and_shutdown() {
+        let server = Server::builder().addr("127.0.0.1:0").build().unwrap();
+        let handle = server.start();
+
+        let mut client = Client::connect(handle.local_addr()).unwrap();
+        assert_eq!(client.command(&["PING"]).unwrap(), RespType::simple("PONG"));
+
+        handle.shutdown();
+        handle.join();
+
+        // The connection is closed and the listener no longer accepts new ones.
+        assert!(client.read_reply().is_err());
+        assert!(TcpStream::connect(server.local_addr()).is_err());
+    }
+}
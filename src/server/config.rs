@@ -0,0 +1,163 @@
+//! Configuration parameters, read by CONFIG GET and changed by CONFIG SET.
+//!
+//! Every parameter is reported, but only the ones the server can apply while running may be set.
+//! The rest are fixed when the server is built.
+
+use crate::{error::RedisError, glob::glob_match, resp_type::RespType};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+/// The parameters CONFIG SET may change.
+const MUTABLE: &[&str] = &["timeout"];
+
+#[derive(Debug)]
+pub(super) struct Config {
+    params: RwLock<BTreeMap<String, String>>,
+    /// Idle clients are disconnected after this many milliseconds, or never if zero. Kept apart
+    /// from the `timeout` parameter, which is in seconds, since the builder allows any duration.
+    idle_timeout: AtomicU64,
+}
+
+impl Config {
+    pub(super) fn new<'a>(
+        params: impl IntoIterator<Item = (&'a str, String)>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let mut params = params
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect::<BTreeMap<_, _>>();
+        params.insert(
+            "timeout".to_string(),
+            idle_timeout
+                .map_or(0, |timeout| timeout.as_secs())
+                .to_string(),
+        );
+
+        Self {
+            params: RwLock::new(params),
+            idle_timeout: AtomicU64::new(
+                idle_timeout.map_or(0, |timeout| timeout.as_millis() as u64),
+            ),
+        }
+    }
+
+    /// The value of the parameter `name`.
+    pub(super) fn value(&self, name: &str) -> Option<String> {
+        self.params.read().unwrap().get(name).cloned()
+    }
+
+    pub(super) fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+
+    /// Every parameter matching any of the glob patterns and its value.
+    pub(super) fn get(&self, patterns: &[String]) -> RespType {
+        RespType::map(
+            self.params
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(name, _)| patterns.iter().any(|p| glob_match(&p.to_lowercase(), name)))
+                .map(|(name, value)| (name.as_str().into(), value.as_str().into()))
+                .collect(),
+        )
+    }
+
+    /// Set each parameter to the value following it. Nothing is changed unless every parameter
+    /// exists, may be set and gets a valid value.
+    pub(super) fn set(&self, pairs: &[(String, String)]) -> Result<(), RedisError> {
+        let mut params = self.params.write().unwrap();
+        let failed = |name: &str, reason: &str| {
+            RedisError::Other(format!(
+                "CONFIG SET failed (possibly related to argument '{name}') - {reason}"
+            ))
+        };
+
+        let mut idle_timeout = None;
+        for (name, value) in pairs {
+            if !params.contains_key(name) {
+                return Err(RedisError::Other(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{name}'"
+                )));
+            }
+
+            if !MUTABLE.contains(&name.as_str()) {
+                return Err(failed(name, "can't set immutable config"));
+            }
+
+            if name == "timeout" {
+                let seconds = value
+                    .parse::<u64>()
+                    .map_err(|_| failed(name, "argument couldn't be parsed into an integer"))?;
+                idle_timeout = Some(seconds.saturating_mul(1000));
+            }
+        }
+
+        for (name, value) in pairs {
+            params.insert(name.clone(), value.clone());
+        }
+        if let Some(millis) = idle_timeout {
+            self.idle_timeout.store(millis, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_set() {
+        let config = Config::new([("port", "6379".to_string())], None);
+        assert_eq!(config.value("timeout").as_deref(), Some("0"));
+        assert_eq!(config.idle_timeout(), None);
+
+        config.set(&pairs(&[("timeout", "30")])).unwrap();
+        assert_eq!(config.value("timeout").as_deref(), Some("30"));
+        assert_eq!(config.idle_timeout(), Some(Duration::from_secs(30)));
+
+        assert_eq!(
+            config
+                .set(&pairs(&[("nope", "1")]))
+                .unwrap_err()
+                .to_string(),
+            "Unknown option or number of arguments for CONFIG SET - 'nope'"
+        );
+        assert_eq!(
+            config
+                .set(&pairs(&[("port", "1")]))
+                .unwrap_err()
+                .to_string(),
+            "CONFIG SET failed (possibly related to argument 'port') - can't set immutable config"
+        );
+
+        // A single invalid value leaves every parameter unchanged.
+        assert!(config
+            .set(&pairs(&[("timeout", "0"), ("timeout", "x")]))
+            .is_err());
+        assert_eq!(config.value("timeout").as_deref(), Some("30"));
+
+        config.set(&pairs(&[("timeout", "0")])).unwrap();
+        assert_eq!(config.idle_timeout(), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Publish/subscribe. Clients subscribe to channels and every message published to a channel is
+//! pushed to its subscribers.
+//!
+//! Messages are published from other clients' threads, so a subscribed connection's output is
+//! written by a thread of its own which gets both the connection's replies and the messages
+//! published to it through its outbox, keeping them in order without interleaving.
+
+use super::{ClientState, Shared};
+use crate::{
+    error::RedisError,
+    resp_type::{Protocol, RespType},
+};
+use bytes::Bytes;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+/// Output for the thread writing a subscribed connection.
+pub(super) type Outbox = mpsc::Sender<Bytes>;
+
+#[derive(Debug)]
+struct Subscriber {
+    outbox: Outbox,
+    /// The protocol the client spoke when it subscribed, which messages are encoded with.
+    protocol: Protocol,
+}
+
+/// The subscribers of each channel by the ID of their connection.
+#[derive(Debug, Default)]
+pub(super) struct PubSub {
+    channels: Mutex<HashMap<String, HashMap<u64, Subscriber>>>,
+}
+
+impl PubSub {
+    /// Subscribe the client to `channels`, replying with a confirmation for each of them.
+    pub(super) fn subscribe(&self, client: &mut ClientState, channels: &[String]) -> RespType {
+        let Some(outbox) = client.outbox.clone() else {
+            return RedisError::Other("SUBSCRIBE requires a connection".to_string()).to_resp();
+        };
+
+        let mut subscribers = self.channels.lock().unwrap();
+        let confirmations = channels
+            .iter()
+            .map(|channel| {
+                subscribers.entry(channel.clone()).or_default().insert(
+                    client.id,
+                    Subscriber {
+                        outbox: outbox.clone(),
+                        protocol: client.protocol,
+                    },
+                );
+                client.subscriptions.insert(channel.clone());
+
+                confirmation("subscribe", channel.as_str().into(), client)
+            })
+            .collect();
+
+        RespType::array(confirmations)
+    }
+
+    /// Unsubscribe the client from `channels`, or from every channel if it's empty, replying with
+    /// a confirmation for each of them.
+    pub(super) fn unsubscribe(&self, client: &mut ClientState, channels: &[String]) -> RespType {
+        let mut channels = match channels {
+            [] => client.subscriptions.iter().cloned().collect(),
+            channels => channels.to_vec(),
+        };
+        channels.sort_unstable();
+
+        if channels.is_empty() {
+            return RespType::array(vec![confirmation("unsubscribe", RespType::null(), client)]);
+        }
+
+        let mut subscribers = self.channels.lock().unwrap();
+        let confirmations = channels
+            .into_iter()
+            .map(|channel| {
+                remove_subscriber(&mut subscribers, &channel, client.id);
+                client.subscriptions.remove(&channel);
+
+                confirmation("unsubscribe", channel.into(), client)
+            })
+            .collect();
+
+        RespType::array(confirmations)
+    }
+
+    /// Push `message` to every subscriber of `channel`, returning how many received it.
+    pub(super) fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut subscribers = self.channels.lock().unwrap();
+        let Some(channel_subscribers) = subscribers.get_mut(channel) else {
+            return 0;
+        };
+
+        let message = RespType::push(vec!["message".into(), channel.into(), message.into()]);
+        // A subscriber whose outbox is closed has disconnected.
+        channel_subscribers.retain(|_, subscriber| {
+            let data = message.to_bytes(subscriber.protocol);
+            subscriber.outbox.send(data.into()).is_ok()
+        });
+
+        let receivers = channel_subscribers.len();
+        if receivers == 0 {
+            subscribers.remove(channel);
+        }
+
+        receivers
+    }
+
+    /// Unsubscribe a disconnected client from every channel.
+    pub(super) fn remove_client(&self, id: u64) {
+        let mut subscribers = self.channels.lock().unwrap();
+        subscribers.retain(|_, channel_subscribers| {
+            channel_subscribers.remove(&id);
+            !channel_subscribers.is_empty()
+        });
+    }
+
+    /// Number of channels with at least one subscriber.
+    pub(super) fn channels(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+}
+
+fn remove_subscriber(
+    subscribers: &mut HashMap<String, HashMap<u64, Subscriber>>,
+    channel: &str,
+    id: u64,
+) {
+    if let Some(channel_subscribers) = subscribers.get_mut(channel) {
+        channel_subscribers.remove(&id);
+        if channel_subscribers.is_empty() {
+            subscribers.remove(channel);
+        }
+    }
+}
+
+/// The confirmation of a subscription change, with the number of channels the client is left
+/// subscribed to.
+fn confirmation(kind: &str, channel: RespType, client: &ClientState) -> RespType {
+    RespType::push(vec![
+        kind.into(),
+        channel,
+        (client.subscriptions.len() as i64).into(),
+    ])
+}
+
+/// Start the thread writing the client's connection, which from now on gets everything the
+/// connection is sent. Returns `None` if the client doesn't have a connection.
+pub(super) fn start_outbox(shared: &Shared, id: u64) -> Option<Outbox> {
+    let (mut stream, _) = shared.clients.connection(id)?;
+    let (sender, receiver) = mpsc::channel::<Bytes>();
+
+    thread::spawn(move || {
+        for data in receiver {
+            if let Err(err) = stream.write_all(&data) {
+                tracing::debug!(%err, "failed to write to subscriber");
+                break;
+            }
+        }
+    });
+
+    Some(sender)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let pubsub = PubSub::default();
+        let (outbox, messages) = mpsc::channel();
+        let mut client = ClientState {
+            id: 1,
+            outbox: Some(outbox),
+            ..Default::default()
+        };
+
+        pubsub.subscribe(&mut client, &["a".to_string(), "b".to_string()]);
+        assert_eq!(pubsub.channels(), 2);
+        assert_eq!(pubsub.publish("a", "hello"), 1);
+        assert_eq!(pubsub.publish("c", "hello"), 0);
+        assert_eq!(
+            messages.try_recv().unwrap(),
+            Bytes::from_static(b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$5\r\nhello\r\n")
+        );
+
+        assert_eq!(
+            pubsub.unsubscribe(&mut client, &[]),
+            RespType::array(vec![
+                RespType::push(vec!["unsubscribe".into(), "a".into(), 1.into()]),
+                RespType::push(vec!["unsubscribe".into(), "b".into(), 0.into()]),
+            ])
+        );
+        assert_eq!(pubsub.channels(), 0);
+        assert_eq!(pubsub.publish("a", "hello"), 0);
+
+        // Subscribers that disconnected are dropped when publishing.
+        pubsub.subscribe(&mut client, &["a".to_string()]);
+        drop(messages);
+        assert_eq!(pubsub.publish("a", "hello"), 0);
+        assert_eq!(pubsub.channels(), 0);
+    }
+}
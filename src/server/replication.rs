@@ -0,0 +1,495 @@
+//! Master-replica replication. A replica connects to its master with the same handshake as Redis,
+//! PING, REPLCONF and PSYNC, loads the RDB snapshot it's sent and then applies the stream of write
+//! commands that follows. A master queues every write command for each of its replicas, and each
+//! queue is written to the replica's connection by a thread of its own so a slow replica doesn't
+//! hold up clients.
+//!
+//! Only full resynchronizations are supported, a replica that reconnects always gets a new snapshot.
+
+use super::{process_frame, process_resp_type, ClientState, Shared};
+use crate::{
+    command::{Command, ReplconfCommand},
+    error::RedisError,
+    persistence,
+    resp_type::{Protocol, RespParser, RespType},
+};
+use bytes::Bytes;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How long to wait before reconnecting to the master after the link failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often a replica acknowledges its offset to the master without being asked, like Redis.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A replica connected to this server.
+#[derive(Debug)]
+struct Replica {
+    /// The ID of the replica's connection.
+    id: u64,
+    ip: String,
+    /// The port the replica listens on, from REPLCONF.
+    port: u16,
+    /// Write commands for the thread writing to the replica.
+    queue: mpsc::Sender<Bytes>,
+    /// The offset the replica last acknowledged.
+    ack_offset: u64,
+}
+
+/// The link to the master while this server is a replica.
+#[derive(Debug, Default)]
+struct Link {
+    master: Option<(String, u16)>,
+    /// Bumped whenever the master changes so a link to the previous one is abandoned.
+    generation: u64,
+    /// The connection to the master, kept to interrupt the link when the master changes.
+    stream: Option<TcpStream>,
+    up: bool,
+    shutdown: bool,
+}
+
+#[derive(Debug)]
+pub(super) struct Replication {
+    replid: Mutex<String>,
+    /// Bytes of write commands sent to replicas, or received from the master on a replica.
+    offset: AtomicU64,
+    replicas: Mutex<Vec<Replica>>,
+    /// Whether the server is a replica, checked by every write.
+    replica: AtomicBool,
+    link: Mutex<Link>,
+    link_changed: Condvar,
+}
+
+/// A full resynchronization to send to a replica after the reply to its PSYNC: the snapshot and
+/// the queue of writes made since it was taken.
+#[derive(Debug)]
+pub(super) struct FullSync {
+    rdb: Vec<u8>,
+    queue: mpsc::Receiver<Bytes>,
+    stream: TcpStream,
+}
+
+impl Replication {
+    pub(super) fn new(master: Option<(String, u16)>) -> Self {
+        Self {
+            replid: Mutex::new(new_replid()),
+            offset: AtomicU64::new(0),
+            replicas: Mutex::new(Vec::new()),
+            replica: AtomicBool::new(master.is_some()),
+            link: Mutex::new(Link {
+                master,
+                ..Default::default()
+            }),
+            link_changed: Condvar::new(),
+        }
+    }
+
+    pub(super) fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::Relaxed)
+    }
+
+    /// Queue a write command for every replica.
+    pub(super) fn propagate(&self, frame: &RespType) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if replicas.is_empty() {
+            return;
+        }
+
+        let data = Bytes::from(frame.to_bytes(Protocol::Resp2));
+        // A replica's offset follows the stream from its master.
+        if !self.is_replica() {
+            self.offset.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+
+        replicas.retain(|replica| replica.queue.send(data.clone()).is_ok());
+    }
+
+    /// Register the replica on the client's connection and take the snapshot to send it, returning
+    /// the reply to its PSYNC. The snapshot is sent once the reply is written.
+    pub(super) fn psync(&self, shared: &Shared, client: &mut ClientState) -> RespType {
+        let Some((stream, addr)) = shared.clients.connection(client.id) else {
+            return RedisError::Other("PSYNC requires a connection".to_string()).to_resp();
+        };
+
+        // Commands are queued from the moment the snapshot is taken, so the replica misses nothing.
+        let mut replicas = self.replicas.lock().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let now = shared.cache.clock().unix_now().as_millis() as u64;
+        let rdb = match persistence::encode(shared.cache.snapshot(), now) {
+            Ok(rdb) => rdb,
+            Err(err) => return err.to_resp(),
+        };
+
+        replicas.retain(|replica| replica.id != client.id);
+        replicas.push(Replica {
+            id: client.id,
+            ip: addr
+                .rsplit_once(':')
+                .map_or(addr.as_str(), |(ip, _)| ip)
+                .to_string(),
+            port: client.replica_port,
+            queue: sender,
+            ack_offset: 0,
+        });
+
+        client.sync = Some(FullSync {
+            rdb,
+            queue: receiver,
+            stream,
+        });
+
+        let replid = self.replid.lock().unwrap();
+        let offset = self.offset.load(Ordering::Relaxed);
+        RespType::simple(format!("FULLRESYNC {replid} {offset}"))
+    }
+
+    pub(super) fn ack(&self, id: u64, offset: u64) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+        }
+    }
+
+    /// Stop sending writes to a replica whose connection was closed.
+    pub(super) fn remove_replica(&self, id: u64) {
+        self.replicas
+            .lock()
+            .unwrap()
+            .retain(|replica| replica.id != id);
+    }
+
+    /// Replicate `master`, or become a master if `None`.
+    pub(super) fn set_master(&self, master: Option<(String, u16)>) {
+        let mut link = self.link.lock().unwrap();
+        if link.master == master {
+            return;
+        }
+
+        // A promoted replica starts a history of its own.
+        if master.is_none() {
+            *self.replid.lock().unwrap() = new_replid();
+        }
+
+        self.replica.store(master.is_some(), Ordering::Relaxed);
+        link.master = master;
+        link.generation += 1;
+        link.up = false;
+        if let Some(stream) = link.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        self.link_changed.notify_all();
+    }
+
+    /// Stop the link to the master for good.
+    pub(super) fn shutdown(&self) {
+        let mut link = self.link.lock().unwrap();
+        link.shutdown = true;
+        if let Some(stream) = link.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        self.link_changed.notify_all();
+    }
+
+    /// The replication section of INFO.
+    pub(super) fn info(&self) -> String {
+        let offset = self.offset.load(Ordering::Relaxed);
+        let mut info = "# Replication\r\n".to_string();
+
+        {
+            let link = self.link.lock().unwrap();
+            match &link.master {
+                Some((host, port)) => {
+                    let status = if link.up { "up" } else { "down" };
+                    info += &format!(
+                        "role:slave\r\n\
+                        master_host:{host}\r\n\
+                        master_port:{port}\r\n\
+                        master_link_status:{status}\r\n\
+                        slave_repl_offset:{offset}\r\n"
+                    );
+                }
+                None => info += "role:master\r\n",
+            }
+        }
+
+        let replicas = self.replicas.lock().unwrap();
+        info += &format!("connected_slaves:{}\r\n", replicas.len());
+        for (i, replica) in replicas.iter().enumerate() {
+            info += &format!(
+                "slave{i}:ip={},port={},state=online,offset={}\r\n",
+                replica.ip, replica.port, replica.ack_offset
+            );
+        }
+
+        info += &format!(
+            "master_replid:{}\r\nmaster_repl_offset:{offset}\r\n",
+            self.replid.lock().unwrap()
+        );
+
+        info
+    }
+}
+
+/// A random 40 character replication ID like the ones Redis uses.
+fn new_replid() -> String {
+    let state = RandomState::new();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut replid = (0..3)
+        .map(|i| {
+            let mut hasher = state.build_hasher();
+            hasher.write_u128(nanos);
+            hasher.write_u64(i);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect::<String>();
+    replid.truncate(40);
+
+    replid
+}
+
+/// Send the snapshot of a full resynchronization, then keep writing the replica's queue to it from
+/// a thread of its own. The thread stops when the replica is removed or its connection fails.
+pub(super) fn start_feed(sync: FullSync, writer: &mut impl Write) -> io::Result<()> {
+    // The snapshot is sent like a bulk string but without the trailing CRLF.
+    write!(writer, "${}\r\n", sync.rdb.len())?;
+    writer.write_all(&sync.rdb)?;
+    writer.flush()?;
+
+    let FullSync {
+        queue, mut stream, ..
+    } = sync;
+    thread::spawn(move || {
+        for data in queue {
+            if let Err(err) = stream.write_all(&data) {
+                tracing::debug!(%err, "failed to write to replica");
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Keep the server in sync with its master whenever it's a replica, reconnecting when the link
+/// fails, until the server shuts down.
+pub(super) fn run_link(shared: &Shared, listening_port: u16) {
+    let replication = &shared.replication;
+
+    loop {
+        let ((host, port), generation) = {
+            let mut link = replication.link.lock().unwrap();
+            loop {
+                if link.shutdown {
+                    return;
+                }
+
+                if let Some(master) = link.master.clone() {
+                    break (master, link.generation);
+                }
+
+                link = replication.link_changed.wait(link).unwrap();
+            }
+        };
+
+        match sync_with_master(shared, &host, port, generation, listening_port) {
+            Ok(()) => tracing::info!(%host, port, "master closed the replication link"),
+            Err(err) => tracing::warn!(%host, port, %err, "replication link failed"),
+        }
+
+        let mut link = replication.link.lock().unwrap();
+        if link.generation == generation {
+            link.up = false;
+            link.stream = None;
+
+            if !link.shutdown {
+                let _ = replication
+                    .link_changed
+                    .wait_timeout(link, RECONNECT_DELAY)
+                    .unwrap();
+            }
+        }
+    }
+}
+
+fn sync_with_master(
+    shared: &Shared,
+    host: &str,
+    port: u16,
+    generation: u64,
+    listening_port: u16,
+) -> Result<(), RedisError> {
+    let replication = &shared.replication;
+    let mut stream = TcpStream::connect((host, port))?;
+
+    {
+        let mut link = replication.link.lock().unwrap();
+        if link.shutdown || link.generation != generation {
+            return Ok(());
+        }
+
+        link.stream = Some(stream.try_clone()?);
+    }
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let listening_port = listening_port.to_string();
+    for command in [
+        &["PING"][..],
+        &["REPLCONF", "listening-port", &listening_port],
+        &["REPLCONF", "capa", "psync2"],
+    ] {
+        send(&mut stream, command)?;
+
+        let line = read_line(&mut reader)?;
+        if let Some(err) = line.strip_prefix('-') {
+            return Err(RedisError::Other(format!(
+                "master replied to {} with {err}",
+                command.join(" ")
+            )));
+        }
+    }
+
+    send(&mut stream, &["PSYNC", "?", "-1"])?;
+    let line = read_line(&mut reader)?;
+    let (replid, offset) = line
+        .strip_prefix("+FULLRESYNC ")
+        .and_then(|rest| rest.split_once(' '))
+        .and_then(|(replid, offset)| Some((replid.to_string(), offset.parse::<u64>().ok()?)))
+        .ok_or_else(|| RedisError::Other(format!("unexpected reply to PSYNC: {line}")))?;
+
+    // Masters send newlines to keep the connection alive while the snapshot is prepared.
+    let len = loop {
+        let line = read_line(&mut reader)?;
+        if !line.is_empty() {
+            break line
+                .strip_prefix('$')
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(|| RedisError::Other(format!("invalid snapshot header: {line}")))?;
+        }
+    };
+
+    let mut rdb = vec![0; len];
+    reader.read_exact(&mut rdb)?;
+
+    shared.cache.flush();
+    let keys = persistence::decode(&rdb, &shared.cache)?;
+    tracing::info!(keys, %replid, offset, "synced with master");
+
+    *replication.replid.lock().unwrap() = replid;
+    replication.offset.store(offset, Ordering::Relaxed);
+    replication.link.lock().unwrap().up = true;
+
+    // Whatever followed the snapshot in the same read is the start of the command stream.
+    let mut parser = RespParser::with_limits(shared.proto_limits);
+    parser.feed(reader.buffer());
+    reader.consume(reader.buffer().len());
+
+    // ACKs are sent both when the master asks for one and every second, so the connection is
+    // shared by the two threads sending them.
+    let acks = Mutex::new(stream);
+    let stopped = (Mutex::new(false), Condvar::new());
+
+    thread::scope(|scope| {
+        scope.spawn(|| send_acks(&replication.offset, &acks, &stopped));
+
+        let result = apply_stream(shared, &mut parser, &mut reader, &acks);
+
+        *stopped.0.lock().unwrap() = true;
+        stopped.1.notify_one();
+
+        result
+    })
+}
+
+/// Apply the command stream from the master until it closes the link.
+fn apply_stream(
+    shared: &Shared,
+    parser: &mut RespParser,
+    reader: &mut impl Read,
+    acks: &Mutex<TcpStream>,
+) -> Result<(), RedisError> {
+    let replication = &shared.replication;
+    let mut client = ClientState {
+        master: true,
+        rate_limit_exempt: true,
+        ..Default::default()
+    };
+
+    // The offset follows the bytes the master sent, which frames don't always encode back to.
+    let mut consumed = parser.consumed();
+    loop {
+        while let Some(frame) = parser.next_frame()? {
+            // Replies to the master are discarded, except the ACKs it asks for.
+            if let Ok(Command::Replconf(ReplconfCommand::GetAck)) = process_resp_type(&frame) {
+                let offset = replication.offset.load(Ordering::Relaxed).to_string();
+                send(&mut acks.lock().unwrap(), &["REPLCONF", "ACK", &offset])?;
+            } else {
+                process_frame(&frame, shared, &mut client, &mut io::sink())?;
+            }
+
+            let len = parser.consumed() - consumed;
+            consumed = parser.consumed();
+            replication.offset.fetch_add(len, Ordering::Relaxed);
+        }
+
+        if parser.read_from(reader)? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Acknowledge the replica's offset to the master every [`ACK_INTERVAL`] until `stopped` is set,
+/// which keeps the offset the master reports for the replica current between the ACKs it asks
+/// for.
+fn send_acks(offset: &AtomicU64, stream: &Mutex<TcpStream>, stopped: &(Mutex<bool>, Condvar)) {
+    loop {
+        let (done, _) = stopped
+            .1
+            .wait_timeout_while(stopped.0.lock().unwrap(), ACK_INTERVAL, |done| !*done)
+            .unwrap();
+        if *done {
+            return;
+        }
+        drop(done);
+
+        let offset = offset.load(Ordering::Relaxed).to_string();
+        if let Err(err) = send(&mut stream.lock().unwrap(), &["REPLCONF", "ACK", &offset]) {
+            // The link fails for the thread reading from it too.
+            tracing::debug!(%err, "failed to acknowledge the replication offset");
+            return;
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, args: &[&str]) -> Result<(), RedisError> {
+    let command = RespType::array(args.iter().map(|arg| RespType::from(*arg)).collect());
+    stream.write_all(&command.to_bytes(Protocol::Resp2))?;
+
+    Ok(())
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, RedisError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,178 @@
+//! A server without sockets or background threads, stepped explicitly so tests of interleaved
+//! clients are reproducible.
+
+use super::{process_frame, ClientState, Shared};
+use crate::{
+    cache::Cache,
+    ratelimit::RateLimiter,
+    resp_type::{RespParser, RespType},
+};
+use bytes::Bytes;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::Ordering,
+};
+
+/// A simulated connection. Replies are written to `replies` exactly like they would be to a socket
+/// and parsed back when read.
+struct SimulatedClient {
+    state: ClientState,
+    replies: RespParser,
+}
+
+/// Runs commands from simulated clients one at a time on the calling thread, in the order they
+/// were sent. Nothing happens between calls to [`Simulation::tick`]: keys only expire during a tick
+/// and time only moves if the server was built with a [`MockClock`](crate::clock::MockClock).
+///
+/// Clients aren't listed by CLIENT LIST and blocking commands aren't supported yet, since they'd
+/// block the only thread.
+pub struct Simulation {
+    shared: Shared,
+    clients: HashMap<u64, SimulatedClient>,
+    queue: VecDeque<(u64, RespType)>,
+}
+
+impl Simulation {
+    pub(super) fn new(shared: Shared) -> Self {
+        Self {
+            shared,
+            clients: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.shared.cache
+    }
+
+    /// Connect a new client, returning its ID.
+    pub fn connect(&mut self) -> u64 {
+        let id = self.shared.clients.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let client = SimulatedClient {
+            state: ClientState {
+                id,
+                limiter: RateLimiter::new(&self.shared.limits),
+                ..Default::default()
+            },
+            replies: RespParser::new(),
+        };
+
+        self.clients.insert(id, client);
+
+        id
+    }
+
+    /// Disconnect a client. Commands it has sent but that haven't run yet are dropped.
+    pub fn disconnect(&mut self, client: u64) {
+        self.clients.remove(&client);
+        self.queue.retain(|(id, _)| *id != client);
+    }
+
+    /// Queue a command from `client`. It runs on the tick after every command sent before it.
+    pub fn send<S: AsRef<[u8]>>(&mut self, client: u64, args: &[S]) {
+        assert!(
+            self.clients.contains_key(&client),
+            "unknown client {client}"
+        );
+
+        let command = args
+            .iter()
+            .map(|arg| RespType::bulk(Bytes::copy_from_slice(arg.as_ref())))
+            .collect();
+
+        self.queue.push_back((client, RespType::array(command)));
+    }
+
+    /// Run the next queued command followed by an expiry cycle. Returns false if there was no
+    /// command to run.
+    pub fn tick(&mut self) -> bool {
+        let ran = match self.queue.pop_front() {
+            Some((id, frame)) => {
+                let client = self
+                    .clients
+                    .get_mut(&id)
+                    .expect("queued by a connected client");
+
+                let mut out = Vec::new();
+                process_frame(&frame, &self.shared, &mut client.state, &mut out)
+                    .expect("writing to a vector doesn't fail");
+                client.replies.feed(&out);
+
+                true
+            }
+            None => false,
+        };
+
+        self.shared.cache.evict_expired();
+
+        ran
+    }
+
+    /// Tick until every queued command has run.
+    pub fn run(&mut self) {
+        while self.tick() {}
+    }
+
+    /// The oldest reply to `client` that hasn't been read yet.
+    pub fn reply(&mut self, client: u64) -> Option<RespType> {
+        self.clients
+            .get_mut(&client)?
+            .replies
+            .next_frame()
+            .expect("replies are valid RESP")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{clock::MockClock, server::Server};
+
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn test_interleaved_clients() {
+        let clock = Arc::new(MockClock::new());
+        let mut sim = Server::builder().clock(clock.clone()).simulate();
+
+        let (a, b) = (sim.connect(), sim.connect());
+        assert_ne!(a, b);
+
+        sim.send(a, &["SET", "k", "1"]);
+        sim.send(b, &["GET", "k"]);
+        sim.send(a, &["SET", "k", "2", "PX", "100"]);
+        sim.send(b, &["GET", "k"]);
+
+        // Nothing runs until the simulation is stepped.
+        assert_eq!(sim.reply(b), None);
+        assert!(sim.tick());
+        assert!(sim.tick());
+        assert_eq!(sim.reply(a), Some(RespType::ok()));
+        assert_eq!(sim.reply(b), Some("1".into()));
+
+        sim.run();
+        assert!(!sim.tick());
+        assert_eq!(sim.reply(a), Some(RespType::ok()));
+        assert_eq!(sim.reply(b), Some("2".into()));
+        assert_eq!(sim.reply(b), None);
+
+        // The key only expires on the first tick after the clock passes its deadline.
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(sim.cache().stats().expired_keys, 0);
+        sim.tick();
+        assert_eq!(sim.cache().stats().expired_keys, 1);
+
+        sim.send(b, &["CLIENT", "ID"]);
+        sim.send(b, &["HELLO", "3"]);
+        sim.send(b, &["GET", "k"]);
+        sim.run();
+        assert_eq!(sim.reply(b), Some((b as i64).into()));
+        assert!(matches!(sim.reply(b), Some(RespType::Map(..))));
+        assert_eq!(sim.reply(b), Some(RespType::Null));
+
+        sim.send(a, &["GET", "k"]);
+        sim.disconnect(a);
+        assert!(!sim.tick());
+    }
+}
@@ -0,0 +1,110 @@
+//! Transactions. Commands sent after MULTI are queued until EXEC runs them all at once.
+//!
+//! Every other command holds the server's exec lock for reading while it runs, so EXEC taking it
+//! for writing keeps other clients from seeing or changing the keyspace halfway through. Blocking
+//! commands let go of it before they wait since EXEC would wait on them while they block, and in
+//! a transaction they give up right away rather than block with the lock held.
+
+use super::{command_span, cross_slot, propagate, run_guarded, ClientState, Shared};
+use crate::{command::Command, error::RedisError, resp_type::RespType};
+
+use std::sync::PoisonError;
+
+/// The commands queued by a client since MULTI.
+#[derive(Debug, Default)]
+pub(super) struct Transaction {
+    /// The commands with the frames they were sent in, which are propagated to replicas.
+    queued: Vec<(Command, RespType)>,
+    /// A command couldn't be queued, so EXEC discards the transaction.
+    failed: bool,
+}
+
+impl Transaction {
+    pub(super) fn queue(&mut self, command: Command, frame: &RespType) -> RespType {
+        self.queued.push((command, frame.clone()));
+
+        RespType::simple("QUEUED")
+    }
+
+    pub(super) fn fail(&mut self) {
+        self.failed = true;
+    }
+}
+
+pub(super) fn multi(client: &mut ClientState) -> RespType {
+    if client.transaction.is_some() {
+        return RedisError::Other("MULTI calls can not be nested".to_string()).to_resp();
+    }
+
+    client.transaction = Some(Transaction::default());
+
+    RespType::ok()
+}
+
+pub(super) fn discard(client: &mut ClientState) -> RespType {
+    match client.transaction.take() {
+        Some(_) => RespType::ok(),
+        None => RedisError::Other("DISCARD without MULTI".to_string()).to_resp(),
+    }
+}
+
+/// Run the queued commands, replying with an array of their replies.
+pub(super) fn exec(shared: &Shared, client: &mut ClientState) -> RespType {
+    let Some(transaction) = client.transaction.take() else {
+        return RedisError::Other("EXEC without MULTI".to_string()).to_resp();
+    };
+
+    if transaction.failed {
+        return RespType::error(
+            "EXECABORT",
+            "Transaction discarded because of previous errors.",
+        );
+    }
+
+    // Each command's keys are checked as it runs, but in a cluster the whole transaction has to
+    // stay in one slot too.
+    if shared.cluster_enabled {
+        let keys: Vec<&str> = transaction
+            .queued
+            .iter()
+            .flat_map(|(command, _)| command.keys())
+            .collect();
+        if cross_slot(&keys) {
+            return RedisError::CrossSlot.to_resp();
+        }
+    }
+
+    let _exclusive = shared
+        .exec_lock
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    // Replicas and the append-only file get the writes as a transaction of their own so they're
+    // applied at once too.
+    let writes = transaction
+        .queued
+        .iter()
+        .any(|(command, _)| command.is_write());
+    if writes {
+        propagate(shared, &RespType::array(vec!["MULTI".into()]));
+    }
+
+    client.deny_blocking = true;
+    let replies = transaction
+        .queued
+        .iter()
+        .map(|(command, frame)| {
+            let span = command_span(command);
+            let _span = span.enter();
+
+            run_guarded(command, frame, shared, client, &span, None)
+        })
+        .collect();
+    client.deny_blocking = false;
+
+    if writes {
+        propagate(shared, &RespType::array(vec!["EXEC".into()]));
+    }
+
+    RespType::array(replies)
+}
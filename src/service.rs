@@ -0,0 +1,138 @@
+//! Integration with service managers: systemd's `sd_notify` protocol for `Type=notify` units and
+//! waiting for the signals used to stop the server.
+
+use crate::server::Server;
+
+use std::{io, sync::Arc, time::Duration};
+
+/// Send a state like `READY=1` to the service manager. Does nothing unless started by one that
+/// set `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_socket(&path.to_string_lossy(), state),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn notify_socket(path: &str, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    // A leading `@` refers to a socket in the abstract namespace.
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_socket(_path: &str, _state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// How often to ping the watchdog, half of `WATCHDOG_USEC` like systemd recommends. `None` if the
+/// watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok();
+    let pid = std::env::var("WATCHDOG_PID").ok();
+
+    parse_watchdog(usec.as_deref(), pid.as_deref(), std::process::id())
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // The variables are inherited by children, so only use them if they're meant for us.
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Ping the watchdog for as long as `server` accepts connections, if it's enabled. The pings come
+/// from the server's timer thread so they stop if it gets stuck.
+pub fn start_watchdog(server: &Arc<Server>) {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+
+    server.heartbeat(interval, || {
+        if let Err(err) = notify("WATCHDOG=1") {
+            tracing::warn!(%err, "failed to ping the watchdog");
+        }
+    });
+}
+
+/// Block until the process receives SIGINT or, on Unix, SIGTERM.
+pub fn wait_for_shutdown_signal() -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate())?;
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => result,
+                _ = terminate.recv() => Ok(()),
+            }
+        }
+
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_watchdog() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 1),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("1"), 1),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("2"), 1), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 1), None);
+        assert_eq!(parse_watchdog(None, None, 1), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(&path.to_string_lossy(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use crate::resp_type::RespType;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Number of linear sub-buckets per power of two, giving a relative error of at most 1/16.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// A log-linear histogram of microsecond latencies. Values are grouped by their highest set bit and
+/// each group is split into equally sized sub-buckets, so memory stays constant regardless of the
+/// number of samples while percentiles stay within a few percent of the true value.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    fn bucket(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+
+        let exponent = 63 - value.leading_zeros();
+        let sub = (value >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+
+        ((exponent - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+    }
+
+    /// The highest value that falls into the bucket.
+    fn bucket_value(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+
+        let exponent = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+        let sub = bucket % SUB_BUCKETS;
+        let width = 1u64 << (exponent - SUB_BUCKET_BITS);
+
+        (1u64 << exponent) + (sub + 1) * width - 1
+    }
+
+    fn record(&mut self, value: u64) {
+        let bucket = Self::bucket(value);
+        if bucket >= self.counts.len() {
+            self.counts.resize(bucket + 1, 0);
+        }
+
+        self.counts[bucket] += 1;
+        self.total += 1;
+    }
+
+    /// The value below which `percentile` percent of all samples fall.
+    fn percentile(&self, percentile: f64) -> u64 {
+        let rank = ((percentile / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_value(bucket);
+            }
+        }
+
+        0
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct CommandStat {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+    latency: Histogram,
+}
+
+/// Per command and per error code counters, reported by the commandstats, latencystats and
+/// errorstats INFO sections. Calls are commands that were executed, with failed calls being the ones replying with
+/// an error. Rejected calls never executed, e.g. due to wrong arity or a before hook.
+#[derive(Debug, Default)]
+pub(crate) struct CommandStats {
+    /// Every executed command, reported as `total_commands_processed` in the INFO stats section.
+    processed: AtomicU64,
+    commands: Mutex<BTreeMap<String, CommandStat>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CommandStats {
+    pub(crate) fn record_call(&self, name: &str, reply: &RespType, elapsed: Duration) {
+        let failed = self.record_reply(reply);
+        self.processed.fetch_add(1, Ordering::Relaxed);
+
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(name.to_string()).or_default();
+        stat.calls += 1;
+        stat.usec += elapsed.as_micros() as u64;
+        stat.latency.record(elapsed.as_micros() as u64);
+        if failed {
+            stat.failed_calls += 1;
+        }
+    }
+
+    /// Record a command that was rejected before being executed. The name is `None` if the command
+    /// doesn't exist.
+    pub(crate) fn record_rejected(&self, name: Option<&str>, reply: &RespType) {
+        self.record_reply(reply);
+
+        if let Some(name) = name {
+            let mut commands = self.commands.lock().unwrap();
+            commands.entry(name.to_string()).or_default().rejected_calls += 1;
+        }
+    }
+
+    /// Count the error code if the reply is an error, returning whether it was.
+    fn record_reply(&self, reply: &RespType) -> bool {
+        let message = match reply {
+            RespType::SimpleError(message) | RespType::BulkError(_, message) => message,
+            _ => return false,
+        };
+
+        let code = message.split(' ').next().unwrap_or_default();
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(code.to_string())
+            .or_default() += 1;
+
+        true
+    }
+
+    /// The lines to add to the stats section.
+    pub(crate) fn stats_info(&self) -> String {
+        format!(
+            "total_commands_processed:{}\r\n",
+            self.processed.load(Ordering::Relaxed)
+        )
+    }
+
+    pub(crate) fn commandstats_info(&self) -> String {
+        let mut info = "# Commandstats\r\n".to_string();
+
+        for (name, stat) in self.commands.lock().unwrap().iter() {
+            let usec_per_call = match stat.calls {
+                0 => 0.0,
+                calls => stat.usec as f64 / calls as f64,
+            };
+
+            info.push_str(&format!(
+                "cmdstat_{name}:calls={},usec={},usec_per_call={usec_per_call:.2},rejected_calls={},failed_calls={}\r\n",
+                stat.calls, stat.usec, stat.rejected_calls, stat.failed_calls,
+            ));
+        }
+
+        info
+    }
+
+    pub(crate) fn latencystats_info(&self) -> String {
+        let mut info = "# Latencystats\r\n".to_string();
+
+        for (name, stat) in self.commands.lock().unwrap().iter() {
+            if stat.latency.total == 0 {
+                continue;
+            }
+
+            info.push_str(&format!(
+                "latency_percentiles_usec_{name}:p50={:.3},p99={:.3},p99.9={:.3}\r\n",
+                stat.latency.percentile(50.0) as f64,
+                stat.latency.percentile(99.0) as f64,
+                stat.latency.percentile(99.9) as f64,
+            ));
+        }
+
+        info
+    }
+
+    pub(crate) fn errorstats_info(&self) -> String {
+        let mut info = "# Errorstats\r\n".to_string();
+
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            info.push_str(&format!("errorstat_{code}:count={count}\r\n"));
+        }
+
+        info
+    }
+}
+
+/// Socket reads and writes made by connections, reported as `total_reads_processed` and
+/// `total_writes_processed` in the INFO stats section.
+#[derive(Debug, Default)]
+pub(crate) struct IoStats {
+    pub(crate) reads: AtomicU64,
+    pub(crate) writes: AtomicU64,
+}
+
+impl IoStats {
+    /// The lines to add to the stats section.
+    pub(crate) fn stats_info(&self) -> String {
+        format!(
+            "total_reads_processed:{}\r\ntotal_writes_processed:{}\r\n",
+            self.reads.load(Ordering::Relaxed),
+            self.writes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        for value in [0, 1, 15, 16, 17, 100, 1000, 123_456, u32::MAX as u64] {
+            let bucket = Histogram::bucket(value);
+            assert!(Histogram::bucket_value(bucket) >= value);
+            assert!(bucket == 0 || Histogram::bucket_value(bucket - 1) < value);
+        }
+
+        let mut histogram = Histogram::default();
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        let within = |actual: u64, expected: u64| actual.abs_diff(expected) <= expected / 16;
+        assert!(within(histogram.percentile(50.0), 500));
+        assert!(within(histogram.percentile(99.0), 990));
+        assert!(within(histogram.percentile(99.9), 999));
+        assert_eq!(histogram.percentile(100.0), 1023);
+    }
+
+    #[test]
+    fn test_command_stats() {
+        let stats = CommandStats::default();
+        stats.record_call("get", &RespType::null(), Duration::from_micros(10));
+        stats.record_call("get", &RespType::null(), Duration::from_micros(20));
+        stats.record_call(
+            "set",
+            &RespType::error("ERR", "syntax error"),
+            Duration::from_micros(1),
+        );
+        stats.record_rejected(
+            Some("set"),
+            &RespType::error("ERR", "wrong number of arguments for 'set' command"),
+        );
+        stats.record_rejected(None, &RespType::error("WRONGTYPE", "Operation"));
+
+        assert_eq!(
+            stats.commandstats_info(),
+            "# Commandstats\r\n\
+             cmdstat_get:calls=2,usec=30,usec_per_call=15.00,rejected_calls=0,failed_calls=0\r\n\
+             cmdstat_set:calls=1,usec=1,usec_per_call=1.00,rejected_calls=1,failed_calls=1\r\n"
+        );
+        assert_eq!(
+            stats.errorstats_info(),
+            "# Errorstats\r\nerrorstat_ERR:count=2\r\nerrorstat_WRONGTYPE:count=1\r\n"
+        );
+        assert_eq!(
+            stats.latencystats_info(),
+            "# Latencystats\r\n\
+             latency_percentiles_usec_get:p50=10.000,p99=20.000,p99.9=20.000\r\n\
+             latency_percentiles_usec_set:p50=1.000,p99=1.000,p99.9=1.000\r\n"
+        );
+    }
+}
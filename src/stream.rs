@@ -0,0 +1,613 @@
+//! The stream type, an append-only log of entries ordered by their IDs, and the commands working
+//! on it. Reading with XREAD BLOCK waits on the server's [`BlockedClients`] for entries to be
+//! added.
+
+use crate::{
+    blocking::{BlockedClients, Blocking, Served},
+    cache::{Cache, Change, MemoryUsage, Value, ValueType},
+    command::StreamCommand,
+    error::RedisError,
+    resp_type::{Protocol, RespType},
+};
+
+use std::{collections::BTreeMap, fmt, ops::Bound};
+
+/// The ID of a stream entry, the Unix time in milliseconds it was added at and a sequence number
+/// for entries added in the same millisecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub(crate) struct StreamId {
+    ms: u64,
+    seq: u64,
+}
+
+impl StreamId {
+    const MIN: Self = Self { ms: 0, seq: 0 };
+    const MAX: Self = Self {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parse `ms-seq`, or just `ms` with the sequence number defaulting to `seq`.
+    fn parse(id: &str, seq: u64) -> Result<Self, RedisError> {
+        let invalid =
+            || RedisError::Other("Invalid stream ID specified as stream command argument".into());
+
+        match id.split_once('-') {
+            Some((ms, seq)) => Ok(Self {
+                ms: ms.parse().map_err(|_| invalid())?,
+                seq: seq.parse().map_err(|_| invalid())?,
+            }),
+            None => Ok(Self {
+                ms: id.parse().map_err(|_| invalid())?,
+                seq,
+            }),
+        }
+    }
+
+    fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self { seq, ..self }),
+            None => Some(Self {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+type Fields = Vec<(String, String)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    /// The ID of the last entry ever added, which new IDs must be greater than even if the entry
+    /// has been trimmed.
+    last_id: StreamId,
+}
+
+impl Stream {
+    /// The ID for a new entry from the ID given to XADD: `*` to generate one from the time, `ms-*`
+    /// to generate the sequence number or an explicit ID.
+    fn next_id(&self, id: &str, now_ms: u64) -> Result<StreamId, RedisError> {
+        let last = self.last_id;
+
+        let id = match id.strip_suffix("-*") {
+            _ if id == "*" => match now_ms > last.ms {
+                true => StreamId { ms: now_ms, seq: 0 },
+                false => last.next().ok_or_else(too_small)?,
+            },
+            Some(ms) => {
+                let ms = StreamId::parse(ms, 0)?.ms;
+                match ms.cmp(&last.ms) {
+                    std::cmp::Ordering::Less => return Err(too_small()),
+                    std::cmp::Ordering::Equal => last.next().ok_or_else(too_small)?,
+                    // 0-0 isn't a valid ID.
+                    std::cmp::Ordering::Greater => StreamId {
+                        ms,
+                        seq: u64::from(ms == 0),
+                    },
+                }
+            }
+            None => StreamId::parse(id, 0)?,
+        };
+
+        if id == StreamId::MIN {
+            return Err(RedisError::Other(
+                "The ID specified in XADD must be greater than 0-0".to_string(),
+            ));
+        }
+
+        if id <= last {
+            return Err(too_small());
+        }
+
+        Ok(id)
+    }
+
+    fn add(&mut self, id: StreamId, fields: Fields, maxlen: Option<usize>) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+
+        if let Some(maxlen) = maxlen {
+            while self.entries.len() > maxlen {
+                self.entries.pop_first();
+            }
+        }
+    }
+
+    /// The commands that recreate the stream under `key`, used to rewrite the append-only file. A
+    /// stream that was trimmed empty gets an entry that's trimmed right away, which keeps its last
+    /// ID.
+    pub(crate) fn commands(&self, key: &str) -> Vec<RespType> {
+        let xadd = |id: StreamId, maxlen: Option<&str>, fields: &[(String, String)]| {
+            let mut args = vec!["XADD".into(), key.into()];
+            if let Some(maxlen) = maxlen {
+                args.extend(["MAXLEN".into(), maxlen.into()]);
+            }
+            args.push(id.to_string().into());
+            for (field, value) in fields {
+                args.extend([field.as_str().into(), value.as_str().into()]);
+            }
+
+            RespType::array(args)
+        };
+
+        match self.entries.is_empty() {
+            true => vec![xadd(
+                self.last_id,
+                Some("0"),
+                &[(String::new(), String::new())],
+            )],
+            false => self
+                .entries
+                .iter()
+                .map(|(id, fields)| xadd(*id, None, fields))
+                .collect(),
+        }
+    }
+
+    /// The entries between `start` and `end`, oldest first unless `rev`.
+    fn range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<RespType> {
+        let count = count.unwrap_or(usize::MAX);
+
+        // BTreeMap::range panics on inverted ranges, which are simply empty here.
+        let inverted = match (start, end) {
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start > end,
+            _ => false,
+        };
+        let empty = matches!((start, end), (Bound::Excluded(a), Bound::Excluded(b)) if a == b);
+        if inverted || empty {
+            return Vec::new();
+        }
+
+        let range = self.entries.range((start, end));
+        if rev {
+            range.rev().take(count).map(entry_reply).collect()
+        } else {
+            range.take(count).map(entry_reply).collect()
+        }
+    }
+
+    pub(crate) fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+}
+
+impl MemoryUsage for Stream {
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .entries
+                .values()
+                .map(|fields| {
+                    std::mem::size_of::<(StreamId, Fields)>()
+                        + fields
+                            .iter()
+                            .map(|(field, value)| {
+                                std::mem::size_of::<(String, String)>()
+                                    + field.capacity()
+                                    + value.capacity()
+                            })
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+
+    fn elements(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn too_small() -> RedisError {
+    RedisError::Other(
+        "The ID specified in XADD is equal or smaller than the target stream top item".to_string(),
+    )
+}
+
+fn entry_reply((id, fields): (&StreamId, &Fields)) -> RespType {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [field.as_str().into(), value.as_str().into()])
+        .collect();
+
+    RespType::array(vec![id.to_string().into(), RespType::array(fields)])
+}
+
+/// Parse a range bound of XRANGE: `-` and `+` for the smallest and largest IDs, a `(` prefix for
+/// an exclusive bound and an ID without a sequence number meaning all of that millisecond.
+fn parse_bound(bound: &str, start: bool) -> Result<Bound<StreamId>, RedisError> {
+    match bound {
+        "-" => return Ok(Bound::Included(StreamId::MIN)),
+        "+" => return Ok(Bound::Included(StreamId::MAX)),
+        _ => (),
+    }
+
+    let default_seq = if start { 0 } else { u64::MAX };
+    match bound.strip_prefix('(') {
+        Some(id) => Ok(Bound::Excluded(StreamId::parse(id, default_seq)?)),
+        None => Ok(Bound::Included(StreamId::parse(bound, default_seq)?)),
+    }
+}
+
+pub(crate) fn execute(command: &StreamCommand, cache: &Cache) -> RespType {
+    let result = match command {
+        StreamCommand::Add {
+            key,
+            id,
+            no_mkstream,
+            maxlen,
+            fields,
+        } => add(key, id, *no_mkstream, *maxlen, fields, cache),
+        StreamCommand::Range {
+            key,
+            start,
+            end,
+            count,
+            rev,
+        } => range(key, start, end, *count, *rev, cache),
+        StreamCommand::Len { key } => {
+            let len = cache.read(key, |value| match value {
+                Value::Stream(stream) => stream.entries.len(),
+                _ => 0,
+            });
+
+            Ok(RespType::from(len.unwrap_or_default() as i64))
+        }
+        StreamCommand::Read { .. } => Err(RedisError::Other("xread requires a server".to_string())),
+    };
+
+    result.unwrap_or_else(|err| err.to_resp())
+}
+
+fn add(
+    key: &str,
+    id: &str,
+    no_mkstream: bool,
+    maxlen: Option<usize>,
+    fields: &Fields,
+    cache: &Cache,
+) -> Result<RespType, RedisError> {
+    let now_ms = cache.clock().unix_now().as_millis() as u64;
+
+    cache.update(key, |current| {
+        let mut created = None;
+        let stream = match current {
+            Some(Value::Stream(stream)) => stream,
+            Some(_) => return (Err(RedisError::WrongType), Change::Keep),
+            None if no_mkstream => return (Ok(RespType::null()), Change::Keep),
+            None => created.insert(Stream::default()),
+        };
+
+        let id = match stream.next_id(id, now_ms) {
+            Ok(id) => id,
+            Err(err) => return (Err(err), Change::Keep),
+        };
+        stream.add(id, fields.clone(), maxlen);
+
+        let reply = Ok(RespType::from(id.to_string()));
+        match created {
+            Some(stream) => (reply, Change::Set(Value::Stream(stream))),
+            None => (reply, Change::Modified),
+        }
+    })
+}
+
+fn range(
+    key: &str,
+    start: &str,
+    end: &str,
+    count: Option<usize>,
+    rev: bool,
+    cache: &Cache,
+) -> Result<RespType, RedisError> {
+    let start = parse_bound(start, true)?;
+    let end = parse_bound(end, false)?;
+
+    let entries = cache.read(key, |value| match value {
+        Value::Stream(stream) => stream.range(start, end, count, rev),
+        _ => Vec::new(),
+    });
+
+    Ok(RespType::array(entries.unwrap_or_default()))
+}
+
+/// XREAD: reply with the entries of each stream after the given IDs. If there aren't any and
+/// `block` is set, wait for one of the streams to get new entries for up to `block`, or forever
+/// if it's zero, and reply with the new entries of that stream. Without `blocked` the client may
+/// not block, like in a transaction.
+pub(crate) fn read(
+    command: &StreamCommand,
+    cache: &Cache,
+    blocked: Option<&BlockedClients>,
+    client_id: u64,
+    protocol: Protocol,
+) -> Blocking {
+    let done = |reply| Blocking::Done(Served::reply(reply));
+    let StreamCommand::Read {
+        count,
+        block,
+        keys,
+        ids,
+    } = command
+    else {
+        return done(RedisError::Syntax.to_resp());
+    };
+
+    // `$` means entries added after now.
+    let mut after = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        match cache.value_type(key) {
+            Some(ValueType::Stream) | None => (),
+            Some(_) => return done(RedisError::WrongType.to_resp()),
+        }
+
+        let id = match id.as_str() {
+            "$" => cache
+                .read(key, |value| match value {
+                    Value::Stream(stream) => stream.last_id(),
+                    _ => StreamId::MIN,
+                })
+                .unwrap_or_default(),
+            id => match StreamId::parse(id, 0) {
+                Ok(id) => id,
+                Err(err) => return done(err.to_resp()),
+            },
+        };
+
+        after.push((key.clone(), id));
+    }
+
+    let streams = after
+        .iter()
+        .filter_map(|(key, id)| new_entries(key, *id, *count, cache))
+        .collect::<Vec<_>>();
+
+    if !streams.is_empty() {
+        return done(streams_reply(streams, protocol));
+    }
+
+    let (Some(block), Some(blocked)) = (block, blocked) else {
+        return done(RespType::null());
+    };
+
+    let count = *count;
+    let timeout = Some(*block).filter(|block| !block.is_zero());
+    blocked.block(client_id, cache, keys, timeout, move |ready, cache| {
+        let (key, id) = after.iter().find(|(key, _)| key == ready)?;
+        let entries = new_entries(key, *id, count, cache)?;
+
+        Some(Served::reply(streams_reply(vec![entries], protocol)))
+    })
+}
+
+/// The entries of the stream after `id`, or `None` if there aren't any.
+fn new_entries(
+    key: &str,
+    id: StreamId,
+    count: Option<usize>,
+    cache: &Cache,
+) -> Option<(String, Vec<RespType>)> {
+    let entries = cache.read(key, |value| match value {
+        Value::Stream(stream) => stream.range(Bound::Excluded(id), Bound::Unbounded, count, false),
+        _ => Vec::new(),
+    })?;
+
+    (!entries.is_empty()).then(|| (key.to_string(), entries))
+}
+
+/// XREAD replies with a map of stream to entries, or pairs of them in RESP2.
+fn streams_reply(streams: Vec<(String, Vec<RespType>)>, protocol: Protocol) -> RespType {
+    let streams = streams
+        .into_iter()
+        .map(|(key, entries)| (RespType::from(key), RespType::array(entries)));
+
+    match protocol {
+        Protocol::Resp2 => RespType::array(
+            streams
+                .map(|(key, entries)| RespType::array(vec![key, entries]))
+                .collect(),
+        ),
+        Protocol::Resp3 => RespType::map(streams.collect()),
+    }
+}
+
+/// The XADD to send to replicas, with the ID the entry got in place of the one given so replicas
+/// don't generate IDs of their own.
+pub(crate) fn propagated(command: &StreamCommand, reply: &RespType) -> Option<RespType> {
+    let (
+        StreamCommand::Add {
+            key,
+            maxlen,
+            fields,
+            ..
+        },
+        RespType::BulkString(_, id),
+    ) = (command, reply)
+    else {
+        return None;
+    };
+
+    let mut args = vec!["XADD".into(), key.as_str().into()];
+    if let Some(maxlen) = maxlen {
+        args.extend(["MAXLEN".into(), maxlen.to_string().into()]);
+    }
+    args.push(RespType::bulk(id.clone()));
+    for (field, value) in fields {
+        args.extend([field.as_str().into(), value.as_str().into()]);
+    }
+
+    Some(RespType::array(args))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock},
+        command::Command,
+        testing::run,
+        timer::TimerWheel,
+    };
+
+    use std::sync::Arc;
+
+    fn entry(id: &str, fields: &[&str]) -> RespType {
+        RespType::array(vec![
+            id.into(),
+            RespType::array(fields.iter().map(|f| RespType::from(*f)).collect()),
+        ])
+    }
+
+    #[test]
+    fn test_add_ids() {
+        let cache = Cache::new(1);
+
+        assert_eq!(run(&cache, &["XADD", "s", "1-1", "a", "1"]), "1-1".into());
+        assert_eq!(run(&cache, &["XADD", "s", "1-*", "a", "2"]), "1-2".into());
+        assert_eq!(run(&cache, &["XADD", "s", "2-*", "a", "3"]), "2-0".into());
+        assert_eq!(run(&cache, &["XADD", "t", "0-*", "a", "1"]), "0-1".into());
+        assert_eq!(
+            run(&cache, &["XADD", "s", "1-5", "a", "4"]),
+            RespType::error(
+                "ERR",
+                "The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        assert_eq!(
+            run(&cache, &["XADD", "u", "0-0", "a", "1"]),
+            RespType::error("ERR", "The ID specified in XADD must be greater than 0-0")
+        );
+        assert_eq!(
+            run(&cache, &["XADD", "s", "NOMKSTREAM", "x", "a"]),
+            RespType::error("ERR", "wrong number of arguments for 'xadd' command")
+        );
+        assert_eq!(
+            run(&cache, &["XADD", "missing", "NOMKSTREAM", "*", "a", "1"]),
+            RespType::null()
+        );
+
+        // Generated IDs use the time, and keep increasing if the clock is behind the last ID.
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::without_eviction_loop(1, clock.clone());
+        let now = clock.unix_now().as_millis();
+        assert_eq!(
+            run(&cache, &["XADD", "s", "*", "a", "1"]),
+            format!("{now}-0").as_str().into()
+        );
+        assert_eq!(
+            run(&cache, &["XADD", "s", "*", "a", "1"]),
+            format!("{now}-1").as_str().into()
+        );
+        assert_eq!(run(&cache, &["XLEN", "s"]), 2.into());
+        assert_eq!(cache.value_type("s"), Some(ValueType::Stream));
+    }
+
+    #[test]
+    fn test_range() {
+        let cache = Cache::new(1);
+        for id in ["1-0", "1-1", "2-0", "3-0"] {
+            run(&cache, &["XADD", "s", id, "id", id]);
+        }
+
+        assert_eq!(
+            run(&cache, &["XRANGE", "s", "-", "+", "COUNT", "2"]),
+            RespType::array(vec![
+                entry("1-0", &["id", "1-0"]),
+                entry("1-1", &["id", "1-1"])
+            ])
+        );
+        assert_eq!(
+            run(&cache, &["XRANGE", "s", "1", "2"]),
+            RespType::array(vec![
+                entry("1-0", &["id", "1-0"]),
+                entry("1-1", &["id", "1-1"]),
+                entry("2-0", &["id", "2-0"]),
+            ])
+        );
+        assert_eq!(
+            run(&cache, &["XRANGE", "s", "(1-1", "+"]),
+            RespType::array(vec![
+                entry("2-0", &["id", "2-0"]),
+                entry("3-0", &["id", "3-0"])
+            ])
+        );
+        assert_eq!(
+            run(&cache, &["XREVRANGE", "s", "+", "-", "COUNT", "1"]),
+            RespType::array(vec![entry("3-0", &["id", "3-0"])])
+        );
+        assert_eq!(
+            run(&cache, &["XRANGE", "s", "3", "1"]),
+            RespType::array(vec![])
+        );
+        assert_eq!(
+            run(&cache, &["XRANGE", "missing", "-", "+"]),
+            RespType::array(vec![])
+        );
+
+        // Trimming keeps the newest entries, and the last ID even when it's removed.
+        run(&cache, &["XADD", "s", "MAXLEN", "1", "4-0", "id", "4-0"]);
+        assert_eq!(run(&cache, &["XLEN", "s"]), 1.into());
+    }
+
+    #[test]
+    fn test_read() {
+        let cache = Cache::new(1);
+        let blocked = BlockedClients::new(&cache, Arc::new(TimerWheel::new()));
+        let start = |args: &[&str]| {
+            let frames = args.iter().map(|a| RespType::from(*a)).collect::<Vec<_>>();
+            let Command::Stream(command) = Command::parse(&frames).unwrap() else {
+                panic!("not a stream command");
+            };
+            read(&command, &cache, Some(&blocked), 1, Protocol::Resp2)
+        };
+        let wait = |blocking| match blocking {
+            Blocking::Done(served) => served.reply,
+            Blocking::Waiting(waiting) => waiting.wait().unwrap_or_else(RespType::null),
+        };
+        let read = |args: &[&str]| wait(start(args));
+
+        run(&cache, &["XADD", "a", "1-0", "f", "v"]);
+        run(&cache, &["XADD", "b", "2-0", "f", "v"]);
+
+        assert_eq!(
+            read(&["XREAD", "STREAMS", "a", "b", "0", "2-0"]),
+            RespType::array(vec![RespType::array(vec![
+                "a".into(),
+                RespType::array(vec![entry("1-0", &["f", "v"])]),
+            ])])
+        );
+        assert_eq!(read(&["XREAD", "STREAMS", "a", "$"]), RespType::null());
+        assert_eq!(
+            read(&["XREAD", "BLOCK", "10", "STREAMS", "a", "$"]),
+            RespType::null()
+        );
+
+        // A blocked read is served by the next entry added to the stream.
+        let reader = start(&["XREAD", "BLOCK", "0", "STREAMS", "a", "$"]);
+        run(&cache, &["XADD", "a", "5-0", "f", "new"]);
+        blocked.serve_ready(&cache, &cache.lock_keys(&["a"]), |_| ());
+
+        assert_eq!(
+            wait(reader),
+            RespType::array(vec![RespType::array(vec![
+                "a".into(),
+                RespType::array(vec![entry("5-0", &["f", "new"])]),
+            ])])
+        );
+    }
+}
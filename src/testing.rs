@@ -0,0 +1,152 @@
+//! Helpers for end-to-end tests against a real server listening on an ephemeral port.
+
+use crate::{
+    client::Client,
+    resp_type::RespType,
+    server::{Server, ServerHandle},
+};
+
+use std::{net::SocketAddr, sync::Arc};
+
+/// A server running in the background for the duration of a test. It's shut down when dropped.
+pub struct TestServer {
+    server: Arc<Server>,
+    handle: Option<ServerHandle>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        Self::start_with(|_| ())
+    }
+
+    /// Start a server after letting `configure` register commands or hooks.
+    pub fn start_with(configure: impl FnOnce(&Server)) -> Self {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .build()
+            .expect("failed to bind test server");
+        configure(&server);
+
+        let handle = server.start();
+
+        Self {
+            server,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    pub fn client(&self) -> Client {
+        Client::connect(self.addr()).expect("failed to connect to test server")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+            handle.join();
+        }
+    }
+}
+
+/// Send a command and assert the reply matches `expected`, given as raw RESP. The expected bytes
+/// are parsed so equivalent encodings, like `$-1` and `*-1` for null, compare equal.
+#[track_caller]
+pub fn assert_reply<S: AsRef<[u8]>>(client: &mut Client, args: &[S], expected: &[u8]) {
+    let reply = client.command(args).expect("failed to read reply");
+    assert_eq!(reply, parse_expected(expected));
+}
+
+/// Assert the next already requested reply matches `expected`, given as raw RESP.
+#[track_caller]
+pub fn assert_next_reply(client: &mut Client, expected: &[u8]) {
+    let reply = client.read_reply().expect("failed to read reply");
+    assert_eq!(reply, parse_expected(expected));
+}
+
+#[track_caller]
+fn parse_expected(expected: &[u8]) -> RespType {
+    let mut reader = expected;
+    let frame = RespType::parse(&mut reader).expect("expected reply is not valid RESP");
+    assert!(reader.is_empty(), "expected reply has trailing data");
+
+    frame
+}
+
+/// Parse and execute a command directly against `cache`, replying with the parse error if it
+/// doesn't parse. Used by the unit tests of the commands.
+#[cfg(test)]
+pub(crate) fn run(cache: &crate::cache::Cache, args: &[&str]) -> RespType {
+    let frames = args.iter().map(|a| RespType::from(*a)).collect::<Vec<_>>();
+    match crate::command::Command::parse(&frames) {
+        Ok(command) => crate::command::execute(&command, cache),
+        Err(err) => err.to_resp(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_get() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        assert_reply(&mut client, &["GET", "k"], b"$-1\r\n");
+        assert_reply(&mut client, &["SET", "k", "hello world"], b"+OK\r\n");
+        assert_reply(&mut client, &["GET", "k"], b"$11\r\nhello world\r\n");
+        assert_reply(&mut client, &["SET", "k", "v"], b"+OK\r\n");
+        assert_reply(&mut client, &["get", "k"], b"$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn test_ttl() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        assert_reply(&mut client, &["SET", "k", "v", "PX", "50"], b"+OK\r\n");
+        assert_reply(&mut client, &["GET", "k"], b"$1\r\nv\r\n");
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_reply(&mut client, &["GET", "k"], b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let server = TestServer::start();
+        let mut client = server.client();
+
+        client
+            .send_raw(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .unwrap();
+        client.send(&["ECHO", "a\r\nb"]).unwrap();
+        client.send(&["GET"]).unwrap();
+
+        assert_next_reply(&mut client, b"+OK\r\n");
+        assert_next_reply(&mut client, b"$1\r\nv\r\n");
+        assert_next_reply(&mut client, b"$4\r\na\r\nb\r\n");
+        assert_next_reply(
+            &mut client,
+            b"-ERR wrong number of arguments for 'get' command\r\n",
+        );
+    }
+
+    #[test]
+    fn test_clients_share_keyspace() {
+        let server = TestServer::start();
+        let mut a = server.client();
+        let mut b = server.client();
+
+        assert_reply(&mut a, &["SET", "k", "v"], b"+OK\r\n");
+        assert_reply(&mut b, &["GET", "k"], b"$1\r\nv\r\n");
+    }
+}
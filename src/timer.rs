@@ -0,0 +1,384 @@
+use crate::error::panic_message;
+
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Each level has `SLOTS` slots, each covering `SLOTS` times the time of a slot in the level below.
+/// Ticks are a millisecond so the four levels cover about 4.6 hours, later timers wait in an
+/// overflow list.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+/// Identifies a scheduled timer so it can be cancelled.
+pub(crate) type TimerId = u64;
+
+struct Entry {
+    id: TimerId,
+    tick: u64,
+    callback: Callback,
+}
+
+/// Where an entry is stored, with `LEVELS` as the level meaning the overflow list.
+type Location = (usize, usize);
+
+struct Wheel {
+    /// The last tick that was processed.
+    now: u64,
+    levels: Vec<Vec<Vec<Entry>>>,
+    overflow: Vec<Entry>,
+    locations: HashMap<TimerId, Location>,
+    next_id: TimerId,
+    stopped: bool,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Self {
+            now: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            overflow: Vec::new(),
+            locations: HashMap::new(),
+            next_id: 0,
+            stopped: false,
+        }
+    }
+
+    /// Put the entry in the lowest level whose current rotation includes its tick, so that entries
+    /// only move down a level when the slot they're in is reached. The tick must not be before the
+    /// current one.
+    fn insert(&mut self, entry: Entry) {
+        let shift = |level: usize| SLOT_BITS * level as u32;
+
+        let location = (0..LEVELS)
+            .find(|&level| entry.tick >> shift(level + 1) == self.now >> shift(level + 1))
+            .map(|level| (level, (entry.tick >> shift(level)) as usize & (SLOTS - 1)))
+            .unwrap_or((LEVELS, 0));
+
+        self.locations.insert(entry.id, location);
+        match location {
+            (LEVELS, _) => self.overflow.push(entry),
+            (level, slot) => self.levels[level][slot].push(entry),
+        }
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        let Some(location) = self.locations.remove(&id) else {
+            return false;
+        };
+
+        let entries = match location {
+            (LEVELS, _) => &mut self.overflow,
+            (level, slot) => &mut self.levels[level][slot],
+        };
+        entries.retain(|entry| entry.id != id);
+
+        true
+    }
+
+    /// Process every tick up to and including `until`, returning the callbacks of expired timers
+    /// in the order they expired.
+    fn advance(&mut self, until: u64) -> Vec<Callback> {
+        let mut expired = Vec::new();
+        while self.now < until {
+            // Skip the ticks where nothing expires or cascades.
+            match self.next_tick() {
+                Some(next) if next <= until => self.now = next,
+                _ => {
+                    self.now = until;
+                    break;
+                }
+            }
+
+            if self.now & ((1 << (SLOT_BITS * LEVELS as u32)) - 1) == 0 {
+                for entry in std::mem::take(&mut self.overflow) {
+                    self.insert(entry);
+                }
+            }
+
+            // When a slot in a higher level is reached its entries are spread over the levels
+            // below, starting from the top so they cascade all the way down.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if self.now & ((1 << shift) - 1) == 0 {
+                    let slot = (self.now >> shift) as usize & (SLOTS - 1);
+                    for entry in std::mem::take(&mut self.levels[level][slot]) {
+                        self.insert(entry);
+                    }
+                }
+            }
+
+            let slot = self.now as usize & (SLOTS - 1);
+            for entry in std::mem::take(&mut self.levels[0][slot]) {
+                self.locations.remove(&entry.id);
+                expired.push(entry.callback);
+            }
+        }
+
+        expired
+    }
+
+    /// The earliest tick anything needs to happen at, either a timer expiring or a slot cascading.
+    fn next_tick(&self) -> Option<u64> {
+        if self.locations.is_empty() {
+            return None;
+        }
+
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let current = (self.now >> shift) as usize & (SLOTS - 1);
+
+            // Slots at this level are only reached going forward in the current rotation, the
+            // ones behind it were emptied when they were reached.
+            for slot in current + 1..SLOTS {
+                if !self.levels[level][slot].is_empty() {
+                    let rotation = self.now >> (shift + SLOT_BITS) << (shift + SLOT_BITS);
+                    return Some(rotation + ((slot as u64) << shift));
+                }
+            }
+        }
+
+        let rotation = 1 << (SLOT_BITS * LEVELS as u32);
+        Some((self.now / rotation + 1) * rotation)
+    }
+}
+
+struct Inner {
+    start: Instant,
+    wheel: Mutex<Wheel>,
+    changed: Condvar,
+}
+
+impl Inner {
+    /// The first tick at or after `instant`, rounding up so a timer never fires early.
+    fn tick_at(&self, instant: Instant) -> u64 {
+        let elapsed = instant.saturating_duration_since(self.start);
+        elapsed.as_nanos().div_ceil(1_000_000) as u64
+    }
+
+    /// The last tick that has fully passed.
+    fn current_tick(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn run(&self) {
+        let mut wheel = self.wheel.lock().unwrap();
+
+        loop {
+            if wheel.stopped {
+                return;
+            }
+
+            let expired = wheel.advance(self.current_tick());
+
+            if !expired.is_empty() {
+                // Callbacks may schedule or cancel timers so they run without the wheel locked.
+                drop(wheel);
+                for callback in expired {
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(callback)) {
+                        tracing::error!(panic = panic_message(&*panic), "timer callback panicked");
+                    }
+                }
+
+                wheel = self.wheel.lock().unwrap();
+                continue;
+            }
+
+            wheel = match wheel.next_tick() {
+                None => self.changed.wait(wheel).unwrap(),
+                Some(tick) => {
+                    let at = self.start + Duration::from_millis(tick);
+                    let timeout = at.saturating_duration_since(Instant::now());
+                    self.changed.wait_timeout(wheel, timeout).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+/// A hierarchical timer wheel running callbacks at their deadline on a single thread, so any number
+/// of timeouts is handled without a thread or a poll per timer. Scheduling and cancelling are
+/// constant time apart from the slot an entry is removed from.
+///
+/// Timers have millisecond resolution and never fire before their deadline. Callbacks run on the
+/// timer thread so they must be quick, like waking a thread or closing a socket.
+pub(crate) struct TimerWheel {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for TimerWheel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TimerWheel")
+            .field("timers", &self.inner.wheel.lock().unwrap().locations.len())
+            .finish()
+    }
+}
+
+impl TimerWheel {
+    /// Create the wheel and start its thread, which stops when the wheel is dropped.
+    pub(crate) fn new() -> Self {
+        let inner = Arc::new(Inner {
+            start: Instant::now(),
+            wheel: Mutex::new(Wheel::new()),
+            changed: Condvar::new(),
+        });
+
+        let thread_inner = inner.clone();
+        thread::Builder::new()
+            .name("timer-wheel".to_string())
+            .spawn(move || thread_inner.run())
+            .expect("failed to spawn timer thread");
+
+        Self { inner }
+    }
+
+    /// Run `callback` once `deadline` has passed unless the timer is cancelled first.
+    pub(crate) fn schedule(
+        &self,
+        deadline: Instant,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> TimerId {
+        let tick = self.inner.tick_at(deadline);
+        let mut wheel = self.inner.wheel.lock().unwrap();
+
+        wheel.next_id += 1;
+        let id = wheel.next_id;
+        let tick = tick.max(wheel.now + 1);
+        wheel.insert(Entry {
+            id,
+            tick,
+            callback: Box::new(callback),
+        });
+
+        // The thread might be sleeping until a later timer.
+        self.inner.changed.notify_one();
+
+        id
+    }
+
+    /// Cancel a timer, returning whether it was still pending.
+    pub(crate) fn cancel(&self, id: TimerId) -> bool {
+        self.inner.wheel.lock().unwrap().cancel(id)
+    }
+}
+
+impl Drop for TimerWheel {
+    fn drop(&mut self) {
+        self.inner.wheel.lock().unwrap().stopped = true;
+        self.inner.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Schedule entries at the given ticks directly on a wheel and collect the order they fire in.
+    fn fire_order(ticks: &[u64], until: u64) -> Vec<u64> {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let mut wheel = Wheel::new();
+
+        for (id, &tick) in ticks.iter().enumerate() {
+            let fired = fired.clone();
+            wheel.insert(Entry {
+                id: id as TimerId,
+                tick,
+                callback: Box::new(move || fired.lock().unwrap().push(tick)),
+            });
+        }
+
+        // Advance in uneven steps to cross cascade boundaries at arbitrary points.
+        let mut now = 0;
+        while now < until {
+            now = (now + 997).min(until);
+            for callback in wheel.advance(now) {
+                callback();
+            }
+        }
+
+        let fired = fired.lock().unwrap().clone();
+        fired
+    }
+
+    #[test]
+    fn test_fires_in_order_across_levels() {
+        let ticks = [
+            5,
+            1,
+            64,
+            63,
+            4096,
+            4095,
+            300_000,
+            1 << 24,
+            (1 << 24) + 1,
+            (1 << 25) + 7,
+        ];
+        let mut expected = ticks.to_vec();
+        expected.sort_unstable();
+
+        assert_eq!(fire_order(&ticks, 1 << 26), expected);
+    }
+
+    #[test]
+    fn test_next_tick() {
+        let mut wheel = Wheel::new();
+        assert_eq!(wheel.next_tick(), None);
+
+        for (id, tick) in [(1, 5000), (2, 70)] {
+            wheel.insert(Entry {
+                id,
+                tick,
+                callback: Box::new(|| ()),
+            });
+        }
+
+        // The timer at 70 is in the second level until the slot starting at 64 cascades.
+        assert_eq!(wheel.next_tick(), Some(64));
+        assert!(wheel.advance(64).is_empty());
+        assert_eq!(wheel.next_tick(), Some(70));
+        assert_eq!(wheel.advance(70).len(), 1);
+
+        assert!(wheel.cancel(1));
+        assert!(!wheel.cancel(1));
+        assert_eq!(wheel.next_tick(), None);
+    }
+
+    #[test]
+    fn test_schedule_and_cancel() {
+        let timers = TimerWheel::new();
+        let (tx, rx) = mpsc::channel();
+
+        let start = Instant::now();
+        for (delay, name) in [(30, "late"), (10, "early"), (20, "cancelled")] {
+            let tx = tx.clone();
+            let id = timers.schedule(start + Duration::from_millis(delay), move || {
+                tx.send((name, start.elapsed())).unwrap()
+            });
+
+            if name == "cancelled" {
+                assert!(timers.cancel(id));
+            }
+        }
+
+        let (first, elapsed) = rx.recv().unwrap();
+        assert_eq!(first, "early");
+        assert!(elapsed >= Duration::from_millis(10));
+
+        let (second, elapsed) = rx.recv().unwrap();
+        assert_eq!(second, "late");
+        assert!(elapsed >= Duration::from_millis(30));
+
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+}